@@ -3,24 +3,36 @@ use std::sync::Arc;
 use anyhow::Result;
 use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
 use iroh::{discovery::pkarr::PkarrRelayClient, node_info::NodeInfo, SecretKey};
-use iroh_dns_server::{config::Config, metrics::Metrics, server::Server, ZoneStore};
+use iroh_dns_server::{
+    config::Config, metrics::Metrics, server::Server, StoreBackend, ZoneStore, ZoneStoreOptions,
+};
 use rand_chacha::rand_core::SeedableRng;
 use tokio::runtime::Runtime;
 
 const LOCALHOST_PKARR: &str = "http://localhost:8080/pkarr";
 
-async fn start_dns_server(config: Config) -> Result<Server> {
+async fn start_dns_server(
+    config: Config,
+    backend: StoreBackend,
+    dir: &std::path::Path,
+) -> Result<Server> {
     let metrics = Arc::new(Metrics::default());
-    let store = ZoneStore::persistent(
-        Config::signed_packet_store_path()?,
-        Default::default(),
-        metrics.clone(),
-    )?;
+    let options = ZoneStoreOptions {
+        backend,
+        ..Default::default()
+    };
+    let store = ZoneStore::persistent(dir.join("signed-packets"), options, metrics.clone())?;
     Server::spawn(config, store, metrics).await
 }
 
 fn benchmark_dns_server(c: &mut Criterion) {
-    let mut group = c.benchmark_group("dns_server_writes");
+    for backend in [StoreBackend::Redb, StoreBackend::Log] {
+        benchmark_dns_server_with_backend(c, backend);
+    }
+}
+
+fn benchmark_dns_server_with_backend(c: &mut Criterion, backend: StoreBackend) {
+    let mut group = c.benchmark_group(format!("dns_server_writes_{backend:?}").to_lowercase());
     group.sample_size(10);
     for iters in [10_u64, 100_u64, 250_u64, 1000_u64].iter() {
         group.throughput(Throughput::Elements(*iters));
@@ -29,7 +41,8 @@ fn benchmark_dns_server(c: &mut Criterion) {
                 let rt = Runtime::new().unwrap();
                 rt.block_on(async move {
                     let config = Config::load("./config.dev.toml").await.unwrap();
-                    let server = start_dns_server(config).await.unwrap();
+                    let dir = tempfile::tempdir().unwrap();
+                    let server = start_dns_server(config, backend, dir.path()).await.unwrap();
 
                     let mut rng = rand_chacha::ChaCha8Rng::seed_from_u64(42);
                     let secret_key = SecretKey::generate(&mut rng);
@@ -13,8 +13,11 @@ use tracing::info;
 
 use crate::{
     dns::DnsConfig,
-    http::{CertMode, HttpConfig, HttpsConfig, RateLimitConfig},
-    store::ZoneStoreOptions,
+    http::{AdminConfig, CertMode, HttpConfig, HttpsConfig, KeyRateLimitConfig, RateLimitConfig},
+    policy::PacketPolicy,
+    replication::ReplicationConfig,
+    republish::RepublishConfig,
+    store::{StoreBackend, ZoneStoreOptions},
 };
 
 const DEFAULT_METRICS_ADDR: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 9117);
@@ -52,10 +55,35 @@ pub struct Config {
     /// Config for pkarr rate limit
     #[serde(default)]
     pub pkarr_put_rate_limit: RateLimitConfig,
+
+    /// Config for pkarr rate limit per public key.
+    ///
+    /// If set to `None` puts are not limited per key.
+    #[serde(default)]
+    pub pkarr_put_key_rate_limit: Option<KeyRateLimitConfig>,
+
+    /// Policy for packets published via pkarr puts.
+    #[serde(default)]
+    pub pkarr_put_policy: PacketPolicy,
+
+    /// Config for replication to other iroh-dns-server instances.
+    ///
+    /// If set to `None` replication is disabled.
+    #[serde(default)]
+    pub replication: Option<ReplicationConfig>,
+
+    /// Config for the admin API to inspect and manage the zone store.
+    ///
+    /// If set to `None` the admin API is disabled.
+    #[serde(default)]
+    pub admin: Option<AdminConfig>,
 }
 
 /// The config for the store.
+///
+/// Unset fields use the defaults of [`ZoneStoreOptions`].
 #[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default)]
 pub struct StoreConfig {
     /// Maximum number of packets to process in a single write transaction.
     max_batch_size: usize,
@@ -71,6 +99,11 @@ pub struct StoreConfig {
     /// Pause between eviction checks.
     #[serde(with = "humantime_serde")]
     eviction_interval: Duration,
+
+    /// The storage backend, `redb` or `log`.
+    ///
+    /// Defaults to `redb`.
+    backend: StoreBackend,
 }
 
 impl Default for StoreConfig {
@@ -86,6 +119,7 @@ impl From<ZoneStoreOptions> for StoreConfig {
             max_batch_time: value.max_batch_time,
             eviction: value.eviction,
             eviction_interval: value.eviction_interval,
+            backend: value.backend,
         }
     }
 }
@@ -97,6 +131,7 @@ impl From<StoreConfig> for ZoneStoreOptions {
             max_batch_time: value.max_batch_time,
             eviction: value.eviction,
             eviction_interval: value.eviction_interval,
+            backend: value.backend,
         }
     }
 }
@@ -131,6 +166,12 @@ pub struct MainlineConfig {
     ///
     /// If empty this will use the default bittorrent mainline bootstrap nodes as defined by pkarr.
     pub bootstrap: Option<Vec<String>>,
+    /// Publish packets received via the pkarr relay API to the mainline DHT.
+    ///
+    /// This is independent of `enabled`, which only controls the lookup. If set to `None`
+    /// packets are not published to the DHT.
+    #[serde(default)]
+    pub republish: Option<RepublishConfig>,
 }
 
 /// Configure the bootstrap servers for mainline DHT resolution.
@@ -149,6 +190,7 @@ impl Default for MainlineConfig {
         Self {
             enabled: false,
             bootstrap: None,
+            republish: None,
         }
     }
 }
@@ -185,6 +227,14 @@ impl Config {
         Ok(Self::data_dir()?.join("signed-packets-1.db"))
     }
 
+    /// Get the path to the store file for `backend`.
+    pub fn signed_packet_store_path_for(backend: StoreBackend) -> Result<PathBuf> {
+        match backend {
+            StoreBackend::Redb => Self::signed_packet_store_path(),
+            StoreBackend::Log => Ok(Self::data_dir()?.join("signed-packets-1.log")),
+        }
+    }
+
     /// Get the address where the metrics server should be bound, if set.
     pub(crate) fn metrics_addr(&self) -> Option<SocketAddr> {
         match &self.metrics {
@@ -209,6 +259,16 @@ impl Config {
             }) => Some(BootstrapOption::Default),
         }
     }
+
+    pub(crate) fn mainline_republish(&self) -> Option<(BootstrapOption, RepublishConfig)> {
+        let mainline = self.mainline.as_ref()?;
+        let republish = mainline.republish.clone()?;
+        let bootstrap = match &mainline.bootstrap {
+            Some(bootstrap) => BootstrapOption::Custom(bootstrap.clone()),
+            None => BootstrapOption::Default,
+        };
+        Some((bootstrap, republish))
+    }
 }
 
 impl Default for Config {
@@ -238,11 +298,20 @@ impl Default for Config {
                 rr_a: Some(Ipv4Addr::LOCALHOST),
                 rr_aaaa: None,
                 rr_ns: Some("ns1.irohdns.example.".to_string()),
+                zone_files: Default::default(),
+                tls_port: None,
+                quic_port: None,
+                transfer: None,
+                dnssec: None,
             },
             zone_store: None,
             metrics: None,
             mainline: None,
             pkarr_put_rate_limit: RateLimitConfig::default(),
+            pkarr_put_key_rate_limit: None,
+            pkarr_put_policy: PacketPolicy::default(),
+            replication: None,
+            admin: None,
         }
     }
 }
@@ -4,28 +4,33 @@ use std::{
     collections::BTreeMap,
     io,
     net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
-    sync::Arc,
-    time::Duration,
+    path::PathBuf,
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, SystemTime},
 };
 
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, bail, Context, Result};
 use async_trait::async_trait;
 use bytes::Bytes;
 use hickory_server::{
     authority::{Catalog, MessageResponse, ZoneType},
     proto::{
         self,
-        op::ResponseCode,
+        op::{OpCode, ResponseCode},
         rr::{
             rdata::{self},
             LowerName, Name, RData, Record, RecordSet, RecordType, RrKey,
         },
-        serialize::{binary::BinEncoder, txt::RDataParser},
+        serialize::{
+            binary::BinEncoder,
+            txt::{Parser, RDataParser},
+        },
         xfer::Protocol,
     },
     server::{Request, RequestHandler, ResponseHandler, ResponseInfo},
     store::in_memory::InMemoryAuthority,
 };
+use rustls::server::ResolvesServerCert;
 use serde::{Deserialize, Serialize};
 use tokio::{
     net::{TcpListener, UdpSocket},
@@ -33,10 +38,17 @@ use tokio::{
 };
 use tracing::{debug, info};
 
-use self::node_authority::NodeAuthority;
+pub use self::{dnssec::DnssecConfig, transfer::TransferConfig};
+use self::{
+    dnssec::ZoneSigner,
+    node_authority::NodeAuthority,
+    transfer::{Journal, ZoneTransfer},
+};
 use crate::{metrics::Metrics, store::ZoneStore};
 
+mod dnssec;
 mod node_authority;
+mod transfer;
 
 const DEFAULT_NS_TTL: u32 = 60 * 60 * 12; // 12h
 const DEFAULT_SOA_TTL: u32 = 60 * 60 * 24 * 14; // 14d
@@ -63,36 +75,108 @@ pub struct DnsConfig {
     pub rr_aaaa: Option<Ipv6Addr>,
     /// `NS` record to set for all origins
     pub rr_ns: Option<String>,
+
+    /// Zone files with static records, keyed by origin.
+    ///
+    /// The files are in the RFC 1035 master file format, relative names are relative to the
+    /// origin. All records must be within the origin. `SOA` records in the files are ignored,
+    /// the `SOA` record is always created from `default_soa`.
+    ///
+    /// The files are reloaded with [`DnsHandler::reload`], which also bumps the `SOA` serial.
+    #[serde(default)]
+    pub zone_files: BTreeMap<String, PathBuf>,
+
+    /// DNSSEC signing of all origins
+    ///
+    /// If set to `None` responses are not signed.
+    #[serde(default)]
+    pub dnssec: Option<DnssecConfig>,
+
+    /// The port to serve DNS-over-TLS at, usually 853.
+    ///
+    /// Uses the certificates of the HTTPS server, which must be configured.
+    /// If set to `None` DNS-over-TLS is disabled.
+    #[serde(default)]
+    pub tls_port: Option<u16>,
+    /// The port to serve DNS-over-QUIC at, usually 853.
+    ///
+    /// Uses the certificates of the HTTPS server, which must be configured.
+    /// If set to `None` DNS-over-QUIC is disabled.
+    #[serde(default)]
+    pub quic_port: Option<u16>,
+
+    /// Zone transfers to secondary name servers
+    ///
+    /// If set to `None` zone transfers are refused.
+    #[serde(default)]
+    pub transfer: Option<TransferConfig>,
 }
 
 /// A DNS server that serves pkarr signed packets.
 pub struct DnsServer {
     local_addr: SocketAddr,
+    tls_addr: Option<SocketAddr>,
+    quic_addr: Option<SocketAddr>,
     server: hickory_server::ServerFuture<DnsHandler>,
 }
 
 impl DnsServer {
     /// Spawn the server.
-    pub async fn spawn(config: DnsConfig, dns_handler: DnsHandler) -> Result<Self> {
+    ///
+    /// The `cert_resolver` provides the certificates for DNS-over-TLS and DNS-over-QUIC, and
+    /// is required if either is enabled in the config.
+    pub async fn spawn(
+        config: DnsConfig,
+        dns_handler: DnsHandler,
+        cert_resolver: Option<Arc<dyn ResolvesServerCert>>,
+    ) -> Result<Self> {
         const TCP_TIMEOUT: Duration = Duration::from_millis(1000);
+        const TLS_TIMEOUT: Duration = Duration::from_millis(3000);
         let mut server = hickory_server::ServerFuture::new(dns_handler);
 
-        let bind_addr = SocketAddr::new(
-            config.bind_addr.unwrap_or(Ipv4Addr::UNSPECIFIED.into()),
-            config.port,
-        );
+        let bind_ip = config.bind_addr.unwrap_or(Ipv4Addr::UNSPECIFIED.into());
+        let bind_addr = SocketAddr::new(bind_ip, config.port);
 
         let socket = UdpSocket::bind(bind_addr).await?;
 
         let socket_addr = socket.local_addr()?;
 
         server.register_socket(socket);
-        server.register_listener(TcpListener::bind(bind_addr).await?, TCP_TIMEOUT);
+        // bind to the port of the UDP socket, in case a random port was requested
+        server.register_listener(TcpListener::bind(socket_addr).await?, TCP_TIMEOUT);
         info!("DNS server listening on {}", bind_addr);
 
+        let cert_resolver = || {
+            cert_resolver.clone().ok_or_else(|| {
+                anyhow!("DNS-over-TLS and DNS-over-QUIC require the HTTPS server to be configured")
+            })
+        };
+        let tls_addr = match config.tls_port {
+            Some(port) => {
+                let listener = TcpListener::bind(SocketAddr::new(bind_ip, port)).await?;
+                let addr = listener.local_addr()?;
+                server.register_tls_listener(listener, TLS_TIMEOUT, cert_resolver()?)?;
+                info!("DNS-over-TLS server listening on {addr}");
+                Some(addr)
+            }
+            None => None,
+        };
+        let quic_addr = match config.quic_port {
+            Some(port) => {
+                let socket = UdpSocket::bind(SocketAddr::new(bind_ip, port)).await?;
+                let addr = socket.local_addr()?;
+                server.register_quic_listener(socket, TLS_TIMEOUT, cert_resolver()?, None)?;
+                info!("DNS-over-QUIC server listening on {addr}");
+                Some(addr)
+            }
+            None => None,
+        };
+
         Ok(Self {
             server,
             local_addr: socket_addr,
+            tls_addr,
+            quic_addr,
         })
     }
 
@@ -101,6 +185,16 @@ impl DnsServer {
         self.local_addr
     }
 
+    /// Get the local address of the DNS-over-TLS socket, if enabled.
+    pub fn tls_addr(&self) -> Option<SocketAddr> {
+        self.tls_addr
+    }
+
+    /// Get the local address of the DNS-over-QUIC socket, if enabled.
+    pub fn quic_addr(&self) -> Option<SocketAddr> {
+        self.quic_addr
+    }
+
     /// Shutdown the server an wait for all tasks to complete.
     pub async fn shutdown(mut self) -> Result<()> {
         self.server.shutdown_gracefully().await?;
@@ -119,38 +213,118 @@ impl DnsServer {
 /// State for serving DNS
 #[derive(Clone, derive_more::Debug)]
 pub struct DnsHandler {
-    #[debug("Catalog")]
-    catalog: Arc<Catalog>,
+    #[debug("Zones")]
+    zones: Arc<RwLock<Zones>>,
+    /// Serializes reloads, which build the new zones without holding `zones`.
+    reload_lock: Arc<Mutex<()>>,
+    zone_store: ZoneStore,
+    transfer: Option<ZoneTransfer>,
     metrics: Arc<Metrics>,
 }
 
+/// The zones served by a [`DnsHandler`], replaced on reload.
+struct Zones {
+    catalog: Arc<Catalog>,
+    authority: Arc<NodeAuthority>,
+    config: DnsConfig,
+    /// The serial of the static records, the serial of the stored packets is kept by the
+    /// [`Journal`] if zone transfers are enabled.
+    serial: u32,
+}
+
 impl DnsHandler {
     /// Create a DNS server given some settings, a connection to the DB for DID-by-username lookups
     /// and the server DID to serve under `_did.<origin>`.
     pub fn new(zone_store: ZoneStore, config: &DnsConfig, metrics: Arc<Metrics>) -> Result<Self> {
-        let origins = config
-            .origins
-            .iter()
-            .map(Name::from_utf8)
-            .collect::<Result<Vec<_>, _>>()?;
-
-        let (static_authority, serial) = create_static_authority(&origins, config)?;
-        let authority = Arc::new(NodeAuthority::new(
+        let serial = parse_soa(config)?.serial();
+        // the serial of zones from files or transferred to secondaries must increase over
+        // restarts, when the zones may have changed
+        let serial = match config.zone_files.is_empty() && config.transfer.is_none() {
+            true => serial,
+            false => serial.max(unix_time_serial()),
+        };
+        let journal = config
+            .transfer
+            .as_ref()
+            .map(|transfer| Arc::new(Journal::new(serial, transfer.journal_size)));
+        let zones = create_zones(zone_store.clone(), config, serial, journal.clone())?;
+        let zones = Arc::new(RwLock::new(zones));
+        let transfer = config
+            .transfer
+            .as_ref()
+            .zip(journal)
+            .map(|(transfer, journal)| {
+                ZoneTransfer::spawn(
+                    transfer,
+                    journal,
+                    zone_store.clone(),
+                    zones.clone(),
+                    metrics.clone(),
+                )
+            });
+        Ok(Self {
+            zones,
+            reload_lock: Default::default(),
             zone_store,
-            static_authority,
-            origins,
-            serial,
-        )?);
+            transfer,
+            metrics,
+        })
+    }
 
-        let mut catalog = Catalog::new();
-        for origin in authority.origins() {
-            catalog.upsert(LowerName::from(origin), vec![authority.clone()]);
+    /// Reload the zones from the config, including the zone files.
+    ///
+    /// The origins, static records and zone files are replaced with the ones from `config`,
+    /// and the `SOA` serial is increased. Settings of the listeners and zone transfers are not
+    /// changed. If `config` is `None`, the current config is used to reload the zone files.
+    ///
+    /// Returns the new `SOA` serial. On error the current zones are kept.
+    ///
+    /// This reads the zone files and blocks, call it from a blocking task. Queries are served
+    /// from the current zones until the new ones are built.
+    pub fn reload(&self, config: Option<&DnsConfig>) -> Result<u32> {
+        let _reload_guard = self.reload_lock.lock().expect("poisoned");
+        let (config, current_serial) = {
+            let zones = self.zones.read().expect("poisoned");
+            let config = config.unwrap_or(&zones.config).clone();
+            (config, self.current_serial(&zones))
+        };
+        let serial = parse_soa(&config)?
+            .serial()
+            .max(unix_time_serial())
+            .max(current_serial.wrapping_add(1));
+        let journal = self.transfer.as_ref().map(|t| t.journal().clone());
+        let new_zones = create_zones(self.zone_store.clone(), &config, serial, journal.clone())?;
+        let mut zones = self.zones.write().expect("poisoned");
+        // the journal may have advanced while the zones were built
+        let serial = serial.max(self.current_serial(&zones).wrapping_add(1));
+        *zones = new_zones;
+        // the changes of the static records are not journaled
+        if let Some(journal) = journal {
+            journal.reset(serial);
         }
+        drop(zones);
+        info!(serial, "DNS zones reloaded");
+        Ok(serial)
+    }
 
-        Ok(Self {
-            catalog: Arc::new(catalog),
-            metrics,
-        })
+    /// Returns the current `SOA` serial of the zones.
+    pub fn serial(&self) -> u32 {
+        self.current_serial(&self.zones.read().expect("poisoned"))
+    }
+
+    fn current_serial(&self, zones: &Zones) -> u32 {
+        match &self.transfer {
+            Some(transfer) => transfer.journal().serial(),
+            None => zones.serial,
+        }
+    }
+
+    fn catalog(&self) -> Arc<Catalog> {
+        self.zones.read().expect("poisoned").catalog.clone()
+    }
+
+    fn authority(&self) -> Arc<NodeAuthority> {
+        self.zones.read().expect("poisoned").authority.clone()
     }
 
     /// Handle a DNS request
@@ -162,6 +336,71 @@ impl DnsHandler {
     }
 }
 
+/// Create the zones of all origins, with `serial` as the `SOA` serial of the static records.
+fn create_zones(
+    zone_store: ZoneStore,
+    config: &DnsConfig,
+    serial: u32,
+    journal: Option<Arc<Journal>>,
+) -> Result<Zones> {
+    let origins = config
+        .origins
+        .iter()
+        .map(Name::from_utf8)
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let soa = parse_soa(config)?;
+    let soa = rdata::SOA::new(
+        soa.mname().clone(),
+        soa.rname().clone(),
+        serial,
+        soa.refresh(),
+        soa.retry(),
+        soa.expire(),
+        soa.minimum(),
+    );
+    let signer = match &config.dnssec {
+        Some(dnssec) => {
+            let negative_ttl = DEFAULT_SOA_TTL.min(soa.minimum());
+            let signer = ZoneSigner::load(dnssec, &origins, negative_ttl)?;
+            for ds in signer.ds_records()? {
+                info!("DNSSEC enabled, DS record for the parent zone: {ds}");
+            }
+            Some(signer)
+        }
+        None => None,
+    };
+    let (static_authority, serial) =
+        create_static_authority(&origins, config, soa, signer.as_ref())?;
+    let authority = Arc::new(NodeAuthority::new(
+        zone_store,
+        static_authority,
+        origins,
+        serial,
+        signer,
+        journal,
+    )?);
+
+    let mut catalog = Catalog::new();
+    for origin in authority.origins() {
+        catalog.upsert(LowerName::from(origin), vec![authority.clone()]);
+    }
+    Ok(Zones {
+        catalog: Arc::new(catalog),
+        authority,
+        config: config.clone(),
+        serial,
+    })
+}
+
+/// Returns the current unix time in seconds, a common choice for `SOA` serials.
+fn unix_time_serial() -> u32 {
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default();
+    u32::try_from(now.as_secs()).unwrap_or(u32::MAX)
+}
+
 #[async_trait::async_trait]
 impl RequestHandler for DnsHandler {
     async fn handle_request<R: ResponseHandler>(
@@ -177,11 +416,34 @@ impl RequestHandler for DnsHandler {
             Protocol::Https => {
                 self.metrics.dns_requests_https.inc();
             }
+            Protocol::Tls => {
+                self.metrics.dns_requests_tls.inc();
+            }
+            Protocol::Quic => {
+                self.metrics.dns_requests_quic.inc();
+            }
             _ => {}
         }
         debug!(protocol=%request.protocol(), queries=?request.queries(), "incoming DNS request");
 
-        let res = self.catalog.handle_request(request, response_handle).await;
+        if let Some(transfer) = &self.transfer {
+            let is_transfer = request.op_code() == OpCode::Query
+                && request
+                    .queries()
+                    .first()
+                    .is_some_and(|q| matches!(q.query_type(), RecordType::AXFR | RecordType::IXFR));
+            if is_transfer {
+                let authority = self.authority();
+                return transfer
+                    .handle_request(request, &authority, response_handle)
+                    .await;
+            }
+        }
+
+        let res = self
+            .catalog()
+            .handle_request(request, response_handle)
+            .await;
         match &res.response_code() {
             ResponseCode::NoError => match res.answer_count() {
                 0 => self.metrics.dns_lookup_notfound.inc(),
@@ -224,17 +486,22 @@ impl ResponseHandler for Handle {
     }
 }
 
-fn create_static_authority(
-    origins: &[Name],
-    config: &DnsConfig,
-) -> Result<(InMemoryAuthority, u32)> {
-    let soa = RData::parse(
+fn parse_soa(config: &DnsConfig) -> Result<rdata::SOA> {
+    RData::parse(
         RecordType::SOA,
         config.default_soa.split_ascii_whitespace(),
         None,
     )?
     .into_soa()
-    .map_err(|_| anyhow!("Couldn't parse SOA: {}", config.default_soa))?;
+    .map_err(|_| anyhow!("Couldn't parse SOA: {}", config.default_soa))
+}
+
+fn create_static_authority(
+    origins: &[Name],
+    config: &DnsConfig,
+    soa: rdata::SOA,
+    signer: Option<&ZoneSigner>,
+) -> Result<(InMemoryAuthority, u32)> {
     let serial = soa.serial();
     let mut records = BTreeMap::new();
     for name in origins {
@@ -266,16 +533,59 @@ fn create_static_authority(
             );
         }
     }
+    for (origin, path) in &config.zone_files {
+        let origin = Name::from_utf8(origin)?;
+        if !origins.contains(&origin) {
+            bail!("zone file {path:?} is for {origin}, which is not a configured origin");
+        }
+        for record in load_zone_file(&origin, path)? {
+            push_record(&mut records, serial, record);
+        }
+    }
+    if let Some(signer) = signer {
+        for record in signer.dnskey_records() {
+            push_record(&mut records, serial, record);
+        }
+    }
 
-    let static_authority = InMemoryAuthority::new(Name::root(), records, ZoneType::Primary, false)
-        .map_err(|e| anyhow!(e))?;
+    // The records are signed by the `NodeAuthority` when they are served.
+    let static_authority =
+        InMemoryAuthority::new(Name::root(), records, ZoneType::Primary, false, None)
+            .map_err(|e| anyhow!(e))?;
 
     Ok((static_authority, serial))
 }
 
+/// Loads the records of a zone file, except for `SOA` records.
+fn load_zone_file(origin: &Name, path: &PathBuf) -> Result<Vec<Record>> {
+    let text = std::fs::read_to_string(path)
+        .with_context(|| format!("failed to read zone file {path:?}"))?;
+    let (_origin, record_sets) = Parser::new(text, Some(path.clone()), Some(origin.clone()))
+        .parse()
+        .with_context(|| format!("failed to parse zone file {path:?}"))?;
+    let mut records = Vec::new();
+    for record in record_sets
+        .values()
+        .flat_map(|set| set.records_without_rrsigs())
+    {
+        if record.record_type() == RecordType::SOA {
+            continue;
+        }
+        if !origin.zone_of(record.name()) {
+            bail!(
+                "zone file {path:?} contains {}, which is not within {origin}",
+                record.name()
+            );
+        }
+        records.push(record.clone());
+    }
+    Ok(records)
+}
+
 fn push_record(records: &mut BTreeMap<RrKey, RecordSet>, serial: u32, record: Record) {
     let key = RrKey::new(record.name().clone().into(), record.record_type());
-    let mut record_set = RecordSet::new(record.name().clone(), record.record_type(), serial);
-    record_set.insert(record, serial);
-    records.insert(key, record_set);
+    records
+        .entry(key)
+        .or_insert_with(|| RecordSet::new(record.name().clone(), record.record_type(), serial))
+        .insert(record, serial);
 }
@@ -0,0 +1,426 @@
+//! Online DNSSEC signing of the records served by the [`NodeAuthority`].
+//!
+//! All origins are signed with the same key signing key (KSK) and zone signing key (ZSK).
+//! Record sets are signed when they are served, because the records derived from pkarr packets
+//! change at any time. Signatures are cached until half of their validity has passed.
+//!
+//! Non-existence is proven with compact denial of existence ([RFC 9824]): instead of an `NSEC`
+//! chain, a name that does not exist is answered with `NOERROR` and an `NSEC` record that covers
+//! only the queried name.
+//!
+//! [`NodeAuthority`]: super::node_authority::NodeAuthority
+//! [RFC 9824]: https://www.rfc-editor.org/rfc/rfc9824
+
+use std::{
+    collections::BTreeSet,
+    num::NonZeroUsize,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use hickory_server::{
+    authority::{AuthLookup, LookupRecords},
+    proto::{
+        dnssec::{
+            crypto::signing_key_from_der,
+            rdata::{DNSSECRData, DNSKEY, DS, NSEC, RRSIG},
+            Algorithm, DigestType, SigSigner, SigningKey, TBS,
+        },
+        rr::{domain::Label, DNSClass, Name, RData, Record, RecordSet, RecordType, RrKey},
+    },
+};
+use lru::LruCache;
+use rustls::pki_types::{PrivateKeyDer, PrivatePkcs8KeyDer};
+use serde::{Deserialize, Serialize};
+use time::OffsetDateTime;
+use tracing::warn;
+
+/// TTL of the `DNSKEY` records
+const DNSKEY_TTL: u32 = 60 * 60; // 1h
+/// How far signature inception is backdated, to allow for clock skew of resolvers
+const INCEPTION_OFFSET: Duration = Duration::from_secs(60 * 60);
+/// Number of signed record sets to cache
+const SIGNATURE_CACHE_CAPACITY: usize = 1024 * 64;
+
+/// DNSSEC settings
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct DnssecConfig {
+    /// Path to the key signing key, a PKCS#8 private key in PEM or DER encoding.
+    ///
+    /// The KSK signs the `DNSKEY` record set. The `DS` records to publish in the parent zone
+    /// are logged on startup.
+    pub key_signing_key: PathBuf,
+    /// Path to the zone signing key, a PKCS#8 private key in PEM or DER encoding.
+    ///
+    /// The ZSK signs all other record sets.
+    pub zone_signing_key: PathBuf,
+    /// Algorithm of both keys.
+    ///
+    /// Defaults to `ED25519`.
+    #[serde(default = "default_algorithm")]
+    pub algorithm: Algorithm,
+    /// How long signatures are valid.
+    ///
+    /// Defaults to 7 days.
+    #[serde(default = "default_signature_validity", with = "humantime_serde")]
+    pub signature_validity: Duration,
+}
+
+fn default_algorithm() -> Algorithm {
+    Algorithm::ED25519
+}
+
+fn default_signature_validity() -> Duration {
+    Duration::from_secs(60 * 60 * 24 * 7)
+}
+
+/// The signing keys of a single origin.
+#[derive(derive_more::Debug)]
+struct OriginKeys {
+    origin: Name,
+    #[debug("SigSigner")]
+    ksk: SigSigner,
+    #[debug("SigSigner")]
+    zsk: SigSigner,
+}
+
+#[derive(Debug)]
+struct CachedSignature {
+    signed_at: Instant,
+    rrset: Arc<RecordSet>,
+}
+
+/// Signs record sets for all origins.
+#[derive(derive_more::Debug)]
+pub struct ZoneSigner {
+    keys: Vec<OriginKeys>,
+    ksk: DNSKEY,
+    zsk: DNSKEY,
+    signature_validity: Duration,
+    negative_ttl: u32,
+    #[debug("{}", cache.lock().map(|c| c.len()).unwrap_or_default())]
+    cache: Mutex<LruCache<RrKey, CachedSignature>>,
+}
+
+impl ZoneSigner {
+    /// Loads the keys from `config` and creates the signers for each origin.
+    ///
+    /// `negative_ttl` is the TTL of the `NSEC` records used to deny existence.
+    pub fn load(config: &DnssecConfig, origins: &[Name], negative_ttl: u32) -> Result<Self> {
+        let ksk = read_private_key(&config.key_signing_key)?;
+        let zsk = read_private_key(&config.zone_signing_key)?;
+        let signer =
+            |key: &PrivateKeyDer<'_>, dnskey: &DNSKEY, origin: &Name| -> Result<SigSigner> {
+                Ok(SigSigner::dnssec(
+                    dnskey.clone(),
+                    signing_key(key, config.algorithm)?,
+                    origin.clone(),
+                    config.signature_validity,
+                ))
+            };
+        let ksk_dnskey = DNSKEY::new(
+            true,
+            true,
+            false,
+            signing_key(&ksk, config.algorithm)?.to_public_key()?,
+        );
+        let zsk_dnskey = DNSKEY::new(
+            true,
+            false,
+            false,
+            signing_key(&zsk, config.algorithm)?.to_public_key()?,
+        );
+        let keys = origins
+            .iter()
+            .map(|origin| {
+                Ok(OriginKeys {
+                    origin: origin.clone(),
+                    ksk: signer(&ksk, &ksk_dnskey, origin)?,
+                    zsk: signer(&zsk, &zsk_dnskey, origin)?,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let capacity = NonZeroUsize::new(SIGNATURE_CACHE_CAPACITY).expect("not zero");
+        Ok(Self {
+            keys,
+            ksk: ksk_dnskey,
+            zsk: zsk_dnskey,
+            signature_validity: config.signature_validity,
+            negative_ttl,
+            cache: Mutex::new(LruCache::new(capacity)),
+        })
+    }
+
+    /// Returns the `DNSKEY` records of all origins.
+    pub fn dnskey_records(&self) -> Vec<Record> {
+        let mut records = Vec::new();
+        for keys in &self.keys {
+            for dnskey in [&self.ksk, &self.zsk] {
+                records.push(Record::from_rdata(
+                    keys.origin.clone(),
+                    DNSKEY_TTL,
+                    RData::DNSSEC(DNSSECRData::DNSKEY(dnskey.clone())),
+                ));
+            }
+        }
+        records
+    }
+
+    /// Returns the `DS` records to publish in the parent zones of the origins.
+    pub fn ds_records(&self) -> Result<Vec<Record>> {
+        self.keys
+            .iter()
+            .map(|keys| {
+                let digest = self.ksk.to_digest(&keys.origin, DigestType::SHA256)?;
+                let ds = DS::new(
+                    self.ksk.calculate_key_tag()?,
+                    keys.ksk.key().algorithm(),
+                    DigestType::SHA256,
+                    digest.as_ref().to_vec(),
+                );
+                Ok(Record::from_rdata(
+                    keys.origin.clone(),
+                    DNSKEY_TTL,
+                    RData::DNSSEC(DNSSECRData::DS(ds)),
+                ))
+            })
+            .collect()
+    }
+
+    /// Signs the record sets of a lookup.
+    ///
+    /// Record sets that cannot be signed are returned unsigned.
+    pub fn sign_lookup(&self, lookup: AuthLookup) -> AuthLookup {
+        match lookup {
+            AuthLookup::Records {
+                answers,
+                additionals,
+            } => AuthLookup::Records {
+                answers: self.sign_records(answers),
+                additionals: additionals.map(|records| self.sign_records(records)),
+            },
+            AuthLookup::SOA(records) => AuthLookup::SOA(self.sign_records(records)),
+            lookup => lookup,
+        }
+    }
+
+    fn sign_records(&self, records: LookupRecords) -> LookupRecords {
+        match records {
+            LookupRecords::Records {
+                lookup_options,
+                records,
+            } => LookupRecords::new(lookup_options, self.sign_or_unsigned(records)),
+            LookupRecords::ManyRecords(lookup_options, records) => LookupRecords::many(
+                lookup_options,
+                records
+                    .into_iter()
+                    .map(|rrset| self.sign_or_unsigned(rrset))
+                    .collect(),
+            ),
+            records => records,
+        }
+    }
+
+    fn sign_or_unsigned(&self, rrset: Arc<RecordSet>) -> Arc<RecordSet> {
+        match self.sign(&rrset) {
+            Ok(signed) => signed,
+            Err(err) => {
+                warn!(name=%rrset.name(), record_type=%rrset.record_type(), "failed to sign record set: {err:#}");
+                rrset
+            }
+        }
+    }
+
+    /// Returns a signed copy of `rrset`.
+    ///
+    /// The `DNSKEY` record set is signed with the KSK, all others with the ZSK.
+    pub fn sign(&self, rrset: &RecordSet) -> Result<Arc<RecordSet>> {
+        let key = RrKey::new(rrset.name().into(), rrset.record_type());
+        {
+            let mut cache = self.cache.lock().expect("poisoned");
+            if let Some(cached) = cache.get(&key) {
+                if cached.signed_at.elapsed() < self.signature_validity / 2
+                    && same_records(&cached.rrset, rrset)
+                {
+                    return Ok(cached.rrset.clone());
+                }
+            }
+        }
+
+        let keys = self
+            .keys_for(rrset.name())
+            .with_context(|| format!("{} is not in a signed zone", rrset.name()))?;
+        let signer = match rrset.record_type() {
+            RecordType::DNSKEY => &keys.ksk,
+            _ => &keys.zsk,
+        };
+        let mut signed = rrset.clone();
+        signed.clear_rrsigs();
+        signed.insert_rrsig(rrsig(&signed, signer)?);
+        let signed = Arc::new(signed);
+
+        self.cache.lock().expect("poisoned").put(
+            key,
+            CachedSignature {
+                signed_at: Instant::now(),
+                rrset: signed.clone(),
+            },
+        );
+        Ok(signed)
+    }
+
+    /// Returns a signed `NSEC` record set that denies all types at `name` except `types`.
+    ///
+    /// The next name is the immediate successor of `name`, so that the record covers no other
+    /// names. For names that do not exist, `types` is empty.
+    pub fn deny(&self, name: &Name, types: BTreeSet<RecordType>) -> Result<Arc<RecordSet>> {
+        let next = name.prepend_label(Label::from_raw_bytes(&[0])?)?;
+        let types = types
+            .into_iter()
+            .chain([RecordType::RRSIG, RecordType::NSEC]);
+        let nsec = NSEC::new(next, types);
+        let record = Record::from_rdata(
+            name.clone(),
+            self.negative_ttl,
+            RData::DNSSEC(DNSSECRData::NSEC(nsec)),
+        );
+        let mut rrset = RecordSet::new(name.clone(), RecordType::NSEC, 0);
+        rrset.insert(record, 0);
+        self.sign(&rrset)
+    }
+
+    /// Returns the keys of the most specific origin that contains `name`.
+    fn keys_for(&self, name: &Name) -> Option<&OriginKeys> {
+        self.keys
+            .iter()
+            .filter(|keys| keys.origin.zone_of(name))
+            .max_by_key(|keys| keys.origin.num_labels())
+    }
+}
+
+fn rrsig(rrset: &RecordSet, signer: &SigSigner) -> Result<Record> {
+    let now = OffsetDateTime::now_utc();
+    let inception = now - INCEPTION_OFFSET;
+    let expiration = now + signer.sig_duration();
+    let tbs = TBS::from_rrset(rrset, DNSClass::IN, inception, expiration, signer)?;
+    let signature = signer.sign(&tbs)?;
+    let rrsig = RRSIG::new(
+        rrset.record_type(),
+        signer.key().algorithm(),
+        rrset.name().num_labels(),
+        rrset.ttl(),
+        expiration.unix_timestamp() as u32,
+        inception.unix_timestamp() as u32,
+        signer.calculate_key_tag()?,
+        signer.signer_name().clone(),
+        signature,
+    );
+    Ok(Record::from_rdata(
+        rrset.name().clone(),
+        rrset.ttl(),
+        RData::DNSSEC(DNSSECRData::RRSIG(rrsig)),
+    ))
+}
+
+fn same_records(a: &RecordSet, b: &RecordSet) -> bool {
+    a.ttl() == b.ttl() && a.records_without_rrsigs().eq(b.records_without_rrsigs())
+}
+
+fn signing_key(key: &PrivateKeyDer<'_>, algorithm: Algorithm) -> Result<Box<dyn SigningKey>> {
+    signing_key_from_der(key, algorithm).map_err(|err| anyhow!("invalid DNSSEC key: {err}"))
+}
+
+fn read_private_key(path: &Path) -> Result<PrivateKeyDer<'static>> {
+    let bytes =
+        std::fs::read(path).with_context(|| format!("failed to read {}", path.display()))?;
+    if bytes.starts_with(b"-----BEGIN") {
+        match rustls_pemfile::private_key(&mut bytes.as_slice())? {
+            Some(key) => Ok(key),
+            None => bail!("no private key found in {}", path.display()),
+        }
+    } else {
+        Ok(PrivatePkcs8KeyDer::from(bytes).into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use hickory_server::proto::dnssec::{crypto::Ed25519SigningKey, Verifier};
+
+    use super::*;
+
+    fn write_key(dir: &Path, name: &str) -> PathBuf {
+        let key = Ed25519SigningKey::generate_pkcs8().unwrap();
+        let path = dir.join(name);
+        std::fs::write(&path, key.secret_pkcs8_der()).unwrap();
+        path
+    }
+
+    #[test]
+    fn sign_and_verify() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let config = DnssecConfig {
+            key_signing_key: write_key(dir.path(), "ksk.der"),
+            zone_signing_key: write_key(dir.path(), "zsk.der"),
+            algorithm: Algorithm::ED25519,
+            signature_validity: default_signature_validity(),
+        };
+        let origin = Name::from_utf8("irohdns.example.")?;
+        let signer = ZoneSigner::load(&config, std::slice::from_ref(&origin), 60)?;
+
+        let dnskeys: Vec<DNSKEY> = signer
+            .dnskey_records()
+            .into_iter()
+            .map(|record| match record.into_data() {
+                RData::DNSSEC(DNSSECRData::DNSKEY(dnskey)) => dnskey,
+                _ => panic!("expected DNSKEY"),
+            })
+            .collect();
+        assert_eq!(dnskeys.len(), 2);
+        let ksk = dnskeys.iter().find(|k| k.secure_entry_point()).unwrap();
+        let zsk = dnskeys.iter().find(|k| !k.secure_entry_point()).unwrap();
+
+        let name = Name::from_utf8("_iroh.foo.irohdns.example.")?;
+        let record = Record::from_rdata(
+            name.clone(),
+            30,
+            RData::A("127.0.0.1".parse::<std::net::Ipv4Addr>()?.into()),
+        );
+        let rrset = RecordSet::from(record);
+        let signed = signer.sign(&rrset)?;
+        let rrsig = match signed.rrsigs()[0].data() {
+            RData::DNSSEC(DNSSECRData::RRSIG(rrsig)) => rrsig.clone(),
+            _ => panic!("expected RRSIG"),
+        };
+        assert_eq!(rrsig.signer_name(), &origin);
+        assert_eq!(rrsig.key_tag(), zsk.calculate_key_tag()?);
+        zsk.verify_rrsig(&name, DNSClass::IN, &rrsig, signed.records_without_rrsigs())?;
+        assert!(ksk
+            .verify_rrsig(&name, DNSClass::IN, &rrsig, signed.records_without_rrsigs())
+            .is_err());
+
+        // Signatures are cached.
+        assert!(Arc::ptr_eq(&signed, &signer.sign(&rrset)?));
+
+        // Names outside of the origins cannot be signed.
+        let rrset = RecordSet::new(Name::from_utf8("example.com.")?, RecordType::A, 0);
+        assert!(signer.sign(&rrset).is_err());
+
+        // Denial of existence covers only the queried name.
+        let nsec = signer.deny(&name, BTreeSet::from([RecordType::A]))?;
+        let record = nsec.records_without_rrsigs().next().unwrap();
+        let RData::DNSSEC(DNSSECRData::NSEC(nsec_rdata)) = record.data() else {
+            panic!("expected NSEC");
+        };
+        assert_eq!(
+            nsec_rdata.next_domain_name().num_labels(),
+            name.num_labels() + 1
+        );
+        assert!(nsec_rdata.type_bit_maps().any(|t| t == RecordType::A));
+        assert_eq!(nsec.rrsigs().len(), 1);
+
+        Ok(())
+    }
+}
@@ -1,21 +1,26 @@
-use std::{fmt, sync::Arc};
+use std::{collections::BTreeSet, fmt, sync::Arc};
 
 use anyhow::{bail, ensure, Context, Result};
 use async_trait::async_trait;
 use hickory_server::{
     authority::{
         AuthLookup, Authority, LookupControlFlow, LookupError, LookupOptions, LookupRecords,
-        MessageRequest, UpdateResult, ZoneType,
+        MessageRequest, Nsec3QueryInfo, UpdateResult, ZoneType,
     },
+    dnssec::NxProofKind,
     proto::{
         op::ResponseCode,
-        rr::{LowerName, Name, RecordType},
+        rr::{LowerName, Name, Record, RecordSet, RecordType},
     },
     server::RequestInfo,
     store::in_memory::InMemoryAuthority,
 };
 use tracing::{debug, trace};
 
+use super::{
+    dnssec::ZoneSigner,
+    transfer::{with_serial, Journal},
+};
 use crate::{
     store::ZoneStore,
     util::{record_set_append_origin, PublicKeyBytes},
@@ -28,6 +33,9 @@ pub struct NodeAuthority {
     #[debug("InMemoryAuthority")]
     static_authority: InMemoryAuthority,
     zones: ZoneStore,
+    signer: Option<ZoneSigner>,
+    /// Holds the serial if zone transfers are enabled, which changes with the stored packets
+    journal: Option<Arc<Journal>>,
     // TODO: This is used by Authority::origin
     // Find out what exactly this is used for - we don't have a primary origin.
     first_origin: LowerName,
@@ -39,6 +47,8 @@ impl NodeAuthority {
         static_authority: InMemoryAuthority,
         origins: Vec<Name>,
         serial: u32,
+        signer: Option<ZoneSigner>,
+        journal: Option<Arc<Journal>>,
     ) -> Result<Self> {
         ensure!(!origins.is_empty(), "at least one origin is required");
         let first_origin = LowerName::from(&origins[0]);
@@ -47,6 +57,8 @@ impl NodeAuthority {
             origins,
             serial,
             zones,
+            signer,
+            journal,
             first_origin,
         })
     }
@@ -56,7 +68,69 @@ impl NodeAuthority {
     }
 
     pub fn serial(&self) -> u32 {
-        self.serial
+        match &self.journal {
+            Some(journal) => journal.serial(),
+            None => self.serial,
+        }
+    }
+
+    /// Returns the `SOA` record of `origin`, with the current serial.
+    pub async fn soa_record(&self, origin: &Name) -> Option<Record> {
+        let lookup = self
+            .static_authority
+            .lookup(&origin.into(), RecordType::SOA, LookupOptions::default())
+            .await
+            .map_result()?
+            .ok()?;
+        let record = lookup.iter().next()?;
+        Some(with_serial(record, self.serial()))
+    }
+
+    /// Returns the static records in the zone of `origin`, except for the `SOA` record.
+    ///
+    /// Records that are within a more specific origin are not included.
+    pub async fn static_records(&self, origin: &Name) -> Vec<Record> {
+        self.static_authority
+            .records()
+            .await
+            .values()
+            .filter(|set| set.record_type() != RecordType::SOA)
+            .filter(|set| {
+                self.origins
+                    .iter()
+                    .filter(|o| o.zone_of(set.name()))
+                    .max_by_key(|o| o.num_labels())
+                    == Some(origin)
+            })
+            .flat_map(|set| set.records_without_rrsigs().cloned())
+            .collect()
+    }
+
+    /// Looks up the `SOA` record, with the current serial.
+    async fn lookup_soa(
+        &self,
+        name: &LowerName,
+        lookup_options: LookupOptions,
+    ) -> LookupControlFlow<AuthLookup> {
+        let res = self
+            .static_authority
+            .lookup(name, RecordType::SOA, lookup_options)
+            .await;
+        if self.journal.is_none() {
+            return res;
+        }
+        match res.map_result() {
+            Some(Ok(lookup)) => {
+                let Some(record) = lookup.iter().next().map(|r| with_serial(r, self.serial()))
+                else {
+                    return LookupControlFlow::Continue(Ok(lookup));
+                };
+                let records = LookupRecords::new(lookup_options, Arc::new(RecordSet::from(record)));
+                LookupControlFlow::Continue(Ok(AuthLookup::answers(records, None)))
+            }
+            Some(Err(err)) => LookupControlFlow::Continue(Err(err)),
+            None => LookupControlFlow::Skip,
+        }
     }
 
     async fn resolve_pkarr(
@@ -87,6 +161,56 @@ impl NodeAuthority {
             None => Err(err_nx_domain("not found")),
         }
     }
+
+    async fn lookup_unsigned(
+        &self,
+        name: &LowerName,
+        record_type: RecordType,
+        lookup_options: LookupOptions,
+    ) -> LookupControlFlow<AuthLookup> {
+        debug!(name=%name, "lookup in node authority");
+        match record_type {
+            RecordType::SOA => self.lookup_soa(name, lookup_options).await,
+            RecordType::NS => {
+                self.static_authority
+                    .lookup(name, record_type, lookup_options)
+                    .await
+            }
+            _ => match parse_name_as_pkarr_with_origin(name, &self.origins) {
+                Ok((name, pubkey, origin)) => {
+                    let res = self
+                        .resolve_pkarr(name, pubkey, origin, record_type, lookup_options)
+                        .await;
+                    LookupControlFlow::Continue(res)
+                }
+                Err(err) => {
+                    debug!(%name, failed_with=%err, "not a pkarr name, resolve in static authority");
+                    self.static_authority
+                        .lookup(name, record_type, lookup_options)
+                        .await
+                }
+            },
+        }
+    }
+
+    /// Returns the record types that exist at `name`.
+    async fn record_types(&self, name: &LowerName) -> Result<BTreeSet<RecordType>> {
+        match parse_name_as_pkarr_with_origin(name, &self.origins) {
+            Ok((name, pubkey, _origin)) => self.zones.record_types(&pubkey, &name).await,
+            Err(_) => {
+                let types = match self
+                    .static_authority
+                    .lookup(name, RecordType::ANY, LookupOptions::default())
+                    .await
+                    .map_result()
+                {
+                    Some(Ok(lookup)) => lookup.iter().map(|record| record.record_type()).collect(),
+                    _ => BTreeSet::new(),
+                };
+                Ok(types)
+            }
+        }
+    }
 }
 
 #[async_trait]
@@ -115,27 +239,18 @@ impl Authority for NodeAuthority {
         record_type: RecordType,
         lookup_options: LookupOptions,
     ) -> LookupControlFlow<Self::Lookup> {
-        debug!(name=%name, "lookup in node authority");
-        match record_type {
-            RecordType::SOA | RecordType::NS => {
-                self.static_authority
-                    .lookup(name, record_type, lookup_options)
-                    .await
-            }
-            _ => match parse_name_as_pkarr_with_origin(name, &self.origins) {
-                Ok((name, pubkey, origin)) => {
-                    let res = self
-                        .resolve_pkarr(name, pubkey, origin, record_type, lookup_options)
-                        .await;
-                    LookupControlFlow::Continue(res)
-                }
-                Err(err) => {
-                    debug!(%name, failed_with=%err, "not a pkarr name, resolve in static authority");
-                    self.static_authority
-                        .lookup(name, record_type, lookup_options)
-                        .await
+        let res = self
+            .lookup_unsigned(name, record_type, lookup_options)
+            .await;
+        match &self.signer {
+            Some(signer) if lookup_options.dnssec_ok() => match res {
+                LookupControlFlow::Continue(res) => {
+                    LookupControlFlow::Continue(sign_result(signer, res))
                 }
+                LookupControlFlow::Break(res) => LookupControlFlow::Break(sign_result(signer, res)),
+                LookupControlFlow::Skip => LookupControlFlow::Skip,
             },
+            _ => res,
         }
     }
 
@@ -149,11 +264,11 @@ impl Authority for NodeAuthority {
         let record_type: RecordType = request_info.query.query_type();
         match record_type {
             RecordType::SOA => {
-                self.static_authority
-                    .lookup(self.origin(), record_type, lookup_options)
+                self.lookup(self.origin(), record_type, lookup_options)
                     .await
             }
-            RecordType::AXFR => {
+            // zone transfers are answered by the `DnsHandler` if enabled
+            RecordType::AXFR | RecordType::IXFR => {
                 LookupControlFlow::Continue(Err(LookupError::from(ResponseCode::Refused)))
             }
             _ => self.lookup(lookup_name, record_type, lookup_options).await,
@@ -162,11 +277,45 @@ impl Authority for NodeAuthority {
 
     async fn get_nsec_records(
         &self,
-        _name: &LowerName,
+        name: &LowerName,
+        lookup_options: LookupOptions,
+    ) -> LookupControlFlow<Self::Lookup> {
+        let Some(signer) = &self.signer else {
+            return LookupControlFlow::Skip;
+        };
+        let res = match self.record_types(name).await {
+            Ok(types) => signer.deny(&name.into(), types).map_err(err_refused),
+            Err(err) => Err(err_refused(err)),
+        };
+        LookupControlFlow::Continue(res.map(|nsec| LookupRecords::new(lookup_options, nsec).into()))
+    }
+
+    async fn get_nsec3_records(
+        &self,
+        _info: Nsec3QueryInfo<'_>,
         _lookup_options: LookupOptions,
     ) -> LookupControlFlow<Self::Lookup> {
         LookupControlFlow::Skip
     }
+
+    fn nx_proof_kind(&self) -> Option<&NxProofKind> {
+        self.signer.as_ref().map(|_| &NxProofKind::Nsec)
+    }
+}
+
+/// Signs the records of a lookup result.
+///
+/// Names that do not exist are answered with `NODATA`, their existence is denied with the
+/// `NSEC` record from [`ZoneSigner::deny`].
+fn sign_result(
+    signer: &ZoneSigner,
+    res: Result<AuthLookup, LookupError>,
+) -> Result<AuthLookup, LookupError> {
+    match res {
+        Ok(lookup) => Ok(signer.sign_lookup(lookup)),
+        Err(err) if err.is_nx_domain() => Err(LookupError::NameExists),
+        Err(err) => Err(err),
+    }
 }
 
 fn parse_name_as_pkarr_with_origin(
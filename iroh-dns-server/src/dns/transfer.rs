@@ -0,0 +1,648 @@
+//! Zone transfers to secondary name servers.
+//!
+//! Secondaries on the allowlist can fetch the zone of every origin with `AXFR`, or only the
+//! changes since their serial with `IXFR`. The zone of an origin contains its static records
+//! and the records of all stored pkarr packets, with the public key and the origin appended to
+//! their names. Packets resolved from the mainline DHT are not part of the zones, and the
+//! transferred records are not signed.
+//!
+//! Every change of a stored packet increases the `SOA` serial. The last changes are kept in a
+//! [`Journal`] to answer `IXFR` requests, secondaries with an older serial get the full zone.
+//! Secondaries are notified of new serials with `NOTIFY` messages.
+
+use std::{
+    collections::VecDeque,
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::{Arc, Mutex, RwLock},
+    time::Duration,
+};
+
+use anyhow::{bail, Result};
+use hickory_server::{
+    authority::MessageResponseBuilder,
+    proto::{
+        op::{Header, Message, MessageType, OpCode, Query, ResponseCode},
+        rr::{LowerName, Name, RData, Record, RecordType},
+        serialize::binary::BinEncodable,
+        xfer::Protocol,
+    },
+    server::{Request, ResponseHandler, ResponseInfo},
+};
+use n0_future::task::AbortOnDropHandle;
+use pkarr::{SignedPacket, Timestamp};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    net::UdpSocket,
+    sync::{broadcast, watch},
+    task::JoinSet,
+};
+use tracing::{debug, info, warn};
+
+use super::{node_authority::NodeAuthority, unix_time_serial, Zones};
+use crate::{
+    metrics::Metrics,
+    store::{PacketChange, ZoneStore},
+    util::{record_set_append_origin, signed_packet_to_hickory_records_without_origin},
+};
+
+/// Maximum size of a message of a transfer, messages over streams are limited to 64 KiB
+const MAX_MESSAGE_SIZE: usize = u16::MAX as usize;
+/// Space kept free in each message of a transfer for the header, the question and EDNS
+const MESSAGE_HEADER_ROOM: usize = 512;
+/// Number of packets to read from the store at once for a full transfer
+const PAGE_SIZE: usize = 1024;
+/// Time to wait for the response to a `NOTIFY` message before sending it again
+const NOTIFY_TIMEOUT: Duration = Duration::from_secs(2);
+/// Number of times a `NOTIFY` message is sent before giving up
+const NOTIFY_ATTEMPTS: usize = 3;
+
+/// Zone transfer settings
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TransferConfig {
+    /// IP addresses of the secondary name servers that may transfer the zones.
+    pub allow: Vec<IpAddr>,
+    /// Addresses of the secondary name servers to notify of changes, usually on port 53.
+    #[serde(default)]
+    pub notify: Vec<SocketAddr>,
+    /// Minimum time between two rounds of `NOTIFY` messages.
+    ///
+    /// Changes within this time are announced together. Defaults to 5 seconds.
+    #[serde(default = "default_notify_interval", with = "humantime_serde")]
+    pub notify_interval: Duration,
+    /// Number of changes to keep for incremental transfers.
+    ///
+    /// Secondaries with an older serial get the full zone. Defaults to 4096.
+    #[serde(default = "default_journal_size")]
+    pub journal_size: usize,
+}
+
+fn default_notify_interval() -> Duration {
+    Duration::from_secs(5)
+}
+
+fn default_journal_size() -> usize {
+    4096
+}
+
+/// The `SOA` serial of the zones and the last changes of the stored packets.
+///
+/// The serial is increased to at least the current unix time on every change, so that it keeps
+/// increasing across restarts unless there were more changes than seconds in between.
+#[derive(Debug)]
+pub(crate) struct Journal {
+    serial: watch::Sender<u32>,
+    entries: Mutex<VecDeque<JournalEntry>>,
+    capacity: usize,
+}
+
+#[derive(Debug, Clone)]
+struct JournalEntry {
+    /// Serial before the change
+    from: u32,
+    /// Serial after the change
+    to: u32,
+    change: PacketChange,
+}
+
+impl Journal {
+    /// Creates a journal that keeps up to `capacity` changes, starting at `serial`.
+    pub(crate) fn new(serial: u32, capacity: usize) -> Self {
+        Self {
+            serial: watch::Sender::new(serial),
+            entries: Default::default(),
+            capacity,
+        }
+    }
+
+    /// Returns the current serial.
+    pub(crate) fn serial(&self) -> u32 {
+        *self.serial.borrow()
+    }
+
+    /// Drops all changes and continues with `serial`.
+    ///
+    /// Used when the zones changed in a way that is not recorded, secondaries then need a full
+    /// transfer.
+    pub(crate) fn reset(&self, serial: u32) {
+        let mut entries = self.entries.lock().expect("poisoned");
+        entries.clear();
+        self.serial.send_replace(serial);
+    }
+
+    /// Records a change and increases the serial.
+    fn push(&self, change: PacketChange) {
+        let mut entries = self.entries.lock().expect("poisoned");
+        let from = self.serial();
+        let to = next_serial(from);
+        if entries.len() >= self.capacity {
+            entries.pop_front();
+        }
+        if self.capacity > 0 {
+            entries.push_back(JournalEntry { from, to, change });
+        }
+        self.serial.send_replace(to);
+    }
+
+    /// Returns the current serial and the changes since `serial`.
+    ///
+    /// Returns `None` for the changes if they are not all in the journal.
+    fn changes_since(&self, serial: u32) -> (u32, Option<Vec<JournalEntry>>) {
+        let entries = self.entries.lock().expect("poisoned");
+        let current = self.serial();
+        if serial == current {
+            return (current, Some(Vec::new()));
+        }
+        let changes = entries
+            .iter()
+            .position(|entry| entry.from == serial)
+            .map(|start| entries.range(start..).cloned().collect());
+        (current, changes)
+    }
+}
+
+fn next_serial(serial: u32) -> u32 {
+    serial.wrapping_add(1).max(unix_time_serial())
+}
+
+/// Answers zone transfer requests and notifies secondaries of changes.
+#[derive(Debug, Clone)]
+pub(crate) struct ZoneTransfer {
+    allow: Arc<Vec<IpAddr>>,
+    journal: Arc<Journal>,
+    zone_store: ZoneStore,
+    metrics: Arc<Metrics>,
+    _tasks: Arc<Vec<AbortOnDropHandle<()>>>,
+}
+
+impl ZoneTransfer {
+    /// Spawns the tasks that record the changes of the store in `journal` and notify the
+    /// secondaries.
+    pub(crate) fn spawn(
+        config: &TransferConfig,
+        journal: Arc<Journal>,
+        zone_store: ZoneStore,
+        zones: Arc<RwLock<Zones>>,
+        metrics: Arc<Metrics>,
+    ) -> Self {
+        let mut tasks = vec![AbortOnDropHandle::new(tokio::spawn(journal_loop(
+            zone_store.subscribe_changes(),
+            journal.clone(),
+        )))];
+        if !config.notify.is_empty() {
+            tasks.push(AbortOnDropHandle::new(tokio::spawn(notify_loop(
+                journal.clone(),
+                zones,
+                config.notify.clone(),
+                config.notify_interval,
+                metrics.clone(),
+            ))));
+        }
+        info!(allow = ?config.allow, notify = ?config.notify, "zone transfers enabled");
+        Self {
+            allow: Arc::new(config.allow.clone()),
+            journal,
+            zone_store,
+            metrics,
+            _tasks: Arc::new(tasks),
+        }
+    }
+
+    /// Returns the journal, which holds the current serial.
+    pub(crate) fn journal(&self) -> &Arc<Journal> {
+        &self.journal
+    }
+
+    /// Answers an `AXFR` or `IXFR` request for the zones of `authority`.
+    pub(crate) async fn handle_request<R: ResponseHandler>(
+        &self,
+        request: &Request,
+        authority: &NodeAuthority,
+        response_handle: R,
+    ) -> ResponseInfo {
+        let mut response = Response::new(request, response_handle);
+        let res = self.answer(request, authority, &mut response).await;
+        match res {
+            Ok(Ok(())) => {
+                self.metrics.dns_transfers.inc();
+                response.finish().await
+            }
+            Ok(Err(code)) => {
+                self.metrics.dns_transfers_refused.inc();
+                response.error(code).await
+            }
+            Err(err) => {
+                warn!(src = %request.src(), "zone transfer failed: {err:#}");
+                let mut header = Header::response_from_request(request.header());
+                header.set_response_code(ResponseCode::ServFail);
+                header.into()
+            }
+        }
+    }
+
+    /// Sends the records of the transfer, or returns the response code to refuse it with.
+    async fn answer<R: ResponseHandler>(
+        &self,
+        request: &Request,
+        authority: &NodeAuthority,
+        response: &mut Response<'_, R>,
+    ) -> Result<Result<(), ResponseCode>> {
+        if !self.allow.contains(&request.src().ip()) {
+            debug!(src = %request.src(), "zone transfer refused: not allowed");
+            return Ok(Err(ResponseCode::Refused));
+        }
+        let Some(query) = request.queries().first() else {
+            return Ok(Err(ResponseCode::FormErr));
+        };
+        let Some(origin) = authority
+            .origins()
+            .find(|origin| LowerName::from(*origin) == *query.name())
+            .cloned()
+        else {
+            return Ok(Err(ResponseCode::NotAuth));
+        };
+        let Some(soa) = authority.soa_record(&origin).await else {
+            return Ok(Err(ResponseCode::ServFail));
+        };
+        // streams can carry the responses of a transfer, UDP only the current serial
+        let stream = matches!(request.protocol(), Protocol::Tcp | Protocol::Tls);
+        let serial = soa_serial(&soa);
+        match query.query_type() {
+            RecordType::AXFR if !stream => return Ok(Err(ResponseCode::Refused)),
+            RecordType::AXFR => {
+                debug!(src = %request.src(), %origin, serial, "AXFR");
+                self.send_zone(authority, &origin, soa, response).await?;
+            }
+            RecordType::IXFR => {
+                let client_serial = request
+                    .name_servers()
+                    .iter()
+                    .find(|record| record.record_type() == RecordType::SOA)
+                    .map(soa_serial);
+                let (current, changes) = match client_serial {
+                    Some(client_serial) => self.journal.changes_since(client_serial),
+                    None => (serial, None),
+                };
+                debug!(src = %request.src(), %origin, ?client_serial, serial, "IXFR");
+                match changes {
+                    // over UDP, the client retries over a stream if the serial changed
+                    _ if !stream => response.push(soa).await?,
+                    // the client is up to date
+                    Some(changes) if changes.is_empty() => response.push(soa).await?,
+                    Some(changes) => {
+                        // the serial may have changed since the SOA record was created
+                        let soa = with_serial(&soa, current);
+                        response.push(soa.clone()).await?;
+                        for entry in changes {
+                            let (removed, added) = entry_records(&entry.change, &origin)?;
+                            response.push(with_serial(&soa, entry.from)).await?;
+                            response.extend(removed).await?;
+                            response.push(with_serial(&soa, entry.to)).await?;
+                            response.extend(added).await?;
+                        }
+                        response.push(soa).await?;
+                    }
+                    None => self.send_zone(authority, &origin, soa, response).await?,
+                }
+            }
+            _ => return Ok(Err(ResponseCode::FormErr)),
+        }
+        Ok(Ok(()))
+    }
+
+    /// Sends all records of the zone of `origin`, enclosed in its `SOA` record.
+    async fn send_zone<R: ResponseHandler>(
+        &self,
+        authority: &NodeAuthority,
+        origin: &Name,
+        soa: Record,
+        response: &mut Response<'_, R>,
+    ) -> Result<()> {
+        response.push(soa.clone()).await?;
+        response
+            .extend(authority.static_records(origin).await)
+            .await?;
+        let mut since = Timestamp::from(0);
+        loop {
+            let packets = self.zone_store.packets_since(since, PAGE_SIZE).await?;
+            for packet in &packets {
+                response.extend(packet_records(packet, origin)?).await?;
+            }
+            match packets.last() {
+                Some(last) if packets.len() >= PAGE_SIZE => {
+                    since = Timestamp::from(u64::from(last.timestamp()) + 1)
+                }
+                _ => break,
+            }
+        }
+        response.push(soa).await?;
+        Ok(())
+    }
+}
+
+/// Collects the records of a transfer into messages of at most [`MAX_MESSAGE_SIZE`].
+struct Response<'a, R> {
+    request: &'a Request,
+    response_handle: R,
+    records: Vec<Record>,
+    /// Encoded size of `records`, without name compression
+    size: usize,
+    info: Option<ResponseInfo>,
+}
+
+impl<'a, R: ResponseHandler> Response<'a, R> {
+    fn new(request: &'a Request, response_handle: R) -> Self {
+        Self {
+            request,
+            response_handle,
+            records: Vec::new(),
+            size: 0,
+            info: None,
+        }
+    }
+
+    async fn push(&mut self, record: Record) -> io::Result<()> {
+        let len = record.to_bytes()?.len();
+        if !self.records.is_empty() && self.size + len > MAX_MESSAGE_SIZE - MESSAGE_HEADER_ROOM {
+            self.flush().await?;
+        }
+        self.records.push(record);
+        self.size += len;
+        Ok(())
+    }
+
+    async fn extend(&mut self, records: impl IntoIterator<Item = Record>) -> io::Result<()> {
+        for record in records {
+            self.push(record).await?;
+        }
+        Ok(())
+    }
+
+    async fn flush(&mut self) -> io::Result<()> {
+        let mut header = Header::response_from_request(self.request.header());
+        header.set_authoritative(true);
+        let message = MessageResponseBuilder::from_message_request(self.request).build(
+            header,
+            self.records.iter(),
+            [],
+            [],
+            [],
+        );
+        let info = self.response_handle.send_response(message).await?;
+        self.info = Some(info);
+        self.records.clear();
+        self.size = 0;
+        Ok(())
+    }
+
+    async fn finish(mut self) -> ResponseInfo {
+        if !self.records.is_empty() {
+            if let Err(err) = self.flush().await {
+                warn!("failed to send zone transfer: {err:#}");
+            }
+        }
+        self.info.unwrap_or_else(|| {
+            let mut header = Header::response_from_request(self.request.header());
+            header.set_response_code(ResponseCode::ServFail);
+            header.into()
+        })
+    }
+
+    async fn error(mut self, code: ResponseCode) -> ResponseInfo {
+        let message = MessageResponseBuilder::from_message_request(self.request)
+            .error_msg(self.request.header(), code);
+        match self.response_handle.send_response(message).await {
+            Ok(info) => info,
+            Err(err) => {
+                warn!("failed to send zone transfer error: {err:#}");
+                let mut header = Header::response_from_request(self.request.header());
+                header.set_response_code(code);
+                header.into()
+            }
+        }
+    }
+}
+
+/// Returns the records of a packet in the zone of `origin`.
+fn packet_records(packet: &SignedPacket, origin: &Name) -> Result<Vec<Record>> {
+    let (label, record_sets) = signed_packet_to_hickory_records_without_origin(packet, |_| true)?;
+    let zone = Name::from_labels([label])?.append_name(origin)?;
+    let mut records = Vec::new();
+    for record_set in record_sets.values() {
+        let record_set = record_set_append_origin(record_set, &zone, 0)?;
+        records.extend(record_set.records_without_rrsigs().cloned());
+    }
+    Ok(records)
+}
+
+/// Returns the removed and added records of a change in the zone of `origin`.
+fn entry_records(change: &PacketChange, origin: &Name) -> Result<(Vec<Record>, Vec<Record>)> {
+    let old = match &change.old {
+        Some(packet) => packet_records(packet, origin)?,
+        None => Vec::new(),
+    };
+    let new = match &change.new {
+        Some(packet) => packet_records(packet, origin)?,
+        None => Vec::new(),
+    };
+    let removed = old.iter().filter(|r| !new.contains(r)).cloned().collect();
+    let added = new.iter().filter(|r| !old.contains(r)).cloned().collect();
+    Ok((removed, added))
+}
+
+fn soa_serial(record: &Record) -> u32 {
+    match record.data() {
+        RData::SOA(soa) => soa.serial(),
+        _ => 0,
+    }
+}
+
+/// Returns a copy of an `SOA` record with a different serial.
+pub(super) fn with_serial(record: &Record, serial: u32) -> Record {
+    match record.data() {
+        RData::SOA(soa) => {
+            let soa = hickory_server::proto::rr::rdata::SOA::new(
+                soa.mname().clone(),
+                soa.rname().clone(),
+                serial,
+                soa.refresh(),
+                soa.retry(),
+                soa.expire(),
+                soa.minimum(),
+            );
+            Record::from_rdata(record.name().clone(), record.ttl(), RData::SOA(soa))
+        }
+        _ => record.clone(),
+    }
+}
+
+/// Records the changes of the store in the journal.
+async fn journal_loop(mut changes: broadcast::Receiver<PacketChange>, journal: Arc<Journal>) {
+    loop {
+        match changes.recv().await {
+            Ok(change) => journal.push(change),
+            Err(broadcast::error::RecvError::Lagged(count)) => {
+                warn!("zone journal missed {count} changes, secondaries need a full transfer");
+                journal.reset(next_serial(journal.serial()));
+            }
+            Err(broadcast::error::RecvError::Closed) => break,
+        }
+    }
+}
+
+/// Sends `NOTIFY` messages for all origins to the secondaries whenever the serial changes.
+async fn notify_loop(
+    journal: Arc<Journal>,
+    zones: Arc<RwLock<Zones>>,
+    secondaries: Vec<SocketAddr>,
+    interval: Duration,
+    metrics: Arc<Metrics>,
+) {
+    let mut serial = journal.serial.subscribe();
+    while serial.changed().await.is_ok() {
+        let current = *serial.borrow_and_update();
+        let origins: Vec<Name> = {
+            let zones = zones.read().expect("poisoned");
+            zones.authority.origins().cloned().collect()
+        };
+        debug!(serial = current, "notifying secondaries");
+        let mut tasks = JoinSet::new();
+        for secondary in &secondaries {
+            for origin in &origins {
+                let (secondary, origin) = (*secondary, origin.clone());
+                tasks.spawn(async move {
+                    let res = send_notify(secondary, &origin, current).await;
+                    (secondary, origin, res)
+                });
+            }
+        }
+        while let Some(res) = tasks.join_next().await {
+            if let Ok((secondary, origin, Err(err))) = res {
+                warn!(%secondary, %origin, "failed to notify secondary: {err:#}");
+                metrics.dns_notify_failed.inc();
+            }
+        }
+        // changes in the meantime are announced together after the interval
+        tokio::time::sleep(interval).await;
+    }
+}
+
+/// Sends a `NOTIFY` message for `origin` and waits for the acknowledgement.
+async fn send_notify(secondary: SocketAddr, origin: &Name, serial: u32) -> Result<()> {
+    let bind_addr: IpAddr = match secondary {
+        SocketAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+        SocketAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+    };
+    let socket = UdpSocket::bind((bind_addr, 0)).await?;
+    socket.connect(secondary).await?;
+    // the id only needs to match the response, the socket is not reused
+    let id = serial as u16;
+    let mut message = Message::new();
+    message
+        .set_id(id)
+        .set_message_type(MessageType::Query)
+        .set_op_code(OpCode::Notify)
+        .set_authoritative(true)
+        .add_query(Query::query(origin.clone(), RecordType::SOA));
+    let message = message.to_vec()?;
+    let mut buf = [0u8; 512];
+    for _ in 0..NOTIFY_ATTEMPTS {
+        socket.send(&message).await?;
+        let Ok(len) = tokio::time::timeout(NOTIFY_TIMEOUT, socket.recv(&mut buf)).await else {
+            continue;
+        };
+        let response = Message::from_vec(&buf[..len?])?;
+        if response.id() != id || response.op_code() != OpCode::Notify {
+            continue;
+        }
+        if response.response_code() != ResponseCode::NoError {
+            bail!("NOTIFY failed: {}", response.response_code());
+        }
+        return Ok(());
+    }
+    bail!("no response after {NOTIFY_ATTEMPTS} attempts")
+}
+
+#[cfg(test)]
+mod tests {
+    use hickory_server::{
+        authority::MessageRequest,
+        proto::{rr::rdata::TXT, serialize::binary::BinDecodable},
+    };
+
+    use super::*;
+    use crate::{dns::Handle, util::PublicKeyBytes};
+
+    fn change(packet: Option<&SignedPacket>) -> PacketChange {
+        PacketChange {
+            key: PublicKeyBytes::new([0; 32]),
+            old: None,
+            new: packet.cloned(),
+        }
+    }
+
+    #[test]
+    fn journal_changes_since() {
+        let journal = Journal::new(10, 2);
+        assert_eq!(journal.changes_since(10).1.unwrap().len(), 0);
+        journal.push(change(None));
+        let first = journal.serial();
+        assert!(first > 10);
+        journal.push(change(None));
+        let second = journal.serial();
+        assert!(second > first);
+
+        let (current, changes) = journal.changes_since(10);
+        assert_eq!(current, second);
+        let changes = changes.unwrap();
+        assert_eq!(changes.len(), 2);
+        assert_eq!((changes[0].from, changes[0].to), (10, first));
+        assert_eq!((changes[1].from, changes[1].to), (first, second));
+        assert_eq!(journal.changes_since(first).1.unwrap().len(), 1);
+
+        // the oldest change is dropped when the journal is full
+        journal.push(change(None));
+        assert!(journal.changes_since(10).1.is_none());
+        assert_eq!(journal.changes_since(first).1.unwrap().len(), 2);
+
+        journal.reset(journal.serial() + 1);
+        assert!(journal.changes_since(first).1.is_none());
+        assert_eq!(journal.changes_since(journal.serial()).1.unwrap().len(), 0);
+    }
+
+    #[tokio::test]
+    async fn response_split_by_size() -> Result<()> {
+        let mut query = Message::new();
+        query.add_query(Query::query(Name::from_utf8("example.")?, RecordType::AXFR));
+        let query = MessageRequest::from_bytes(&query.to_vec()?)?;
+        let request = Request::new(query, "127.0.0.1:53".parse()?, Protocol::Tcp);
+        let (tx, mut rx) = broadcast::channel(64);
+        let mut response = Response::new(&request, Handle(tx));
+
+        // each record is about 1 KiB, too many to fit into a single message
+        let name = Name::from_utf8("large.example.")?;
+        let txt = TXT::new(vec!["a".repeat(250); 4]);
+        let count = 200;
+        for _ in 0..count {
+            response
+                .push(Record::from_rdata(
+                    name.clone(),
+                    30,
+                    RData::TXT(txt.clone()),
+                ))
+                .await?;
+        }
+        response.finish().await;
+
+        let mut messages = 0;
+        let mut records = 0;
+        while let Ok(bytes) = rx.try_recv() {
+            assert!(bytes.len() <= MAX_MESSAGE_SIZE);
+            records += Message::from_vec(&bytes)?.answers().len();
+            messages += 1;
+        }
+        assert!(messages > 1);
+        assert_eq!(records, count);
+        Ok(())
+    }
+}
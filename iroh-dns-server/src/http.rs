@@ -2,6 +2,7 @@
 
 use std::{
     net::{IpAddr, Ipv4Addr, SocketAddr},
+    sync::Arc,
     time::Instant,
 };
 
@@ -9,13 +10,15 @@ use anyhow::{bail, Context, Result};
 use axum::{
     extract::{ConnectInfo, Request, State},
     handler::Handler,
-    http::Method,
+    http::{header, HeaderMap, Method},
     middleware::{self, Next},
     response::IntoResponse,
-    routing::get,
+    routing::{get, post, put},
     Router,
 };
+use rustls::server::ResolvesServerCert;
 use serde::{Deserialize, Serialize};
+use subtle::ConstantTimeEq;
 use tokio::{net::TcpListener, task::JoinSet};
 use tower_http::{
     cors::{self, CorsLayer},
@@ -23,13 +26,20 @@ use tower_http::{
 };
 use tracing::{info, span, warn, Level};
 
+mod admin;
 mod doh;
 mod error;
 mod pkarr;
 mod rate_limiting;
+mod replication;
 mod tls;
 
-pub use self::{rate_limiting::RateLimitConfig, tls::CertMode};
+pub(crate) use self::pkarr::MAX_WATCH_CONNECTIONS;
+pub use self::{
+    admin::AdminConfig,
+    rate_limiting::{KeyRateLimitConfig, KeyRateLimiter, RateLimitConfig},
+    tls::CertMode,
+};
 use crate::{config::Config, state::AppState};
 
 /// Config for the HTTP server
@@ -63,6 +73,7 @@ pub struct HttpServer {
     tasks: JoinSet<std::io::Result<()>>,
     http_addr: Option<SocketAddr>,
     https_addr: Option<SocketAddr>,
+    cert_resolver: Option<Arc<dyn ResolvesServerCert>>,
 }
 
 impl HttpServer {
@@ -100,6 +111,7 @@ impl HttpServer {
         };
 
         // launch https
+        let mut cert_resolver = None;
         let https_addr = if let Some(config) = https_config {
             let bind_addr = SocketAddr::new(
                 config.bind_addr.unwrap_or(Ipv4Addr::UNSPECIFIED.into()),
@@ -124,6 +136,7 @@ impl HttpServer {
                     )
                     .await?
             };
+            cert_resolver = Some(acceptor.cert_resolver());
             let listener = TcpListener::bind(bind_addr).await?.into_std()?;
             let bound_addr = listener.local_addr()?;
             let fut = axum_server::from_tcp(listener)
@@ -140,6 +153,7 @@ impl HttpServer {
             tasks,
             http_addr,
             https_addr,
+            cert_resolver,
         })
     }
 
@@ -153,6 +167,13 @@ impl HttpServer {
         self.https_addr
     }
 
+    /// Get the resolver for the certificates of the HTTPS server.
+    ///
+    /// This allows serving other TLS protocols with the same certificates.
+    pub fn cert_resolver(&self) -> Option<Arc<dyn ResolvesServerCert>> {
+        self.cert_resolver.clone()
+    }
+
     /// Shutdown the server and wait for all tasks to complete.
     pub async fn shutdown(mut self) -> Result<()> {
         // TODO: Graceful cancellation.
@@ -188,7 +209,7 @@ pub(crate) fn create_app(state: AppState, rate_limit_config: &RateLimitConfig) -
     // configure cors middleware
     let cors = CorsLayer::new()
         // allow `GET` and `POST` when accessing the resource
-        .allow_methods([Method::GET, Method::POST, Method::PUT])
+        .allow_methods([Method::GET, Method::POST, Method::PUT, Method::DELETE])
         // allow requests from any origin
         .allow_origin(cors::Any);
 
@@ -216,6 +237,7 @@ pub(crate) fn create_app(state: AppState, rate_limit_config: &RateLimitConfig) -
     // only the pkarr::put route gets a rate limit
     let router = Router::new()
         .route("/dns-query", get(doh::get).post(doh::post))
+        .route("/pkarr/watch", get(pkarr::watch))
         .route(
             "/pkarr/{key}",
             if let Some(rate_limit) = rate_limit {
@@ -225,8 +247,35 @@ pub(crate) fn create_app(state: AppState, rate_limit_config: &RateLimitConfig) -
             },
         )
         .route("/healthcheck", get(|| async { "OK" }))
-        .route("/", get(|| async { "Hi!" }))
-        .with_state(state.clone());
+        .route("/", get(|| async { "Hi!" }));
+
+    // only expose the replication routes if replication is enabled
+    let router = if state.replication.is_some() {
+        router.route(
+            &format!("/{}", crate::replication::PACKETS_PATH),
+            get(replication::get).put(replication::put),
+        )
+    } else {
+        router
+    };
+    // only expose the admin routes if the admin api is enabled
+    let router = if state.admin.is_some() {
+        router
+            .route("/admin/keys", get(admin::list_keys))
+            .route(
+                "/admin/keys/{key}",
+                get(admin::get_key).delete(admin::delete_key),
+            )
+            .route("/admin/blocked", get(admin::list_blocked))
+            .route(
+                "/admin/blocked/{key}",
+                put(admin::block_key).delete(admin::unblock_key),
+            )
+            .route("/admin/zones/reload", post(admin::reload_zones))
+    } else {
+        router
+    };
+    let router = router.with_state(state.clone());
 
     // configure app
     router
@@ -235,6 +284,16 @@ pub(crate) fn create_app(state: AppState, rate_limit_config: &RateLimitConfig) -
         .route_layer(middleware::from_fn_with_state(state, metrics_middleware))
 }
 
+/// Checks that the `Bearer` authorization header carries the `expected` token.
+///
+/// The token is compared in constant time to not leak it through response timings.
+fn has_bearer_token(headers: &HeaderMap, expected: &str) -> bool {
+    headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.as_bytes().strip_prefix(b"Bearer "))
+        .is_some_and(|token| bool::from(token.ct_eq(expected.as_bytes())))
+}
+
 /// Record request metrics.
 // TODO:
 // * Request duration would be much better tracked as a histogram.
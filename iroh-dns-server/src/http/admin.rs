@@ -0,0 +1,217 @@
+//! Administrative API to inspect and manage the zone store.
+
+use anyhow::Result;
+use axum::{
+    extract::{Path, Query, State},
+    response::IntoResponse,
+    Json,
+};
+use http::{HeaderMap, StatusCode};
+use pkarr::{SignedPacket, Timestamp};
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+use super::error::AppError;
+use crate::{
+    state::AppState,
+    util::{signed_packet_to_hickory_message, PublicKeyBytes},
+};
+
+/// Default number of keys returned by a list request
+const DEFAULT_LIST_LIMIT: usize = 100;
+/// Maximum number of keys returned by a list request
+const MAX_LIST_LIMIT: usize = 1024;
+
+/// Config for the admin API
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AdminConfig {
+    /// Bearer token required for all requests to the admin API.
+    pub token: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ListQuery {
+    /// Only list keys with a packet timestamp equal to or newer than this, in microseconds.
+    #[serde(default)]
+    since: u64,
+    /// Maximum number of keys to list, capped at [`MAX_LIST_LIMIT`].
+    limit: Option<usize>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct KeyList {
+    keys: Vec<KeyInfo>,
+    /// Value for `since` to fetch the next page, if there may be more keys.
+    next: Option<u64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct KeyInfo {
+    /// z32 encoded public key
+    key: String,
+    /// Timestamp of the packet, in microseconds
+    timestamp: u64,
+    /// Size of the signed packet in bytes
+    size: usize,
+}
+
+impl KeyInfo {
+    fn new(packet: &SignedPacket) -> Self {
+        Self {
+            key: packet.public_key().to_z32(),
+            timestamp: packet.timestamp().into(),
+            size: packet.as_bytes().len(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct KeyDetails {
+    #[serde(flatten)]
+    info: KeyInfo,
+    /// The records of the packet in zone file format
+    records: Vec<String>,
+}
+
+/// Lists the stored keys, ordered by packet timestamp.
+pub async fn list_keys(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(query): Query<ListQuery>,
+) -> Result<impl IntoResponse, AppError> {
+    authorize(&state, &headers)?;
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_LIST_LIMIT)
+        .clamp(1, MAX_LIST_LIMIT);
+    let packets = state
+        .store
+        .packets_since(Timestamp::from(query.since), limit)
+        .await?;
+    let next = match packets.last() {
+        Some(last) if packets.len() >= limit => Some(u64::from(last.timestamp()) + 1),
+        _ => None,
+    };
+    let keys = packets.iter().map(KeyInfo::new).collect();
+    Ok(Json(KeyList { keys, next }))
+}
+
+/// Returns the decoded records of a key.
+pub async fn get_key(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(key): Path<String>,
+) -> Result<impl IntoResponse, AppError> {
+    authorize(&state, &headers)?;
+    let key = parse_key(&key)?;
+    let packet = state
+        .store
+        .get_signed_packet(&key)
+        .await?
+        .ok_or_else(|| AppError::with_status(StatusCode::NOT_FOUND))?;
+    let message = signed_packet_to_hickory_message(&packet)?;
+    let records = message.answers().iter().map(|r| r.to_string()).collect();
+    Ok(Json(KeyDetails {
+        info: KeyInfo::new(&packet),
+        records,
+    }))
+}
+
+/// Removes the packet of a key.
+pub async fn delete_key(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(key): Path<String>,
+) -> Result<impl IntoResponse, AppError> {
+    authorize(&state, &headers)?;
+    let key = parse_key(&key)?;
+    if !state.store.remove(&key).await? {
+        return Err(AppError::with_status(StatusCode::NOT_FOUND));
+    }
+    info!(key = %key, "admin: removed packet");
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Lists the blocked keys.
+pub async fn list_blocked(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<impl IntoResponse, AppError> {
+    authorize(&state, &headers)?;
+    let keys: Vec<String> = state
+        .store
+        .blocked()
+        .await?
+        .into_iter()
+        .map(|key| key.to_z32())
+        .collect();
+    Ok(Json(keys))
+}
+
+/// Blocks a key and removes its packet.
+pub async fn block_key(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(key): Path<String>,
+) -> Result<impl IntoResponse, AppError> {
+    authorize(&state, &headers)?;
+    let key = parse_key(&key)?;
+    state.store.set_blocked(&key, true).await?;
+    info!(key = %key, "admin: blocked key");
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Unblocks a key.
+pub async fn unblock_key(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(key): Path<String>,
+) -> Result<impl IntoResponse, AppError> {
+    authorize(&state, &headers)?;
+    let key = parse_key(&key)?;
+    if !state.store.set_blocked(&key, false).await? {
+        return Err(AppError::with_status(StatusCode::NOT_FOUND));
+    }
+    info!(key = %key, "admin: unblocked key");
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Debug, Serialize)]
+pub struct ReloadResult {
+    /// The new `SOA` serial of the zones
+    serial: u32,
+}
+
+/// Reloads the static zone data from the configured zone files.
+pub async fn reload_zones(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<impl IntoResponse, AppError> {
+    authorize(&state, &headers)?;
+    let dns_handler = state.dns_handler.clone();
+    let serial = tokio::task::spawn_blocking(move || dns_handler.reload(None))
+        .await
+        .map_err(anyhow::Error::from)??;
+    info!(serial, "admin: reloaded zones");
+    Ok(Json(ReloadResult { serial }))
+}
+
+fn parse_key(key: &str) -> Result<PublicKeyBytes, AppError> {
+    PublicKeyBytes::from_z32(key)
+        .map_err(|e| AppError::new(StatusCode::BAD_REQUEST, Some(format!("invalid key: {e}"))))
+}
+
+/// Checks the bearer token against the configured admin token.
+fn authorize(state: &AppState, headers: &HeaderMap) -> Result<(), AppError> {
+    let authorized = state
+        .admin
+        .as_ref()
+        .is_some_and(|c| super::has_bearer_token(headers, &c.token));
+    if !authorized {
+        return Err(AppError::new(
+            StatusCode::UNAUTHORIZED,
+            Some("invalid admin token"),
+        ));
+    }
+    Ok(())
+}
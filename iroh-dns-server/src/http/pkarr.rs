@@ -1,14 +1,33 @@
+use std::{collections::HashSet, convert::Infallible};
+
 use anyhow::Result;
 use axum::{
-    extract::{Path, State},
-    response::IntoResponse,
+    extract::{Path, Query, State},
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        IntoResponse,
+    },
 };
 use bytes::Bytes;
 use http::{header, StatusCode};
-use tracing::info;
+use n0_future::StreamExt;
+use pkarr::SignedPacket;
+use serde::Deserialize;
+use tokio::sync::{broadcast, mpsc};
+use tokio_stream::wrappers::ReceiverStream;
+use tracing::{debug, info};
 
 use super::error::AppError;
-use crate::{state::AppState, store::PacketSource, util::PublicKeyBytes};
+use crate::{
+    state::AppState,
+    store::{PacketSource, ZoneStore},
+    util::PublicKeyBytes,
+};
+
+/// Maximum number of keys that can be watched with a single request
+const MAX_WATCH_KEYS: usize = 256;
+/// Maximum number of watch requests served at the same time
+pub(crate) const MAX_WATCH_CONNECTIONS: usize = 4096;
 
 pub async fn put(
     State(state): State<AppState>,
@@ -18,12 +37,32 @@ pub async fn put(
     let key = pkarr::PublicKey::try_from(key.as_str())
         .map_err(|e| AppError::new(StatusCode::BAD_REQUEST, Some(format!("invalid key: {e}"))))?;
     let label = &key.to_z32()[..10];
+    let key_bytes = PublicKeyBytes::new(key.to_bytes());
+    if state.store.is_blocked(&key_bytes).await? {
+        return Err(AppError::new(StatusCode::FORBIDDEN, Some("key is blocked")));
+    }
     let signed_packet = pkarr::SignedPacket::from_relay_payload(&key, &body).map_err(|e| {
         AppError::new(
             StatusCode::BAD_REQUEST,
             Some(format!("invalid body payload: {e}")),
         )
     })?;
+    // Only charge the key once the signature is verified, so that others cannot use up the
+    // limit of a key.
+    if let Some(limiter) = &state.pkarr_put_key_rate_limit {
+        if !limiter.check(&key_bytes) {
+            state.metrics.pkarr_publish_rate_limited.inc();
+            return Err(AppError::with_status(StatusCode::TOO_MANY_REQUESTS));
+        }
+    }
+    if let Err(err) = state.pkarr_put_policy.check(&signed_packet) {
+        state.metrics.pkarr_publish_rejected.inc();
+        info!(key = %label, "pkarr upsert rejected: {err:#}");
+        return Err(AppError::new(
+            StatusCode::BAD_REQUEST,
+            Some(format!("packet rejected: {err:#}")),
+        ));
+    }
 
     let updated = state
         .store
@@ -48,3 +87,103 @@ pub async fn get(
     let headers = [(header::CONTENT_TYPE, "application/x-pkarr-signed-packet")];
     Ok((headers, body))
 }
+
+#[derive(Debug, Deserialize)]
+pub struct WatchQuery {
+    /// Comma separated list of z32 encoded keys to watch.
+    keys: String,
+}
+
+/// Streams the packets of a set of keys as server-sent events.
+///
+/// The current packets of the keys are sent first, followed by every newer packet accepted by
+/// the store. Each event has the type `packet` and the data `<z32 key> <payload>`, where the
+/// payload is the base64url encoded relay payload of the packet.
+pub async fn watch(
+    State(state): State<AppState>,
+    Query(query): Query<WatchQuery>,
+) -> Result<impl IntoResponse, AppError> {
+    let keys = query
+        .keys
+        .split(',')
+        .map(PublicKeyBytes::from_z32)
+        .collect::<Result<HashSet<_>>>()
+        .map_err(|e| AppError::new(StatusCode::BAD_REQUEST, Some(format!("invalid key: {e}"))))?;
+    if keys.is_empty() || keys.len() > MAX_WATCH_KEYS {
+        return Err(AppError::new(
+            StatusCode::BAD_REQUEST,
+            Some(format!("between 1 and {MAX_WATCH_KEYS} keys are required")),
+        ));
+    }
+
+    // held until the watch stream is dropped
+    let Ok(permit) = state.pkarr_watch_connections.clone().try_acquire_owned() else {
+        return Err(AppError::new(
+            StatusCode::SERVICE_UNAVAILABLE,
+            Some("too many watch connections"),
+        ));
+    };
+
+    let (send, recv) = mpsc::channel(16);
+    // subscribe before sending the current packets, to not miss any update in between
+    let updates = state.store.subscribe();
+    tokio::spawn(async move {
+        forward_updates(state.store, keys, updates, send).await;
+        drop(permit);
+    });
+    let stream = ReceiverStream::new(recv).map(|packet: SignedPacket| {
+        let data = format!(
+            "{} {}",
+            packet.public_key().to_z32(),
+            base64_url::encode(&packet.to_relay_payload())
+        );
+        Ok::<_, Infallible>(Event::default().event("packet").data(data))
+    });
+    Ok(Sse::new(stream).keep_alive(KeepAlive::default()))
+}
+
+/// Sends the current and all future packets of `keys` until the receiver is dropped.
+async fn forward_updates(
+    store: ZoneStore,
+    keys: HashSet<PublicKeyBytes>,
+    mut updates: broadcast::Receiver<SignedPacket>,
+    send: mpsc::Sender<SignedPacket>,
+) {
+    if send_current(&store, &keys, &send).await.is_err() {
+        return;
+    }
+    loop {
+        let update = tokio::select! {
+            _ = send.closed() => break,
+            update = updates.recv() => update,
+        };
+        let res = match update {
+            Ok(packet) if keys.contains(&PublicKeyBytes::from_signed_packet(&packet)) => {
+                send.send(packet).await.map_err(anyhow::Error::from)
+            }
+            Ok(_) => Ok(()),
+            Err(broadcast::error::RecvError::Lagged(count)) => {
+                // the missed updates may contain our keys, send their current packets again
+                debug!(count, "watch: lagged behind store updates");
+                send_current(&store, &keys, &send).await
+            }
+            Err(broadcast::error::RecvError::Closed) => break,
+        };
+        if res.is_err() {
+            break;
+        }
+    }
+}
+
+async fn send_current(
+    store: &ZoneStore,
+    keys: &HashSet<PublicKeyBytes>,
+    send: &mpsc::Sender<SignedPacket>,
+) -> Result<()> {
+    for key in keys {
+        if let Some(packet) = store.get_signed_packet(key).await? {
+            send.send(packet).await?;
+        }
+    }
+    Ok(())
+}
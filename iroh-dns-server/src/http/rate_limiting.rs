@@ -1,6 +1,12 @@
-use std::{sync::Arc, time::Duration};
+use std::{
+    num::NonZeroU32,
+    sync::{Arc, Weak},
+    time::Duration,
+};
 
-use governor::{clock::QuantaInstant, middleware::NoOpMiddleware};
+use governor::{
+    clock::QuantaInstant, middleware::NoOpMiddleware, DefaultKeyedRateLimiter, Quota, RateLimiter,
+};
 use serde::{Deserialize, Serialize};
 use tower_governor::{
     governor::GovernorConfigBuilder,
@@ -8,6 +14,11 @@ use tower_governor::{
     GovernorLayer,
 };
 
+use crate::util::PublicKeyBytes;
+
+/// Interval in which expired rate limiting state is cleaned up.
+const GC_INTERVAL: Duration = Duration::from_secs(60);
+
 /// Config for http server rate limit.
 #[derive(Debug, Deserialize, Default, Serialize, Clone)]
 #[serde(rename_all = "lowercase")]
@@ -69,10 +80,9 @@ pub fn create(
     let governor_conf = Arc::new(governor_conf);
 
     // The governor needs a background task for garbage collection (to clear expired records)
-    let gc_interval = Duration::from_secs(60);
     let governor_limiter = governor_conf.limiter().clone();
     std::thread::spawn(move || loop {
-        std::thread::sleep(gc_interval);
+        std::thread::sleep(GC_INTERVAL);
         tracing::debug!("rate limiting storage size: {}", governor_limiter.len());
         governor_limiter.retain_recent();
     });
@@ -81,3 +91,64 @@ pub fn create(
         config: governor_conf,
     })
 }
+
+/// Config for rate limiting pkarr puts per public key.
+///
+/// This complements the [`RateLimitConfig`] per IP address: nodes behind a NAT share an IP
+/// address, while a single key can publish from many IP addresses.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct KeyRateLimitConfig {
+    /// Number of puts per key that are replenished per second.
+    ///
+    /// Defaults to 1.
+    pub per_second: NonZeroU32,
+    /// Maximum number of puts per key in a burst.
+    ///
+    /// Defaults to 4.
+    pub burst_size: NonZeroU32,
+}
+
+impl Default for KeyRateLimitConfig {
+    fn default() -> Self {
+        Self {
+            per_second: NonZeroU32::new(1).expect("not zero"),
+            burst_size: NonZeroU32::new(4).expect("not zero"),
+        }
+    }
+}
+
+/// Rate limiter for pkarr puts keyed by the public key.
+#[derive(Debug, Clone)]
+pub struct KeyRateLimiter(Arc<DefaultKeyedRateLimiter<PublicKeyBytes>>);
+
+impl KeyRateLimiter {
+    /// Create a new rate limiter.
+    ///
+    /// This spawns a background thread to clean up the rate limiting state, which exits once
+    /// the limiter is dropped.
+    pub fn new(config: &KeyRateLimitConfig) -> Self {
+        tracing::info!("Rate limiting per key enabled ({config:?})");
+        let quota = Quota::per_second(config.per_second).allow_burst(config.burst_size);
+        let limiter = Arc::new(RateLimiter::keyed(quota));
+        let weak = Arc::downgrade(&limiter);
+        std::thread::spawn(move || gc_loop(weak));
+        Self(limiter)
+    }
+
+    /// Returns `true` if a put for `key` is allowed.
+    pub(crate) fn check(&self, key: &PublicKeyBytes) -> bool {
+        self.0.check_key(key).is_ok()
+    }
+}
+
+fn gc_loop(limiter: Weak<DefaultKeyedRateLimiter<PublicKeyBytes>>) {
+    loop {
+        std::thread::sleep(GC_INTERVAL);
+        let Some(limiter) = limiter.upgrade() else {
+            break;
+        };
+        tracing::debug!("key rate limiting storage size: {}", limiter.len());
+        limiter.retain_recent();
+    }
+}
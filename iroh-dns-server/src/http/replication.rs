@@ -0,0 +1,89 @@
+//! Endpoints for replication between iroh-dns-server instances, see [`crate::replication`].
+
+use anyhow::Result;
+use axum::{
+    extract::{Query, State},
+    response::IntoResponse,
+};
+use bytes::Bytes;
+use http::{header, HeaderMap, StatusCode};
+use pkarr::Timestamp;
+use serde::Deserialize;
+use tracing::debug;
+
+use super::error::AppError;
+use crate::{
+    replication::{decode_packets, encode_packets, MAX_PAGE_SIZE, PACKETS_CONTENT_TYPE},
+    state::AppState,
+    store::PacketSource,
+};
+
+#[derive(Debug, Deserialize)]
+pub struct PacketsQuery {
+    /// Only return packets with a timestamp equal to or newer than this, in microseconds.
+    #[serde(default)]
+    since: u64,
+    /// Maximum number of packets to return, capped at [`MAX_PAGE_SIZE`].
+    limit: Option<usize>,
+}
+
+/// Receives a batch of packets pushed by a peer.
+pub async fn put(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<impl IntoResponse, AppError> {
+    authorize(&state, &headers)?;
+    let packets = decode_packets(body).map_err(|e| {
+        AppError::new(
+            StatusCode::BAD_REQUEST,
+            Some(format!("invalid body payload: {e:#}")),
+        )
+    })?;
+    let count = packets.len();
+    let mut updated = 0;
+    for packet in packets {
+        if state
+            .store
+            .insert(packet, PacketSource::Replication)
+            .await?
+        {
+            state.metrics.replication_packets_received.inc();
+            updated += 1;
+        }
+    }
+    debug!(count, updated, "replication: received packets");
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Returns the stored packets ordered by timestamp, used by peers to catch up.
+pub async fn get(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(query): Query<PacketsQuery>,
+) -> Result<impl IntoResponse, AppError> {
+    authorize(&state, &headers)?;
+    let limit = query.limit.unwrap_or(MAX_PAGE_SIZE).min(MAX_PAGE_SIZE);
+    let packets = state
+        .store
+        .packets_since(Timestamp::from(query.since), limit)
+        .await?;
+    let body = encode_packets(&packets);
+    let headers = [(header::CONTENT_TYPE, PACKETS_CONTENT_TYPE)];
+    Ok((headers, body))
+}
+
+/// Checks the bearer token against the configured replication token.
+fn authorize(state: &AppState, headers: &HeaderMap) -> Result<(), AppError> {
+    let authorized = state
+        .replication
+        .as_ref()
+        .is_some_and(|c| super::has_bearer_token(headers, &c.token));
+    if !authorized {
+        return Err(AppError::new(
+            StatusCode::UNAUTHORIZED,
+            Some("invalid replication token"),
+        ));
+    }
+    Ok(())
+}
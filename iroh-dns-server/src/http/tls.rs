@@ -11,6 +11,7 @@ use axum_server::{
     tls_rustls::{RustlsAcceptor, RustlsConfig},
 };
 use n0_future::{future::Boxed as BoxFuture, FutureExt};
+use rustls::server::ResolvesServerCert;
 use serde::{Deserialize, Serialize};
 use tokio::io::{AsyncRead, AsyncWrite};
 use tokio_rustls_acme::{axum::AxumAcceptor, caches::DirCache, AcmeConfig};
@@ -51,10 +52,13 @@ impl CertMode {
 }
 
 /// TLS Certificate Authority acceptor.
+///
+/// Each variant also holds the certificate resolver, to use the same certificates for other
+/// TLS listeners.
 #[derive(Clone)]
 pub enum TlsAcceptor {
-    LetsEncrypt(AxumAcceptor),
-    Manual(RustlsAcceptor),
+    LetsEncrypt(AxumAcceptor, Arc<dyn ResolvesServerCert>),
+    Manual(RustlsAcceptor, Arc<dyn ResolvesServerCert>),
 }
 
 impl<I: AsyncRead + AsyncWrite + Unpin + Send + 'static, S: Send + 'static> Accept<I, S>
@@ -66,19 +70,28 @@ impl<I: AsyncRead + AsyncWrite + Unpin + Send + 'static, S: Send + 'static> Acce
 
     fn accept(&self, stream: I, service: S) -> Self::Future {
         match self {
-            Self::LetsEncrypt(a) => a.accept(stream, service).boxed(),
-            Self::Manual(a) => a.accept(stream, service).boxed(),
+            Self::LetsEncrypt(a, _) => a.accept(stream, service).boxed(),
+            Self::Manual(a, _) => a.accept(stream, service).boxed(),
         }
     }
 }
 
 impl TlsAcceptor {
+    /// Returns the resolver for the certificates of this acceptor.
+    pub(crate) fn cert_resolver(&self) -> Arc<dyn ResolvesServerCert> {
+        match self {
+            Self::LetsEncrypt(_, resolver) => resolver.clone(),
+            Self::Manual(_, resolver) => resolver.clone(),
+        }
+    }
+
     async fn self_signed(domains: Vec<String>) -> Result<Self> {
         let rcgen::CertifiedKey { cert, key_pair } = rcgen::generate_simple_self_signed(domains)?;
         let config =
             RustlsConfig::from_der(vec![cert.der().to_vec()], key_pair.serialize_der()).await?;
+        let resolver = config.get_inner().cert_resolver.clone();
         let acceptor = RustlsAcceptor::new(config);
-        Ok(Self::Manual(acceptor))
+        Ok(Self::Manual(acceptor, resolver))
     }
 
     async fn manual(domains: Vec<String>, dir: PathBuf) -> Result<Self> {
@@ -94,9 +107,10 @@ impl TlsAcceptor {
         let secret_key = load_secret_key(key_path).await?;
 
         let config = config.with_single_cert(certs, secret_key)?;
+        let resolver = config.cert_resolver.clone();
         let config = RustlsConfig::from_config(Arc::new(config));
         let acceptor = RustlsAcceptor::new(config);
-        Ok(Self::Manual(acceptor))
+        Ok(Self::Manual(acceptor, resolver))
     }
 
     fn letsencrypt(
@@ -112,7 +126,8 @@ impl TlsAcceptor {
             .directory_lets_encrypt(is_production)
             .state();
 
-        let config = config.with_cert_resolver(state.resolver());
+        let resolver = state.resolver();
+        let config = config.with_cert_resolver(resolver.clone());
         let acceptor = state.acceptor();
 
         tokio::spawn(
@@ -128,7 +143,7 @@ impl TlsAcceptor {
         );
         let config = Arc::new(config);
         let acceptor = AxumAcceptor::new(acceptor, config);
-        Ok(Self::LetsEncrypt(acceptor))
+        Ok(Self::LetsEncrypt(acceptor, resolver))
     }
 }
 
@@ -6,13 +6,18 @@ pub mod config;
 pub mod dns;
 pub mod http;
 pub mod metrics;
+pub mod policy;
+pub mod replication;
+pub mod republish;
 pub mod server;
 pub mod state;
 mod store;
+#[cfg(test)]
+mod test_utils;
 mod util;
 
 // Re-export to be able to construct your own dns-server
-pub use store::ZoneStore;
+pub use store::{StoreBackend, ZoneStore, ZoneStoreOptions};
 
 #[cfg(test)]
 mod tests {
@@ -31,7 +36,10 @@ mod tests {
     use tracing_test::traced_test;
 
     use crate::{
-        config::BootstrapOption,
+        config::{BootstrapOption, Config, MainlineConfig},
+        http::{AdminConfig, KeyRateLimitConfig, RateLimitConfig},
+        policy::PacketPolicy,
+        replication::ReplicationConfig,
         server::Server,
         store::{PacketSource, ZoneStoreOptions},
         util::PublicKeyBytes,
@@ -185,6 +193,806 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    #[traced_test]
+    async fn replication() -> Result<()> {
+        let origin = "irohdns.example.";
+        let token = "secret".to_string();
+        let pkarr_relay = |url: &url::Url| {
+            let mut url = url.clone();
+            url.set_path("/pkarr");
+            PkarrRelayClient::new(url)
+        };
+        let publish = |pkarr: PkarrRelayClient| async move {
+            let secret_key = SecretKey::generate(rand::thread_rng());
+            let relay_url: RelayUrl = "https://relay.example.".parse()?;
+            let node_info = NodeInfo::new(secret_key.public()).with_relay_url(Some(relay_url));
+            pkarr
+                .publish(&node_info.to_pkarr_signed_packet(&secret_key, 30)?)
+                .await?;
+            anyhow::Ok(secret_key.public())
+        };
+
+        // commit quickly, so that the catch-up sees the published packet
+        let zone_store = ZoneStoreOptions {
+            max_batch_time: Duration::from_millis(10),
+            ..Default::default()
+        };
+
+        // server a starts without peers, a node publishes to it
+        let config = Config {
+            zone_store: Some(zone_store.into()),
+            replication: Some(ReplicationConfig {
+                peers: vec![],
+                token: token.clone(),
+                catch_up: true,
+            }),
+            ..Default::default()
+        };
+        let (server_a, nameserver_a, http_url_a) =
+            Server::spawn_for_tests_with_config(config).await?;
+        let node_a = publish(pkarr_relay(&http_url_a)).await?;
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        // server b catches up on the packets of server a
+        let config = Config {
+            zone_store: Some(zone_store.into()),
+            replication: Some(ReplicationConfig {
+                peers: vec![http_url_a.clone()],
+                token: token.clone(),
+                catch_up: true,
+            }),
+            ..Default::default()
+        };
+        let (server_b, nameserver_b, http_url_b) =
+            Server::spawn_for_tests_with_config(config).await?;
+        // a new resolver is used for every attempt to not hit the negative cache
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while test_resolver(nameserver_b)
+                .lookup_node_by_id(&node_a, origin)
+                .await
+                .is_err()
+            {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+        })
+        .await?;
+
+        // packets published to server b are pushed to server a
+        let node_b = publish(pkarr_relay(&http_url_b)).await?;
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while test_resolver(nameserver_a)
+                .lookup_node_by_id(&node_b, origin)
+                .await
+                .is_err()
+            {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+        })
+        .await?;
+
+        // the replication endpoints require the token
+        let url = http_url_a.join(crate::replication::PACKETS_PATH)?;
+        let res = reqwest::get(url).await?;
+        assert_eq!(res.status(), reqwest::StatusCode::UNAUTHORIZED);
+
+        server_a.shutdown().await?;
+        server_b.shutdown().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn admin_api() -> Result<()> {
+        let config = Config {
+            admin: Some(AdminConfig {
+                token: "secret".to_string(),
+            }),
+            // the same packet is published repeatedly
+            pkarr_put_rate_limit: RateLimitConfig::Disabled,
+            ..Default::default()
+        };
+        let (server, _nameserver, http_url) = Server::spawn_for_tests_with_config(config).await?;
+        let mut pkarr_url = http_url.clone();
+        pkarr_url.set_path("/pkarr");
+        let pkarr = PkarrRelayClient::new(pkarr_url);
+
+        let secret_key = SecretKey::generate(rand::thread_rng());
+        let node_id = secret_key.public();
+        let key = pkarr::PublicKey::try_from(node_id.as_bytes())?.to_z32();
+        let relay_url: RelayUrl = "https://relay.example.".parse()?;
+        let node_info = NodeInfo::new(node_id).with_relay_url(Some(relay_url));
+        let signed_packet = node_info.to_pkarr_signed_packet(&secret_key, 30)?;
+        pkarr.publish(&signed_packet).await?;
+
+        let client = reqwest::Client::new();
+        let admin = |method: reqwest::Method, path: &str| {
+            client
+                .request(method, http_url.join(path).unwrap())
+                .bearer_auth("secret")
+        };
+
+        // all requests require the token
+        let res = client.get(http_url.join("/admin/keys")?).send().await?;
+        assert_eq!(res.status(), reqwest::StatusCode::UNAUTHORIZED);
+
+        // list and inspect the published key
+        let list = admin(reqwest::Method::GET, "/admin/keys")
+            .send()
+            .await?
+            .error_for_status()?
+            .text()
+            .await?;
+        let list: serde_json::Value = serde_json::from_str(&list)?;
+        assert_eq!(list["keys"][0]["key"], key.as_str());
+        assert_eq!(
+            list["keys"][0]["timestamp"],
+            u64::from(signed_packet.timestamp())
+        );
+        let details = admin(reqwest::Method::GET, &format!("/admin/keys/{key}"))
+            .send()
+            .await?
+            .error_for_status()?
+            .text()
+            .await?;
+        let details: serde_json::Value = serde_json::from_str(&details)?;
+        assert_eq!(details["key"], key.as_str());
+        assert!(details["records"][0]
+            .as_str()
+            .unwrap()
+            .contains("relay=https://relay.example."));
+
+        // delete the key
+        admin(reqwest::Method::DELETE, &format!("/admin/keys/{key}"))
+            .send()
+            .await?
+            .error_for_status()?;
+        let res = admin(reqwest::Method::GET, &format!("/admin/keys/{key}"))
+            .send()
+            .await?;
+        assert_eq!(res.status(), reqwest::StatusCode::NOT_FOUND);
+
+        // a blocked key can not be published
+        admin(reqwest::Method::PUT, &format!("/admin/blocked/{key}"))
+            .send()
+            .await?
+            .error_for_status()?;
+        let blocked = admin(reqwest::Method::GET, "/admin/blocked")
+            .send()
+            .await?
+            .error_for_status()?
+            .text()
+            .await?;
+        let blocked: Vec<String> = serde_json::from_str(&blocked)?;
+        assert_eq!(blocked, vec![key.clone()]);
+        assert!(pkarr.publish(&signed_packet).await.is_err());
+
+        // after unblocking the key can be published again
+        admin(reqwest::Method::DELETE, &format!("/admin/blocked/{key}"))
+            .send()
+            .await?
+            .error_for_status()?;
+        pkarr.publish(&signed_packet).await?;
+        let res = admin(reqwest::Method::GET, &format!("/admin/keys/{key}"))
+            .send()
+            .await?;
+        assert_eq!(res.status(), reqwest::StatusCode::OK);
+
+        server.shutdown().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn pkarr_put_key_rate_limit_and_policy() -> Result<()> {
+        let config = Config {
+            pkarr_put_rate_limit: RateLimitConfig::Disabled,
+            pkarr_put_key_rate_limit: Some(KeyRateLimitConfig {
+                per_second: 1.try_into()?,
+                burst_size: 2.try_into()?,
+            }),
+            pkarr_put_policy: PacketPolicy {
+                max_ttl: Some(60),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let (server, _nameserver, http_url) = Server::spawn_for_tests_with_config(config).await?;
+        let mut pkarr_url = http_url.clone();
+        pkarr_url.set_path("/pkarr");
+        let pkarr = PkarrRelayClient::new(pkarr_url);
+        let relay_url: RelayUrl = "https://relay.example.".parse()?;
+
+        // puts with an invalid signature do not count against the limit of the key
+        let secret_key = SecretKey::generate(rand::thread_rng());
+        let node_info = NodeInfo::new(secret_key.public()).with_relay_url(Some(relay_url.clone()));
+        let key_url = http_url.join(&format!(
+            "/pkarr/{}",
+            z32::encode(secret_key.public().as_bytes())
+        ))?;
+        let http = reqwest::Client::new();
+        for _ in 0..3 {
+            let res = http
+                .put(key_url.clone())
+                .body(vec![0u8; 128])
+                .send()
+                .await?;
+            assert_eq!(res.status(), reqwest::StatusCode::BAD_REQUEST);
+        }
+
+        // packets with a TTL above the maximum are rejected
+        assert!(pkarr
+            .publish(&node_info.to_pkarr_signed_packet(&secret_key, 120)?)
+            .await
+            .is_err());
+
+        // the burst of a key is exhausted after the first rejected put and one more
+        pkarr
+            .publish(&node_info.to_pkarr_signed_packet(&secret_key, 30)?)
+            .await?;
+        assert!(pkarr
+            .publish(&node_info.to_pkarr_signed_packet(&secret_key, 30)?)
+            .await
+            .is_err());
+
+        // other keys are not affected
+        let secret_key = SecretKey::generate(rand::thread_rng());
+        let node_info = NodeInfo::new(secret_key.public()).with_relay_url(Some(relay_url));
+        pkarr
+            .publish(&node_info.to_pkarr_signed_packet(&secret_key, 30)?)
+            .await?;
+
+        server.shutdown().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn integration_dns_over_tls() -> Result<()> {
+        use std::sync::Arc;
+
+        use hickory_server::proto::{
+            op::{Message, Query},
+            rr::{Name, RData, RecordType},
+        };
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        use crate::dns::{DnsHandler, DnsServer};
+
+        let rcgen::CertifiedKey { cert, key_pair } =
+            rcgen::generate_simple_self_signed(vec!["localhost".to_string()])?;
+        let server_config = rustls::ServerConfig::builder()
+            .with_no_client_auth()
+            .with_single_cert(
+                vec![cert.der().clone()],
+                rustls::pki_types::PrivateKeyDer::Pkcs8(key_pair.serialize_der().into()),
+            )?;
+
+        let mut config = Config::default().dns;
+        config.port = 0;
+        config.bind_addr = Some(Ipv4Addr::LOCALHOST.into());
+        config.tls_port = Some(0);
+        let store = ZoneStore::in_memory(Default::default(), Default::default())?;
+        let handler = DnsHandler::new(store, &config, Default::default())?;
+        let server =
+            DnsServer::spawn(config, handler, Some(server_config.cert_resolver.clone())).await?;
+
+        // query the A record of the origin via DoT
+        let mut roots = rustls::RootCertStore::empty();
+        roots.add(cert.der().clone())?;
+        let mut client_config = rustls::ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        client_config.alpn_protocols = vec![b"dot".to_vec()];
+        let connector = tokio_rustls::TlsConnector::from(Arc::new(client_config));
+        let stream = tokio::net::TcpStream::connect(server.tls_addr().unwrap()).await?;
+        let mut stream = connector.connect("localhost".try_into()?, stream).await?;
+
+        let mut query = Message::new();
+        query.add_query(Query::query(
+            Name::from_utf8("irohdns.example.")?,
+            RecordType::A,
+        ));
+        let query = query.to_vec()?;
+        stream.write_u16(query.len() as u16).await?;
+        stream.write_all(&query).await?;
+        let len = stream.read_u16().await?;
+        let mut response = vec![0u8; len as usize];
+        stream.read_exact(&mut response).await?;
+        let response = Message::from_vec(&response)?;
+        assert_eq!(
+            response.answers()[0].data(),
+            &RData::A(Ipv4Addr::LOCALHOST.into())
+        );
+
+        server.shutdown().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn integration_dns_over_quic() -> Result<()> {
+        use std::sync::Arc;
+
+        use hickory_server::proto::{
+            op::{Message, Query},
+            quic::QuicClientStream,
+            rr::{Name, RData, RecordType},
+            xfer::{DnsRequest, DnsRequestOptions, DnsRequestSender},
+        };
+        use n0_future::StreamExt;
+
+        use crate::{
+            dns::{DnsHandler, DnsServer},
+            metrics::Metrics,
+        };
+
+        let rcgen::CertifiedKey { cert, key_pair } =
+            rcgen::generate_simple_self_signed(vec!["localhost".to_string()])?;
+        let server_config = rustls::ServerConfig::builder()
+            .with_no_client_auth()
+            .with_single_cert(
+                vec![cert.der().clone()],
+                rustls::pki_types::PrivateKeyDer::Pkcs8(key_pair.serialize_der().into()),
+            )?;
+
+        let mut config = Config::default().dns;
+        config.port = 0;
+        config.bind_addr = Some(Ipv4Addr::LOCALHOST.into());
+        config.quic_port = Some(0);
+        let store = ZoneStore::in_memory(Default::default(), Default::default())?;
+        let metrics = Arc::new(Metrics::default());
+        let handler = DnsHandler::new(store, &config, metrics.clone())?;
+        let server =
+            DnsServer::spawn(config, handler, Some(server_config.cert_resolver.clone())).await?;
+
+        // query the A record of the origin via DoQ
+        let mut roots = rustls::RootCertStore::empty();
+        roots.add(cert.der().clone())?;
+        let client_config = rustls::ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let mut builder = QuicClientStream::builder();
+        builder.crypto_config(client_config);
+        let mut stream = builder
+            .build(server.quic_addr().unwrap(), "localhost".to_string())
+            .await?;
+
+        let mut query = Message::new();
+        query.add_query(Query::query(
+            Name::from_utf8("irohdns.example.")?,
+            RecordType::A,
+        ));
+        let response = stream
+            .send_message(DnsRequest::new(query, DnsRequestOptions::default()))
+            .next()
+            .await
+            .expect("response")?;
+        assert_eq!(
+            response.answers()[0].data(),
+            &RData::A(Ipv4Addr::LOCALHOST.into())
+        );
+        assert_eq!(metrics.dns_requests_quic.get(), 1);
+
+        server.shutdown().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn integration_dnssec() -> Result<()> {
+        use hickory_server::proto::{
+            dnssec::{
+                crypto::Ed25519SigningKey,
+                rdata::{DNSSECRData, DNSKEY, RRSIG},
+                Algorithm, Verifier,
+            },
+            op::{Edns, Message, Query, ResponseCode},
+            rr::{DNSClass, Name, RData, Record, RecordType},
+        };
+        use tokio::net::UdpSocket;
+
+        use crate::dns::DnssecConfig;
+
+        /// Sends a query over UDP, with the DNSSEC OK bit if `dnssec_ok` is set.
+        async fn query(
+            nameserver: SocketAddr,
+            name: &Name,
+            record_type: RecordType,
+            dnssec_ok: bool,
+        ) -> Result<Message> {
+            let mut query = Message::new();
+            query.add_query(Query::query(name.clone(), record_type));
+            let mut edns = Edns::new();
+            edns.set_max_payload(4096).set_dnssec_ok(dnssec_ok);
+            query.set_edns(edns);
+            let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+            socket.send_to(&query.to_vec()?, nameserver).await?;
+            let mut buf = [0u8; 4096];
+            let len = tokio::time::timeout(DNS_TIMEOUT, socket.recv(&mut buf)).await??;
+            Ok(Message::from_vec(&buf[..len])?)
+        }
+
+        /// Returns the `RRSIG` records and the other records of a section.
+        fn split_rrsigs(records: &[Record]) -> (Vec<RRSIG>, Vec<Record>) {
+            let mut rrsigs = Vec::new();
+            let mut others = Vec::new();
+            for record in records {
+                match record.data() {
+                    RData::DNSSEC(DNSSECRData::RRSIG(rrsig)) => rrsigs.push(rrsig.clone()),
+                    _ => others.push(record.clone()),
+                }
+            }
+            (rrsigs, others)
+        }
+
+        let dir = tempfile::tempdir()?;
+        let ksk_path = dir.path().join("ksk.der");
+        let zsk_path = dir.path().join("zsk.der");
+        for path in [&ksk_path, &zsk_path] {
+            std::fs::write(
+                path,
+                Ed25519SigningKey::generate_pkcs8()?.secret_pkcs8_der(),
+            )?;
+        }
+        let mut config = Config::default();
+        config.dns.dnssec = Some(DnssecConfig {
+            key_signing_key: ksk_path,
+            zone_signing_key: zsk_path,
+            algorithm: Algorithm::ED25519,
+            signature_validity: Duration::from_secs(60 * 60),
+        });
+        let (server, nameserver, http_url) = Server::spawn_for_tests_with_config(config).await?;
+        let origin = Name::from_utf8("irohdns.example.")?;
+
+        // the DNSKEY record set of the origin is signed by the key signing key
+        let res = query(nameserver, &origin, RecordType::DNSKEY, true).await?;
+        assert_eq!(res.response_code(), ResponseCode::NoError);
+        let (rrsigs, dnskeys) = split_rrsigs(res.answers());
+        let keys: Vec<DNSKEY> = dnskeys
+            .iter()
+            .map(|record| match record.data() {
+                RData::DNSSEC(DNSSECRData::DNSKEY(dnskey)) => dnskey.clone(),
+                _ => panic!("expected DNSKEY"),
+            })
+            .collect();
+        assert_eq!(keys.len(), 2);
+        let ksk = keys.iter().find(|k| k.secure_entry_point()).unwrap();
+        let zsk = keys.iter().find(|k| !k.secure_entry_point()).unwrap();
+        assert_eq!(rrsigs.len(), 1);
+        assert_eq!(rrsigs[0].key_tag(), ksk.calculate_key_tag()?);
+        ksk.verify_rrsig(&origin, DNSClass::IN, &rrsigs[0], dnskeys.iter())?;
+
+        // the DS record is published in the parent zone, its absence here is proven by a
+        // signed NSEC record
+        let res = query(nameserver, &origin, RecordType::DS, true).await?;
+        assert_eq!(res.response_code(), ResponseCode::NoError);
+        assert!(res.answers().is_empty());
+        let (rrsigs, records) = split_rrsigs(res.name_servers());
+        let nsec = records
+            .iter()
+            .find(|record| record.record_type() == RecordType::NSEC)
+            .expect("NSEC record");
+        let RData::DNSSEC(DNSSECRData::NSEC(nsec_rdata)) = nsec.data() else {
+            panic!("expected NSEC");
+        };
+        assert!(nsec_rdata.type_bit_maps().any(|t| t == RecordType::DNSKEY));
+        assert!(!nsec_rdata.type_bit_maps().any(|t| t == RecordType::DS));
+        let nsec_rrsig = rrsigs
+            .iter()
+            .find(|rrsig| rrsig.type_covered() == RecordType::NSEC)
+            .expect("RRSIG of the NSEC record");
+        zsk.verify_rrsig(&origin, DNSClass::IN, nsec_rrsig, std::iter::once(nsec))?;
+
+        // answers are signed by the zone signing key, only if the DNSSEC OK bit is set
+        let res = query(nameserver, &origin, RecordType::A, true).await?;
+        let (rrsigs, records) = split_rrsigs(res.answers());
+        assert_eq!(records[0].data(), &RData::A(Ipv4Addr::LOCALHOST.into()));
+        assert_eq!(rrsigs.len(), 1);
+        assert_eq!(rrsigs[0].key_tag(), zsk.calculate_key_tag()?);
+        zsk.verify_rrsig(&origin, DNSClass::IN, &rrsigs[0], records.iter())?;
+        let res = query(nameserver, &origin, RecordType::A, false).await?;
+        assert!(split_rrsigs(res.answers()).0.is_empty());
+
+        // records of nodes are signed as well
+        let mut pkarr_url = http_url.clone();
+        pkarr_url.set_path("/pkarr");
+        let signed_packet = random_signed_packet()?;
+        PkarrRelayClient::new(pkarr_url)
+            .publish(&signed_packet)
+            .await?;
+        let name = Name::from_utf8(format!(
+            "_iroh.{}.irohdns.example.",
+            signed_packet.public_key().to_z32()
+        ))?;
+        let res = query(nameserver, &name, RecordType::TXT, true).await?;
+        let (rrsigs, records) = split_rrsigs(res.answers());
+        assert!(!records.is_empty());
+        assert_eq!(rrsigs.len(), 1);
+        zsk.verify_rrsig(&name, DNSClass::IN, &rrsigs[0], records.iter())?;
+
+        // names that do not exist are denied with a signed NSEC record
+        let name = Name::from_utf8("missing.irohdns.example.")?;
+        let res = query(nameserver, &name, RecordType::A, false).await?;
+        assert_eq!(res.response_code(), ResponseCode::NXDomain);
+        let res = query(nameserver, &name, RecordType::A, true).await?;
+        assert!(res.answers().is_empty());
+        let (rrsigs, records) = split_rrsigs(res.name_servers());
+        let nsec = records
+            .iter()
+            .find(|record| record.record_type() == RecordType::NSEC)
+            .expect("NSEC record");
+        assert_eq!(nsec.name(), &name);
+        let nsec_rrsig = rrsigs
+            .iter()
+            .find(|rrsig| rrsig.type_covered() == RecordType::NSEC)
+            .expect("RRSIG of the NSEC record");
+        zsk.verify_rrsig(&name, DNSClass::IN, nsec_rrsig, std::iter::once(nsec))?;
+
+        server.shutdown().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn pkarr_watch() -> Result<()> {
+        use iroh::discovery::{pkarr::PkarrWatcher, Discovery};
+        use n0_future::StreamExt;
+
+        let config = Config {
+            pkarr_put_rate_limit: RateLimitConfig::Disabled,
+            ..Default::default()
+        };
+        let (server, _nameserver, http_url) = Server::spawn_for_tests_with_config(config).await?;
+        let mut pkarr_url = http_url.clone();
+        pkarr_url.set_path("/pkarr");
+        let pkarr = PkarrRelayClient::new(pkarr_url.clone());
+
+        let secret_key = SecretKey::generate(rand::thread_rng());
+        let node_id = secret_key.public();
+        let watcher = PkarrWatcher::new(pkarr_url);
+        let mut updates = watcher.subscribe().expect("supports subscribe");
+        watcher.watch(node_id);
+
+        // every published packet of the node is streamed to the subscribers
+        for relay_url in ["https://relay1.example.", "https://relay2.example."] {
+            let relay_url: RelayUrl = relay_url.parse()?;
+            let node_info = NodeInfo::new(node_id).with_relay_url(Some(relay_url.clone()));
+            // the watch connection is established in the background, publish until it is
+            let item = tokio::time::timeout(Duration::from_secs(5), async {
+                loop {
+                    pkarr
+                        .publish(&node_info.to_pkarr_signed_packet(&secret_key, 30)?)
+                        .await?;
+                    if let Ok(Some(item)) =
+                        tokio::time::timeout(Duration::from_millis(200), updates.next()).await
+                    {
+                        break anyhow::Ok(item);
+                    }
+                }
+            })
+            .await??;
+            assert_eq!(item.node_id(), node_id);
+            assert_eq!(item.relay_url(), Some(&relay_url));
+            // skip items of packets published before the item was received
+            while let Ok(Some(_)) =
+                tokio::time::timeout(Duration::from_millis(100), updates.next()).await
+            {}
+        }
+
+        server.shutdown().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn zone_file_reload() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let zone_file = dir.path().join("irohdns.example.zone");
+        std::fs::write(
+            &zone_file,
+            "@ 300 IN TXT \"verify=1\"\nwww 300 IN A 127.0.0.2\n",
+        )?;
+
+        let mut config = Config {
+            admin: Some(AdminConfig {
+                token: "secret".to_string(),
+            }),
+            ..Default::default()
+        };
+        config
+            .dns
+            .zone_files
+            .insert("irohdns.example.".to_string(), zone_file.clone());
+        let (server, nameserver, http_url) = Server::spawn_for_tests_with_config(config).await?;
+        let serial = server.dns_handler().serial();
+
+        // the static records are served next to the configured ones
+        let res = test_resolver(nameserver)
+            .lookup_ipv4("www.irohdns.example.", DNS_TIMEOUT)
+            .await?;
+        assert_eq!(res.collect::<Vec<_>>(), vec![Ipv4Addr::new(127, 0, 0, 2)]);
+        let res = test_resolver(nameserver)
+            .lookup_txt("irohdns.example.", DNS_TIMEOUT)
+            .await?;
+        let records = res.into_iter().map(|t| t.to_string()).collect::<Vec<_>>();
+        assert_eq!(records, vec!["verify=1".to_string()]);
+
+        // change the zone file and reload via the admin api
+        std::fs::write(&zone_file, "www 300 IN A 127.0.0.3\n")?;
+        let mut url = http_url.clone();
+        url.set_path("/admin/zones/reload");
+        let res = reqwest::Client::new()
+            .post(url)
+            .bearer_auth("secret")
+            .send()
+            .await?;
+        assert_eq!(res.status(), reqwest::StatusCode::OK);
+        let res: serde_json::Value = serde_json::from_str(&res.text().await?)?;
+        let new_serial = res["serial"].as_u64().unwrap() as u32;
+        assert!(new_serial > serial);
+        assert_eq!(server.dns_handler().serial(), new_serial);
+
+        let res = test_resolver(nameserver)
+            .lookup_ipv4("www.irohdns.example.", DNS_TIMEOUT)
+            .await?;
+        assert_eq!(res.collect::<Vec<_>>(), vec![Ipv4Addr::new(127, 0, 0, 3)]);
+        let res = test_resolver(nameserver)
+            .lookup_txt("irohdns.example.", DNS_TIMEOUT)
+            .await;
+        assert!(res.is_err());
+
+        // a broken zone file keeps the current zones
+        std::fs::write(&zone_file, "www 300 IN A not-an-ip\n")?;
+        assert!(server.dns_handler().reload(None).is_err());
+        assert_eq!(server.dns_handler().serial(), new_serial);
+        let res = test_resolver(nameserver)
+            .lookup_ipv4("www.irohdns.example.", DNS_TIMEOUT)
+            .await?;
+        assert_eq!(res.collect::<Vec<_>>(), vec![Ipv4Addr::new(127, 0, 0, 3)]);
+
+        server.shutdown().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn zone_transfer() -> Result<()> {
+        use hickory_server::proto::{
+            op::{Message, MessageType, OpCode, Query, ResponseCode},
+            rr::{Name, RData, Record, RecordType},
+        };
+        use tokio::{
+            io::{AsyncReadExt, AsyncWriteExt},
+            net::{TcpStream, UdpSocket},
+        };
+
+        use crate::dns::TransferConfig;
+
+        /// Runs a zone transfer over TCP and returns the records of all response messages.
+        async fn transfer(nameserver: SocketAddr, query: Message) -> Result<Vec<Record>> {
+            let mut stream = TcpStream::connect(nameserver).await?;
+            let query = query.to_vec()?;
+            stream.write_u16(query.len() as u16).await?;
+            stream.write_all(&query).await?;
+            let mut records: Vec<Record> = Vec::new();
+            loop {
+                let len = stream.read_u16().await?;
+                let mut response = vec![0u8; len as usize];
+                stream.read_exact(&mut response).await?;
+                let response = Message::from_vec(&response)?;
+                anyhow::ensure!(
+                    response.response_code() == ResponseCode::NoError,
+                    "transfer failed: {}",
+                    response.response_code()
+                );
+                records.extend(response.answers().iter().cloned());
+                // the transfer ends with the SOA record it started with
+                if records.len() == 1 || (records.len() > 1 && records.last() == records.first()) {
+                    return Ok(records);
+                }
+            }
+        }
+
+        fn serial(record: &Record) -> u32 {
+            match record.data() {
+                RData::SOA(soa) => soa.serial(),
+                _ => panic!("not an SOA record"),
+            }
+        }
+
+        let secondary = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+        let mut config = Config::default();
+        config.dns.transfer = Some(TransferConfig {
+            allow: vec![Ipv4Addr::LOCALHOST.into()],
+            notify: vec![secondary.local_addr()?],
+            notify_interval: Duration::from_millis(10),
+            journal_size: 16,
+        });
+        let (server, nameserver, http_url) = Server::spawn_for_tests_with_config(config).await?;
+        let mut pkarr_url = http_url.clone();
+        pkarr_url.set_path("/pkarr");
+        let pkarr = PkarrRelayClient::new(pkarr_url);
+        let origin = Name::from_utf8("irohdns.example.")?;
+
+        // publishing a packet notifies the secondary
+        let first = random_signed_packet()?;
+        pkarr.publish(&first).await?;
+        let mut buf = [0u8; 512];
+        let (len, from) =
+            tokio::time::timeout(Duration::from_secs(5), secondary.recv_from(&mut buf)).await??;
+        let notify = Message::from_vec(&buf[..len])?;
+        assert_eq!(notify.op_code(), OpCode::Notify);
+        assert_eq!(notify.queries()[0].query_type(), RecordType::SOA);
+        let mut ack = Message::new();
+        ack.set_id(notify.id())
+            .set_message_type(MessageType::Response)
+            .set_op_code(OpCode::Notify)
+            .add_query(notify.queries()[0].clone());
+        secondary.send_to(&ack.to_vec()?, from).await?;
+
+        // the full zone contains the static records and the records of the packet
+        let mut axfr = Message::new();
+        axfr.add_query(Query::query(origin.clone(), RecordType::AXFR));
+        let records = transfer(nameserver, axfr).await?;
+        let soa = records[0].clone();
+        let first_name = Name::from_utf8(format!(
+            "_iroh.{}.irohdns.example.",
+            first.public_key().to_z32()
+        ))?;
+        assert!(records
+            .iter()
+            .any(|r| r.name() == &origin && r.data() == &RData::A(Ipv4Addr::LOCALHOST.into())));
+        assert!(records.iter().any(|r| r.name() == &first_name));
+
+        // an incremental transfer only contains the changes since the serial
+        let second = random_signed_packet()?;
+        pkarr.publish(&second).await?;
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while server.dns_handler().serial() == serial(&soa) {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await?;
+        let mut ixfr = Message::new();
+        ixfr.add_query(Query::query(origin.clone(), RecordType::IXFR));
+        ixfr.add_name_server(soa.clone());
+        let records = transfer(nameserver, ixfr).await?;
+        let current = server.dns_handler().serial();
+        let serials: Vec<u32> = records
+            .iter()
+            .filter(|r| r.record_type() == RecordType::SOA)
+            .map(serial)
+            .collect();
+        assert_eq!(serials, vec![current, serial(&soa), current, current]);
+        let second_name = Name::from_utf8(format!(
+            "_iroh.{}.irohdns.example.",
+            second.public_key().to_z32()
+        ))?;
+        assert!(records.iter().any(|r| r.name() == &second_name));
+        assert!(!records.iter().any(|r| r.name() == &first_name));
+
+        // an up to date secondary only gets the SOA record
+        let mut ixfr = Message::new();
+        ixfr.add_query(Query::query(origin.clone(), RecordType::IXFR));
+        ixfr.add_name_server(records[0].clone());
+        let records = transfer(nameserver, ixfr).await?;
+        assert_eq!(records.len(), 1);
+        assert_eq!(serial(&records[0]), current);
+
+        // AXFR is refused over UDP
+        let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+        let mut axfr = Message::new();
+        axfr.add_query(Query::query(origin.clone(), RecordType::AXFR));
+        socket.send_to(&axfr.to_vec()?, nameserver).await?;
+        let len = socket.recv(&mut buf).await?;
+        let response = Message::from_vec(&buf[..len])?;
+        assert_eq!(response.response_code(), ResponseCode::Refused);
+
+        server.shutdown().await?;
+        Ok(())
+    }
+
     #[tokio::test]
     #[traced_test]
     async fn store_eviction() -> TestResult<()> {
@@ -254,6 +1062,51 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    #[traced_test]
+    async fn integration_mainline_republish() -> Result<()> {
+        // run a mainline testnet
+        let testnet = pkarr::mainline::Testnet::new_async(5).await?;
+
+        // spawn our server with publishing to our DHT
+        let config = Config {
+            mainline: Some(MainlineConfig {
+                enabled: false,
+                bootstrap: Some(testnet.bootstrap.clone()),
+                republish: Some(Default::default()),
+            }),
+            ..Default::default()
+        };
+        let (server, _nameserver, http_url) = Server::spawn_for_tests_with_config(config).await?;
+
+        // publish a signed packet to our server
+        let mut pkarr_relay = http_url.clone();
+        pkarr_relay.set_path("/pkarr");
+        let signed_packet = random_signed_packet()?;
+        PkarrRelayClient::new(pkarr_relay)
+            .publish(&signed_packet)
+            .await?;
+
+        // resolve from our DHT
+        let pkarr = pkarr::Client::builder()
+            .no_default_network()
+            .dht(|builder| builder.bootstrap(&testnet.bootstrap))
+            .build()?;
+        let res = tokio::time::timeout(Duration::from_secs(10), async {
+            loop {
+                if let Some(packet) = pkarr.resolve_most_recent(&signed_packet.public_key()).await {
+                    break packet;
+                }
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+        })
+        .await?;
+        assert_eq!(res.as_bytes(), signed_packet.as_bytes());
+
+        server.shutdown().await?;
+        Ok(())
+    }
+
     fn test_resolver(nameserver: SocketAddr) -> DnsResolver {
         DnsResolver::with_nameserver(nameserver)
     }
@@ -2,7 +2,10 @@ use std::path::PathBuf;
 
 use anyhow::Result;
 use clap::Parser;
-use iroh_dns_server::{config::Config, server::run_with_config_until_ctrl_c};
+use iroh_dns_server::{
+    config::Config,
+    server::{run_with_config_file_until_ctrl_c, run_with_config_until_ctrl_c},
+};
 use tracing::debug;
 
 #[derive(Parser, Debug)]
@@ -17,13 +20,10 @@ async fn main() -> Result<()> {
     tracing_subscriber::fmt::init();
     let args = Cli::parse();
 
-    let config = if let Some(path) = args.config {
-        debug!("loading config from {:?}", path);
-        Config::load(path).await?
+    if let Some(path) = args.config {
+        run_with_config_file_until_ctrl_c(path).await
     } else {
         debug!("using default config");
-        Config::default()
-    };
-
-    run_with_config_until_ctrl_c(config).await
+        run_with_config_until_ctrl_c(Config::default()).await
+    }
 }
@@ -10,18 +10,32 @@ pub struct Metrics {
     pub pkarr_publish_update: Counter,
     /// Number of pkarr relay puts that did not update the state
     pub pkarr_publish_noop: Counter,
+    /// Number of pkarr relay puts rejected by the per-key rate limit
+    pub pkarr_publish_rate_limited: Counter,
+    /// Number of pkarr relay puts rejected by the packet policy
+    pub pkarr_publish_rejected: Counter,
     /// DNS requests (total)
     pub dns_requests: Counter,
     /// DNS requests via UDP
     pub dns_requests_udp: Counter,
     /// DNS requests via HTTPS (DoH)
     pub dns_requests_https: Counter,
+    /// DNS requests via TLS (DoT)
+    pub dns_requests_tls: Counter,
+    /// DNS requests via QUIC (DoQ)
+    pub dns_requests_quic: Counter,
     /// DNS lookup responses with at least one answer
     pub dns_lookup_success: Counter,
     /// DNS lookup responses with no answers
     pub dns_lookup_notfound: Counter,
     /// DNS lookup responses which failed
     pub dns_lookup_error: Counter,
+    /// Zone transfers (AXFR and IXFR) sent to secondaries
+    pub dns_transfers: Counter,
+    /// Zone transfer requests which were refused
+    pub dns_transfers_refused: Counter,
+    /// NOTIFY messages which were not acknowledged by a secondary
+    pub dns_notify_failed: Counter,
     /// Number of HTTP requests
     pub http_requests: Counter,
     /// Number of HTTP requests with a 2xx status code
@@ -38,4 +52,16 @@ pub struct Metrics {
     pub store_packets_updated: Counter,
     /// Number of expired packets
     pub store_packets_expired: Counter,
+    /// Signed packets sent to replication peers
+    pub replication_packets_sent: Counter,
+    /// Signed packets that could not be sent to replication peers
+    pub replication_packets_dropped: Counter,
+    /// Signed packets received from replication peers that updated the state
+    pub replication_packets_received: Counter,
+    /// Signed packets published to the mainline DHT
+    pub mainline_packets_published: Counter,
+    /// Signed packets that failed to publish to the mainline DHT
+    pub mainline_publish_errors: Counter,
+    /// Signed packets that were not published to the mainline DHT because the queue was full
+    pub mainline_packets_dropped: Counter,
 }
@@ -0,0 +1,117 @@
+//! Policy for signed packets published via the pkarr relay API.
+
+use anyhow::{bail, Result};
+use hickory_server::proto::rr::RecordType;
+use pkarr::SignedPacket;
+use serde::{Deserialize, Serialize};
+
+use crate::util::signed_packet_to_hickory_message;
+
+/// Policy that packets published via the pkarr relay API have to satisfy.
+///
+/// Packets that violate the policy are rejected before they are inserted into the store.
+/// The default policy accepts all valid packets.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PacketPolicy {
+    /// Maximum size of the encoded DNS packet in bytes.
+    ///
+    /// Pkarr limits packets to 1000 bytes.
+    pub max_packet_size: Option<usize>,
+    /// Record types that are allowed in a packet, e.g. `["TXT"]`.
+    ///
+    /// If not set, all record types are allowed.
+    pub allowed_record_types: Option<Vec<RecordType>>,
+    /// Minimum TTL of all records, in seconds.
+    pub min_ttl: Option<u32>,
+    /// Maximum TTL of all records, in seconds.
+    pub max_ttl: Option<u32>,
+}
+
+impl PacketPolicy {
+    /// Checks a packet against the policy.
+    ///
+    /// Returns an error that describes the violation if the packet is not allowed.
+    pub fn check(&self, packet: &SignedPacket) -> Result<()> {
+        if let Some(max) = self.max_packet_size {
+            let size = packet.encoded_packet().len();
+            if size > max {
+                bail!("packet size {size} exceeds the maximum of {max} bytes");
+            }
+        }
+        if self.allowed_record_types.is_none() && self.min_ttl.is_none() && self.max_ttl.is_none() {
+            return Ok(());
+        }
+        let message = signed_packet_to_hickory_message(packet)?;
+        for record in message.answers() {
+            let record_type = record.record_type();
+            if let Some(allowed) = &self.allowed_record_types {
+                if !allowed.contains(&record_type) {
+                    bail!("record type {record_type} is not allowed");
+                }
+            }
+            let ttl = record.ttl();
+            if self.min_ttl.is_some_and(|min| ttl < min) {
+                bail!("TTL {ttl} of {record_type} record is below the minimum");
+            }
+            if self.max_ttl.is_some_and(|max| ttl > max) {
+                bail!("TTL {ttl} of {record_type} record is above the maximum");
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use pkarr::{Keypair, SignedPacket};
+
+    use super::PacketPolicy;
+
+    fn test_packet(ttl: u32) -> SignedPacket {
+        SignedPacket::builder()
+            .txt(
+                "_iroh".try_into().unwrap(),
+                "foo=bar".try_into().unwrap(),
+                ttl,
+            )
+            .address("_iroh".try_into().unwrap(), Ipv4Addr::LOCALHOST.into(), ttl)
+            .sign(&Keypair::random())
+            .unwrap()
+    }
+
+    #[test]
+    fn check_policy() {
+        let packet = test_packet(30);
+        assert!(PacketPolicy::default().check(&packet).is_ok());
+
+        let size = packet.encoded_packet().len();
+        let policy = PacketPolicy {
+            max_packet_size: Some(size - 1),
+            ..Default::default()
+        };
+        assert!(policy.check(&packet).is_err());
+
+        let policy = PacketPolicy {
+            allowed_record_types: Some(vec!["TXT".parse().unwrap()]),
+            ..Default::default()
+        };
+        assert!(policy.check(&packet).is_err());
+        let policy = PacketPolicy {
+            allowed_record_types: Some(vec!["TXT".parse().unwrap(), "A".parse().unwrap()]),
+            ..Default::default()
+        };
+        assert!(policy.check(&packet).is_ok());
+
+        let policy = PacketPolicy {
+            min_ttl: Some(10),
+            max_ttl: Some(60),
+            ..Default::default()
+        };
+        assert!(policy.check(&packet).is_ok());
+        assert!(policy.check(&test_packet(5)).is_err());
+        assert!(policy.check(&test_packet(120)).is_err());
+    }
+}
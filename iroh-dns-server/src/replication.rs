@@ -0,0 +1,305 @@
+//! Replication of signed packets between iroh-dns-server instances.
+//!
+//! Packets published to this server are pushed to all configured peers in batches. Peers verify
+//! the signatures and store the packets with the same newer-timestamp rule as for packets
+//! published via the pkarr relay API. Packets received from a peer are not forwarded again, so
+//! every instance needs to list all other instances as peers.
+//!
+//! On startup, all packets are fetched from the peers to catch up on packets published while
+//! this instance was not running.
+//!
+//! Only packets are replicated. Removing or blocking a key via the admin API only affects
+//! this instance, so it has to be done on every instance. A removed packet comes back from a
+//! peer which still has it when this instance catches up on startup.
+
+use std::{sync::Arc, time::Duration};
+
+use anyhow::{bail, ensure, Context, Result};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use n0_future::task::AbortOnDropHandle;
+use pkarr::{SignedPacket, Timestamp};
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+use tracing::{debug, info, warn};
+use url::Url;
+
+use crate::{
+    metrics::Metrics,
+    store::{PacketSource, ZoneStore},
+};
+
+/// Path of the replication endpoint, relative to the base URL of a peer
+pub(crate) const PACKETS_PATH: &str = "replication/packets";
+/// Content type of a list of packets, see [`encode_packets`]
+pub(crate) const PACKETS_CONTENT_TYPE: &str = "application/x-iroh-dns-packets";
+/// Maximum number of packets returned from a single catch-up request
+pub(crate) const MAX_PAGE_SIZE: usize = 1024;
+
+/// Maximum number of packets to queue for replication
+const QUEUE_CAPACITY: usize = 1024 * 16;
+/// Maximum number of batches to queue per peer
+const PEER_QUEUE_CAPACITY: usize = 64;
+/// Maximum number of packets to send in a single request
+const MAX_BATCH_SIZE: usize = 256;
+/// Maximum time to wait for more packets before sending a batch
+const MAX_BATCH_TIME: Duration = Duration::from_millis(100);
+/// Timeout for requests to peers
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Replication settings
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReplicationConfig {
+    /// Base URLs of the other instances, e.g. `https://dns2.example.org/`.
+    pub peers: Vec<Url>,
+    /// Shared secret for the replication endpoints.
+    ///
+    /// Sent to the peers as bearer token, and required from them.
+    pub token: String,
+    /// Whether to fetch all packets from the peers on startup.
+    ///
+    /// Defaults to `true`.
+    #[serde(default = "default_catch_up")]
+    pub catch_up: bool,
+}
+
+fn default_catch_up() -> bool {
+    true
+}
+
+/// Runs the replication tasks, they are aborted when dropped.
+#[derive(Debug)]
+pub(crate) struct Replicator {
+    _tasks: Vec<AbortOnDropHandle<()>>,
+}
+
+impl Replicator {
+    /// Spawns the replication tasks and returns the store that forwards published packets
+    /// to them.
+    pub(crate) fn spawn(
+        config: ReplicationConfig,
+        store: ZoneStore,
+        metrics: Arc<Metrics>,
+    ) -> Result<(Self, ZoneStore)> {
+        let client = reqwest::Client::builder()
+            .timeout(REQUEST_TIMEOUT)
+            .build()?;
+        let peers = config
+            .peers
+            .iter()
+            .map(|url| {
+                Ok(Arc::new(Peer {
+                    url: url.join(PACKETS_PATH)?,
+                    token: config.token.clone(),
+                    client: client.clone(),
+                }))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let mut tasks = Vec::new();
+        if config.catch_up {
+            for peer in &peers {
+                let peer = peer.clone();
+                let store = store.clone();
+                let metrics = metrics.clone();
+                tasks.push(AbortOnDropHandle::new(tokio::spawn(async move {
+                    match peer.catch_up(&store, &metrics).await {
+                        Ok(count) => info!(peer = %peer.url, "caught up on {count} packets"),
+                        Err(err) => warn!(peer = %peer.url, "catch-up failed: {err:#}"),
+                    }
+                })));
+            }
+        }
+
+        let mut peer_queues = Vec::new();
+        for peer in peers {
+            let (send, recv) = mpsc::channel(PEER_QUEUE_CAPACITY);
+            let metrics = metrics.clone();
+            tasks.push(AbortOnDropHandle::new(tokio::spawn(async move {
+                peer.push_loop(recv, &metrics).await
+            })));
+            peer_queues.push(send);
+        }
+        let (send, recv) = mpsc::channel(QUEUE_CAPACITY);
+        tasks.push(AbortOnDropHandle::new(tokio::spawn(batch_loop(
+            recv,
+            peer_queues,
+            metrics,
+        ))));
+
+        info!(peers = ?config.peers, "replication enabled");
+        Ok((Self { _tasks: tasks }, store.with_replication(send)))
+    }
+}
+
+/// Collects published packets into batches and hands them to the peer tasks.
+async fn batch_loop(
+    mut recv: mpsc::Receiver<SignedPacket>,
+    peers: Vec<mpsc::Sender<(usize, Bytes)>>,
+    metrics: Arc<Metrics>,
+) {
+    let mut batch = Vec::new();
+    while let Some(packet) = recv.recv().await {
+        batch.push(packet);
+        let timeout = tokio::time::sleep(MAX_BATCH_TIME);
+        tokio::pin!(timeout);
+        while batch.len() < MAX_BATCH_SIZE {
+            tokio::select! {
+                _ = &mut timeout => break,
+                packet = recv.recv() => match packet {
+                    Some(packet) => batch.push(packet),
+                    None => break,
+                },
+            }
+        }
+        let body = encode_packets(&batch);
+        for peer in &peers {
+            if peer.try_send((batch.len(), body.clone())).is_err() {
+                metrics
+                    .replication_packets_dropped
+                    .inc_by(batch.len() as u64);
+            }
+        }
+        batch.clear();
+    }
+}
+
+#[derive(Debug)]
+struct Peer {
+    url: Url,
+    token: String,
+    client: reqwest::Client,
+}
+
+impl Peer {
+    async fn push_loop(&self, mut recv: mpsc::Receiver<(usize, Bytes)>, metrics: &Metrics) {
+        while let Some((count, body)) = recv.recv().await {
+            match self.push(body).await {
+                Ok(()) => {
+                    debug!(peer = %self.url, "sent {count} packets");
+                    metrics.replication_packets_sent.inc_by(count as u64);
+                }
+                Err(err) => {
+                    warn!(peer = %self.url, "failed to send {count} packets: {err:#}");
+                    metrics.replication_packets_dropped.inc_by(count as u64);
+                }
+            }
+        }
+    }
+
+    async fn push(&self, body: Bytes) -> Result<()> {
+        let request = self
+            .client
+            .put(self.url.clone())
+            .header(reqwest::header::CONTENT_TYPE, PACKETS_CONTENT_TYPE)
+            .body(body)
+            .bearer_auth(&self.token);
+        request.send().await?.error_for_status()?;
+        Ok(())
+    }
+
+    async fn fetch(&self, since: Timestamp) -> Result<Vec<SignedPacket>> {
+        let mut url = self.url.clone();
+        url.query_pairs_mut()
+            .append_pair("since", &u64::from(since).to_string())
+            .append_pair("limit", &MAX_PAGE_SIZE.to_string());
+        let request = self.client.get(url).bearer_auth(&self.token);
+        let body = request.send().await?.error_for_status()?.bytes().await?;
+        decode_packets(body)
+    }
+
+    /// Fetches all packets of the peer and inserts them into `store`.
+    ///
+    /// Returns the number of packets that updated the state.
+    async fn catch_up(&self, store: &ZoneStore, metrics: &Metrics) -> Result<usize> {
+        let mut since = Timestamp::from(0);
+        let mut count = 0;
+        loop {
+            let packets = self.fetch(since).await?;
+            let Some(last) = packets.last() else {
+                break;
+            };
+            // pages never end in the middle of a timestamp
+            since = Timestamp::from(u64::from(last.timestamp()) + 1);
+            let done = packets.len() < MAX_PAGE_SIZE;
+            for packet in packets {
+                if store.insert(packet, PacketSource::Replication).await? {
+                    metrics.replication_packets_received.inc();
+                    count += 1;
+                }
+            }
+            if done {
+                break;
+            }
+        }
+        Ok(count)
+    }
+}
+
+/// Encodes packets as a sequence of `public key | payload length (u32, big endian) | payload`,
+/// where the payload is the pkarr relay payload of the packet.
+pub(crate) fn encode_packets(packets: &[SignedPacket]) -> Bytes {
+    let mut buf = BytesMut::new();
+    for packet in packets {
+        let payload = packet.to_relay_payload();
+        buf.put_slice(&packet.public_key().to_bytes());
+        buf.put_u32(payload.len() as u32);
+        buf.put_slice(&payload);
+    }
+    buf.freeze()
+}
+
+/// Decodes packets encoded with [`encode_packets`] and verifies their signatures.
+pub(crate) fn decode_packets(mut bytes: Bytes) -> Result<Vec<SignedPacket>> {
+    let mut packets = Vec::new();
+    while bytes.has_remaining() {
+        ensure!(bytes.remaining() >= 32 + 4, "truncated packet header");
+        let public_key = pkarr::PublicKey::try_from(&bytes[..32]).context("invalid public key")?;
+        bytes.advance(32);
+        let len = bytes.get_u32() as usize;
+        if bytes.remaining() < len {
+            bail!("truncated packet payload");
+        }
+        let payload = bytes.split_to(len);
+        let packet = SignedPacket::from_relay_payload(&public_key, &payload)
+            .with_context(|| format!("invalid packet for {public_key}"))?;
+        packets.push(packet);
+    }
+    Ok(packets)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::packet;
+
+    #[test]
+    fn encode_decode_packets() -> Result<()> {
+        let packets = vec![
+            packet(&pkarr::Keypair::random(), "hi", u64::from(Timestamp::now()))?,
+            packet(
+                &pkarr::Keypair::random(),
+                "there",
+                u64::from(Timestamp::now()),
+            )?,
+        ];
+        let encoded = encode_packets(&packets);
+        let decoded = decode_packets(encoded.clone())?;
+        let payloads = |packets: &[SignedPacket]| {
+            packets
+                .iter()
+                .map(|packet| (packet.public_key(), packet.to_relay_payload()))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(payloads(&decoded), payloads(&packets));
+
+        // A payload that does not match the signature is rejected.
+        let mut tampered = BytesMut::from(&encoded[..]);
+        let last = tampered.len() - 1;
+        tampered[last] ^= 1;
+        assert!(decode_packets(tampered.freeze()).is_err());
+
+        // Truncated input is rejected.
+        assert!(decode_packets(encoded.slice(..encoded.len() - 1)).is_err());
+        Ok(())
+    }
+}
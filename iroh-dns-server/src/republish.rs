@@ -0,0 +1,199 @@
+//! Publishing of signed packets to the bittorrent mainline DHT.
+//!
+//! Packets published to this server via the pkarr relay API are published to the DHT as well,
+//! so that nodes that resolve directly from the DHT can find them. The DHT forgets packets after
+//! a few hours, so all packets in the store are republished periodically.
+
+use std::{num::NonZeroU32, sync::Arc, time::Duration};
+
+use anyhow::Result;
+use governor::{DefaultDirectRateLimiter, Quota, RateLimiter};
+use n0_future::task::AbortOnDropHandle;
+use pkarr::{Client as PkarrClient, SignedPacket, Timestamp};
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+use tracing::{debug, info, warn};
+
+use crate::{config::BootstrapOption, metrics::Metrics, store::ZoneStore};
+
+/// Maximum number of freshly published packets to queue for publishing
+const QUEUE_CAPACITY: usize = 1024 * 16;
+/// Maximum number of packets read from the store to queue for republishing
+const REPUBLISH_QUEUE_CAPACITY: usize = 1024;
+/// Number of packets to read from the store at once when republishing
+const REPUBLISH_PAGE_SIZE: usize = 1024;
+
+/// Settings for publishing packets to the mainline DHT
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RepublishConfig {
+    /// Maximum number of packets to publish per second.
+    ///
+    /// Defaults to 10.
+    pub rate_limit: NonZeroU32,
+    /// Maximum number of packets to publish concurrently.
+    ///
+    /// Defaults to 16.
+    pub max_concurrency: usize,
+    /// Interval in which all packets in the store are republished.
+    ///
+    /// This should be well below the time after which DHT nodes drop packets, which is usually
+    /// about two hours. Defaults to one hour.
+    #[serde(with = "humantime_serde")]
+    pub interval: Duration,
+}
+
+impl Default for RepublishConfig {
+    fn default() -> Self {
+        Self {
+            rate_limit: NonZeroU32::new(10).expect("not zero"),
+            max_concurrency: 16,
+            interval: Duration::from_secs(60 * 60),
+        }
+    }
+}
+
+/// Runs the DHT publishing tasks, they are aborted when dropped.
+#[derive(Debug)]
+pub(crate) struct Republisher {
+    _tasks: Vec<AbortOnDropHandle<()>>,
+}
+
+impl Republisher {
+    /// Spawns the publishing tasks and returns the store that forwards published packets to
+    /// them.
+    pub(crate) fn spawn(
+        config: RepublishConfig,
+        bootstrap: BootstrapOption,
+        store: ZoneStore,
+        metrics: Arc<Metrics>,
+    ) -> Result<(Self, ZoneStore)> {
+        let mut builder = PkarrClient::builder();
+        builder.no_relays();
+        if let BootstrapOption::Custom(bootstrap) = bootstrap {
+            builder.bootstrap(&bootstrap);
+        }
+        let publisher = Publisher {
+            client: builder.build()?,
+            limiter: RateLimiter::direct(Quota::per_second(config.rate_limit)),
+            metrics,
+        };
+
+        // Fresh publishes get their own queue, so a running republish does not fill the queue
+        // the store feeds without waiting.
+        let (send, recv) = mpsc::channel(QUEUE_CAPACITY);
+        let (republish_send, republish_recv) = mpsc::channel(REPUBLISH_QUEUE_CAPACITY);
+        let tasks = vec![
+            AbortOnDropHandle::new(tokio::spawn(publish_loop(
+                recv,
+                republish_recv,
+                publisher,
+                config.max_concurrency.max(1),
+            ))),
+            AbortOnDropHandle::new(tokio::spawn(republish_loop(
+                store.clone(),
+                republish_send,
+                config.interval,
+            ))),
+        ];
+
+        info!(rate_limit = %config.rate_limit, interval = ?config.interval, "publishing to mainline enabled");
+        Ok((Self { _tasks: tasks }, store.with_republish(send)))
+    }
+}
+
+/// Publishes queued packets in batches of up to `max_concurrency` packets.
+///
+/// Freshly published packets from `fresh` are taken before packets from `republish`.
+async fn publish_loop(
+    mut fresh: mpsc::Receiver<SignedPacket>,
+    mut republish: mpsc::Receiver<SignedPacket>,
+    publisher: Publisher,
+    max_concurrency: usize,
+) {
+    let mut batch: Vec<SignedPacket> = Vec::with_capacity(max_concurrency);
+    loop {
+        let packet = tokio::select! {
+            biased;
+            Some(packet) = fresh.recv() => packet,
+            Some(packet) = republish.recv() => packet,
+            else => break,
+        };
+        batch.push(packet);
+        while batch.len() < max_concurrency {
+            let Ok(packet) = fresh.try_recv().or_else(|_| republish.try_recv()) else {
+                break;
+            };
+            // publishing two packets for the same key concurrently fails, keep the newest one
+            match batch
+                .iter_mut()
+                .find(|p| p.public_key() == packet.public_key())
+            {
+                Some(existing) if existing.timestamp() < packet.timestamp() => *existing = packet,
+                Some(_) => {}
+                None => batch.push(packet),
+            }
+        }
+        n0_future::join_all(batch.iter().map(|packet| publisher.publish(packet))).await;
+        batch.clear();
+    }
+}
+
+/// Queues all packets in the store for publishing once per `interval`.
+async fn republish_loop(store: ZoneStore, queue: mpsc::Sender<SignedPacket>, interval: Duration) {
+    let mut interval = tokio::time::interval(interval);
+    // the first tick completes immediately, packets are republished after a restart
+    loop {
+        interval.tick().await;
+        match republish_all(&store, &queue).await {
+            Ok(count) => debug!("queued {count} packets for republishing"),
+            Err(err) => warn!("failed to republish packets: {err:#}"),
+        }
+    }
+}
+
+async fn republish_all(store: &ZoneStore, queue: &mpsc::Sender<SignedPacket>) -> Result<usize> {
+    let mut since = Timestamp::from(0);
+    let mut count = 0;
+    loop {
+        let packets = store.packets_since(since, REPUBLISH_PAGE_SIZE).await?;
+        let Some(last) = packets.last() else {
+            break;
+        };
+        // pages never end in the middle of a timestamp
+        since = Timestamp::from(u64::from(last.timestamp()) + 1);
+        let done = packets.len() < REPUBLISH_PAGE_SIZE;
+        for packet in packets {
+            queue.send(packet).await?;
+            count += 1;
+        }
+        if done {
+            break;
+        }
+    }
+    Ok(count)
+}
+
+#[derive(Debug)]
+struct Publisher {
+    client: PkarrClient,
+    limiter: DefaultDirectRateLimiter,
+    metrics: Arc<Metrics>,
+}
+
+impl Publisher {
+    async fn publish(&self, packet: &SignedPacket) {
+        self.limiter.until_ready().await;
+        let key = packet.public_key();
+        match self.client.publish(packet, None).await {
+            Ok(()) => {
+                debug!(key = %key.to_z32(), "published packet to mainline");
+                self.metrics.mainline_packets_published.inc();
+            }
+            Err(err) => {
+                debug!(key = %key.to_z32(), "failed to publish packet to mainline: {err}");
+                self.metrics.mainline_publish_errors.inc();
+            }
+        }
+    }
+}
@@ -1,27 +1,46 @@
 //! The main server which combines the DNS and HTTP(S) servers.
 
-use std::sync::Arc;
+use std::{path::PathBuf, sync::Arc};
 
 use anyhow::Result;
 use iroh_metrics::service::start_metrics_server;
-use tracing::info;
+use tokio::sync::Semaphore;
+use tracing::{info, warn};
 
 use crate::{
     config::Config,
     dns::{DnsHandler, DnsServer},
-    http::HttpServer,
+    http::{HttpServer, KeyRateLimiter, MAX_WATCH_CONNECTIONS},
     metrics::Metrics,
+    replication::Replicator,
+    republish::Republisher,
     state::AppState,
-    store::ZoneStore,
+    store::{ZoneStore, ZoneStoreOptions},
 };
 
 /// Spawn the server and run until the `Ctrl-C` signal is received, then shutdown.
+///
+/// On unix, the static zone data is reloaded from the configured zone files on `SIGHUP`.
 pub async fn run_with_config_until_ctrl_c(config: Config) -> Result<()> {
+    run_until_ctrl_c(config, None).await
+}
+
+/// Load the config from `path`, spawn the server and run until the `Ctrl-C` signal is received,
+/// then shutdown.
+///
+/// On unix, the config file is re-read on `SIGHUP` and the DNS zone configuration (origins, SOA
+/// and NS records and zone files) is reloaded from it.
+pub async fn run_with_config_file_until_ctrl_c(path: PathBuf) -> Result<()> {
+    let config = Config::load(&path).await?;
+    run_until_ctrl_c(config, Some(path)).await
+}
+
+async fn run_until_ctrl_c(config: Config, config_path: Option<PathBuf>) -> Result<()> {
     let metrics = Arc::new(Metrics::default());
-    let zone_store_options = config.zone_store.clone().unwrap_or_default();
+    let zone_store_options: ZoneStoreOptions = config.zone_store.clone().unwrap_or_default().into();
     let mut store = ZoneStore::persistent(
-        Config::signed_packet_store_path()?,
-        zone_store_options.into(),
+        Config::signed_packet_store_path_for(zone_store_options.backend)?,
+        zone_store_options,
         metrics.clone(),
     )?;
     if let Some(bootstrap) = config.mainline_enabled() {
@@ -29,17 +48,59 @@ pub async fn run_with_config_until_ctrl_c(config: Config) -> Result<()> {
         store = store.with_mainline_fallback(bootstrap);
     };
     let server = Server::spawn(config, store, metrics).await?;
+    let reload_task = spawn_reload_on_sighup(server.dns_handler().clone(), config_path)?;
     tokio::signal::ctrl_c().await?;
     info!("shutdown");
+    reload_task.abort();
     server.shutdown().await?;
     Ok(())
 }
 
+/// Reload the DNS zones whenever `SIGHUP` is received.
+#[cfg(unix)]
+fn spawn_reload_on_sighup(
+    dns_handler: DnsHandler,
+    config_path: Option<PathBuf>,
+) -> Result<tokio::task::JoinHandle<()>> {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut sighup = signal(SignalKind::hangup())?;
+    Ok(tokio::task::spawn(async move {
+        while sighup.recv().await.is_some() {
+            info!("SIGHUP received, reloading DNS zones");
+            if let Err(err) = reload_zones(&dns_handler, config_path.as_ref()).await {
+                warn!("failed to reload DNS zones: {err:#}");
+            }
+        }
+    }))
+}
+
+#[cfg(not(unix))]
+fn spawn_reload_on_sighup(
+    _dns_handler: DnsHandler,
+    _config_path: Option<PathBuf>,
+) -> Result<tokio::task::JoinHandle<()>> {
+    Ok(tokio::task::spawn(async {}))
+}
+
+#[cfg(unix)]
+async fn reload_zones(dns_handler: &DnsHandler, config_path: Option<&PathBuf>) -> Result<()> {
+    let config = match config_path {
+        Some(path) => Some(Config::load(path).await?.dns),
+        None => None,
+    };
+    let dns_handler = dns_handler.clone();
+    tokio::task::spawn_blocking(move || dns_handler.reload(config.as_ref())).await??;
+    Ok(())
+}
+
 /// The iroh-dns server.
 pub struct Server {
     http_server: HttpServer,
     dns_server: DnsServer,
+    dns_handler: DnsHandler,
     metrics_task: tokio::task::JoinHandle<anyhow::Result<()>>,
+    _replicator: Option<Replicator>,
+    _republisher: Option<Republisher>,
 }
 
 impl Server {
@@ -49,13 +110,38 @@ impl Server {
     /// * A DNS server task
     /// * A HTTP server task, if `config.http` is not empty
     /// * A HTTPS server task, if `config.https` is not empty
+    /// * Replication tasks, if `config.replication` is not empty
+    /// * Mainline DHT publishing tasks, if `config.mainline.republish` is not empty
     pub async fn spawn(config: Config, store: ZoneStore, metrics: Arc<Metrics>) -> Result<Self> {
+        let (replicator, store) = match config.replication.clone() {
+            Some(replication) => {
+                let (replicator, store) = Replicator::spawn(replication, store, metrics.clone())?;
+                (Some(replicator), store)
+            }
+            None => (None, store),
+        };
+        let (republisher, store) = match config.mainline_republish() {
+            Some((bootstrap, republish)) => {
+                let (republisher, store) =
+                    Republisher::spawn(republish, bootstrap, store, metrics.clone())?;
+                (Some(republisher), store)
+            }
+            None => (None, store),
+        };
         let dns_handler = DnsHandler::new(store.clone(), &config.dns, metrics.clone())?;
 
         let state = AppState {
             store,
-            dns_handler,
+            dns_handler: dns_handler.clone(),
             metrics: metrics.clone(),
+            replication: config.replication.clone(),
+            admin: config.admin.clone(),
+            pkarr_put_key_rate_limit: config
+                .pkarr_put_key_rate_limit
+                .as_ref()
+                .map(KeyRateLimiter::new),
+            pkarr_put_policy: config.pkarr_put_policy.clone(),
+            pkarr_watch_connections: Arc::new(Semaphore::new(MAX_WATCH_CONNECTIONS)),
         };
 
         let metrics_addr = config.metrics_addr();
@@ -74,14 +160,27 @@ impl Server {
             state.clone(),
         )
         .await?;
-        let dns_server = DnsServer::spawn(config.dns, state.dns_handler.clone()).await?;
+        let dns_server = DnsServer::spawn(
+            config.dns,
+            state.dns_handler.clone(),
+            http_server.cert_resolver(),
+        )
+        .await?;
         Ok(Self {
             http_server,
             dns_server,
+            dns_handler,
             metrics_task,
+            _replicator: replicator,
+            _republisher: republisher,
         })
     }
 
+    /// Get the DNS handler, which can be used to reload the static zone data.
+    pub fn dns_handler(&self) -> &DnsHandler {
+        &self.dns_handler
+    }
+
     /// Cancel the server tasks and wait for all tasks to complete.
     pub async fn shutdown(self) -> Result<()> {
         self.metrics_task.abort();
@@ -114,6 +213,37 @@ impl Server {
         Self::spawn_for_tests_with_options(None, None).await
     }
 
+    /// Spawn a server suitable for testing, with a custom config.
+    ///
+    /// The ports and bind addresses of the DNS and HTTP servers are overridden to listen on
+    /// random ports on localhost, the HTTPS and metrics servers are disabled. The store is kept
+    /// in memory.
+    #[cfg(test)]
+    pub async fn spawn_for_tests_with_config(
+        mut config: Config,
+    ) -> Result<(Self, std::net::SocketAddr, url::Url)> {
+        use std::net::{IpAddr, Ipv4Addr};
+
+        use crate::config::MetricsConfig;
+
+        config.dns.port = 0;
+        config.dns.bind_addr = Some(IpAddr::V4(Ipv4Addr::LOCALHOST));
+        config.http = Some(crate::http::HttpConfig {
+            port: 0,
+            bind_addr: Some(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+        });
+        config.https = None;
+        config.metrics = Some(MetricsConfig::disabled());
+
+        let options = config.zone_store.clone().unwrap_or_default().into();
+        let store = ZoneStore::in_memory(options, Default::default())?;
+        let server = Self::spawn(config, store, Default::default()).await?;
+        let dns_addr = server.dns_server.local_addr();
+        let http_addr = server.http_server.http_addr().expect("http is set");
+        let http_url = format!("http://{http_addr}").parse()?;
+        Ok((server, dns_addr, http_url))
+    }
+
     /// Spawn a server suitable for testing, while optionally enabling mainline with custom
     /// bootstrap addresses.
     #[cfg(test)]
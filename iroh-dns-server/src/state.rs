@@ -2,7 +2,16 @@
 
 use std::sync::Arc;
 
-use crate::{dns::DnsHandler, metrics::Metrics, store::ZoneStore};
+use tokio::sync::Semaphore;
+
+use crate::{
+    dns::DnsHandler,
+    http::{AdminConfig, KeyRateLimiter},
+    metrics::Metrics,
+    policy::PacketPolicy,
+    replication::ReplicationConfig,
+    store::ZoneStore,
+};
 
 /// The shared app state.
 #[derive(Clone)]
@@ -13,4 +22,14 @@ pub struct AppState {
     pub dns_handler: DnsHandler,
     /// Metrics collector.
     pub metrics: Arc<Metrics>,
+    /// Replication settings, if enabled.
+    pub replication: Option<ReplicationConfig>,
+    /// Admin API settings, if enabled.
+    pub admin: Option<AdminConfig>,
+    /// Rate limiter for pkarr puts per public key, if enabled.
+    pub pkarr_put_key_rate_limit: Option<KeyRateLimiter>,
+    /// Policy for packets published via pkarr puts.
+    pub pkarr_put_policy: PacketPolicy,
+    /// Limits the number of pkarr watch requests served at the same time.
+    pub pkarr_watch_connections: Arc<Semaphore>,
 }
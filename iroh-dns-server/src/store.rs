@@ -1,13 +1,19 @@
 //! Pkarr packet store used to resolve DNS queries.
 
-use std::{collections::BTreeMap, num::NonZeroUsize, path::Path, sync::Arc, time::Duration};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    num::NonZeroUsize,
+    path::Path,
+    sync::Arc,
+    time::Duration,
+};
 
 use anyhow::Result;
-use hickory_server::proto::rr::{Name, RecordSet, RecordType, RrKey};
+use hickory_server::proto::rr::{LowerName, Name, RecordSet, RecordType, RrKey};
 use lru::LruCache;
-use pkarr::{Client as PkarrClient, SignedPacket};
-use tokio::sync::Mutex;
-use tracing::{debug, trace};
+use pkarr::{Client as PkarrClient, SignedPacket, Timestamp};
+use tokio::sync::{broadcast, mpsc, Mutex};
+use tracing::{debug, trace, warn};
 use ttl_cache::TtlCache;
 
 use self::signed_packets::SignedPacketStore;
@@ -18,17 +24,23 @@ use crate::{
 };
 
 mod signed_packets;
-pub use signed_packets::Options as ZoneStoreOptions;
+pub(crate) use signed_packets::PacketChange;
+pub use signed_packets::{Options as ZoneStoreOptions, StoreBackend};
 
 /// Cache up to 1 million pkarr zones by default
 pub const DEFAULT_CACHE_CAPACITY: usize = 1024 * 1024;
 /// Default TTL for DHT cache entries
 pub const DHT_CACHE_TTL: Duration = Duration::from_secs(300);
+/// Number of updates buffered for each subscriber before it lags behind
+const UPDATES_CAPACITY: usize = 1024;
 
 /// Where a new pkarr packet comes from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum PacketSource {
     /// Received via HTTPS relay PUT
     PkarrPublish,
+    /// Received from a replication peer
+    Replication,
 }
 
 /// A store for pkarr signed packets.
@@ -40,6 +52,9 @@ pub struct ZoneStore {
     cache: Arc<Mutex<ZoneCache>>,
     store: Arc<SignedPacketStore>,
     pkarr: Option<Arc<PkarrClient>>,
+    replication: Option<mpsc::Sender<SignedPacket>>,
+    republish: Option<mpsc::Sender<SignedPacket>>,
+    updates: broadcast::Sender<SignedPacket>,
     metrics: Arc<Metrics>,
 }
 
@@ -80,6 +95,28 @@ impl ZoneStore {
         }
     }
 
+    /// Forward packets published to this server to the replication peers.
+    ///
+    /// Packets that updated the state are sent to `replication`, unless they were received
+    /// from a replication peer themselves.
+    pub(crate) fn with_replication(self, replication: mpsc::Sender<SignedPacket>) -> Self {
+        Self {
+            replication: Some(replication),
+            ..self
+        }
+    }
+
+    /// Forward packets published to this server to the mainline DHT publisher.
+    ///
+    /// Packets that updated the state are sent to `republish`, unless they were received from a
+    /// replication peer, which publishes them itself.
+    pub(crate) fn with_republish(self, republish: mpsc::Sender<SignedPacket>) -> Self {
+        Self {
+            republish: Some(republish),
+            ..self
+        }
+    }
+
     /// Create a new zone store.
     pub fn new(store: SignedPacketStore, metrics: Arc<Metrics>) -> Self {
         let zone_cache = ZoneCache::new(DEFAULT_CACHE_CAPACITY);
@@ -87,10 +124,26 @@ impl ZoneStore {
             store: Arc::new(store),
             cache: Arc::new(Mutex::new(zone_cache)),
             pkarr: None,
+            replication: None,
+            republish: None,
+            updates: broadcast::channel(UPDATES_CAPACITY).0,
             metrics,
         }
     }
 
+    /// Subscribe to packets that updated the state.
+    ///
+    /// Every packet accepted by [`Self::insert`] is sent to all subscribers, regardless of its
+    /// source.
+    pub fn subscribe(&self) -> broadcast::Receiver<SignedPacket> {
+        self.updates.subscribe()
+    }
+
+    /// Subscribe to all changes of the stored packets, including removals.
+    pub(crate) fn subscribe_changes(&self) -> broadcast::Receiver<PacketChange> {
+        self.store.subscribe_changes()
+    }
+
     /// Resolve a DNS query.
     #[allow(clippy::unused_async)]
     pub async fn resolve(
@@ -113,6 +166,9 @@ impl ZoneStore {
         };
 
         if let Some(pkarr) = self.pkarr.as_ref() {
+            if self.store.is_blocked(pubkey).await? {
+                return Ok(None);
+            }
             let key = pkarr::PublicKey::try_from(pubkey.as_bytes()).expect("valid public key");
             // use the more expensive `resolve_most_recent` here.
             //
@@ -140,17 +196,97 @@ impl ZoneStore {
         self.store.get(pubkey).await
     }
 
+    /// Get up to about `limit` signed packets with a timestamp of at least `since`, ordered by
+    /// timestamp.
+    pub async fn packets_since(&self, since: Timestamp, limit: usize) -> Result<Vec<SignedPacket>> {
+        self.store.packets_since(since, limit).await
+    }
+
+    /// Remove the signed packet for a pubkey.
+    ///
+    /// Returns whether a packet was removed.
+    pub async fn remove(&self, pubkey: &PublicKeyBytes) -> Result<bool> {
+        let removed = self.store.remove(pubkey).await?;
+        self.cache.lock().await.remove(pubkey);
+        Ok(removed)
+    }
+
+    /// Block or unblock a pubkey.
+    ///
+    /// Blocking a pubkey removes its signed packet. New packets for blocked pubkeys are rejected
+    /// and they are not resolved from the DHT. Returns whether this changed the state of the
+    /// pubkey.
+    pub async fn set_blocked(&self, pubkey: &PublicKeyBytes, blocked: bool) -> Result<bool> {
+        let changed = self.store.set_blocked(pubkey, blocked).await?;
+        self.cache.lock().await.remove(pubkey);
+        Ok(changed)
+    }
+
+    /// Whether a pubkey is blocked.
+    pub async fn is_blocked(&self, pubkey: &PublicKeyBytes) -> Result<bool> {
+        self.store.is_blocked(pubkey).await
+    }
+
+    /// Get all blocked pubkeys.
+    pub async fn blocked(&self) -> Result<Vec<PublicKeyBytes>> {
+        self.store.blocked().await
+    }
+
+    /// Get the record types that exist at `name` in the zone of `pubkey`.
+    pub async fn record_types(
+        &self,
+        pubkey: &PublicKeyBytes,
+        name: &Name,
+    ) -> Result<BTreeSet<RecordType>> {
+        let Some(signed_packet) = self.get_signed_packet(pubkey).await? else {
+            return Ok(BTreeSet::new());
+        };
+        let (_label, records) =
+            signed_packet_to_hickory_records_without_origin(&signed_packet, |_| true)?;
+        let name = LowerName::from(name);
+        Ok(records
+            .keys()
+            .filter(|key| key.name == name)
+            .map(|key| key.record_type)
+            .collect())
+    }
+
     /// Insert a signed packet into the cache and the store.
     ///
     /// Returns whether this produced an update, i.e. whether the packet is the newest for its
     /// pubkey.
     // allow unused async: this will be async soon.
     #[allow(clippy::unused_async)]
-    pub async fn insert(&self, signed_packet: SignedPacket, _source: PacketSource) -> Result<bool> {
+    pub async fn insert(&self, signed_packet: SignedPacket, source: PacketSource) -> Result<bool> {
         let pubkey = PublicKeyBytes::from_signed_packet(&signed_packet);
+        let forward = source == PacketSource::PkarrPublish
+            && (self.replication.is_some() || self.republish.is_some());
+        let watched = self.updates.receiver_count() > 0;
+        let packet = (forward || watched).then(|| signed_packet.clone());
         if self.store.upsert(signed_packet).await? {
             self.metrics.pkarr_publish_update.inc();
             self.cache.lock().await.remove(&pubkey);
+            if let Some(signed_packet) = packet {
+                if watched {
+                    // only fails if all subscribers are gone in the meantime
+                    self.updates.send(signed_packet.clone()).ok();
+                }
+                if !forward {
+                    return Ok(true);
+                }
+                if let Some(replication) = &self.replication {
+                    if replication.try_send(signed_packet.clone()).is_err() {
+                        warn!(%pubkey, "replication queue full, dropping packet");
+                        self.metrics.replication_packets_dropped.inc();
+                    }
+                }
+                if let Some(republish) = &self.republish {
+                    if republish.try_send(signed_packet).is_err() {
+                        warn!(%pubkey, "mainline publish queue full, dropping packet");
+                        self.metrics.mainline_packets_dropped.inc();
+                    }
+                }
+            }
             Ok(true)
         } else {
             self.metrics.pkarr_publish_noop.inc();
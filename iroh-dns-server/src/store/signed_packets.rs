@@ -1,25 +1,94 @@
-use std::{future::Future, path::Path, result, sync::Arc, time::Duration};
+use std::{future::Future, path::Path, sync::Arc, time::Duration};
 
 use anyhow::{Context, Result};
 use pkarr::{SignedPacket, Timestamp};
-use redb::{
-    backends::InMemoryBackend, Database, MultimapTableDefinition, ReadableTable, TableDefinition,
-};
-use tokio::sync::{mpsc, oneshot};
+use serde::{Deserialize, Serialize};
+use tokio::sync::{broadcast, mpsc, oneshot};
 use tokio_util::sync::CancellationToken;
 use tracing::{debug, error, info, trace};
 
+use self::{log_storage::LogStorage, redb_storage::RedbStorage};
 use crate::{metrics::Metrics, util::PublicKeyBytes};
 
-pub type SignedPacketsKey = [u8; 32];
-const SIGNED_PACKETS_TABLE: TableDefinition<&SignedPacketsKey, &[u8]> =
-    TableDefinition::new("signed-packets-1");
-const UPDATE_TIME_TABLE: MultimapTableDefinition<[u8; 8], SignedPacketsKey> =
-    MultimapTableDefinition::new("update-time-1");
+mod log_storage;
+mod redb_storage;
+
+/// Number of changes buffered for each subscriber before it lags behind
+const CHANGES_CAPACITY: usize = 1024;
+
+/// Storage backend for signed packets.
+///
+/// The methods are only called from the store actor, which runs on its own thread, so
+/// implementations may do blocking IO. Writes may be buffered until [`Storage::commit`].
+pub trait Storage: Send + 'static {
+    /// Get the packet for `key`.
+    fn get(&mut self, key: &PublicKeyBytes) -> Result<Option<SignedPacket>>;
+
+    /// Insert `packet`, replacing the existing packet for its key.
+    ///
+    /// Returns whether a packet was replaced. Checking that the new packet is more recent is up
+    /// to the caller.
+    fn upsert(&mut self, packet: &SignedPacket) -> Result<bool>;
+
+    /// Remove the packet for `key`, returns the removed packet.
+    fn remove(&mut self, key: &PublicKeyBytes) -> Result<Option<SignedPacket>>;
+
+    /// Returns the keys of up to `limit` packets with a timestamp older than `before`, oldest
+    /// first.
+    fn expired(&mut self, before: Timestamp, limit: usize) -> Result<Vec<PublicKeyBytes>>;
+
+    /// Returns the packets with a timestamp of at least `since`, ordered by timestamp.
+    ///
+    /// Stops after the first timestamp at which `limit` packets are reached, so that packets
+    /// with the same timestamp are never split between two calls.
+    fn packets_since(&mut self, since: Timestamp, limit: usize) -> Result<Vec<SignedPacket>>;
+
+    /// Blocks or unblocks `key`.
+    ///
+    /// Returns whether this changed the state of the key. Blocking a key does not remove its
+    /// packet.
+    fn set_blocked(&mut self, key: &PublicKeyBytes, blocked: bool) -> Result<bool>;
+
+    /// Returns whether `key` is blocked.
+    fn is_blocked(&mut self, key: &PublicKeyBytes) -> Result<bool>;
+
+    /// Returns all blocked keys.
+    fn blocked(&mut self) -> Result<Vec<PublicKeyBytes>>;
+
+    /// Persist all writes since the last commit.
+    fn commit(&mut self) -> Result<()>;
+}
+
+/// The storage backend to use for a persistent store.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StoreBackend {
+    /// A [redb](https://docs.rs/redb) database.
+    #[default]
+    Redb,
+    /// An append-only log with an in-memory index, which is compacted when it contains more
+    /// overwritten than live packets.
+    ///
+    /// Writes are cheaper than with [`StoreBackend::Redb`], at the cost of keeping the index of
+    /// all keys in memory and replaying the log on startup.
+    Log,
+}
+
+/// A change of the packet stored for a key.
+#[derive(Debug, Clone)]
+pub struct PacketChange {
+    /// The key whose packet changed
+    pub key: PublicKeyBytes,
+    /// The packet before the change, if there was one
+    pub old: Option<SignedPacket>,
+    /// The packet after the change, `None` if the packet was removed
+    pub new: Option<SignedPacket>,
+}
 
 #[derive(Debug)]
 pub struct SignedPacketStore {
     send: mpsc::Sender<Message>,
+    changes: broadcast::Sender<PacketChange>,
     cancel: CancellationToken,
     _write_thread: IoThread,
     _evict_thread: IoThread,
@@ -33,7 +102,7 @@ impl Drop for SignedPacketStore {
     }
 }
 
-#[derive(derive_more::Debug)]
+#[derive(Debug)]
 enum Message {
     Upsert {
         packet: SignedPacket,
@@ -47,24 +116,36 @@ enum Message {
         key: PublicKeyBytes,
         res: oneshot::Sender<bool>,
     },
-    Snapshot {
-        #[debug(skip)]
-        res: oneshot::Sender<Snapshot>,
+    PacketsSince {
+        since: Timestamp,
+        limit: usize,
+        res: oneshot::Sender<Vec<SignedPacket>>,
     },
-    CheckExpired {
-        time: [u8; 8],
+    SetBlocked {
         key: PublicKeyBytes,
+        blocked: bool,
+        res: oneshot::Sender<bool>,
+    },
+    IsBlocked {
+        key: PublicKeyBytes,
+        res: oneshot::Sender<bool>,
     },
+    Blocked {
+        res: oneshot::Sender<Vec<PublicKeyBytes>>,
+    },
+    Evict,
 }
 
 struct Actor {
-    db: Database,
+    storage: Box<dyn Storage>,
     recv: PeekableReceiver<Message>,
     cancel: CancellationToken,
     options: Options,
+    changes: broadcast::Sender<PacketChange>,
     metrics: Arc<Metrics>,
 }
 
+/// Options for the signed packet store.
 #[derive(Debug, Clone, Copy)]
 pub struct Options {
     /// Maximum number of packets to process in a single write transaction.
@@ -75,6 +156,8 @@ pub struct Options {
     pub eviction: Duration,
     /// Pause between eviction checks.
     pub eviction_interval: Duration,
+    /// The storage backend, only used for persistent stores.
+    pub backend: StoreBackend,
 }
 
 impl Default for Options {
@@ -88,6 +171,7 @@ impl Default for Options {
             eviction: Duration::from_secs(3600 * 24 * 7),
             // eviction can run frequently since it does not do a full scan
             eviction_interval: Duration::from_secs(10),
+            backend: StoreBackend::default(),
         }
     }
 }
@@ -104,129 +188,115 @@ impl Actor {
     }
 
     async fn run0(&mut self) -> anyhow::Result<()> {
-        let expiry_us = self.options.eviction.as_micros() as u64;
         while let Some(msg) = self.recv.recv().await {
-            // if we get a snapshot message here we don't need to do a write transaction
-            let msg = if let Message::Snapshot { res } = msg {
-                let snapshot = Snapshot::new(&self.db)?;
-                res.send(snapshot).ok();
-                continue;
-            } else {
-                msg
-            };
             trace!("batch");
             self.recv.push_back(msg).unwrap();
-            let transaction = self.db.begin_write()?;
-            let mut tables = Tables::new(&transaction)?;
             let timeout = tokio::time::sleep(self.options.max_batch_time);
-            let expired = Timestamp::now() - expiry_us;
             tokio::pin!(timeout);
             for _ in 0..self.options.max_batch_size {
                 tokio::select! {
                     _ = self.cancel.cancelled() => {
-                        drop(tables);
-                        transaction.commit()?;
+                        self.storage.commit()?;
                         return Ok(());
                     }
                     _ = &mut timeout => break,
-                    Some(msg) = self.recv.recv() => {
-                        match msg {
-                            Message::Get { key, res } => {
-                                trace!("get {}", key);
-                                let packet = get_packet(&tables.signed_packets, &key).context("get packet failed")?;
-                                res.send(packet).ok();
-                            }
-                            Message::Upsert { packet, res } => {
-                                let key = PublicKeyBytes::from_signed_packet(&packet);
-                                trace!("upsert {}", key);
-                                let replaced = if let Some(existing) = get_packet(&tables.signed_packets, &key)? {
-                                    if existing.more_recent_than(&packet) {
-                                        res.send(false).ok();
-                                        continue;
-                                    } else {
-                                        // remove the packet from the update time index
-                                        tables.update_time.remove(&packet.timestamp().to_bytes(), key.as_bytes())?;
-                                        true
-                                    }
-                                } else {
-                                    false
-                                };
-                                let value = packet.serialize();
-                                tables.signed_packets.insert(key.as_bytes(), &value[..])?;
-                                tables.update_time.insert(&packet.timestamp().to_bytes(), key.as_bytes())?;
-                                if replaced {
-                                    self.metrics.store_packets_updated.inc();
-                                } else {
-                                    self.metrics.store_packets_inserted.inc();
-                                }
-                                res.send(true).ok();
-                            }
-                            Message::Remove { key, res } => {
-                                trace!("remove {}", key);
-                                let updated = if let Some(row) = tables.signed_packets.remove(key.as_bytes())? {
-                                    let packet = SignedPacket::deserialize(row.value())?;
-                                    tables.update_time.remove(&packet.timestamp().to_bytes(), key.as_bytes())?;
-                                    self.metrics.store_packets_removed.inc();
-                                    true
-                                } else {
-                                    false
-                                };
-                                res.send(updated).ok();
-                            }
-                            Message::Snapshot { res } => {
-                                trace!("snapshot");
-                                res.send(Snapshot::new(&self.db)?).ok();
-                            }
-                            Message::CheckExpired { key, time } => {
-                                trace!("check expired {} at {}", key, u64::from_be_bytes(time));
-                                if let Some(packet) = get_packet(&tables.signed_packets, &key)? {
-                                    if packet.timestamp() < expired {
-                                        tables.update_time.remove(&time, key.as_bytes())?;
-                                        let _ = tables.signed_packets.remove(key.as_bytes())?;
-                                        self.metrics.store_packets_expired.inc();
-                                    }
-                                }
-                            }
-                        }
-                    }
+                    Some(msg) = self.recv.recv() => self.handle(msg)?,
                 }
             }
-            drop(tables);
-            transaction.commit()?;
+            self.storage.commit()?;
         }
         Ok(())
     }
-}
-
-/// A struct similar to [`redb::Table`] but for all tables that make up the
-/// signed packet store.
-pub(super) struct Tables<'a> {
-    pub signed_packets: redb::Table<'a, &'static SignedPacketsKey, &'static [u8]>,
-    pub update_time: redb::MultimapTable<'a, [u8; 8], SignedPacketsKey>,
-}
 
-impl<'txn> Tables<'txn> {
-    pub fn new(tx: &'txn redb::WriteTransaction) -> result::Result<Self, redb::TableError> {
-        Ok(Self {
-            signed_packets: tx.open_table(SIGNED_PACKETS_TABLE)?,
-            update_time: tx.open_multimap_table(UPDATE_TIME_TABLE)?,
-        })
+    fn handle(&mut self, msg: Message) -> Result<()> {
+        match msg {
+            Message::Get { key, res } => {
+                trace!("get {}", key);
+                let packet = self.storage.get(&key).context("get packet failed")?;
+                res.send(packet).ok();
+            }
+            Message::Upsert { packet, res } => {
+                let key = PublicKeyBytes::from_signed_packet(&packet);
+                trace!("upsert {}", key);
+                if self.storage.is_blocked(&key)? {
+                    debug!("rejecting packet for blocked key {}", key);
+                    res.send(false).ok();
+                    return Ok(());
+                }
+                let existing = self.storage.get(&key)?;
+                if let Some(existing) = &existing {
+                    if existing.more_recent_than(&packet) {
+                        res.send(false).ok();
+                        return Ok(());
+                    }
+                }
+                if self.storage.upsert(&packet)? {
+                    self.metrics.store_packets_updated.inc();
+                } else {
+                    self.metrics.store_packets_inserted.inc();
+                }
+                res.send(true).ok();
+                self.send_change(key, existing, Some(packet));
+            }
+            Message::Remove { key, res } => {
+                trace!("remove {}", key);
+                let removed = self.storage.remove(&key)?;
+                if removed.is_some() {
+                    self.metrics.store_packets_removed.inc();
+                }
+                res.send(removed.is_some()).ok();
+                self.send_change(key, removed, None);
+            }
+            Message::PacketsSince { since, limit, res } => {
+                trace!("packets since {}", since);
+                res.send(self.storage.packets_since(since, limit)?).ok();
+            }
+            Message::SetBlocked { key, blocked, res } => {
+                trace!("set blocked {} {}", key, blocked);
+                let removed = match blocked {
+                    true => self.storage.remove(&key)?,
+                    false => None,
+                };
+                if removed.is_some() {
+                    self.metrics.store_packets_removed.inc();
+                }
+                res.send(self.storage.set_blocked(&key, blocked)?).ok();
+                self.send_change(key, removed, None);
+            }
+            Message::IsBlocked { key, res } => {
+                res.send(self.storage.is_blocked(&key)?).ok();
+            }
+            Message::Blocked { res } => {
+                res.send(self.storage.blocked()?).ok();
+            }
+            Message::Evict => {
+                let expiry_us = self.options.eviction.as_micros() as u64;
+                let expired = Timestamp::now() - expiry_us;
+                trace!("evicting packets older than {}", expired);
+                for key in self.storage.expired(expired, self.options.max_batch_size)? {
+                    debug!("evicting expired packet {}", key);
+                    let removed = self.storage.remove(&key)?;
+                    if removed.is_some() {
+                        self.metrics.store_packets_expired.inc();
+                    }
+                    self.send_change(key, removed, None);
+                }
+            }
+        }
+        Ok(())
     }
-}
 
-pub(super) struct Snapshot {
-    #[allow(dead_code)]
-    pub signed_packets: redb::ReadOnlyTable<&'static SignedPacketsKey, &'static [u8]>,
-    pub update_time: redb::ReadOnlyMultimapTable<[u8; 8], SignedPacketsKey>,
-}
-
-impl Snapshot {
-    pub fn new(db: &Database) -> Result<Self> {
-        let tx = db.begin_read()?;
-        Ok(Self {
-            signed_packets: tx.open_table(SIGNED_PACKETS_TABLE)?,
-            update_time: tx.open_multimap_table(UPDATE_TIME_TABLE)?,
-        })
+    /// Sends a change to the subscribers, if there are any and the packet changed.
+    fn send_change(
+        &self,
+        key: PublicKeyBytes,
+        old: Option<SignedPacket>,
+        new: Option<SignedPacket>,
+    ) {
+        if (old.is_some() || new.is_some()) && self.changes.receiver_count() > 0 {
+            // only fails if all subscribers are gone in the meantime
+            self.changes.send(PacketChange { key, old, new }).ok();
+        }
     }
 }
 
@@ -237,7 +307,11 @@ impl SignedPacketStore {
         metrics: Arc<Metrics>,
     ) -> Result<Self> {
         let path = path.as_ref();
-        info!("loading packet database from {}", path.to_string_lossy());
+        info!(
+            backend = ?options.backend,
+            "loading packet database from {}",
+            path.to_string_lossy()
+        );
         if let Some(parent) = path.parent() {
             std::fs::create_dir_all(parent).with_context(|| {
                 format!(
@@ -246,33 +320,35 @@ impl SignedPacketStore {
                 )
             })?;
         }
-        let db = Database::builder()
-            .create(path)
-            .context("failed to open packet database")?;
-        Self::open(db, options, metrics)
+        let storage: Box<dyn Storage> = match options.backend {
+            StoreBackend::Redb => Box::new(RedbStorage::persistent(path)?),
+            StoreBackend::Log => Box::new(LogStorage::open(path)?),
+        };
+        Self::open(storage, options, metrics)
     }
 
     pub fn in_memory(options: Options, metrics: Arc<Metrics>) -> Result<Self> {
         info!("using in-memory packet database");
-        let db = Database::builder().create_with_backend(InMemoryBackend::new())?;
-        Self::open(db, options, metrics)
+        Self::open(Box::new(RedbStorage::in_memory()?), options, metrics)
     }
 
-    pub fn open(db: Database, options: Options, metrics: Arc<Metrics>) -> Result<Self> {
-        // create tables
-        let write_tx = db.begin_write()?;
-        let _ = Tables::new(&write_tx)?;
-        write_tx.commit()?;
+    pub fn open(
+        storage: Box<dyn Storage>,
+        options: Options,
+        metrics: Arc<Metrics>,
+    ) -> Result<Self> {
         let (send, recv) = mpsc::channel(1024);
         let send2 = send.clone();
         let cancel = CancellationToken::new();
         let cancel2 = cancel.clone();
         let cancel3 = cancel.clone();
+        let changes = broadcast::channel(CHANGES_CAPACITY).0;
         let actor = Actor {
-            db,
+            storage,
             recv: PeekableReceiver::new(recv),
             cancel: cancel2,
             options,
+            changes: changes.clone(),
             metrics,
         };
         // start an io thread and donate it to the tokio runtime so we can do blocking IO
@@ -283,12 +359,21 @@ impl SignedPacketStore {
         })?;
         Ok(Self {
             send,
+            changes,
             cancel,
             _write_thread,
             _evict_thread,
         })
     }
 
+    /// Subscribe to all changes of the stored packets.
+    ///
+    /// Changes are sent in the order in which they are applied, including removals and
+    /// evictions.
+    pub fn subscribe_changes(&self) -> broadcast::Receiver<PacketChange> {
+        self.changes.subscribe()
+    }
+
     pub async fn upsert(&self, packet: SignedPacket) -> Result<bool> {
         let (tx, rx) = oneshot::channel();
         self.send.send(Message::Upsert { packet, res: tx }).await?;
@@ -301,6 +386,21 @@ impl SignedPacketStore {
         Ok(rx.await?)
     }
 
+    /// Get up to about `limit` packets with a timestamp of at least `since`.
+    ///
+    /// See [`Storage::packets_since`].
+    pub async fn packets_since(&self, since: Timestamp, limit: usize) -> Result<Vec<SignedPacket>> {
+        let (tx, rx) = oneshot::channel();
+        self.send
+            .send(Message::PacketsSince {
+                since,
+                limit,
+                res: tx,
+            })
+            .await?;
+        Ok(rx.await?)
+    }
+
     pub async fn remove(&self, key: &PublicKeyBytes) -> Result<bool> {
         let (tx, rx) = oneshot::channel();
         self.send
@@ -308,17 +408,35 @@ impl SignedPacketStore {
             .await?;
         Ok(rx.await?)
     }
-}
 
-fn get_packet(
-    table: &impl ReadableTable<&'static SignedPacketsKey, &'static [u8]>,
-    key: &PublicKeyBytes,
-) -> Result<Option<SignedPacket>> {
-    let Some(row) = table.get(key.as_ref()).context("database fetch failed")? else {
-        return Ok(None);
-    };
-    let packet = SignedPacket::deserialize(row.value()).context("parsing signed packet failed")?;
-    Ok(Some(packet))
+    /// Blocks or unblocks `key`, returns whether this changed the state of the key.
+    ///
+    /// Blocking a key removes its packet, and packets for blocked keys are rejected.
+    pub async fn set_blocked(&self, key: &PublicKeyBytes, blocked: bool) -> Result<bool> {
+        let (tx, rx) = oneshot::channel();
+        self.send
+            .send(Message::SetBlocked {
+                key: *key,
+                blocked,
+                res: tx,
+            })
+            .await?;
+        Ok(rx.await?)
+    }
+
+    pub async fn is_blocked(&self, key: &PublicKeyBytes) -> Result<bool> {
+        let (tx, rx) = oneshot::channel();
+        self.send
+            .send(Message::IsBlocked { key: *key, res: tx })
+            .await?;
+        Ok(rx.await?)
+    }
+
+    pub async fn blocked(&self) -> Result<Vec<PublicKeyBytes>> {
+        let (tx, rx) = oneshot::channel();
+        self.send.send(Message::Blocked { res: tx }).await?;
+        Ok(rx.await?)
+    }
 }
 
 async fn evict_task(send: mpsc::Sender<Message>, options: Options, cancel: CancellationToken) {
@@ -335,51 +453,11 @@ async fn evict_task(send: mpsc::Sender<Message>, options: Options, cancel: Cance
         .await;
 }
 
-/// Periodically check for expired packets and remove them.
+/// Periodically ask the actor to remove expired packets.
 async fn evict_task_inner(send: mpsc::Sender<Message>, options: Options) -> anyhow::Result<()> {
-    let expiry_us = options.eviction.as_micros() as u64;
     loop {
-        let (tx, rx) = oneshot::channel();
-        let _ = send.send(Message::Snapshot { res: tx }).await.ok();
-        // if we can't get the snapshot we exit the loop, main actor dead
-        let Ok(snapshot) = rx.await else {
-            anyhow::bail!("failed to get snapshot");
-        };
-        let expired = Timestamp::now() - expiry_us;
-        trace!("evicting packets older than {}", expired);
-        // if getting the range fails we exit the loop and shut down
-        // if individual reads fail we log the error and limp on
-        for item in snapshot.update_time.range(..expired.to_bytes())? {
-            let (time, keys) = match item {
-                Ok(v) => v,
-                Err(e) => {
-                    error!("failed to read update_time row {:?}", e);
-                    continue;
-                }
-            };
-            let time = time.value();
-            trace!("evicting expired packets at {}", u64::from_be_bytes(time));
-            for item in keys {
-                let key = match item {
-                    Ok(v) => v,
-                    Err(e) => {
-                        error!(
-                            "failed to read update_time item at {}: {:?}",
-                            u64::from_be_bytes(time),
-                            e
-                        );
-                        continue;
-                    }
-                };
-                let key = PublicKeyBytes::new(key.value());
-                debug!(
-                    "evicting expired packet {} {}",
-                    u64::from_be_bytes(time),
-                    key
-                );
-                send.send(Message::CheckExpired { time, key }).await?;
-            }
-        }
+        // if we can't send the message we exit the loop, main actor dead
+        send.send(Message::Evict).await?;
         // sleep for the eviction interval so we don't constantly check
         tokio::time::sleep(options.eviction_interval).await;
     }
@@ -459,3 +537,58 @@ impl<T> PeekableReceiver<T> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::packet;
+
+    #[tokio::test]
+    async fn backends() -> Result<()> {
+        for backend in [StoreBackend::Redb, StoreBackend::Log] {
+            let dir = tempfile::tempdir()?;
+            let options = Options {
+                backend,
+                ..Default::default()
+            };
+            let store = SignedPacketStore::persistent(
+                dir.path().join("packets"),
+                options,
+                Default::default(),
+            )?;
+            let (a, b) = (pkarr::Keypair::random(), pkarr::Keypair::random());
+            let key_a = PublicKeyBytes::new(a.public_key().to_bytes());
+            // recent timestamps, so that the packets are not evicted
+            let now = u64::from(Timestamp::now());
+
+            assert!(store.upsert(packet(&a, "hi", now + 20)?).await?);
+            assert!(
+                !store.upsert(packet(&a, "hi", now + 10)?).await?,
+                "{backend:?}"
+            );
+            assert!(store.upsert(packet(&b, "hi", now + 30)?).await?);
+            assert!(store.upsert(packet(&a, "hi", now + 40)?).await?);
+            let since = store.packets_since(Timestamp::from(now + 25), 10).await?;
+            let timestamps: Vec<_> = since
+                .iter()
+                .map(|p| u64::from(p.timestamp()) - now)
+                .collect();
+            assert_eq!(timestamps, vec![30, 40], "{backend:?}");
+
+            assert!(store.remove(&key_a).await?);
+            assert!(store.get(&key_a).await?.is_none());
+            assert!(!store.remove(&key_a).await?);
+
+            let key_b = PublicKeyBytes::new(b.public_key().to_bytes());
+            assert!(store.set_blocked(&key_b, true).await?);
+            assert!(!store.set_blocked(&key_b, true).await?);
+            assert!(store.get(&key_b).await?.is_none(), "{backend:?}");
+            assert!(!store.upsert(packet(&b, "hi", now + 50)?).await?);
+            assert_eq!(store.blocked().await?, vec![key_b]);
+            assert!(store.set_blocked(&key_b, false).await?);
+            assert!(!store.is_blocked(&key_b).await?);
+            assert!(store.upsert(packet(&b, "hi", now + 50)?).await?);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,666 @@
+use std::{
+    collections::{BTreeSet, HashMap, HashSet},
+    fs::{File, OpenOptions},
+    io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    thread::JoinHandle,
+};
+
+use anyhow::{bail, ensure, Context, Result};
+use pkarr::{SignedPacket, Timestamp};
+use tracing::{info, warn};
+
+use super::Storage;
+use crate::util::PublicKeyBytes;
+
+/// Magic bytes at the start of the log file, includes the format version.
+const MAGIC: &[u8; 8] = b"irohpkl2";
+/// Length of a record header: kind, key, timestamp, payload length and checksum
+const HEADER_LEN: u64 = 1 + 32 + 8 + 4 + 4;
+/// Checksum over the other header fields and the payload of a record
+const CRC: crc::Crc<u32> = crc::Crc::<u32>::new(&crc::CRC_32_ISCSI);
+/// Record that stores a packet, the payload is the serialized packet
+const KIND_PUT: u8 = 1;
+/// Record that removes the packet of a key, without payload
+const KIND_REMOVE: u8 = 2;
+/// Record that blocks a key, without payload
+const KIND_BLOCK: u8 = 3;
+/// Record that unblocks a key, without payload
+const KIND_UNBLOCK: u8 = 4;
+/// Minimum number of garbage bytes before the log is compacted
+const MIN_COMPACTION_GARBAGE: u64 = 64 * 1024 * 1024;
+
+/// [`Storage`] in an append-only log file.
+///
+/// Every write appends a record to the log. The position and timestamp of the current packet
+/// of every key are kept in memory and rebuilt by replaying the log on startup. Every record
+/// carries a checksum, the log is truncated at the first torn or corrupted record on startup.
+///
+/// Overwritten and removed records are garbage, once there is more garbage than live data
+/// the live records are copied to a new log on a background thread. The records appended in
+/// the meantime are added to the new log on a later commit, which then replaces the old log.
+#[derive(Debug)]
+pub(super) struct LogStorage {
+    path: PathBuf,
+    writer: BufWriter<File>,
+    reader: File,
+    /// Length of the log, including buffered writes
+    len: u64,
+    /// Whether the writer holds data not yet written to the file
+    dirty: bool,
+    index: HashMap<PublicKeyBytes, Entry>,
+    by_time: BTreeSet<(u64, PublicKeyBytes)>,
+    blocked: HashSet<PublicKeyBytes>,
+    /// Number of bytes in records that are overwritten or removed
+    garbage: u64,
+    /// The compaction running in the background, if any
+    compaction: Option<Compaction>,
+}
+
+/// A compaction of the log running on a background thread.
+#[derive(Debug)]
+struct Compaction {
+    /// Length of the log when the compaction started, later records are not compacted
+    len: u64,
+    /// Garbage in the log when the compaction started
+    garbage: u64,
+    handle: JoinHandle<Result<Compacted>>,
+}
+
+/// The result of a compaction, a new log with the live records of the old log.
+#[derive(Debug)]
+struct Compacted {
+    path: PathBuf,
+    len: u64,
+    /// New payload offset of every compacted packet, by the payload offset in the old log
+    offsets: HashMap<u64, u64>,
+}
+
+/// Kind, key and location of a record in the log
+type Record = (u8, PublicKeyBytes, Entry);
+
+/// Location of the current packet of a key in the log.
+#[derive(Debug, Clone, Copy)]
+struct Entry {
+    /// Offset of the payload
+    offset: u64,
+    len: u32,
+    timestamp: u64,
+}
+
+impl LogStorage {
+    /// Opens the log at `path`, creating it if it does not exist.
+    pub fn open(path: &Path) -> Result<Self> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)
+            .context("failed to open packet log")?;
+        // left over from a compaction that did not finish
+        std::fs::remove_file(compaction_path(path)).ok();
+        let file_len = file.metadata()?.len();
+        if file_len == 0 {
+            file.write_all(MAGIC)?;
+            file.sync_all()?;
+        }
+        let (records, len) = read_records(&mut file, path)?;
+        if len < file_len {
+            warn!(
+                "truncating {} bytes of incomplete records from packet log",
+                file_len - len
+            );
+            file.set_len(len)?;
+            file.sync_all()?;
+        }
+        file.seek(SeekFrom::Start(len))?;
+        let mut this = Self {
+            path: path.to_owned(),
+            writer: BufWriter::new(file),
+            // a separate handle, so that reads do not move the write position
+            reader: File::open(path)?,
+            len,
+            dirty: false,
+            index: HashMap::new(),
+            by_time: BTreeSet::new(),
+            blocked: HashSet::new(),
+            garbage: 0,
+            compaction: None,
+        };
+        for (kind, key, entry) in records {
+            match kind {
+                KIND_PUT => {
+                    this.insert_entry(key, entry);
+                }
+                KIND_REMOVE => {
+                    this.remove_entry(&key);
+                    this.garbage += HEADER_LEN;
+                }
+                KIND_BLOCK => {
+                    this.blocked.insert(key);
+                }
+                _ => {
+                    this.blocked.remove(&key);
+                    // both the block and the unblock record
+                    this.garbage += 2 * HEADER_LEN;
+                }
+            }
+        }
+        info!(
+            packets = this.index.len(),
+            garbage = this.garbage,
+            "loaded packet log"
+        );
+        Ok(this)
+    }
+
+    fn insert_entry(&mut self, key: PublicKeyBytes, entry: Entry) -> bool {
+        let replaced = self.remove_entry(&key);
+        self.index.insert(key, entry);
+        self.by_time.insert((entry.timestamp, key));
+        replaced
+    }
+
+    fn remove_entry(&mut self, key: &PublicKeyBytes) -> bool {
+        let Some(entry) = self.index.remove(key) else {
+            return false;
+        };
+        self.by_time.remove(&(entry.timestamp, *key));
+        self.garbage += HEADER_LEN + entry.len as u64;
+        true
+    }
+
+    /// Appends a record and returns the offset of its payload.
+    fn append(
+        &mut self,
+        kind: u8,
+        key: &PublicKeyBytes,
+        timestamp: u64,
+        payload: &[u8],
+    ) -> Result<u64> {
+        write_record(&mut self.writer, kind, key, timestamp, payload)?;
+        let offset = self.len + HEADER_LEN;
+        self.len = offset + payload.len() as u64;
+        self.dirty = true;
+        Ok(offset)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        if self.dirty {
+            self.writer.flush()?;
+            self.dirty = false;
+        }
+        Ok(())
+    }
+
+    fn read_packet(&mut self, entry: &Entry) -> Result<SignedPacket> {
+        self.flush()?;
+        let payload = read_payload(&mut self.reader, entry)?;
+        SignedPacket::deserialize(&payload).context("parsing signed packet failed")
+    }
+
+    /// Starts copying the current packets and blocked keys to a new log in the background.
+    fn start_compaction(&mut self) -> Result<()> {
+        self.flush()?;
+        // read in log order to keep the reads sequential
+        let mut entries: Vec<_> = self.index.iter().map(|(k, e)| (*k, *e)).collect();
+        entries.sort_unstable_by_key(|(_, entry)| entry.offset);
+        let blocked: Vec<_> = self.blocked.iter().copied().collect();
+        let reader = File::open(&self.path)?;
+        let path = compaction_path(&self.path);
+        let handle = std::thread::Builder::new()
+            .name("packet-log-compaction".to_string())
+            .spawn(move || write_compacted(reader, path, entries, blocked))?;
+        self.compaction = Some(Compaction {
+            len: self.len,
+            garbage: self.garbage,
+            handle,
+        });
+        Ok(())
+    }
+
+    /// Replaces the log with the compacted log once the background compaction is done.
+    ///
+    /// The records appended since the compaction started are copied to the new log. With
+    /// `wait` this blocks until the compaction is done.
+    fn finish_compaction(&mut self, wait: bool) -> Result<()> {
+        let Some(compaction) = self.compaction.take() else {
+            return Ok(());
+        };
+        if !wait && !compaction.handle.is_finished() {
+            self.compaction = Some(compaction);
+            return Ok(());
+        }
+        let compacted = match compaction.handle.join() {
+            Ok(Ok(compacted)) => compacted,
+            Ok(Err(err)) => {
+                warn!("compacting packet log failed: {err:#}");
+                std::fs::remove_file(compaction_path(&self.path)).ok();
+                return Ok(());
+            }
+            Err(_) => bail!("packet log compaction panicked"),
+        };
+        self.flush()?;
+
+        // copy the records appended in the meantime
+        let mut file = OpenOptions::new().append(true).open(&compacted.path)?;
+        self.reader.seek(SeekFrom::Start(compaction.len))?;
+        let tail_len = io::copy(
+            &mut (&mut self.reader).take(self.len - compaction.len),
+            &mut file,
+        )?;
+        ensure!(
+            tail_len == self.len - compaction.len,
+            "packet log is shorter than expected"
+        );
+        file.sync_all()?;
+        drop(file);
+
+        let mut index = HashMap::with_capacity(self.index.len());
+        for (key, entry) in &self.index {
+            let offset = if entry.offset >= compaction.len {
+                entry.offset - compaction.len + compacted.len
+            } else {
+                *compacted
+                    .offsets
+                    .get(&entry.offset)
+                    .context("packet missing from compacted log")?
+            };
+            index.insert(*key, Entry { offset, ..*entry });
+        }
+        std::fs::rename(&compacted.path, &self.path).context("failed to replace packet log")?;
+
+        let mut file = OpenOptions::new().write(true).open(&self.path)?;
+        file.seek(SeekFrom::End(0))?;
+        self.writer = BufWriter::new(file);
+        self.reader = File::open(&self.path)?;
+        self.len = compacted.len + tail_len;
+        self.index = index;
+        let garbage = compaction.garbage;
+        // the garbage of the tail and of packets replaced since the compaction started is kept
+        self.garbage -= garbage;
+        info!(packets = self.index.len(), garbage, "compacted packet log");
+        Ok(())
+    }
+
+    /// Compacts the log, blocking until it is done.
+    #[cfg(test)]
+    fn compact(&mut self) -> Result<()> {
+        self.start_compaction()?;
+        self.finish_compaction(true)
+    }
+}
+
+impl Storage for LogStorage {
+    fn get(&mut self, key: &PublicKeyBytes) -> Result<Option<SignedPacket>> {
+        let Some(entry) = self.index.get(key).copied() else {
+            return Ok(None);
+        };
+        self.read_packet(&entry).map(Some)
+    }
+
+    fn upsert(&mut self, packet: &SignedPacket) -> Result<bool> {
+        let key = PublicKeyBytes::from_signed_packet(packet);
+        let timestamp = u64::from(packet.timestamp());
+        let payload = packet.serialize();
+        let offset = self.append(KIND_PUT, &key, timestamp, &payload)?;
+        let entry = Entry {
+            offset,
+            len: payload.len() as u32,
+            timestamp,
+        };
+        Ok(self.insert_entry(key, entry))
+    }
+
+    fn remove(&mut self, key: &PublicKeyBytes) -> Result<Option<SignedPacket>> {
+        let Some(entry) = self.index.get(key).copied() else {
+            return Ok(None);
+        };
+        let packet = self.read_packet(&entry)?;
+        self.append(KIND_REMOVE, key, 0, &[])?;
+        self.remove_entry(key);
+        self.garbage += HEADER_LEN;
+        Ok(Some(packet))
+    }
+
+    fn expired(&mut self, before: Timestamp, limit: usize) -> Result<Vec<PublicKeyBytes>> {
+        let end = (u64::from(before), PublicKeyBytes::new([0u8; 32]));
+        Ok(self
+            .by_time
+            .range(..end)
+            .take(limit)
+            .map(|(_, key)| *key)
+            .collect())
+    }
+
+    fn packets_since(&mut self, since: Timestamp, limit: usize) -> Result<Vec<SignedPacket>> {
+        let start = (u64::from(since), PublicKeyBytes::new([0u8; 32]));
+        let mut selected = Vec::new();
+        for (timestamp, key) in self.by_time.range(start..) {
+            // never split packets with the same timestamp
+            if selected.len() >= limit && selected.last().map(|(t, _)| t) != Some(timestamp) {
+                break;
+            }
+            selected.push((*timestamp, self.index[key]));
+        }
+        selected
+            .into_iter()
+            .map(|(_, entry)| self.read_packet(&entry))
+            .collect()
+    }
+
+    fn set_blocked(&mut self, key: &PublicKeyBytes, blocked: bool) -> Result<bool> {
+        if blocked {
+            if !self.blocked.insert(*key) {
+                return Ok(false);
+            }
+            self.append(KIND_BLOCK, key, 0, &[])?;
+        } else {
+            if !self.blocked.remove(key) {
+                return Ok(false);
+            }
+            self.append(KIND_UNBLOCK, key, 0, &[])?;
+            self.garbage += 2 * HEADER_LEN;
+        }
+        Ok(true)
+    }
+
+    fn is_blocked(&mut self, key: &PublicKeyBytes) -> Result<bool> {
+        Ok(self.blocked.contains(key))
+    }
+
+    fn blocked(&mut self) -> Result<Vec<PublicKeyBytes>> {
+        Ok(self.blocked.iter().copied().collect())
+    }
+
+    fn commit(&mut self) -> Result<()> {
+        self.writer.flush()?;
+        self.writer.get_ref().sync_data()?;
+        self.dirty = false;
+        if self.compaction.is_some() {
+            self.finish_compaction(false)?;
+        } else {
+            let live = self.len - MAGIC.len() as u64 - self.garbage;
+            if self.garbage >= MIN_COMPACTION_GARBAGE && self.garbage > live {
+                self.start_compaction()?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Reads the records of the log, stopping at the first incomplete or invalid record.
+///
+/// Returns the records and the length of the valid part of the log.
+fn read_records(file: &mut File, path: &Path) -> Result<(Vec<Record>, u64)> {
+    let file_len = file.metadata()?.len();
+    file.seek(SeekFrom::Start(0))?;
+    let mut reader = BufReader::new(file);
+    let mut magic = [0u8; MAGIC.len()];
+    reader
+        .read_exact(&mut magic)
+        .context("failed to read packet log header")?;
+    if &magic != MAGIC {
+        bail!("{} is not a packet log", path.display());
+    }
+    let mut offset = MAGIC.len() as u64;
+    let mut records = Vec::new();
+    let mut payload = Vec::new();
+    loop {
+        let mut header = [0u8; HEADER_LEN as usize];
+        match reader.read_exact(&mut header) {
+            Ok(()) => {}
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => break,
+            Err(err) => return Err(err.into()),
+        }
+        let (kind, key, timestamp, len, crc) = parse_header(&header);
+        let end = offset + HEADER_LEN + len as u64;
+        if !matches!(kind, KIND_PUT | KIND_REMOVE | KIND_BLOCK | KIND_UNBLOCK) || end > file_len {
+            break;
+        }
+        payload.resize(len as usize, 0);
+        reader.read_exact(&mut payload)?;
+        if checksum(&header, &payload) != crc {
+            warn!(offset, "packet log record has an invalid checksum");
+            break;
+        }
+        let entry = Entry {
+            offset: offset + HEADER_LEN,
+            len,
+            timestamp,
+        };
+        records.push((kind, key, entry));
+        offset = end;
+    }
+    Ok((records, offset))
+}
+
+fn parse_header(header: &[u8; HEADER_LEN as usize]) -> (u8, PublicKeyBytes, u64, u32, u32) {
+    let kind = header[0];
+    let key = PublicKeyBytes::new(header[1..33].try_into().expect("valid length"));
+    let timestamp = u64::from_be_bytes(header[33..41].try_into().expect("valid length"));
+    let len = u32::from_be_bytes(header[41..45].try_into().expect("valid length"));
+    let crc = u32::from_be_bytes(header[45..49].try_into().expect("valid length"));
+    (kind, key, timestamp, len, crc)
+}
+
+/// Computes the checksum of a record, over the header without the checksum and the payload.
+fn checksum(header: &[u8; HEADER_LEN as usize], payload: &[u8]) -> u32 {
+    let mut digest = CRC.digest();
+    digest.update(&header[..45]);
+    digest.update(payload);
+    digest.finalize()
+}
+
+fn write_record(
+    out: &mut impl Write,
+    kind: u8,
+    key: &PublicKeyBytes,
+    timestamp: u64,
+    payload: &[u8],
+) -> io::Result<()> {
+    let mut header = [0u8; HEADER_LEN as usize];
+    header[0] = kind;
+    header[1..33].copy_from_slice(key.as_bytes());
+    header[33..41].copy_from_slice(&timestamp.to_be_bytes());
+    header[41..45].copy_from_slice(&(payload.len() as u32).to_be_bytes());
+    let crc = checksum(&header, payload);
+    header[45..49].copy_from_slice(&crc.to_be_bytes());
+    out.write_all(&header)?;
+    out.write_all(payload)
+}
+
+fn read_payload(reader: &mut File, entry: &Entry) -> Result<Vec<u8>> {
+    let mut payload = vec![0u8; entry.len as usize];
+    reader.seek(SeekFrom::Start(entry.offset))?;
+    reader.read_exact(&mut payload)?;
+    Ok(payload)
+}
+
+/// Path of the new log written by a compaction.
+fn compaction_path(path: &Path) -> PathBuf {
+    path.with_extension("compact")
+}
+
+/// Writes a new log with the given packets and blocked keys.
+///
+/// The packets are read from `reader`, a handle to the old log.
+fn write_compacted(
+    mut reader: File,
+    path: PathBuf,
+    entries: Vec<(PublicKeyBytes, Entry)>,
+    blocked: Vec<PublicKeyBytes>,
+) -> Result<Compacted> {
+    let mut out = BufWriter::new(File::create(&path)?);
+    out.write_all(MAGIC)?;
+    let mut len = MAGIC.len() as u64;
+    let mut offsets = HashMap::with_capacity(entries.len());
+    for (key, entry) in entries {
+        let payload = read_payload(&mut reader, &entry)?;
+        write_record(&mut out, KIND_PUT, &key, entry.timestamp, &payload)?;
+        let offset = len + HEADER_LEN;
+        offsets.insert(entry.offset, offset);
+        len = offset + entry.len as u64;
+    }
+    for key in blocked {
+        write_record(&mut out, KIND_BLOCK, &key, 0, &[])?;
+        len += HEADER_LEN;
+    }
+    out.flush()?;
+    out.get_ref().sync_all()?;
+    Ok(Compacted { path, len, offsets })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::packet;
+
+    fn key(keypair: &pkarr::Keypair) -> PublicKeyBytes {
+        PublicKeyBytes::new(keypair.public_key().to_bytes())
+    }
+
+    #[test]
+    fn replay_truncate_and_compact() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("packets.log");
+        let (a, b, c) = (
+            pkarr::Keypair::random(),
+            pkarr::Keypair::random(),
+            pkarr::Keypair::random(),
+        );
+
+        let mut log = LogStorage::open(&path)?;
+        assert!(!log.upsert(&packet(&a, "a1", 10)?)?);
+        assert!(!log.upsert(&packet(&b, "b1", 20)?)?);
+        assert!(log.upsert(&packet(&a, "a2", 30)?)?);
+        assert!(!log.upsert(&packet(&c, "c1", 40)?)?);
+        assert!(log.remove(&key(&b))?.is_some());
+        assert!(log.set_blocked(&key(&b), true)?);
+        log.commit()?;
+        drop(log);
+
+        // simulate a torn write
+        let len = std::fs::metadata(&path)?.len();
+        let mut file = OpenOptions::new().append(true).open(&path)?;
+        file.write_all(&[KIND_PUT, 1, 2, 3])?;
+        drop(file);
+
+        let mut log = LogStorage::open(&path)?;
+        assert_eq!(std::fs::metadata(&path)?.len(), len);
+        assert_eq!(
+            log.get(&key(&a))?.map(|p| p.timestamp()),
+            Some(Timestamp::from(30))
+        );
+        assert!(log.get(&key(&b))?.is_none());
+        assert!(log.is_blocked(&key(&b))?);
+        assert_eq!(
+            log.expired(Timestamp::from(35), 10)?,
+            vec![key(&a)],
+            "the replaced packet of a is not in the time index"
+        );
+        let since = log.packets_since(Timestamp::from(0), 10)?;
+        assert_eq!(since.len(), 2);
+        assert!(log.garbage > 0);
+
+        log.compact()?;
+        assert_eq!(log.garbage, 0);
+        assert!(std::fs::metadata(&path)?.len() < len);
+        assert!(log.upsert(&packet(&c, "c2", 50)?)?);
+        log.commit()?;
+        drop(log);
+
+        let mut log = LogStorage::open(&path)?;
+        assert_eq!(log.index.len(), 2);
+        assert_eq!(log.blocked()?, vec![key(&b)]);
+        assert_eq!(
+            log.get(&key(&c))?.map(|p| p.timestamp()),
+            Some(Timestamp::from(50))
+        );
+        // only the first record of c is garbage
+        let garbage = HEADER_LEN + packet(&c, "c1", 40)?.serialize().len() as u64;
+        assert_eq!(log.garbage, garbage);
+        Ok(())
+    }
+
+    #[test]
+    fn replay_truncates_corrupted_record() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("packets.log");
+        let (a, b) = (pkarr::Keypair::random(), pkarr::Keypair::random());
+
+        let mut log = LogStorage::open(&path)?;
+        log.upsert(&packet(&a, "a1", 10)?)?;
+        let valid_len = log.len;
+        log.upsert(&packet(&b, "b1", 20)?)?;
+        log.commit()?;
+        drop(log);
+
+        // flip a bit in the payload of the last record
+        let mut data = std::fs::read(&path)?;
+        let last = data.len() - 1;
+        data[last] ^= 1;
+        std::fs::write(&path, data)?;
+
+        let mut log = LogStorage::open(&path)?;
+        assert_eq!(std::fs::metadata(&path)?.len(), valid_len);
+        assert!(log.get(&key(&a))?.is_some());
+        assert!(log.get(&key(&b))?.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn compact_with_concurrent_writes() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("packets.log");
+        let (a, b, c) = (
+            pkarr::Keypair::random(),
+            pkarr::Keypair::random(),
+            pkarr::Keypair::random(),
+        );
+
+        let mut log = LogStorage::open(&path)?;
+        log.upsert(&packet(&a, "a1", 10)?)?;
+        log.upsert(&packet(&a, "a2", 20)?)?;
+        log.upsert(&packet(&b, "b1", 30)?)?;
+        log.commit()?;
+
+        log.start_compaction()?;
+        // written while the compaction runs, these end up in the tail of the new log
+        log.upsert(&packet(&b, "b2", 40)?)?;
+        log.upsert(&packet(&c, "c1", 50)?)?;
+        log.set_blocked(&key(&a), true)?;
+        log.finish_compaction(true)?;
+        assert!(log.compaction.is_none());
+        assert!(!compaction_path(&path).exists());
+
+        let check = |log: &mut LogStorage| -> Result<()> {
+            assert_eq!(
+                log.get(&key(&a))?.map(|p| p.timestamp()),
+                Some(Timestamp::from(20))
+            );
+            assert_eq!(
+                log.get(&key(&b))?.map(|p| p.timestamp()),
+                Some(Timestamp::from(40))
+            );
+            assert_eq!(
+                log.get(&key(&c))?.map(|p| p.timestamp()),
+                Some(Timestamp::from(50))
+            );
+            assert!(log.is_blocked(&key(&a))?);
+            Ok(())
+        };
+        check(&mut log)?;
+        // the compacted copy of the first packet of b is garbage
+        let garbage = HEADER_LEN + packet(&b, "b1", 30)?.serialize().len() as u64;
+        assert_eq!(log.garbage, garbage);
+        log.commit()?;
+        drop(log);
+
+        let mut log = LogStorage::open(&path)?;
+        check(&mut log)?;
+        assert_eq!(log.garbage, garbage);
+        Ok(())
+    }
+}
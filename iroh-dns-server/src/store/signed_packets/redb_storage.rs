@@ -0,0 +1,209 @@
+use std::{path::Path, result};
+
+use anyhow::{Context, Result};
+use pkarr::{SignedPacket, Timestamp};
+use redb::{
+    backends::InMemoryBackend, Database, MultimapTableDefinition, ReadableMultimapTable,
+    ReadableTable, TableDefinition, WriteTransaction,
+};
+
+use super::Storage;
+use crate::util::PublicKeyBytes;
+
+pub type SignedPacketsKey = [u8; 32];
+const SIGNED_PACKETS_TABLE: TableDefinition<&SignedPacketsKey, &[u8]> =
+    TableDefinition::new("signed-packets-1");
+const UPDATE_TIME_TABLE: MultimapTableDefinition<[u8; 8], SignedPacketsKey> =
+    MultimapTableDefinition::new("update-time-1");
+const BLOCKED_KEYS_TABLE: TableDefinition<&SignedPacketsKey, ()> =
+    TableDefinition::new("blocked-keys-1");
+
+/// [`Storage`] in a redb database.
+///
+/// All operations between two commits share a single write transaction.
+#[derive(derive_more::Debug)]
+pub(super) struct RedbStorage {
+    db: Database,
+    #[debug(skip)]
+    transaction: Option<WriteTransaction>,
+}
+
+impl RedbStorage {
+    pub fn persistent(path: &Path) -> Result<Self> {
+        let db = Database::builder()
+            .create(path)
+            .context("failed to open packet database")?;
+        Self::new(db)
+    }
+
+    pub fn in_memory() -> Result<Self> {
+        let db = Database::builder().create_with_backend(InMemoryBackend::new())?;
+        Self::new(db)
+    }
+
+    fn new(db: Database) -> Result<Self> {
+        // create tables
+        let write_tx = db.begin_write()?;
+        let _ = Tables::new(&write_tx)?;
+        write_tx.commit()?;
+        Ok(Self {
+            db,
+            transaction: None,
+        })
+    }
+
+    fn tables(&mut self) -> Result<Tables<'_>> {
+        let transaction = match self.transaction {
+            Some(ref transaction) => transaction,
+            None => self.transaction.insert(self.db.begin_write()?),
+        };
+        Ok(Tables::new(transaction)?)
+    }
+}
+
+impl Storage for RedbStorage {
+    fn get(&mut self, key: &PublicKeyBytes) -> Result<Option<SignedPacket>> {
+        let tables = self.tables()?;
+        get_packet(&tables.signed_packets, key)
+    }
+
+    fn upsert(&mut self, packet: &SignedPacket) -> Result<bool> {
+        let key = PublicKeyBytes::from_signed_packet(packet);
+        let mut tables = self.tables()?;
+        let existing = get_packet(&tables.signed_packets, &key)?;
+        if let Some(existing) = &existing {
+            // remove the existing packet from the update time index
+            tables
+                .update_time
+                .remove(&existing.timestamp().to_bytes(), key.as_bytes())?;
+        }
+        let value = packet.serialize();
+        tables.signed_packets.insert(key.as_bytes(), &value[..])?;
+        tables
+            .update_time
+            .insert(&packet.timestamp().to_bytes(), key.as_bytes())?;
+        Ok(existing.is_some())
+    }
+
+    fn remove(&mut self, key: &PublicKeyBytes) -> Result<Option<SignedPacket>> {
+        let mut tables = self.tables()?;
+        let Some(row) = tables.signed_packets.remove(key.as_bytes())? else {
+            return Ok(None);
+        };
+        let packet = SignedPacket::deserialize(row.value())?;
+        drop(row);
+        tables
+            .update_time
+            .remove(&packet.timestamp().to_bytes(), key.as_bytes())?;
+        Ok(Some(packet))
+    }
+
+    fn expired(&mut self, before: Timestamp, limit: usize) -> Result<Vec<PublicKeyBytes>> {
+        let mut tables = self.tables()?;
+        let mut keys = Vec::new();
+        let mut stale = Vec::new();
+        for item in tables.update_time.range(..before.to_bytes())? {
+            let (time, values) = item?;
+            let time = time.value();
+            for key in values {
+                let key = PublicKeyBytes::new(key?.value());
+                // databases written by older versions may contain index entries of packets
+                // that were replaced by a newer one
+                match get_packet(&tables.signed_packets, &key)? {
+                    Some(packet) if packet.timestamp().to_bytes() == time => keys.push(key),
+                    _ => stale.push((time, key)),
+                }
+            }
+            if keys.len() >= limit {
+                break;
+            }
+        }
+        for (time, key) in stale {
+            tables.update_time.remove(&time, key.as_bytes())?;
+        }
+        Ok(keys)
+    }
+
+    fn packets_since(&mut self, since: Timestamp, limit: usize) -> Result<Vec<SignedPacket>> {
+        let tables = self.tables()?;
+        let mut packets = Vec::new();
+        for item in tables.update_time.range(since.to_bytes()..)? {
+            let (time, keys) = item?;
+            let time = time.value();
+            for key in keys {
+                let key = PublicKeyBytes::new(key?.value());
+                // see above, skip stale index entries
+                match get_packet(&tables.signed_packets, &key)? {
+                    Some(packet) if packet.timestamp().to_bytes() == time => packets.push(packet),
+                    _ => {}
+                }
+            }
+            if packets.len() >= limit {
+                break;
+            }
+        }
+        Ok(packets)
+    }
+
+    fn set_blocked(&mut self, key: &PublicKeyBytes, blocked: bool) -> Result<bool> {
+        let mut tables = self.tables()?;
+        let changed = if blocked {
+            tables.blocked_keys.insert(key.as_bytes(), ())?.is_none()
+        } else {
+            tables.blocked_keys.remove(key.as_bytes())?.is_some()
+        };
+        Ok(changed)
+    }
+
+    fn is_blocked(&mut self, key: &PublicKeyBytes) -> Result<bool> {
+        let tables = self.tables()?;
+        let blocked = tables.blocked_keys.get(key.as_bytes())?.is_some();
+        Ok(blocked)
+    }
+
+    fn blocked(&mut self) -> Result<Vec<PublicKeyBytes>> {
+        let tables = self.tables()?;
+        let mut keys = Vec::new();
+        for item in tables.blocked_keys.iter()? {
+            let (key, _) = item?;
+            keys.push(PublicKeyBytes::new(*key.value()));
+        }
+        Ok(keys)
+    }
+
+    fn commit(&mut self) -> Result<()> {
+        if let Some(transaction) = self.transaction.take() {
+            transaction.commit()?;
+        }
+        Ok(())
+    }
+}
+
+/// A struct similar to [`redb::Table`] but for all tables that make up the
+/// signed packet store.
+pub(super) struct Tables<'a> {
+    pub signed_packets: redb::Table<'a, &'static SignedPacketsKey, &'static [u8]>,
+    pub update_time: redb::MultimapTable<'a, [u8; 8], SignedPacketsKey>,
+    pub blocked_keys: redb::Table<'a, &'static SignedPacketsKey, ()>,
+}
+
+impl<'txn> Tables<'txn> {
+    pub fn new(tx: &'txn redb::WriteTransaction) -> result::Result<Self, redb::TableError> {
+        Ok(Self {
+            signed_packets: tx.open_table(SIGNED_PACKETS_TABLE)?,
+            update_time: tx.open_multimap_table(UPDATE_TIME_TABLE)?,
+            blocked_keys: tx.open_table(BLOCKED_KEYS_TABLE)?,
+        })
+    }
+}
+
+fn get_packet(
+    table: &impl ReadableTable<&'static SignedPacketsKey, &'static [u8]>,
+    key: &PublicKeyBytes,
+) -> Result<Option<SignedPacket>> {
+    let Some(row) = table.get(key.as_ref()).context("database fetch failed")? else {
+        return Ok(None);
+    };
+    let packet = SignedPacket::deserialize(row.value()).context("parsing signed packet failed")?;
+    Ok(Some(packet))
+}
@@ -0,0 +1,21 @@
+//! Helpers shared by the unit tests.
+
+use anyhow::Result;
+use pkarr::{SignedPacket, Timestamp};
+
+/// Creates a signed packet with a single `_hello` TXT record.
+pub(crate) fn packet(keypair: &pkarr::Keypair, txt: &str, timestamp: u64) -> Result<SignedPacket> {
+    use pkarr::dns;
+    let mut packet = dns::Packet::new_reply(0);
+    packet.answers.push(dns::ResourceRecord::new(
+        dns::Name::new("_hello").unwrap(),
+        dns::CLASS::IN,
+        30,
+        dns::rdata::RData::TXT(txt.try_into()?),
+    ));
+    Ok(SignedPacket::new(
+        keypair,
+        &packet.answers,
+        Timestamp::from(timestamp),
+    )?)
+}
@@ -61,6 +61,19 @@ pub struct ClientBuilder {
     dns_resolver: DnsResolver,
     /// Cache for public keys of remote nodes.
     key_cache: KeyCache,
+    /// Prefer relaying over QUIC, default is None
+    #[cfg(not(wasm_browser))]
+    quic_relay: Option<QuicRelay>,
+}
+
+/// Where to relay over QUIC, see [`ClientBuilder::quic_relay`].
+#[cfg(not(wasm_browser))]
+#[derive(Debug, Clone)]
+struct QuicRelay {
+    /// The endpoint to make the QUIC connection from.
+    endpoint: quinn::Endpoint,
+    /// The UDP port of the relay's QUIC server.
+    port: u16,
 }
 
 impl ClientBuilder {
@@ -86,6 +99,8 @@ impl ClientBuilder {
             #[cfg(not(wasm_browser))]
             dns_resolver,
             key_cache: KeyCache::new(128),
+            #[cfg(not(wasm_browser))]
+            quic_relay: None,
         }
     }
 
@@ -137,8 +152,30 @@ impl ClientBuilder {
         self
     }
 
+    /// Prefers relaying over QUIC datagrams, to the relay's QUIC server on `port`.
+    ///
+    /// Relayed packets are sent as QUIC datagrams, avoiding the head-of-line blocking of the
+    /// TCP based [`Protocol`]s.  The QUIC connection is made from the given `endpoint`.  When
+    /// it can not be established, e.g. because UDP is blocked, the client falls back to the
+    /// configured [`Protocol`].
+    ///
+    /// Not used when an HTTP proxy is configured using [`ClientBuilder::proxy_url`].
+    ///
+    /// The relay's QUIC server normally listens on
+    /// [`DEFAULT_RELAY_QUIC_PORT`](crate::defaults::DEFAULT_RELAY_QUIC_PORT).
+    #[cfg(not(wasm_browser))]
+    pub fn quic_relay(mut self, endpoint: quinn::Endpoint, port: u16) -> Self {
+        self.quic_relay = Some(QuicRelay { endpoint, port });
+        self
+    }
+
     /// Establishes a new connection to the relay server.
     pub async fn connect(&self) -> Result<Client> {
+        #[cfg(not(wasm_browser))]
+        if let Some(client) = self.connect_quic_relay().await {
+            return Ok(client);
+        }
+
         let (conn, local_addr) = match self.protocol {
             #[cfg(wasm_browser)]
             Protocol::Websocket => {
@@ -204,6 +241,14 @@ pub struct Client {
 }
 
 impl Client {
+    /// Returns the underlying connection, to exchange [`Frame`]s with the server.
+    ///
+    /// [`Frame`]: crate::protos::relay::Frame
+    #[cfg(feature = "server")]
+    pub(crate) fn into_conn(self) -> Conn {
+        self.conn
+    }
+
     /// Splits the client into a sink and a stream.
     pub fn split(self) -> (ClientStream, ClientSink) {
         let (sink, stream) = split(self.conn);
@@ -22,6 +22,7 @@ use crate::protos::relay::{ClientInfo, Frame, MAX_PACKET_SIZE, PROTOCOL_VERSION}
 use crate::{
     client::streams::{MaybeTlsStream, MaybeTlsStreamChained, ProxyStream},
     protos::relay::RelayCodec,
+    quic::QuicRelayStream,
 };
 
 /// Error for sending messages to the relay server.
@@ -87,6 +88,8 @@ pub(crate) enum Conn {
         conn: tokio_websockets::WebSocketStream<MaybeTlsStream<ProxyStream>>,
         key_cache: KeyCache,
     },
+    #[cfg(not(wasm_browser))]
+    Quic { conn: QuicRelayStream },
     #[cfg(wasm_browser)]
     WsBrowser {
         #[debug("WebSocketStream")]
@@ -141,6 +144,17 @@ impl Conn {
 
         Ok(conn)
     }
+
+    /// Constructs a new connection relaying over QUIC, including the initial server handshake.
+    #[cfg(not(wasm_browser))]
+    pub(crate) async fn new_quic(conn: QuicRelayStream, secret_key: &SecretKey) -> Result<Self> {
+        let mut conn = Self::Quic { conn };
+
+        // exchange information with the server
+        server_handshake(&mut conn, secret_key).await?;
+
+        Ok(conn)
+    }
 }
 
 /// Sends the server handshake message.
@@ -156,20 +170,18 @@ async fn server_handshake(writer: &mut Conn, secret_key: &SecretKey) -> Result<(
     Ok(())
 }
 
-impl Stream for Conn {
-    type Item = Result<ReceivedMessage>;
-
-    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+impl Conn {
+    /// Polls the next [`Frame`] received from the server.
+    ///
+    /// This is the lower-level interface of the [`Stream`] of [`ReceivedMessage`]s, used by
+    /// relay servers meshing with each other.
+    pub(crate) fn poll_next_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame>>> {
         match *self {
             #[cfg(not(wasm_browser))]
-            Self::Relay { ref mut conn } => match ready!(Pin::new(conn).poll_next(cx)) {
-                Some(Ok(frame)) => {
-                    let message = ReceivedMessage::try_from(frame);
-                    Poll::Ready(Some(message))
-                }
-                Some(Err(err)) => Poll::Ready(Some(Err(err))),
-                None => Poll::Ready(None),
-            },
+            Self::Relay { ref mut conn } => Pin::new(conn).poll_next(cx),
             #[cfg(not(wasm_browser))]
             Self::Ws {
                 ref mut conn,
@@ -188,20 +200,22 @@ impl Stream for Conn {
                         );
                         return Poll::Pending;
                     }
-                    let frame = Frame::decode_from_ws_msg(msg.into_payload().into(), key_cache)?;
-                    Poll::Ready(Some(ReceivedMessage::try_from(frame)))
+                    let frame = Frame::decode_from_ws_msg(msg.into_payload().into(), key_cache);
+                    Poll::Ready(Some(frame))
                 }
                 Some(Err(e)) => Poll::Ready(Some(Err(e.into()))),
                 None => Poll::Ready(None),
             },
+            #[cfg(not(wasm_browser))]
+            Self::Quic { ref mut conn } => Pin::new(conn).poll_next(cx),
             #[cfg(wasm_browser)]
             Self::WsBrowser {
                 ref mut conn,
                 ref key_cache,
             } => match ready!(Pin::new(conn).poll_next(cx)) {
                 Some(ws_stream_wasm::WsMessage::Binary(vec)) => {
-                    let frame = Frame::decode_from_ws_msg(Bytes::from(vec), key_cache)?;
-                    Poll::Ready(Some(ReceivedMessage::try_from(frame)))
+                    let frame = Frame::decode_from_ws_msg(Bytes::from(vec), key_cache);
+                    Poll::Ready(Some(frame))
                 }
                 Some(msg) => {
                     tracing::warn!(?msg, "Got websocket message of unsupported type, skipping.");
@@ -213,6 +227,18 @@ impl Stream for Conn {
     }
 }
 
+impl Stream for Conn {
+    type Item = Result<ReceivedMessage>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        match ready!(self.poll_next_frame(cx)) {
+            Some(Ok(frame)) => Poll::Ready(Some(ReceivedMessage::try_from(frame))),
+            Some(Err(err)) => Poll::Ready(Some(Err(err))),
+            None => Poll::Ready(None),
+        }
+    }
+}
+
 impl Sink<Frame> for Conn {
     type Error = ConnSendError;
 
@@ -222,6 +248,8 @@ impl Sink<Frame> for Conn {
             Self::Relay { ref mut conn } => Pin::new(conn).poll_ready(cx).map_err(Into::into),
             #[cfg(not(wasm_browser))]
             Self::Ws { ref mut conn, .. } => Pin::new(conn).poll_ready(cx).map_err(Into::into),
+            #[cfg(not(wasm_browser))]
+            Self::Quic { ref mut conn } => Pin::new(conn).poll_ready(cx).map_err(Into::into),
             #[cfg(wasm_browser)]
             Self::WsBrowser { ref mut conn, .. } => {
                 Pin::new(conn).poll_ready(cx).map_err(Into::into)
@@ -244,6 +272,8 @@ impl Sink<Frame> for Conn {
                     tokio_websockets::Payload::from(frame.encode_for_ws_msg()),
                 ))
                 .map_err(Into::into),
+            #[cfg(not(wasm_browser))]
+            Self::Quic { ref mut conn } => Pin::new(conn).start_send(frame).map_err(Into::into),
             #[cfg(wasm_browser)]
             Self::WsBrowser { ref mut conn, .. } => Pin::new(conn)
                 .start_send(ws_stream_wasm::WsMessage::Binary(frame.encode_for_ws_msg()))
@@ -257,6 +287,8 @@ impl Sink<Frame> for Conn {
             Self::Relay { ref mut conn } => Pin::new(conn).poll_flush(cx).map_err(Into::into),
             #[cfg(not(wasm_browser))]
             Self::Ws { ref mut conn, .. } => Pin::new(conn).poll_flush(cx).map_err(Into::into),
+            #[cfg(not(wasm_browser))]
+            Self::Quic { ref mut conn } => Pin::new(conn).poll_flush(cx).map_err(Into::into),
             #[cfg(wasm_browser)]
             Self::WsBrowser { ref mut conn, .. } => {
                 Pin::new(conn).poll_flush(cx).map_err(Into::into)
@@ -270,6 +302,8 @@ impl Sink<Frame> for Conn {
             Self::Relay { ref mut conn } => Pin::new(conn).poll_close(cx).map_err(Into::into),
             #[cfg(not(wasm_browser))]
             Self::Ws { ref mut conn, .. } => Pin::new(conn).poll_flush(cx).map_err(Into::into),
+            #[cfg(not(wasm_browser))]
+            Self::Quic { ref mut conn } => Pin::new(conn).poll_flush(cx).map_err(Into::into),
             #[cfg(wasm_browser)]
             Self::WsBrowser { ref mut conn, .. } => {
                 Pin::new(conn).poll_close(cx).map_err(Into::into)
@@ -287,6 +321,8 @@ impl Sink<SendMessage> for Conn {
             Self::Relay { ref mut conn } => Pin::new(conn).poll_ready(cx).map_err(Into::into),
             #[cfg(not(wasm_browser))]
             Self::Ws { ref mut conn, .. } => Pin::new(conn).poll_ready(cx).map_err(Into::into),
+            #[cfg(not(wasm_browser))]
+            Self::Quic { ref mut conn } => Pin::new(conn).poll_ready(cx).map_err(Into::into),
             #[cfg(wasm_browser)]
             Self::WsBrowser { ref mut conn, .. } => {
                 Pin::new(conn).poll_ready(cx).map_err(Into::into)
@@ -310,6 +346,8 @@ impl Sink<SendMessage> for Conn {
                     tokio_websockets::Payload::from(frame.encode_for_ws_msg()),
                 ))
                 .map_err(Into::into),
+            #[cfg(not(wasm_browser))]
+            Self::Quic { ref mut conn } => Pin::new(conn).start_send(frame).map_err(Into::into),
             #[cfg(wasm_browser)]
             Self::WsBrowser { ref mut conn, .. } => Pin::new(conn)
                 .start_send(ws_stream_wasm::WsMessage::Binary(frame.encode_for_ws_msg()))
@@ -323,6 +361,8 @@ impl Sink<SendMessage> for Conn {
             Self::Relay { ref mut conn } => Pin::new(conn).poll_flush(cx).map_err(Into::into),
             #[cfg(not(wasm_browser))]
             Self::Ws { ref mut conn, .. } => Pin::new(conn).poll_flush(cx).map_err(Into::into),
+            #[cfg(not(wasm_browser))]
+            Self::Quic { ref mut conn } => Pin::new(conn).poll_flush(cx).map_err(Into::into),
             #[cfg(wasm_browser)]
             Self::WsBrowser { ref mut conn, .. } => {
                 Pin::new(conn).poll_flush(cx).map_err(Into::into)
@@ -336,6 +376,8 @@ impl Sink<SendMessage> for Conn {
             Self::Relay { ref mut conn } => Pin::new(conn).poll_close(cx).map_err(Into::into),
             #[cfg(not(wasm_browser))]
             Self::Ws { ref mut conn, .. } => Pin::new(conn).poll_close(cx).map_err(Into::into),
+            #[cfg(not(wasm_browser))]
+            Self::Quic { ref mut conn } => Pin::new(conn).poll_close(cx).map_err(Into::into),
             #[cfg(wasm_browser)]
             Self::WsBrowser { ref mut conn, .. } => {
                 Pin::new(conn).poll_close(cx).map_err(Into::into)
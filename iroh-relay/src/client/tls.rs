@@ -25,6 +25,7 @@ use hyper::{
     Request,
 };
 use n0_future::{task, time};
+use quinn::crypto::rustls::QuicClientConfig;
 use rustls::client::Resumption;
 use tokio::io::{AsyncRead, AsyncWrite};
 use tracing::{error, info_span, Instrument};
@@ -33,7 +34,10 @@ use super::{
     streams::{downcast_upgrade, MaybeTlsStream, ProxyStream},
     *,
 };
-use crate::defaults::timeouts::*;
+use crate::{
+    defaults::timeouts::*,
+    quic::{QuicRelayStream, ALPN_QUIC_RELAY},
+};
 
 #[derive(Debug, Clone)]
 pub struct MaybeTlsStreamBuilder {
@@ -74,23 +78,11 @@ impl MaybeTlsStreamBuilder {
     }
 
     pub async fn connect(self) -> Result<MaybeTlsStream<ProxyStream>> {
-        let roots = rustls::RootCertStore {
-            roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
-        };
-        let mut config = rustls::client::ClientConfig::builder_with_provider(Arc::new(
-            rustls::crypto::ring::default_provider(),
-        ))
-        .with_safe_default_protocol_versions()
-        .expect("protocols supported by ring")
-        .with_root_certificates(roots)
-        .with_no_client_auth();
-        #[cfg(any(test, feature = "test-utils"))]
-        if self.insecure_skip_cert_verify {
-            warn!("Insecure config: SSL certificates from relay servers not verified");
-            config
-                .dangerous()
-                .set_certificate_verifier(Arc::new(NoCertVerifier));
-        }
+        let mut config = tls_client_config(
+            rustls::DEFAULT_VERSIONS,
+            #[cfg(any(test, feature = "test-utils"))]
+            self.insecure_skip_cert_verify,
+        );
         config.resumption = Resumption::default();
         let tls_connector: tokio_rustls::TlsConnector = Arc::new(config).into();
 
@@ -351,6 +343,75 @@ impl ClientBuilder {
         Ok((conn, local_addr))
     }
 
+    /// Connects to the relay's QUIC server, if relaying over QUIC is configured.
+    ///
+    /// Returns `None` if the QUIC connection could not be established, in which case the
+    /// client falls back to the configured [`Protocol`].
+    pub(super) async fn connect_quic_relay(&self) -> Option<Client> {
+        let quic_relay = self.quic_relay.as_ref()?;
+        if self.proxy_url.is_some() {
+            // proxies only carry TCP
+            return None;
+        }
+        match time::timeout(QUIC_RELAY_DIAL_TIMEOUT, self.connect_quic(quic_relay)).await {
+            Ok(Ok((conn, local_addr))) => {
+                event!(
+                    target: "events.net.relay.connected",
+                    Level::DEBUG,
+                    url = %self.url,
+                    protocol = "quic",
+                );
+                Some(Client {
+                    conn,
+                    local_addr: Some(local_addr),
+                })
+            }
+            Ok(Err(err)) => {
+                debug!(protocol = ?self.protocol, "relaying over QUIC failed, falling back: {err:#}");
+                None
+            }
+            Err(_) => {
+                debug!(protocol = ?self.protocol, "relaying over QUIC timed out, falling back");
+                None
+            }
+        }
+    }
+
+    /// Connects to the relay's QUIC server using [`ALPN_QUIC_RELAY`].
+    async fn connect_quic(&self, quic_relay: &QuicRelay) -> Result<(Conn, SocketAddr)> {
+        let dst_ip = self
+            .dns_resolver
+            .resolve_host(&self.url, self.prefer_ipv6(), DNS_TIMEOUT)
+            .await?;
+        let addr = SocketAddr::new(dst_ip, quic_relay.port);
+        let host = self.url.host_str().context("Invalid URL")?;
+        let host = host.strip_suffix('.').unwrap_or(host);
+
+        debug!(%addr, "Dialing relay over QUIC");
+        let conn = quic_relay
+            .endpoint
+            .connect_with(self.quic_client_config()?, addr, host)?
+            .await?;
+        let local_addr = quic_relay.endpoint.local_addr()?;
+        let (send, recv) = conn.open_bi().await?;
+        let stream = QuicRelayStream::new(conn, send, recv, self.key_cache.clone());
+        let conn = Conn::new_quic(stream, &self.secret_key).await?;
+
+        Ok((conn, local_addr))
+    }
+
+    /// Creates the QUIC client config for relaying over QUIC.
+    fn quic_client_config(&self) -> Result<quinn::ClientConfig> {
+        let mut config = tls_client_config(
+            &[&rustls::version::TLS13],
+            #[cfg(any(test, feature = "test-utils"))]
+            self.insecure_skip_cert_verify,
+        );
+        config.alpn_protocols = vec![ALPN_QUIC_RELAY.to_vec()];
+        let config = QuicClientConfig::try_from(config)?;
+        Ok(quinn::ClientConfig::new(Arc::new(config)))
+    }
+
     /// Sends the HTTP upgrade request to the relay server.
     async fn http_upgrade_relay<T>(&self, io: T) -> Result<hyper::Response<Incoming>>
     where
@@ -399,6 +460,35 @@ impl ClientBuilder {
     }
 }
 
+/// Creates the TLS config for connecting to relay servers.
+///
+/// Relay server certificates are verified against the webpki roots, unless
+/// `insecure_skip_cert_verify` is set.
+fn tls_client_config(
+    versions: &[&'static rustls::SupportedProtocolVersion],
+    #[cfg(any(test, feature = "test-utils"))] insecure_skip_cert_verify: bool,
+) -> rustls::ClientConfig {
+    let roots = rustls::RootCertStore {
+        roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+    };
+    #[allow(unused_mut)]
+    let mut config = rustls::client::ClientConfig::builder_with_provider(Arc::new(
+        rustls::crypto::ring::default_provider(),
+    ))
+    .with_protocol_versions(versions)
+    .expect("protocols supported by ring")
+    .with_root_certificates(roots)
+    .with_no_client_auth();
+    #[cfg(any(test, feature = "test-utils"))]
+    if insecure_skip_cert_verify {
+        warn!("Insecure config: SSL certificates from relay servers not verified");
+        config
+            .dangerous()
+            .set_certificate_verifier(Arc::new(NoCertVerifier));
+    }
+    config
+}
+
 fn host_header_value(relay_url: RelayUrl) -> Result<String> {
     // grab the host, turns e.g. https://example.com:8080/xyz -> example.com.
     let relay_url_host = relay_url.host_str().context("Invalid URL")?;
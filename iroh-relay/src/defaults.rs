@@ -35,6 +35,9 @@ pub(crate) mod timeouts {
     /// Timeout used by the relay client while connecting to the relay server,
     /// using `TcpStream::connect`
     pub(crate) const DIAL_NODE_TIMEOUT: Duration = Duration::from_millis(1500);
+    /// Timeout used by the relay client while relaying over QUIC is attempted, before
+    /// falling back to relaying over TCP.
+    pub(crate) const QUIC_RELAY_DIAL_TIMEOUT: Duration = Duration::from_secs(3);
     /// Timeout for our async dns resolver
     pub(crate) const DNS_TIMEOUT: Duration = Duration::from_secs(1);
 
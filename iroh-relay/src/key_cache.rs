@@ -42,6 +42,19 @@ impl KeyCache {
         Self::Shared(Arc::new(Mutex::new(cache)))
     }
 
+    /// Changes the capacity of the cache.
+    ///
+    /// An enabled cache is resized in place, this affects all its clones.  Enabling or
+    /// disabling the cache only affects this instance and clones made after this call.
+    #[cfg(feature = "server")]
+    pub fn set_capacity(&mut self, capacity: usize) {
+        if let (Self::Shared(cache), Some(capacity)) = (&*self, NonZeroUsize::new(capacity)) {
+            cache.lock().expect("not poisoned").resize(capacity);
+            return;
+        }
+        *self = Self::new(capacity);
+    }
+
     /// Get a key from a slice of bytes.
     pub fn key_from_slice(&self, slice: &[u8]) -> Result<PublicKey, SignatureError> {
         let Self::Shared(cache) = self else {
@@ -5,14 +5,16 @@
 
 use std::{
     net::{Ipv6Addr, SocketAddr},
+    num::NonZeroUsize,
     path::{Path, PathBuf},
     sync::Arc,
+    time::{Duration, SystemTime},
 };
 
 use anyhow::{anyhow, bail, Context as _, Result};
 use clap::Parser;
 use http::StatusCode;
-use iroh_base::NodeId;
+use iroh_base::{NodeId, RelayUrl, SecretKey};
 use iroh_relay::{
     defaults::{
         DEFAULT_HTTPS_PORT, DEFAULT_HTTP_PORT, DEFAULT_METRICS_PORT, DEFAULT_RELAY_QUIC_PORT,
@@ -23,7 +25,7 @@ use iroh_relay::{
 use n0_future::FutureExt;
 use serde::{Deserialize, Serialize};
 use tokio_rustls_acme::{caches::DirCache, AcmeConfig};
-use tracing::{debug, warn};
+use tracing::{debug, info, warn};
 use tracing_subscriber::{prelude::*, EnvFilter};
 use url::Url;
 
@@ -31,8 +33,22 @@ use url::Url;
 const DEV_MODE_HTTP_PORT: u16 = 3340;
 /// The header name for setting the node id in HTTP auth requests.
 const X_IROH_NODE_ID: &str = "X-Iroh-NodeId";
+/// The header name for setting the remote IP address in HTTP auth requests.
+const X_IROH_REMOTE_IP: &str = "X-Iroh-Remote-Ip";
+/// The header name for setting the relay protocol in HTTP auth requests.
+const X_IROH_RELAY_PROTOCOL: &str = "X-Iroh-Relay-Protocol";
+/// The header name for setting the TLS server name in HTTP auth requests.
+const X_IROH_SERVER_NAME: &str = "X-Iroh-Server-Name";
+/// The number of cached HTTP access decisions, see [`HttpAccessConfig::cache_ttl_secs`].
+const HTTP_ACCESS_CACHE_CAPACITY: usize = 10_000;
 /// Environment variable to read a bearer token for HTTP auth requests from.
 const ENV_HTTP_BEARER_TOKEN: &str = "IROH_RELAY_HTTP_BEARER_TOKEN";
+/// Environment variable to read the bearer token for the admin API from.
+const ENV_ADMIN_BEARER_TOKEN: &str = "IROH_RELAY_ADMIN_BEARER_TOKEN";
+/// How often the config file is checked for changes.
+const CONFIG_WATCH_INTERVAL: Duration = Duration::from_secs(5);
+/// Environment variable to read the secret key for connecting to mesh peers from.
+const ENV_MESH_SECRET_KEY: &str = "IROH_RELAY_MESH_SECRET_KEY";
 
 /// A relay server for iroh.
 #[derive(Parser, Debug, Clone)]
@@ -49,6 +65,9 @@ struct Cli {
     ///
     /// If provided and no configuration file exists the default configuration will be
     /// written to the file.
+    ///
+    /// Changes to the `access`, `limits` and `key_cache_capacity` settings are applied
+    /// without a restart when the file changes or the process receives `SIGHUP`.
     #[clap(long, short)]
     config_path: Option<PathBuf>,
 }
@@ -175,6 +194,18 @@ struct Config {
     /// This controls which nodes are allowed to relay connections, other endpoints, like STUN are not controlled by this.
     #[serde(default)]
     access: AccessConfig,
+    /// Meshing with other relay servers.
+    ///
+    /// Disabled if not present.
+    mesh: Option<MeshConfig>,
+    /// Per-node quotas.
+    ///
+    /// Disabled if not present.
+    quotas: Option<QuotasConfig>,
+    /// The admin HTTP API.
+    ///
+    /// Disabled if not present.
+    admin: Option<AdminConfig>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, Default, PartialEq, Eq)]
@@ -189,10 +220,13 @@ enum AccessConfig {
     Denylist(Vec<NodeId>),
     /// Performs a HTTP POST request to determine access for each node that connects to the relay.
     ///
-    /// The request will have a header `X-Iroh-Node-Id` set to the hex-encoded node id attempting
-    /// to connect to the relay.
+    /// The request will have a header `X-Iroh-NodeId` set to the hex-encoded node id attempting
+    /// to connect to the relay.  The headers `X-Iroh-Relay-Protocol` (`relay`, `websocket` or
+    /// `quic`), `X-Iroh-Remote-Ip` and `X-Iroh-Server-Name` (the TLS SNI) are set if known.
     ///
     /// To grant access, the HTTP endpoint must return a `200` response with `true` as the response text.
+    /// To grant access with limits, the HTTP endpoint must return a `200` response with a JSON
+    /// quota as the response text, e.g. `{"bytes_per_day": 1000000, "rx": {"bytes_per_second": 1024}}`.
     /// In all other cases, the node will be denied access.
     Http(HttpAccessConfig),
 }
@@ -208,6 +242,60 @@ struct HttpAccessConfig {
     /// If both the config and the environment variable are set, the value from the environment variable
     /// is used.
     bearer_token: Option<String>,
+    /// How long to cache access decisions, in seconds.
+    ///
+    /// Decisions are cached per node id, remote IP, protocol and TLS server name.  Not cached
+    /// if not set.
+    cache_ttl_secs: Option<u64>,
+}
+
+/// Configuration for meshing with other relay servers.
+///
+/// Every relay server in the mesh must list all other relay servers as peers.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+struct MeshConfig {
+    /// The secret key used to connect to the mesh peers, hex or base32 encoded.
+    ///
+    /// The peers must list the node id of this key.  The secret key can also be set via the
+    /// `IROH_RELAY_MESH_SECRET_KEY` environment variable.  If both the config and the
+    /// environment variable are set, the value from the environment variable is used.
+    secret_key: Option<String>,
+    /// The other relay servers in the mesh.
+    #[serde(default)]
+    peers: Vec<MeshPeer>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+struct MeshPeer {
+    /// The URL of the peer relay server.
+    url: RelayUrl,
+    /// The node id the peer relay server uses to connect to this relay server.
+    node_id: NodeId,
+}
+
+impl TryFrom<MeshConfig> for iroh_relay::server::MeshConfig {
+    type Error = anyhow::Error;
+
+    fn try_from(cfg: MeshConfig) -> Result<Self> {
+        // Allow to set the secret key via environment variable as well.
+        let secret_key = std::env::var(ENV_MESH_SECRET_KEY)
+            .ok()
+            .or(cfg.secret_key)
+            .context("mesh requires a secret_key")?;
+        let secret_key: SecretKey = secret_key.parse().context("invalid mesh secret_key")?;
+        Ok(Self {
+            secret_key,
+            peers: cfg
+                .peers
+                .into_iter()
+                .map(|peer| iroh_relay::server::MeshPeer {
+                    // Deserializing skips the normalisation done when parsing a RelayUrl.
+                    url: Url::from(peer.url).into(),
+                    node_id: peer.node_id,
+                })
+                .collect(),
+        })
+    }
 }
 
 impl From<AccessConfig> for iroh_relay::server::AccessConfig {
@@ -216,10 +304,10 @@ impl From<AccessConfig> for iroh_relay::server::AccessConfig {
             AccessConfig::Everyone => iroh_relay::server::AccessConfig::Everyone,
             AccessConfig::Allowlist(allow_list) => {
                 let allow_list = Arc::new(allow_list);
-                iroh_relay::server::AccessConfig::Restricted(Box::new(move |node_id| {
+                iroh_relay::server::AccessConfig::Restricted(Box::new(move |request| {
                     let allow_list = allow_list.clone();
                     async move {
-                        if allow_list.contains(&node_id) {
+                        if allow_list.contains(&request.node_id) {
                             iroh_relay::server::Access::Allow
                         } else {
                             iroh_relay::server::Access::Deny
@@ -230,10 +318,10 @@ impl From<AccessConfig> for iroh_relay::server::AccessConfig {
             }
             AccessConfig::Denylist(deny_list) => {
                 let deny_list = Arc::new(deny_list);
-                iroh_relay::server::AccessConfig::Restricted(Box::new(move |node_id| {
+                iroh_relay::server::AccessConfig::Restricted(Box::new(move |request| {
                     let deny_list = deny_list.clone();
                     async move {
-                        if deny_list.contains(&node_id) {
+                        if deny_list.contains(&request.node_id) {
                             iroh_relay::server::Access::Deny
                         } else {
                             iroh_relay::server::Access::Allow
@@ -248,30 +336,39 @@ impl From<AccessConfig> for iroh_relay::server::AccessConfig {
                 if let Ok(token) = std::env::var(ENV_HTTP_BEARER_TOKEN) {
                     config.bearer_token = Some(token);
                 }
+                let cache_ttl = config.cache_ttl_secs.map(Duration::from_secs);
                 let config = Arc::new(config);
-                iroh_relay::server::AccessConfig::Restricted(Box::new(move |node_id| {
-                    let client = client.clone();
-                    let config = config.clone();
-                    async move { http_access_check(&client, &config, node_id).await }.boxed()
-                }))
+                let access =
+                    iroh_relay::server::AccessConfig::Restricted(Box::new(move |request| {
+                        let client = client.clone();
+                        let config = config.clone();
+                        async move { http_access_check(&client, &config, request).await }.boxed()
+                    }));
+                match cache_ttl {
+                    Some(ttl) => access.with_cache(
+                        ttl,
+                        NonZeroUsize::new(HTTP_ACCESS_CACHE_CAPACITY).expect("non-zero"),
+                    ),
+                    None => access,
+                }
             }
         }
     }
 }
 
-#[tracing::instrument("http-access-check", skip_all, fields(node_id=%node_id.fmt_short()))]
+#[tracing::instrument("http-access-check", skip_all, fields(node_id=%request.node_id.fmt_short()))]
 async fn http_access_check(
     client: &reqwest::Client,
     config: &HttpAccessConfig,
-    node_id: NodeId,
+    request: relay::AccessRequest,
 ) -> iroh_relay::server::Access {
     use iroh_relay::server::Access;
     debug!(url=%config.url, "Check relay access via HTTP POST");
 
-    match http_access_check_inner(client, config, node_id).await {
-        Ok(()) => {
-            debug!("HTTP access check OK: Allow access");
-            Access::Allow
+    match http_access_check_inner(client, config, request).await {
+        Ok(access) => {
+            debug!("HTTP access check OK: Allow access ({access:?})");
+            access
         }
         Err(err) => {
             debug!("HTTP access check failed: Deny access (reason: {err:#})");
@@ -283,11 +380,18 @@ async fn http_access_check(
 async fn http_access_check_inner(
     client: &reqwest::Client,
     config: &HttpAccessConfig,
-    node_id: NodeId,
-) -> Result<()> {
+    access_request: relay::AccessRequest,
+) -> Result<relay::Access> {
     let mut request = client
         .post(config.url.clone())
-        .header(X_IROH_NODE_ID, node_id.to_string());
+        .header(X_IROH_NODE_ID, access_request.node_id.to_string())
+        .header(X_IROH_RELAY_PROTOCOL, access_request.protocol.to_string());
+    if let Some(remote_ip) = access_request.remote_ip {
+        request = request.header(X_IROH_REMOTE_IP, remote_ip.to_string());
+    }
+    if let Some(server_name) = access_request.server_name.as_ref() {
+        request = request.header(X_IROH_SERVER_NAME, server_name);
+    }
     if let Some(token) = config.bearer_token.as_ref() {
         request = request.header(http::header::AUTHORIZATION, format!("Bearer {token}"));
     }
@@ -298,8 +402,13 @@ async fn http_access_check_inner(
             Err(err).context("Failed to fetch response")
         }
         Ok(res) if res.status() == StatusCode::OK => match res.text().await {
-            Ok(text) if text == "true" => Ok(()),
-            Ok(_) => Err(anyhow!("Invalid response text (must be 'true')")),
+            Ok(text) if text == "true" => Ok(relay::Access::Allow),
+            Ok(text) => match serde_json::from_str::<QuotaConfig>(&text) {
+                Ok(quota) => Ok(relay::Access::AllowWithLimits(quota.build()?)),
+                Err(_) => Err(anyhow!(
+                    "Invalid response text (must be 'true' or a JSON quota)"
+                )),
+            },
             Err(err) => Err(err).context("Failed to read response"),
         },
         Ok(res) => Err(anyhow!("Received invalid status code ({})", res.status())),
@@ -337,6 +446,9 @@ impl Default for Config {
             metrics_bind_addr: None,
             key_cache_capacity: Default::default(),
             access: AccessConfig::Everyone,
+            mesh: None,
+            quotas: None,
+            admin: None,
         }
     }
 }
@@ -497,6 +609,121 @@ struct RateLimitConfig {
     max_burst_bytes: Option<u32>,
 }
 
+impl RateLimitConfig {
+    fn build(&self) -> Result<Option<ClientRateLimit>> {
+        if self.bytes_per_second.is_none() && self.max_burst_bytes.is_some() {
+            bail!("bytes_per_seconds must be specified to enable the rate-limiter");
+        }
+        let Some(bps) = self.bytes_per_second else {
+            return Ok(None);
+        };
+        Ok(Some(ClientRateLimit {
+            bytes_per_second: bps
+                .try_into()
+                .context("bytes_per_second must be non-zero u32")?,
+            max_burst_bytes: self
+                .max_burst_bytes
+                .map(|v| v.try_into().context("max_burst_bytes must be non-zero u32"))
+                .transpose()?,
+        }))
+    }
+}
+
+/// Per-node quotas.
+///
+/// Nodes in a group share the quota of the group, all other nodes each have the `default`
+/// quota.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct QuotasConfig {
+    /// The quota of each node which is not in a group.
+    ///
+    /// These nodes have no quota if not present.
+    default: Option<QuotaConfig>,
+    /// Groups of nodes sharing a quota.
+    #[serde(default)]
+    groups: Vec<QuotaGroupConfig>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct QuotaConfig {
+    /// Rate limit for the incoming data, shared by all nodes of a group.
+    rx: Option<RateLimitConfig>,
+    /// Maximum number of bytes received per day, days start at midnight UTC.
+    bytes_per_day: Option<u64>,
+    /// Maximum number of nodes connected at the same time.
+    max_connections: Option<usize>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct QuotaGroupConfig {
+    /// Name of the group, used when reporting usage.
+    name: String,
+    /// The nodes in the group.
+    nodes: Vec<NodeId>,
+    /// The quota shared by the nodes in the group.
+    quota: QuotaConfig,
+}
+
+impl QuotaConfig {
+    fn build(&self) -> Result<relay::Quota> {
+        Ok(relay::Quota {
+            rx_rate_limit: self.rx.as_ref().map(|rx| rx.build()).transpose()?.flatten(),
+            bytes_per_day: self.bytes_per_day,
+            max_connections: self.max_connections,
+        })
+    }
+}
+
+impl QuotasConfig {
+    fn build(&self) -> Result<relay::QuotaConfig> {
+        Ok(relay::QuotaConfig {
+            default: self.default.as_ref().map(|q| q.build()).transpose()?,
+            groups: self
+                .groups
+                .iter()
+                .map(|group| {
+                    Ok(relay::QuotaGroup {
+                        name: group.name.clone(),
+                        nodes: group.nodes.clone(),
+                        quota: group
+                            .quota
+                            .build()
+                            .with_context(|| format!("invalid quota for group {}", group.name))?,
+                    })
+                })
+                .collect::<Result<_>>()?,
+        })
+    }
+}
+
+/// The admin HTTP API.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct AdminConfig {
+    /// The socket address to serve the admin API on.
+    ///
+    /// This should not be reachable from the public internet.
+    bind_addr: SocketAddr,
+    /// The bearer token required to use the admin API.
+    ///
+    /// The bearer token can also be set via the `IROH_RELAY_ADMIN_BEARER_TOKEN` environment
+    /// variable.  If both the config and the environment variable are set, the value from
+    /// the environment variable is used.
+    bearer_token: Option<String>,
+}
+
+impl AdminConfig {
+    fn build(&self) -> Result<relay::AdminConfig> {
+        let bearer_token = std::env::var(ENV_ADMIN_BEARER_TOKEN)
+            .ok()
+            .or_else(|| self.bearer_token.clone())
+            .context("admin API requires a bearer_token")?;
+        Ok(relay::AdminConfig {
+            bind_addr: self.bind_addr,
+            bearer_token,
+        })
+    }
+}
+
 impl Config {
     async fn load(opts: &Cli) -> Result<Self> {
         let config_path = if let Some(config_path) = &opts.config_path {
@@ -555,16 +782,96 @@ async fn main() -> Result<()> {
     debug!("{relay_config:#?}");
 
     let mut relay = relay::Server::spawn(relay_config).await?;
+    let mut config_watcher = ConfigWatcher::new(cli.config_path.clone())?;
 
-    tokio::select! {
-        biased;
-        _ = tokio::signal::ctrl_c() => (),
-        _ = relay.task_handle() => (),
+    loop {
+        tokio::select! {
+            biased;
+            _ = tokio::signal::ctrl_c() => break,
+            _ = relay.task_handle() => break,
+            _ = config_watcher.changed() => {
+                if let Err(err) = reload_config(&cli, &relay).await {
+                    warn!("failed to reload config: {err:#}");
+                }
+            }
+        }
     }
 
     relay.shutdown().await
 }
 
+/// Reloads the config file and applies the changes which do not need a restart.
+///
+/// These are the `access`, `limits` and `key_cache_capacity` settings.
+async fn reload_config(cli: &Cli, relay: &relay::Server) -> Result<()> {
+    let config_path = cli.config_path.as_ref().context("no config file")?;
+    let cfg = Config::read_from_file(config_path).await?;
+    let update = build_relay_config_update(&cfg)?;
+    relay.update_relay_config(update).await?;
+    info!("reloaded config from {}", config_path.display());
+    Ok(())
+}
+
+/// Detects when the config file should be reloaded.
+///
+/// The modification time of the config file is polled, on unix receiving `SIGHUP` also
+/// triggers a reload.
+struct ConfigWatcher {
+    path: Option<PathBuf>,
+    modified: Option<SystemTime>,
+    interval: tokio::time::Interval,
+    #[cfg(unix)]
+    sighup: tokio::signal::unix::Signal,
+}
+
+impl ConfigWatcher {
+    fn new(path: Option<PathBuf>) -> Result<Self> {
+        let modified = path.as_deref().and_then(modified_time);
+        let mut interval = tokio::time::interval(CONFIG_WATCH_INTERVAL);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        Ok(Self {
+            path,
+            modified,
+            interval,
+            #[cfg(unix)]
+            sighup: tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())
+                .context("failed to listen for SIGHUP")?,
+        })
+    }
+
+    /// Waits until the config file changed or a reload was requested.
+    async fn changed(&mut self) {
+        #[cfg(unix)]
+        let sighup = self.sighup.recv();
+        #[cfg(not(unix))]
+        let sighup = std::future::pending::<Option<()>>();
+        tokio::pin!(sighup);
+        loop {
+            tokio::select! {
+                _ = &mut sighup => {
+                    debug!("received SIGHUP, reloading config");
+                    return;
+                }
+                _ = self.interval.tick(), if self.path.is_some() => {
+                    let modified = self.path.as_deref().and_then(modified_time);
+                    if modified != self.modified {
+                        debug!("config file changed, reloading config");
+                        self.modified = modified;
+                        return;
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Returns the modification time of the file, if available.
+fn modified_time(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path)
+        .and_then(|meta| meta.modified())
+        .ok()
+}
+
 async fn maybe_load_tls(
     cfg: &Config,
 ) -> Result<Option<relay::TlsConfig<std::io::Error, std::io::Error>>> {
@@ -670,38 +977,7 @@ async fn build_relay_config(cfg: Config) -> Result<relay::ServerConfig<std::io::
             bail!("Must have a valid TLS configuration to enable a QUIC server for QUIC address discovery")
         }
     };
-    let limits = match cfg.limits {
-        Some(ref limits) => {
-            let client_rx = match &limits.client {
-                Some(PerClientRateLimitConfig { rx: Some(rx) }) => {
-                    if rx.bytes_per_second.is_none() && rx.max_burst_bytes.is_some() {
-                        bail!("bytes_per_seconds must be specified to enable the rate-limiter");
-                    }
-                    match rx.bytes_per_second {
-                        Some(bps) => Some(ClientRateLimit {
-                            bytes_per_second: bps
-                                .try_into()
-                                .context("bytes_per_second must be non-zero u32")?,
-                            max_burst_bytes: rx
-                                .max_burst_bytes
-                                .map(|v| {
-                                    v.try_into().context("max_burst_bytes must be non-zero u32")
-                                })
-                                .transpose()?,
-                        }),
-                        None => None,
-                    }
-                }
-                Some(PerClientRateLimitConfig { rx: None }) | None => None,
-            };
-            relay::Limits {
-                accept_conn_limit: limits.accept_conn_limit,
-                accept_conn_burst: limits.accept_conn_burst,
-                client_rx,
-            }
-        }
-        None => Default::default(),
-    };
+    let limits = build_limits(&cfg)?;
 
     let relay_config = relay::RelayConfig {
         http_bind_addr: cfg.http_bind_addr(),
@@ -710,6 +986,9 @@ async fn build_relay_config(cfg: Config) -> Result<relay::ServerConfig<std::io::
         limits,
         key_cache_capacity: cfg.key_cache_capacity,
         access: cfg.access.clone().into(),
+        mesh: cfg.mesh.clone().map(TryInto::try_into).transpose()?,
+        quotas: cfg.quotas.as_ref().map(|q| q.build()).transpose()?,
+        admin: cfg.admin.as_ref().map(|a| a.build()).transpose()?,
     };
 
     let stun_config = relay::StunConfig {
@@ -724,6 +1003,34 @@ async fn build_relay_config(cfg: Config) -> Result<relay::ServerConfig<std::io::
     })
 }
 
+/// Builds the rate limits of the relay server from the config.
+fn build_limits(cfg: &Config) -> Result<relay::Limits> {
+    let limits = match cfg.limits {
+        Some(ref limits) => {
+            let client_rx = match &limits.client {
+                Some(PerClientRateLimitConfig { rx: Some(rx) }) => rx.build()?,
+                Some(PerClientRateLimitConfig { rx: None }) | None => None,
+            };
+            relay::Limits {
+                accept_conn_limit: limits.accept_conn_limit,
+                accept_conn_burst: limits.accept_conn_burst,
+                client_rx,
+            }
+        }
+        None => Default::default(),
+    };
+    Ok(limits)
+}
+
+/// Builds the parts of the relay server config which can be changed while running.
+fn build_relay_config_update(cfg: &Config) -> Result<relay::RelayConfigUpdate> {
+    Ok(relay::RelayConfigUpdate {
+        limits: build_limits(cfg)?,
+        key_cache_capacity: cfg.key_cache_capacity,
+        access: cfg.access.clone().into(),
+    })
+}
+
 #[cfg(test)]
 mod tests {
     use std::num::NonZeroU32;
@@ -799,7 +1106,8 @@ mod tests {
             config.access,
             AccessConfig::Http(HttpAccessConfig {
                 url: "https://example.com/foo/bar?boo=baz".parse().unwrap(),
-                bearer_token: None
+                bearer_token: None,
+                cache_ttl_secs: None,
             })
         );
         let config = r#"
@@ -812,7 +1120,23 @@ mod tests {
             config.access,
             AccessConfig::Http(HttpAccessConfig {
                 url: "https://example.com/foo/bar?boo=baz".parse().unwrap(),
-                bearer_token: Some("foo".to_string())
+                bearer_token: Some("foo".to_string()),
+                cache_ttl_secs: None,
+            })
+        );
+
+        let config = r#"
+            access.http.url = "https://example.com/foo"
+            access.http.cache_ttl_secs = 60
+        "#
+        .to_string();
+        let config = Config::from_str(dbg!(&config))?;
+        assert_eq!(
+            config.access,
+            AccessConfig::Http(HttpAccessConfig {
+                url: "https://example.com/foo".parse().unwrap(),
+                bearer_token: None,
+                cache_ttl_secs: Some(60),
             })
         );
 
@@ -825,7 +1149,8 @@ mod tests {
             config.access,
             AccessConfig::Http(HttpAccessConfig {
                 url: "https://example.com/foo".parse().unwrap(),
-                bearer_token: None
+                bearer_token: None,
+                cache_ttl_secs: None,
             })
         );
 
@@ -838,9 +1163,94 @@ mod tests {
             config.access,
             AccessConfig::Http(HttpAccessConfig {
                 url: "https://example.com/foo".parse().unwrap(),
-                bearer_token: Some("foo".to_string())
+                bearer_token: Some("foo".to_string()),
+                cache_ttl_secs: None,
             })
         );
         Ok(())
     }
+
+    #[tokio::test]
+    async fn test_mesh_config() -> TestResult {
+        let mut rng = ChaCha8Rng::seed_from_u64(0);
+        let secret_key = SecretKey::generate(&mut rng);
+        let peer_id = SecretKey::generate(&mut rng).public();
+
+        let config = format!(
+            r#"
+            [mesh]
+            secret_key = "{}"
+            peers = [
+              {{ url = "https://relay2.example.com", node_id = "{peer_id}" }},
+            ]
+        "#,
+            data_encoding::HEXLOWER.encode(&secret_key.to_bytes())
+        );
+        let config = Config::from_str(&config)?;
+        let mesh: relay::MeshConfig = config.mesh.context("missing mesh")?.try_into()?;
+        assert_eq!(mesh.secret_key.public(), secret_key.public());
+        assert_eq!(
+            mesh.peers,
+            vec![relay::MeshPeer {
+                url: "https://relay2.example.com".parse()?,
+                node_id: peer_id,
+            }]
+        );
+
+        let config = Config::from_str("[mesh]")?;
+        assert!(relay::MeshConfig::try_from(config.mesh.context("missing mesh")?).is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_http_access_limits() -> TestResult {
+        let quota: QuotaConfig =
+            serde_json::from_str(r#"{"bytes_per_day": 1000, "rx": {"bytes_per_second": 1024}}"#)?;
+        let quota = quota.build()?;
+        assert_eq!(quota.bytes_per_day, Some(1000));
+        assert_eq!(
+            quota.rx_rate_limit.map(|rl| rl.bytes_per_second.get()),
+            Some(1024)
+        );
+        assert!(serde_json::from_str::<QuotaConfig>("false").is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_quotas_config() -> TestResult {
+        let mut rng = ChaCha8Rng::seed_from_u64(0);
+        let node_id = SecretKey::generate(&mut rng).public();
+
+        let config = format!(
+            r#"
+            [quotas.default]
+            bytes_per_day = 1000
+
+            [[quotas.groups]]
+            name = "customer"
+            nodes = ["{node_id}"]
+            quota.rx.bytes_per_second = 100
+            quota.max_connections = 2
+        "#
+        );
+        let config = Config::from_str(&config)?;
+        let quotas = config.quotas.context("missing quotas")?.build()?;
+        let default = quotas.default.context("missing default")?;
+        assert_eq!(default.bytes_per_day, Some(1000));
+        assert!(default.rx_rate_limit.is_none());
+        assert_eq!(quotas.groups.len(), 1);
+        let group = &quotas.groups[0];
+        assert_eq!(group.name, "customer");
+        assert_eq!(group.nodes, vec![node_id]);
+        assert_eq!(group.quota.max_connections, Some(2));
+        assert_eq!(
+            group
+                .quota
+                .rx_rate_limit
+                .as_ref()
+                .map(|rl| rl.bytes_per_second.get()),
+            Some(100)
+        );
+        Ok(())
+    }
 }
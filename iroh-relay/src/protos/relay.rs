@@ -92,8 +92,23 @@ pub(crate) enum FrameType {
     ///
     /// 32B pub key of peer that's gone
     PeerGone = 8,
-    /// Frames 9-11 concern meshing, which we have eliminated from our version of the protocol.
-    /// Messages with these frames will be ignored.
+    /// Frames 9-11 concern meshing, they are only exchanged between relay servers of the
+    /// same mesh.  Servers which do not mesh ignore them.
+    ///
+    /// Sent from server to a watching mesh peer to announce a node is connected.
+    ///
+    /// 32B pub key of the connected node
+    PeerPresent = 9,
+    /// Sent from a mesh peer to the server to deliver a packet to a node connected to the
+    /// server.
+    ///
+    /// 32B src pub key + 32B dst pub key + packet bytes
+    ForwardPacket = 10,
+    /// Sent from a mesh peer to the server to subscribe to `FrameType::PeerPresent` and
+    /// `FrameType::PeerGone` announcements.
+    ///
+    /// no payload
+    WatchConns = 11,
     /// 8 byte ping payload, to be echoed back in FrameType::Pong
     Ping = 12,
     /// 8 byte payload, the contents of ping being replied to
@@ -250,6 +265,15 @@ pub(crate) enum Frame {
     NodeGone {
         node_id: PublicKey,
     },
+    PeerPresent {
+        node_id: PublicKey,
+    },
+    ForwardPacket {
+        src_key: PublicKey,
+        dst_key: PublicKey,
+        packet: Bytes,
+    },
+    WatchConns,
     Ping {
         data: [u8; 8],
     },
@@ -274,6 +298,9 @@ impl Frame {
             Frame::KeepAlive => FrameType::KeepAlive,
             Frame::NotePreferred { .. } => FrameType::NotePreferred,
             Frame::NodeGone { .. } => FrameType::PeerGone,
+            Frame::PeerPresent { .. } => FrameType::PeerPresent,
+            Frame::ForwardPacket { .. } => FrameType::ForwardPacket,
+            Frame::WatchConns => FrameType::WatchConns,
             Frame::Ping { .. } => FrameType::Ping,
             Frame::Pong { .. } => FrameType::Pong,
             Frame::Health { .. } => FrameType::Health,
@@ -298,6 +325,9 @@ impl Frame {
             Frame::KeepAlive => 0,
             Frame::NotePreferred { .. } => 1,
             Frame::NodeGone { .. } => PublicKey::LENGTH,
+            Frame::PeerPresent { .. } => PublicKey::LENGTH,
+            Frame::ForwardPacket { packet, .. } => 2 * PublicKey::LENGTH + packet.len(),
+            Frame::WatchConns => 0,
             Frame::Ping { .. } => 8,
             Frame::Pong { .. } => 8,
             Frame::Health { problem } => problem.len(),
@@ -365,6 +395,19 @@ impl Frame {
             Frame::NodeGone { node_id: peer } => {
                 dst.put(peer.as_ref());
             }
+            Frame::PeerPresent { node_id } => {
+                dst.put(node_id.as_ref());
+            }
+            Frame::ForwardPacket {
+                src_key,
+                dst_key,
+                packet,
+            } => {
+                dst.put(src_key.as_ref());
+                dst.put(dst_key.as_ref());
+                dst.put(packet.as_ref());
+            }
+            Frame::WatchConns => {}
             Frame::Ping { data } => {
                 dst.put(&data[..]);
             }
@@ -462,6 +505,39 @@ impl Frame {
                 let peer = cache.key_from_slice(&content[..32])?;
                 Self::NodeGone { node_id: peer }
             }
+            FrameType::PeerPresent => {
+                anyhow::ensure!(
+                    content.len() == PublicKey::LENGTH,
+                    "invalid peer present frame length"
+                );
+                let node_id = cache.key_from_slice(&content[..PublicKey::LENGTH])?;
+                Self::PeerPresent { node_id }
+            }
+            FrameType::ForwardPacket => {
+                ensure!(
+                    content.len() >= 2 * PublicKey::LENGTH,
+                    "invalid forward packet frame length: {}",
+                    content.len()
+                );
+                let packet_len = content.len() - 2 * PublicKey::LENGTH;
+                ensure!(
+                    packet_len <= MAX_PACKET_SIZE,
+                    "data packet longer ({packet_len}) than max of {MAX_PACKET_SIZE}"
+                );
+                let src_key = cache.key_from_slice(&content[..PublicKey::LENGTH])?;
+                let dst_key =
+                    cache.key_from_slice(&content[PublicKey::LENGTH..2 * PublicKey::LENGTH])?;
+                let packet = content.slice(2 * PublicKey::LENGTH..);
+                Self::ForwardPacket {
+                    src_key,
+                    dst_key,
+                    packet,
+                }
+            }
+            FrameType::WatchConns => {
+                anyhow::ensure!(content.is_empty(), "invalid watch conns frame length");
+                Self::WatchConns
+            }
             FrameType::Ping => {
                 anyhow::ensure!(content.len() == 8, "invalid ping frame length");
                 let mut data = [0u8; 8];
@@ -689,6 +765,27 @@ mod tests {
                 a7 89 be 0c 76 b2 92 03 34 03 9b fa 8b 3d 36 8d
                 61",
             ),
+            (
+                Frame::PeerPresent {
+                    node_id: client_key.public(),
+                },
+                "09 19 7f 6b 23 e1 6c 85 32 c6 ab c8 38 fa cd 5e
+                a7 89 be 0c 76 b2 92 03 34 03 9b fa 8b 3d 36 8d
+                61",
+            ),
+            (
+                Frame::ForwardPacket {
+                    src_key: client_key.public(),
+                    dst_key: client_key.public(),
+                    packet: "Hi!".into(),
+                },
+                "0a 19 7f 6b 23 e1 6c 85 32 c6 ab c8 38 fa cd 5e
+                a7 89 be 0c 76 b2 92 03 34 03 9b fa 8b 3d 36 8d
+                61 19 7f 6b 23 e1 6c 85 32 c6 ab c8 38 fa cd 5e
+                a7 89 be 0c 76 b2 92 03 34 03 9b fa 8b 3d 36 8d
+                61 48 69 21",
+            ),
+            (Frame::WatchConns, "0b"),
             (
                 Frame::Ping { data: [42u8; 8] },
                 "0c 2a 2a 2a 2a 2a 2a 2a 2a",
@@ -787,6 +884,14 @@ mod proptests {
         let keep_alive = Just(Frame::KeepAlive);
         let note_preferred = any::<bool>().prop_map(|preferred| Frame::NotePreferred { preferred });
         let peer_gone = key().prop_map(|peer| Frame::NodeGone { node_id: peer });
+        let peer_present = key().prop_map(|node_id| Frame::PeerPresent { node_id });
+        let forward_packet =
+            (key(), key(), data(64)).prop_map(|(src_key, dst_key, packet)| Frame::ForwardPacket {
+                src_key,
+                dst_key,
+                packet,
+            });
+        let watch_conns = Just(Frame::WatchConns);
         let ping = prop::array::uniform8(any::<u8>()).prop_map(|data| Frame::Ping { data });
         let pong = prop::array::uniform8(any::<u8>()).prop_map(|data| Frame::Pong { data });
         let health = data(0).prop_map(|problem| Frame::Health { problem });
@@ -802,6 +907,9 @@ mod proptests {
             keep_alive,
             note_preferred,
             peer_gone,
+            peer_present,
+            forward_packet,
+            watch_conns,
             ping,
             pong,
             health,
@@ -817,11 +925,14 @@ mod proptests {
                 | FrameType::Ping
                 | FrameType::Pong
                 | FrameType::Restarting
-                | FrameType::PeerGone => true,
+                | FrameType::PeerGone
+                | FrameType::PeerPresent
+                | FrameType::WatchConns => true,
                 FrameType::ClientInfo
                 | FrameType::Health
                 | FrameType::SendPacket
                 | FrameType::RecvPacket
+                | FrameType::ForwardPacket
                 | FrameType::Unknown => false,
             }
         }
@@ -1,13 +1,17 @@
 //! Create a QUIC server that accepts connections
-//! for QUIC address discovery.
+//! for QUIC address discovery and for relaying over QUIC.
 use std::{net::SocketAddr, sync::Arc};
 
 use anyhow::Result;
 use n0_future::time::Duration;
 use quinn::{crypto::rustls::QuicClientConfig, VarInt};
+#[cfg(not(wasm_browser))]
+pub(crate) use relay_stream::QuicRelayStream;
 
 /// ALPN for our quic addr discovery
 pub const ALPN_QUIC_ADDR_DISC: &[u8] = b"/iroh-qad/0";
+/// ALPN for relaying over QUIC
+pub const ALPN_QUIC_RELAY: &[u8] = b"/iroh-relay-quic/0";
 /// Endpoint close error code
 pub const QUIC_ADDR_DISC_CLOSE_CODE: VarInt = VarInt::from_u32(1);
 /// Endpoint close reason
@@ -15,13 +19,17 @@ pub const QUIC_ADDR_DISC_CLOSE_REASON: &[u8] = b"finished";
 
 #[cfg(feature = "server")]
 pub(crate) mod server {
-    use quinn::{crypto::rustls::QuicServerConfig, ApplicationClose};
+    use quinn::{
+        crypto::rustls::{HandshakeData, QuicServerConfig},
+        ApplicationClose,
+    };
     use tokio::task::JoinSet;
     use tokio_util::{sync::CancellationToken, task::AbortOnDropHandle};
     use tracing::{debug, info, info_span, Instrument};
 
     use super::*;
     pub use crate::server::QuicConfig;
+    use crate::server::RelayService;
 
     pub struct QuicServer {
         bind_addr: SocketAddr,
@@ -57,6 +65,9 @@ pub(crate) mod server {
         /// Spawns a QUIC server that creates and QUIC endpoint and listens
         /// for QUIC connections for address discovery
         ///
+        /// If a `relay` service is given the server also accepts connections relaying over
+        /// QUIC, using [`ALPN_QUIC_RELAY`].
+        ///
         /// # Errors
         /// If the given `quic_config` contains a [`rustls::ServerConfig`] that cannot
         /// be converted to a [`QuicServerConfig`], usually because it does not support
@@ -66,16 +77,27 @@ pub(crate) mod server {
         /// If there is a panic during a connection, it will be propagated
         /// up here. Any other errors in a connection will be logged as a
         ///  warning.
-        pub(crate) fn spawn(mut quic_config: QuicConfig) -> Result<Self> {
+        pub(crate) fn spawn(
+            mut quic_config: QuicConfig,
+            relay: Option<RelayService>,
+        ) -> Result<Self> {
             quic_config.server_config.alpn_protocols =
                 vec![crate::quic::ALPN_QUIC_ADDR_DISC.to_vec()];
+            if relay.is_some() {
+                quic_config
+                    .server_config
+                    .alpn_protocols
+                    .push(ALPN_QUIC_RELAY.to_vec());
+            }
             let server_config = QuicServerConfig::try_from(quic_config.server_config)?;
             let mut server_config = quinn::ServerConfig::with_crypto(Arc::new(server_config));
             let transport_config =
                 Arc::get_mut(&mut server_config.transport).expect("not used yet");
+            // relaying only needs a single control stream, opened by the client
+            let max_bidi_streams = if relay.is_some() { 1_u8 } else { 0_u8 };
             transport_config
                 .max_concurrent_uni_streams(0_u8.into())
-                .max_concurrent_bidi_streams(0_u8.into())
+                .max_concurrent_bidi_streams(max_bidi_streams.into())
                 // enable sending quic address discovery frames
                 .send_observed_address_reports(true);
 
@@ -111,7 +133,7 @@ pub(crate) mod server {
                                      debug!("accepting connection");
                                      let remote_addr = conn.remote_address();
                                      set.spawn(
-                                         handle_connection(conn).instrument(info_span!("qad-conn", %remote_addr))
+                                         handle_connection(conn, relay.clone()).instrument(info_span!("quic-conn", %remote_addr))
                                      );                                }
                                 None => {
                                     debug!("endpoint closed");
@@ -170,7 +192,13 @@ pub(crate) mod server {
     }
 
     /// Handle the connection from the client.
-    async fn handle_connection(incoming: quinn::Incoming) -> Result<()> {
+    ///
+    /// Connections using [`ALPN_QUIC_RELAY`] are handed to the relay service, all others
+    /// are only used for QUIC address discovery.
+    async fn handle_connection(
+        incoming: quinn::Incoming,
+        relay: Option<RelayService>,
+    ) -> Result<()> {
         let connection = match incoming.await {
             Ok(conn) => conn,
             Err(e) => {
@@ -178,6 +206,14 @@ pub(crate) mod server {
             }
         };
         debug!("established");
+        let alpn = connection
+            .handshake_data()
+            .and_then(|data| data.downcast::<HandshakeData>().ok())
+            .and_then(|data| data.protocol);
+        if let (Some(relay), Some(ALPN_QUIC_RELAY)) = (relay, alpn.as_deref()) {
+            debug!("relaying over QUIC");
+            return relay.accept_quic(connection).await;
+        }
         // wait for the client to close the connection
         let connection_err = connection.closed().await;
         match connection_err {
@@ -280,6 +316,178 @@ impl QuicClient {
     }
 }
 
+/// Relaying [`Frame`]s over a QUIC connection.
+///
+/// [`Frame`]: crate::protos::relay::Frame
+#[cfg(not(wasm_browser))]
+mod relay_stream {
+    use std::{
+        io,
+        pin::Pin,
+        task::{Context, Poll},
+    };
+
+    use bytes::Bytes;
+    use n0_future::{future::Boxed as BoxFuture, Sink, Stream};
+    use quinn::SendDatagramError;
+    use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+    use tokio_util::codec::Framed;
+
+    use crate::{
+        protos::relay::{Frame, RelayCodec},
+        KeyCache,
+    };
+
+    /// A Stream and Sink for [`Frame`]s relayed over a QUIC connection.
+    ///
+    /// Packets, [`Frame::SendPacket`] and [`Frame::RecvPacket`], are sent as unreliable QUIC
+    /// datagrams so they do not suffer from head-of-line blocking.  All other frames, and
+    /// packets too large to fit in a datagram, are sent on a bidirectional control stream
+    /// opened by the client.
+    #[derive(derive_more::Debug)]
+    pub(crate) struct QuicRelayStream {
+        conn: quinn::Connection,
+        #[debug("Framed<QuicBiStream, RelayCodec>")]
+        control: Framed<QuicBiStream, RelayCodec>,
+        /// The pending read of the next datagram.
+        #[debug(skip)]
+        datagram: Option<BoxFuture<Result<Bytes, quinn::ConnectionError>>>,
+        key_cache: KeyCache,
+    }
+
+    impl QuicRelayStream {
+        pub(crate) fn new(
+            conn: quinn::Connection,
+            send: quinn::SendStream,
+            recv: quinn::RecvStream,
+            key_cache: KeyCache,
+        ) -> Self {
+            let control = Framed::new(
+                QuicBiStream { send, recv },
+                RelayCodec::new(key_cache.clone()),
+            );
+            Self {
+                conn,
+                control,
+                datagram: None,
+                key_cache,
+            }
+        }
+
+        fn poll_datagram(&mut self, cx: &mut Context<'_>) -> Poll<Option<anyhow::Result<Frame>>> {
+            let conn = &self.conn;
+            let datagram = self.datagram.get_or_insert_with(|| {
+                let conn = conn.clone();
+                Box::pin(async move { conn.read_datagram().await })
+            });
+            let res = std::task::ready!(datagram.as_mut().poll(cx));
+            self.datagram = None;
+            match res {
+                Ok(bytes) => {
+                    let frame =
+                        Frame::decode_from_ws_msg(bytes, &self.key_cache).and_then(|frame| {
+                            match frame {
+                                Frame::SendPacket { .. } | Frame::RecvPacket { .. } => Ok(frame),
+                                _ => {
+                                    anyhow::bail!("unexpected frame in datagram: {:?}", frame.typ())
+                                }
+                            }
+                        });
+                    Poll::Ready(Some(frame))
+                }
+                Err(
+                    quinn::ConnectionError::ApplicationClosed(_)
+                    | quinn::ConnectionError::LocallyClosed,
+                ) => Poll::Ready(None),
+                Err(err) => Poll::Ready(Some(Err(err.into()))),
+            }
+        }
+    }
+
+    impl Sink<Frame> for QuicRelayStream {
+        type Error = io::Error;
+
+        fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.control).poll_ready(cx)
+        }
+
+        fn start_send(mut self: Pin<&mut Self>, frame: Frame) -> io::Result<()> {
+            let is_packet = matches!(frame, Frame::SendPacket { .. } | Frame::RecvPacket { .. });
+            // The datagram carries the frame type, but no length.
+            let fits_datagram = self
+                .conn
+                .max_datagram_size()
+                .is_some_and(|max| frame.len() < max);
+            if is_packet && fits_datagram {
+                return match self.conn.send_datagram(frame.encode_for_ws_msg().into()) {
+                    Ok(()) => Ok(()),
+                    Err(SendDatagramError::ConnectionLost(err)) => {
+                        Err(io::Error::new(io::ErrorKind::ConnectionReset, err))
+                    }
+                    Err(err) => Err(io::Error::other(err)),
+                };
+            }
+            Pin::new(&mut self.control).start_send(frame)
+        }
+
+        fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.control).poll_flush(cx)
+        }
+
+        fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.control).poll_close(cx)
+        }
+    }
+
+    impl Stream for QuicRelayStream {
+        type Item = anyhow::Result<Frame>;
+
+        fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+            // The control stream carries little traffic, poll it first so a flood of
+            // datagrams can not starve it.
+            if let Poll::Ready(item) = Pin::new(&mut self.control).poll_next(cx) {
+                return Poll::Ready(item);
+            }
+            self.poll_datagram(cx)
+        }
+    }
+
+    /// The control stream of a [`QuicRelayStream`].
+    #[derive(Debug)]
+    struct QuicBiStream {
+        send: quinn::SendStream,
+        recv: quinn::RecvStream,
+    }
+
+    impl AsyncRead for QuicBiStream {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.recv).poll_read(cx, buf)
+        }
+    }
+
+    impl AsyncWrite for QuicBiStream {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            AsyncWrite::poll_write(Pin::new(&mut self.send), cx, buf)
+        }
+
+        fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            AsyncWrite::poll_flush(Pin::new(&mut self.send), cx)
+        }
+
+        fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            AsyncWrite::poll_shutdown(Pin::new(&mut self.send), cx)
+        }
+    }
+}
+
 #[cfg(all(test, feature = "server"))]
 mod tests {
     use std::net::Ipv4Addr;
@@ -304,10 +512,13 @@ mod tests {
         // create a server config with self signed certificates
         let (_, server_config) = super::super::server::testing::self_signed_tls_certs_and_config();
         let bind_addr = SocketAddr::new(host.into(), 0);
-        let quic_server = QuicServer::spawn(QuicConfig {
-            server_config,
-            bind_addr,
-        })?;
+        let quic_server = QuicServer::spawn(
+            QuicConfig {
+                server_config,
+                bind_addr,
+            },
+            None,
+        )?;
 
         // create a client-side endpoint
         let client_endpoint = quinn::Endpoint::client(SocketAddr::new(host.into(), 0))?;
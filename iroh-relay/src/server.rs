@@ -15,8 +15,16 @@
 //! - HTTPS `/ping`: Used for net_report probes.
 //! - HTTPS `/generate_204`: Used for net_report probes.
 //! - STUN: UDP port for STUN requests/responses.
-
-use std::{fmt, future::Future, net::SocketAddr, num::NonZeroU32, pin::Pin, sync::Arc};
+//! - QUIC: UDP port for QUIC address discovery and relaying over QUIC datagrams.
+
+use std::{
+    fmt,
+    future::Future,
+    net::{IpAddr, Ipv6Addr, SocketAddr},
+    num::{NonZeroU32, NonZeroUsize},
+    pin::Pin,
+    sync::Arc,
+};
 
 use anyhow::{anyhow, bail, Context, Result};
 use derive_more::Debug;
@@ -24,10 +32,8 @@ use http::{
     response::Builder as ResponseBuilder, HeaderMap, Method, Request, Response, StatusCode,
 };
 use hyper::body::Incoming;
-use iroh_base::NodeId;
-#[cfg(feature = "test-utils")]
-use iroh_base::RelayUrl;
-use n0_future::{future::Boxed, StreamExt};
+use iroh_base::{NodeId, RelayUrl, SecretKey};
+use n0_future::{future::Boxed, FutureExt, StreamExt};
 use tokio::{
     net::{TcpListener, UdpSocket},
     task::JoinSet,
@@ -36,23 +42,30 @@ use tokio_util::task::AbortOnDropHandle;
 use tracing::{debug, error, info, info_span, instrument, trace, warn, Instrument};
 
 use crate::{
-    defaults::DEFAULT_KEY_CACHE_CAPACITY,
+    defaults::{DEFAULT_HTTP_PORT, DEFAULT_KEY_CACHE_CAPACITY},
     http::RELAY_PROBE_PATH,
     protos,
     quic::server::{QuicServer, ServerHandle as QuicServerHandle},
 };
 
+mod access_cache;
+mod admin;
 mod client;
 mod clients;
 mod http_server;
+mod mesh;
 mod metrics;
+mod quotas;
 pub(crate) mod resolver;
 pub(crate) mod streams;
 #[cfg(feature = "test-utils")]
 pub mod testing;
 
+pub(crate) use self::http_server::RelayService;
 pub use self::{
+    admin::AdminConfig,
     metrics::{Metrics, RelayMetrics, StunMetrics},
+    quotas::{Quota, QuotaConfig, QuotaGroup, QuotaUsage},
     resolver::{ReloadingResolver, DEFAULT_CERT_RELOAD_INTERVAL},
 };
 
@@ -101,6 +114,9 @@ pub struct ServerConfig<EC: fmt::Debug, EA: fmt::Debug = EC> {
 ///
 /// This includes the HTTP services hosted by the Relay server, the Relay `/relay` HTTP
 /// endpoint is only one of the services served.
+///
+/// Prefer constructing this with `..Default::default()` for the fields you do not set, new
+/// optional fields may be added in the future.
 #[derive(Debug)]
 pub struct RelayConfig<EC: fmt::Debug, EA: fmt::Debug = EC> {
     /// The socket address on which the Relay HTTP server should bind.
@@ -122,6 +138,48 @@ pub struct RelayConfig<EC: fmt::Debug, EA: fmt::Debug = EC> {
     pub key_cache_capacity: Option<usize>,
     /// Access configuration.
     pub access: AccessConfig,
+    /// Mesh configuration.
+    ///
+    /// If set, this relay server forwards packets to and from the peer relay servers of
+    /// the mesh.
+    pub mesh: Option<MeshConfig>,
+    /// Per-node quotas.
+    ///
+    /// If `None` only the [`RelayConfig::limits`] apply.
+    pub quotas: Option<QuotaConfig>,
+    /// Configuration for the admin HTTP API, disabled if `None`.
+    pub admin: Option<AdminConfig>,
+}
+
+impl<EC: fmt::Debug, EA: fmt::Debug> Default for RelayConfig<EC, EA> {
+    /// Serves plain HTTP on port `80` of all interfaces, with all optional features disabled.
+    fn default() -> Self {
+        Self {
+            http_bind_addr: (Ipv6Addr::UNSPECIFIED, DEFAULT_HTTP_PORT).into(),
+            tls: None,
+            limits: Default::default(),
+            key_cache_capacity: None,
+            access: AccessConfig::Everyone,
+            mesh: None,
+            quotas: None,
+            admin: None,
+        }
+    }
+}
+
+/// The parts of the [`RelayConfig`] which can be changed while the server is running.
+///
+/// Apply it using [`Server::update_relay_config`].
+#[derive(Debug)]
+pub struct RelayConfigUpdate {
+    /// Rate limits, see [`RelayConfig::limits`].
+    ///
+    /// Only [`Limits::client_rx`] can be changed.
+    pub limits: Limits,
+    /// Key cache capacity, see [`RelayConfig::key_cache_capacity`].
+    pub key_cache_capacity: Option<usize>,
+    /// Access configuration, see [`RelayConfig::access`].
+    pub access: AccessConfig,
 }
 
 /// Controls which nodes are allowed to use the relay.
@@ -129,33 +187,148 @@ pub struct RelayConfig<EC: fmt::Debug, EA: fmt::Debug = EC> {
 pub enum AccessConfig {
     /// Everyone
     Everyone,
-    /// Only nodes for which the function returns `Access::Allow`.
+    /// Only nodes for which the function returns [`Access::Allow`] or
+    /// [`Access::AllowWithLimits`].
     #[debug("restricted")]
-    Restricted(Box<dyn Fn(NodeId) -> Boxed<Access> + Send + Sync + 'static>),
+    Restricted(Box<dyn Fn(AccessRequest) -> Boxed<Access> + Send + Sync + 'static>),
 }
 
 impl AccessConfig {
-    /// Is this node allowed?
-    pub async fn is_allowed(&self, node: NodeId) -> bool {
+    /// Checks the access of a connecting client.
+    pub async fn check(&self, request: AccessRequest) -> Access {
         match self {
-            Self::Everyone => true,
+            Self::Everyone => Access::Allow,
+            Self::Restricted(check) => check(request).await,
+        }
+    }
+
+    /// Is this client allowed?
+    pub async fn is_allowed(&self, request: AccessRequest) -> bool {
+        self.check(request).await.is_allowed()
+    }
+
+    /// Caches the decisions of a [`AccessConfig::Restricted`] check for `ttl`.
+    ///
+    /// Decisions are cached per [`AccessRequest`], for at most `capacity` requests.  This
+    /// avoids running expensive checks, like asking an external authorization service, for
+    /// every connection when clients reconnect.
+    pub fn with_cache(self, ttl: std::time::Duration, capacity: NonZeroUsize) -> Self {
+        match self {
+            Self::Everyone => Self::Everyone,
             Self::Restricted(check) => {
-                let res = check(node).await;
-                matches!(res, Access::Allow)
+                let check = Arc::new(check);
+                let cache = Arc::new(access_cache::AccessCache::new(ttl, capacity));
+                Self::Restricted(Box::new(move |request| {
+                    let check = check.clone();
+                    let cache = cache.clone();
+                    async move {
+                        let decision = check(request.clone());
+                        cache.get_or_check(request, decision).await
+                    }
+                    .boxed()
+                }))
             }
         }
     }
 }
 
+/// A client connecting to the relay, as passed to the [`AccessConfig`].
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct AccessRequest {
+    /// The [`NodeId`] of the client.
+    pub node_id: NodeId,
+    /// The IP address the client connects from.
+    ///
+    /// IPv4-mapped IPv6 addresses are converted to IPv4 addresses.
+    pub remote_ip: Option<IpAddr>,
+    /// The protocol the client relays over.
+    pub protocol: RelayProtocol,
+    /// The server name the client requested using TLS SNI, if connecting using TLS.
+    pub server_name: Option<String>,
+}
+
+/// The protocol a client relays over.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RelayProtocol {
+    /// The custom relaying protocol over an upgraded HTTP connection.
+    Relay,
+    /// Websockets.
+    Websocket,
+    /// QUIC datagrams, see [`QuicConfig`].
+    Quic,
+}
+
+impl From<crate::http::Protocol> for RelayProtocol {
+    fn from(protocol: crate::http::Protocol) -> Self {
+        match protocol {
+            crate::http::Protocol::Relay => Self::Relay,
+            crate::http::Protocol::Websocket => Self::Websocket,
+        }
+    }
+}
+
+impl fmt::Display for RelayProtocol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Relay => f.write_str("relay"),
+            Self::Websocket => f.write_str("websocket"),
+            Self::Quic => f.write_str("quic"),
+        }
+    }
+}
+
+/// Configuration for meshing relay servers.
+///
+/// Relay servers in a mesh announce which nodes are connected to them and forward packets
+/// for these nodes to each other.  A node can then reach any other node connected to a relay
+/// server in the mesh, no matter which relay server it is connected to itself.
+///
+/// The mesh must be configured on every relay server in the mesh, each listing all other
+/// relay servers as peers.
+#[derive(Debug, Clone)]
+pub struct MeshConfig {
+    /// The secret key this relay server uses to connect to its mesh peers.
+    ///
+    /// The [`NodeId`] of this key must be configured as [`MeshPeer::node_id`] on the peers.
+    pub secret_key: SecretKey,
+    /// The other relay servers in the mesh.
+    pub peers: Vec<MeshPeer>,
+}
+
+/// A peer relay server in a mesh.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MeshPeer {
+    /// The URL of the peer relay server.
+    pub url: RelayUrl,
+    /// The [`NodeId`] the peer relay server uses to connect to this server.
+    ///
+    /// Connections using this [`NodeId`] are trusted to watch the connected nodes and to
+    /// forward packets, they bypass the [`AccessConfig`].
+    pub node_id: NodeId,
+}
+
 /// Access restriction for a node.
-#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Access {
     /// Access is allowed.
     Allow,
+    /// Access is allowed, with the given quota for the node.
+    ///
+    /// The node is accounted to its own account with this quota, instead of the account
+    /// it has according to the [`RelayConfig::quotas`].
+    AllowWithLimits(Quota),
     /// Access is denied.
     Deny,
 }
 
+impl Access {
+    /// Whether access is allowed.
+    pub fn is_allowed(&self) -> bool {
+        matches!(self, Self::Allow | Self::AllowWithLimits(_))
+    }
+}
+
 /// Configuration for the STUN server.
 #[derive(Debug)]
 pub struct StunConfig {
@@ -213,7 +386,7 @@ pub struct Limits {
 }
 
 /// Per-client rate limit configuration.
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub struct ClientRateLimit {
     /// Max number of bytes per second to read from the client connection.
     pub bytes_per_second: NonZeroU32,
@@ -221,6 +394,17 @@ pub struct ClientRateLimit {
     pub max_burst_bytes: Option<NonZeroU32>,
 }
 
+impl ClientRateLimit {
+    /// Creates a rate-limiter enforcing this limit.
+    pub(crate) fn limiter(&self) -> governor::DefaultDirectRateLimiter {
+        let mut quota = governor::Quota::per_second(self.bytes_per_second);
+        if let Some(max_burst) = self.max_burst_bytes {
+            quota = quota.allow_burst(max_burst);
+        }
+        governor::RateLimiter::direct(quota)
+    }
+}
+
 /// TLS certificate configuration.
 #[derive(derive_more::Debug)]
 pub enum CertConfig<EC: fmt::Debug, EA: fmt::Debug = EC> {
@@ -257,8 +441,12 @@ pub struct Server {
     https_addr: Option<SocketAddr>,
     /// The address of the QUIC server, if configured.
     quic_addr: Option<SocketAddr>,
+    /// The address of the admin API, if configured.
+    admin_addr: Option<SocketAddr>,
     /// Handle to the relay server.
     relay_handle: Option<http_server::ServerHandle>,
+    /// The relay service, used to update its configuration.
+    relay_service: Option<RelayService>,
     /// Handle to the quic server.
     quic_handle: Option<QuicServerHandle>,
     /// The main task running the server.
@@ -274,6 +462,20 @@ pub struct Server {
 impl Server {
     /// Starts the server.
     pub async fn spawn<EC, EA>(config: ServerConfig<EC, EA>) -> Result<Self>
+    where
+        EC: fmt::Debug + 'static,
+        EA: fmt::Debug + 'static,
+    {
+        Self::spawn_with_listener(config, None).await
+    }
+
+    /// Starts the server, serving the relay on `relay_listener` if given.
+    ///
+    /// The listener replaces binding the relay's HTTP(S) socket address from the config.
+    async fn spawn_with_listener<EC, EA>(
+        config: ServerConfig<EC, EA>,
+        relay_listener: Option<std::net::TcpListener>,
+    ) -> Result<Self>
     where
         EC: fmt::Debug + 'static,
         EA: fmt::Debug + 'static,
@@ -325,17 +527,7 @@ impl Server {
             })
         });
 
-        let quic_server = match config.quic {
-            Some(quic_config) => {
-                debug!("Starting QUIC server {}", quic_config.bind_addr);
-                Some(QuicServer::spawn(quic_config)?)
-            }
-            None => None,
-        };
-        let quic_addr = quic_server.as_ref().map(|srv| srv.bind_addr());
-        let quic_handle = quic_server.as_ref().map(|srv| srv.handle());
-
-        let (relay_server, http_addr) = match config.relay {
+        let (relay_server, http_addr, admin_addr) = match config.relay {
             Some(relay_config) => {
                 debug!("Starting Relay server");
                 let mut headers = HeaderMap::new();
@@ -349,11 +541,21 @@ impl Server {
                 let key_cache_capacity = relay_config
                     .key_cache_capacity
                     .unwrap_or(DEFAULT_KEY_CACHE_CAPACITY);
+                let mesh = relay_config.mesh.map(|mesh_config| {
+                    debug!("Starting mesh with {} peers", mesh_config.peers.len());
+                    mesh::Mesh::spawn(mesh_config, metrics.server.clone(), &mut tasks)
+                });
+                let quotas = relay_config
+                    .quotas
+                    .map(|quotas| quotas::Quotas::new(quotas, metrics.server.clone()));
                 let mut builder = http_server::ServerBuilder::new(relay_bind_addr)
+                    .listener(relay_listener)
                     .metrics(metrics.server.clone())
                     .headers(headers)
                     .key_cache_capacity(key_cache_capacity)
                     .access(relay_config.access)
+                    .mesh(mesh)
+                    .quotas(quotas)
                     .request_handler(Method::GET, "/", Box::new(root_handler))
                     .request_handler(Method::GET, "/index.html", Box::new(root_handler))
                     .request_handler(Method::GET, RELAY_PROBE_PATH, Box::new(probe_handler))
@@ -421,14 +623,46 @@ impl Server {
                     }
                 };
                 let relay_server = builder.spawn().await?;
-                (Some(relay_server), http_addr)
+                let admin_addr = match relay_config.admin {
+                    Some(admin) => {
+                        let listener = TcpListener::bind(&admin.bind_addr)
+                            .await
+                            .context("failed to bind admin api")?;
+                        let admin_addr = listener.local_addr()?;
+                        info!("admin API listening on {admin_addr}");
+                        tasks.spawn(
+                            admin::run_admin_service(
+                                listener,
+                                admin.bearer_token,
+                                relay_server.relay_service(),
+                            )
+                            .instrument(info_span!("admin-service", addr = %admin_addr)),
+                        );
+                        Some(admin_addr)
+                    }
+                    None => None,
+                };
+                (Some(relay_server), http_addr, admin_addr)
+            }
+            None => (None, None, None),
+        };
+        // The QUIC server relays over QUIC using the Relay server, so start it afterwards.
+        let quic_server = match config.quic {
+            Some(quic_config) => {
+                debug!("Starting QUIC server {}", quic_config.bind_addr);
+                let relay_service = relay_server.as_ref().map(|srv| srv.relay_service());
+                Some(QuicServer::spawn(quic_config, relay_service)?)
             }
-            None => (None, None),
+            None => None,
         };
+        let quic_addr = quic_server.as_ref().map(|srv| srv.bind_addr());
+        let quic_handle = quic_server.as_ref().map(|srv| srv.handle());
+
         // If http_addr is Some then relay_server is serving HTTPS.  If http_addr is None
         // relay_server is serving HTTP, including the /generate_204 service.
         let relay_addr = relay_server.as_ref().map(|srv| srv.addr());
         let relay_handle = relay_server.as_ref().map(|srv| srv.handle());
+        let relay_service = relay_server.as_ref().map(|srv| srv.relay_service());
         let task = tokio::spawn(relay_supervisor(tasks, relay_server, quic_server));
 
         Ok(Self {
@@ -436,7 +670,9 @@ impl Server {
             stun_addr,
             https_addr: http_addr.and(relay_addr),
             quic_addr,
+            admin_addr,
             relay_handle,
+            relay_service,
             quic_handle,
             supervisor: AbortOnDropHandle::new(task),
             certificates,
@@ -459,6 +695,22 @@ impl Server {
         self.supervisor.await?
     }
 
+    /// Applies a new configuration to the running relay server.
+    ///
+    /// New connections use the new configuration immediately.  The rate limit also applies
+    /// to connected clients, connected clients which are denied by the new access
+    /// configuration are disconnected.
+    ///
+    /// Errors if the relay server is not running.
+    pub async fn update_relay_config(&self, update: RelayConfigUpdate) -> Result<()> {
+        let service = self
+            .relay_service
+            .as_ref()
+            .context("relay server not running")?;
+        service.update_config(update).await;
+        Ok(())
+    }
+
     /// Returns the handle for the task.
     ///
     /// This allows waiting for the server's supervisor task to finish.  Can be useful in
@@ -487,6 +739,11 @@ impl Server {
         self.stun_addr
     }
 
+    /// The socket address the admin API is listening on.
+    pub fn admin_addr(&self) -> Option<SocketAddr> {
+        self.admin_addr
+    }
+
     /// The certificates chain if configured with manual TLS certificates.
     pub fn certificates(&self) -> Option<Vec<rustls::pki_types::CertificateDer<'static>>> {
         self.certificates.clone()
@@ -840,6 +1097,7 @@ mod tests {
                 limits: Default::default(),
                 key_cache_capacity: Some(1024),
                 access: AccessConfig::Everyone,
+                ..Default::default()
             }),
             quic: None,
             stun: None,
@@ -895,6 +1153,7 @@ mod tests {
                 limits: Default::default(),
                 key_cache_capacity: Some(1024),
                 access: AccessConfig::Everyone,
+                ..Default::default()
             }),
             stun: None,
             quic: None,
@@ -1130,6 +1389,88 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    #[traced_test]
+    async fn test_relay_clients_quic_and_fallback() -> TestResult<()> {
+        let (_, server_config) = testing::self_signed_tls_certs_and_config();
+        let server = Server::spawn(ServerConfig::<(), ()> {
+            relay: Some(RelayConfig::<(), ()> {
+                http_bind_addr: (Ipv4Addr::LOCALHOST, 0).into(),
+                tls: None,
+                limits: Default::default(),
+                key_cache_capacity: Some(1024),
+                access: AccessConfig::Everyone,
+                ..Default::default()
+            }),
+            quic: Some(QuicConfig {
+                bind_addr: (Ipv4Addr::LOCALHOST, 0).into(),
+                server_config,
+            }),
+            stun: None,
+            metrics_addr: None,
+        })
+        .await?;
+        let quic_port = server.quic_addr().unwrap().port();
+
+        let relay_url = format!("http://{}", server.http_addr().unwrap());
+        let relay_url: RelayUrl = relay_url.parse().unwrap();
+
+        // set up client a, relaying over QUIC
+        let a_secret_key = SecretKey::generate(rand::thread_rng());
+        let a_key = a_secret_key.public();
+        let a_endpoint = quinn::Endpoint::client((Ipv4Addr::LOCALHOST, 0).into())?;
+        let mut client_a = ClientBuilder::new(relay_url.clone(), a_secret_key, dns_resolver())
+            .quic_relay(a_endpoint.clone(), quic_port)
+            .insecure_skip_cert_verify(true)
+            .connect()
+            .await?;
+
+        // set up client b, the QUIC port does not answer so it falls back to TCP
+        let b_secret_key = SecretKey::generate(rand::thread_rng());
+        let b_key = b_secret_key.public();
+        let b_endpoint = quinn::Endpoint::client((Ipv4Addr::LOCALHOST, 0).into())?;
+        let silent_socket = tokio::net::UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+        let mut client_b = ClientBuilder::new(relay_url.clone(), b_secret_key, dns_resolver())
+            .quic_relay(b_endpoint, silent_socket.local_addr()?.port())
+            .insecure_skip_cert_verify(true)
+            .connect()
+            .await?;
+
+        // send message from a to b
+        let msg = Bytes::from("hello, b");
+        let res = try_send_recv(&mut client_a, &mut client_b, b_key, msg.clone()).await?;
+        if let ReceivedMessage::ReceivedPacket {
+            remote_node_id,
+            data,
+        } = res
+        {
+            assert_eq!(a_key, remote_node_id);
+            assert_eq!(msg, data);
+        } else {
+            panic!("client_b received unexpected message {res:?}");
+        }
+
+        // Both connections were accepted by the server once a packet made it across, the
+        // clients themselves do not wait for this.
+        assert_eq!(server.metrics().server.quic_relay_accepts.get(), 1);
+        assert_eq!(server.metrics().server.relay_accepts.get(), 1);
+
+        // send message from b to a, larger than fits in a datagram
+        let msg = Bytes::from(vec![42u8; 4096]);
+        let res = try_send_recv(&mut client_b, &mut client_a, a_key, msg.clone()).await?;
+        if let ReceivedMessage::ReceivedPacket {
+            remote_node_id,
+            data,
+        } = res
+        {
+            assert_eq!(b_key, remote_node_id);
+            assert_eq!(msg, data);
+        } else {
+            panic!("client_a received unexpected message {res:?}");
+        }
+        Ok(())
+    }
+
     #[tokio::test]
     #[traced_test]
     async fn test_stun() {
@@ -1174,11 +1515,14 @@ mod tests {
                 tls: None,
                 limits: Default::default(),
                 key_cache_capacity: Some(1024),
-                access: AccessConfig::Restricted(Box::new(move |node_id| {
+                access: AccessConfig::Restricted(Box::new(move |request| {
                     async move {
-                        info!("checking {}", node_id);
+                        info!("checking {:?}", request);
+                        assert_eq!(request.protocol, RelayProtocol::Relay);
+                        assert_eq!(request.remote_ip, Some(Ipv4Addr::LOCALHOST.into()));
+                        assert_eq!(request.server_name, None);
                         // reject node a
-                        if node_id == a_key {
+                        if request.node_id == a_key {
                             Access::Deny
                         } else {
                             Access::Allow
@@ -1186,6 +1530,7 @@ mod tests {
                     }
                     .boxed()
                 })),
+                ..Default::default()
             }),
             quic: None,
             stun: None,
@@ -1285,4 +1630,310 @@ mod tests {
         }
         Ok(())
     }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_relay_mesh() -> TestResult<()> {
+        // The servers need to know each other's URL before they start, so bind the
+        // listeners up front and hand them to the servers.
+        let mut listeners = vec![
+            std::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0))?,
+            std::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0))?,
+        ];
+        let addrs = listeners
+            .iter()
+            .map(|listener| listener.local_addr())
+            .collect::<Result<Vec<_>, _>>()?;
+        let urls: Vec<RelayUrl> = addrs
+            .iter()
+            .map(|addr| format!("http://{addr}").parse())
+            .collect::<Result<_, _>>()?;
+        let mesh_keys = [
+            SecretKey::generate(rand::thread_rng()),
+            SecretKey::generate(rand::thread_rng()),
+        ];
+        let a_secret_key = SecretKey::generate(rand::thread_rng());
+        let a_key = a_secret_key.public();
+        let b_secret_key = SecretKey::generate(rand::thread_rng());
+        let b_key = b_secret_key.public();
+
+        let mut servers = Vec::new();
+        for (i, listener) in listeners.drain(..).enumerate() {
+            let peer = 1 - i;
+            let server = Server::spawn_with_listener(
+                ServerConfig::<(), ()> {
+                    relay: Some(RelayConfig::<(), ()> {
+                        http_bind_addr: addrs[i],
+                        tls: None,
+                        limits: Default::default(),
+                        key_cache_capacity: Some(1024),
+                        // The mesh peers are allowed regardless of the access config.
+                        access: AccessConfig::Restricted(Box::new(move |request| {
+                            async move {
+                                if request.node_id == a_key || request.node_id == b_key {
+                                    Access::Allow
+                                } else {
+                                    Access::Deny
+                                }
+                            }
+                            .boxed()
+                        })),
+                        mesh: Some(MeshConfig {
+                            secret_key: mesh_keys[i].clone(),
+                            peers: vec![MeshPeer {
+                                url: urls[peer].clone(),
+                                node_id: mesh_keys[peer].public(),
+                            }],
+                        }),
+                        ..Default::default()
+                    }),
+                    quic: None,
+                    stun: None,
+                    metrics_addr: None,
+                },
+                Some(listener),
+            )
+            .await?;
+            servers.push(server);
+        }
+
+        // client a on the first server, client b on the second server
+        let resolver = dns_resolver();
+        let mut client_a = ClientBuilder::new(urls[0].clone(), a_secret_key, resolver.clone())
+            .connect()
+            .await?;
+        let mut client_b = ClientBuilder::new(urls[1].clone(), b_secret_key, resolver.clone())
+            .connect()
+            .await?;
+
+        // send message from a to b, via the mesh
+        let msg = Bytes::from("hello, b");
+        let res = try_send_recv(&mut client_a, &mut client_b, b_key, msg.clone()).await?;
+        let ReceivedMessage::ReceivedPacket {
+            remote_node_id,
+            data,
+        } = res
+        else {
+            panic!("client_b received unexpected message {res:?}");
+        };
+        assert_eq!(a_key, remote_node_id);
+        assert_eq!(msg, data);
+
+        // send message from b to a, via the mesh
+        let msg = Bytes::from("howdy, a");
+        let res = try_send_recv(&mut client_b, &mut client_a, a_key, msg.clone()).await?;
+        let ReceivedMessage::ReceivedPacket {
+            remote_node_id,
+            data,
+        } = res
+        else {
+            panic!("client_a received unexpected message {res:?}");
+        };
+        assert_eq!(b_key, remote_node_id);
+        assert_eq!(msg, data);
+
+        let metrics = servers[0].metrics();
+        assert!(metrics.server.mesh_packets_forwarded.get() > 0);
+        assert!(metrics.server.mesh_packets_recv.get() > 0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_relay_update_config() -> TestResult<()> {
+        let server = spawn_local_relay().await?;
+        let relay_url = format!("http://{}", server.http_addr().unwrap());
+        let relay_url: RelayUrl = relay_url.parse()?;
+        let resolver = dns_resolver();
+
+        let a_secret_key = SecretKey::generate(rand::thread_rng());
+        let a_key = a_secret_key.public();
+        let mut client_a =
+            ClientBuilder::new(relay_url.clone(), a_secret_key.clone(), resolver.clone())
+                .connect()
+                .await?;
+        let b_secret_key = SecretKey::generate(rand::thread_rng());
+        let b_key = b_secret_key.public();
+        let mut client_b = ClientBuilder::new(relay_url.clone(), b_secret_key, resolver.clone())
+            .connect()
+            .await?;
+        let msg = Bytes::from("hello, b");
+        try_send_recv(&mut client_a, &mut client_b, b_key, msg).await?;
+
+        // deny node a, which should only disconnect client a
+        server
+            .update_relay_config(RelayConfigUpdate {
+                limits: Default::default(),
+                key_cache_capacity: Some(16),
+                access: AccessConfig::Restricted(Box::new(move |request| {
+                    async move {
+                        if request.node_id == a_key {
+                            Access::Deny
+                        } else {
+                            Access::Allow
+                        }
+                    }
+                    .boxed()
+                })),
+            })
+            .await?;
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while let Some(Ok(_)) = client_a.next().await {}
+        })
+        .await?;
+
+        // node a can no longer connect
+        let mut client_a = ClientBuilder::new(relay_url.clone(), a_secret_key, resolver.clone())
+            .connect()
+            .await?;
+        let msg = tokio::time::timeout(Duration::from_millis(500), client_a.next()).await?;
+        assert!(matches!(msg, Some(Ok(ReceivedMessage::Health { .. }))));
+
+        // client b is still connected and new clients are allowed
+        let c_secret_key = SecretKey::generate(rand::thread_rng());
+        let c_key = c_secret_key.public();
+        let mut client_c = ClientBuilder::new(relay_url.clone(), c_secret_key, resolver)
+            .connect()
+            .await?;
+        let msg = Bytes::from("hello, c");
+        let res = try_send_recv(&mut client_b, &mut client_c, c_key, msg.clone()).await?;
+        let ReceivedMessage::ReceivedPacket {
+            remote_node_id,
+            data,
+        } = res
+        else {
+            panic!("client_c received unexpected message {res:?}");
+        };
+        assert_eq!(b_key, remote_node_id);
+        assert_eq!(msg, data);
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_relay_admin_api() -> TestResult<()> {
+        let server = Server::spawn(ServerConfig::<(), ()> {
+            relay: Some(RelayConfig::<(), ()> {
+                http_bind_addr: (Ipv4Addr::LOCALHOST, 0).into(),
+                tls: None,
+                limits: Default::default(),
+                key_cache_capacity: Some(1024),
+                access: AccessConfig::Everyone,
+                mesh: None,
+                quotas: None,
+                admin: Some(AdminConfig {
+                    bind_addr: (Ipv4Addr::LOCALHOST, 0).into(),
+                    bearer_token: "secret".to_string(),
+                }),
+            }),
+            quic: None,
+            stun: None,
+            metrics_addr: None,
+        })
+        .await?;
+        let relay_url: RelayUrl = format!("http://{}", server.http_addr().unwrap()).parse()?;
+        let admin_url = format!("http://{}", server.admin_addr().unwrap());
+        let resolver = dns_resolver();
+        let http = reqwest::Client::new();
+
+        let a_secret_key = SecretKey::generate(rand::thread_rng());
+        let a_key = a_secret_key.public();
+        let mut client_a =
+            ClientBuilder::new(relay_url.clone(), a_secret_key.clone(), resolver.clone())
+                .connect()
+                .await?;
+        let b_secret_key = SecretKey::generate(rand::thread_rng());
+        let b_key = b_secret_key.public();
+        let mut client_b = ClientBuilder::new(relay_url.clone(), b_secret_key, resolver.clone())
+            .connect()
+            .await?;
+        let msg = Bytes::from("hello, b");
+        try_send_recv(&mut client_a, &mut client_b, b_key, msg.clone()).await?;
+
+        // requests must be authenticated
+        let res = http.get(format!("{admin_url}/clients")).send().await?;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+
+        let res = http
+            .get(format!("{admin_url}/clients"))
+            .bearer_auth("secret")
+            .send()
+            .await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        let clients: Vec<serde_json::Value> = serde_json::from_str(&res.text().await?)?;
+        assert_eq!(clients.len(), 2);
+        let client = clients
+            .iter()
+            .find(|client| client["node_id"] == a_key.to_string())
+            .expect("client a listed");
+        assert_eq!(client["protocol"], "relay");
+        assert_eq!(client["remote_ip"], "127.0.0.1");
+        // the message may have been resent
+        assert!(client["bytes_recv"].as_u64().unwrap() >= msg.len() as u64);
+
+        // a ban which would end too far in the future is rejected
+        let res = http
+            .put(format!(
+                "{admin_url}/bans/{a_key}?duration_secs={}",
+                u64::MAX
+            ))
+            .bearer_auth("secret")
+            .send()
+            .await?;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+
+        // ban node a, which disconnects it
+        let res = http
+            .put(format!("{admin_url}/bans/{a_key}?duration_secs=60"))
+            .bearer_auth("secret")
+            .send()
+            .await?;
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while let Some(Ok(_)) = client_a.next().await {}
+        })
+        .await?;
+
+        // node a can not reconnect while banned
+        let mut client_a = ClientBuilder::new(relay_url.clone(), a_secret_key, resolver.clone())
+            .connect()
+            .await?;
+        let msg = tokio::time::timeout(Duration::from_millis(500), client_a.next()).await?;
+        assert!(matches!(msg, Some(Ok(ReceivedMessage::Health { .. }))));
+
+        let res = http
+            .get(format!("{admin_url}/bans"))
+            .bearer_auth("secret")
+            .send()
+            .await?;
+        let bans: Vec<serde_json::Value> = serde_json::from_str(&res.text().await?)?;
+        assert_eq!(bans.len(), 1);
+        assert_eq!(bans[0]["node_id"], a_key.to_string());
+
+        let res = http
+            .delete(format!("{admin_url}/bans/{a_key}"))
+            .bearer_auth("secret")
+            .send()
+            .await?;
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+
+        // disconnect node b, it is not banned
+        let res = http
+            .post(format!("{admin_url}/clients/{b_key}/disconnect"))
+            .bearer_auth("secret")
+            .send()
+            .await?;
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while let Some(Ok(_)) = client_b.next().await {}
+        })
+        .await?;
+        let res = http
+            .post(format!("{admin_url}/clients/{b_key}/disconnect"))
+            .bearer_auth("secret")
+            .send()
+            .await?;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        Ok(())
+    }
 }
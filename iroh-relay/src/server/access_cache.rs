@@ -0,0 +1,119 @@
+//! Caching of access decisions, see [`AccessConfig::with_cache`].
+//!
+//! [`AccessConfig::with_cache`]: crate::server::AccessConfig::with_cache
+
+use std::{
+    future::Future,
+    num::NonZeroUsize,
+    sync::{Arc, Mutex},
+};
+
+use lru::LruCache;
+use n0_future::time::{Duration, Instant};
+use tokio::sync::OnceCell;
+
+use crate::server::{Access, AccessRequest};
+
+/// A TTL cache of access decisions.
+///
+/// Concurrent checks of the same request share a single decision, so a reconnect storm
+/// results in a single check per TTL.
+#[derive(derive_more::Debug)]
+pub(super) struct AccessCache {
+    ttl: Duration,
+    #[debug("{}", entries.lock().map(|e| e.len()).unwrap_or_default())]
+    entries: Mutex<LruCache<AccessRequest, Entry>>,
+}
+
+#[derive(Debug)]
+struct Entry {
+    created: Instant,
+    decision: Arc<OnceCell<Access>>,
+}
+
+impl AccessCache {
+    pub(super) fn new(ttl: Duration, capacity: NonZeroUsize) -> Self {
+        Self {
+            ttl,
+            entries: Mutex::new(LruCache::new(capacity)),
+        }
+    }
+
+    /// Returns the cached decision for the request, running `check` if there is none.
+    pub(super) async fn get_or_check<F>(&self, request: AccessRequest, check: F) -> Access
+    where
+        F: Future<Output = Access>,
+    {
+        let decision = {
+            let mut entries = self.entries.lock().expect("poisoned");
+            match entries.get(&request) {
+                Some(entry) if entry.created.elapsed() < self.ttl => entry.decision.clone(),
+                _ => {
+                    let decision = Arc::new(OnceCell::new());
+                    entries.put(
+                        request,
+                        Entry {
+                            created: Instant::now(),
+                            decision: decision.clone(),
+                        },
+                    );
+                    decision
+                }
+            }
+        };
+        // If the check is cancelled the cell stays empty and the next caller checks.
+        decision.get_or_init(|| check).await.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use iroh_base::SecretKey;
+
+    use super::*;
+    use crate::server::RelayProtocol;
+
+    fn request(node_id: iroh_base::NodeId, remote_ip: &str) -> AccessRequest {
+        AccessRequest {
+            node_id,
+            remote_ip: Some(remote_ip.parse().unwrap()),
+            protocol: RelayProtocol::Relay,
+            server_name: None,
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_access_cache() {
+        let cache = AccessCache::new(Duration::from_secs(10), NonZeroUsize::new(8).unwrap());
+        let node_id = SecretKey::generate(rand::thread_rng()).public();
+        let checks = AtomicUsize::new(0);
+        let check = || async {
+            checks.fetch_add(1, Ordering::Relaxed);
+            Access::Allow
+        };
+
+        let res = cache
+            .get_or_check(request(node_id, "1.1.1.1"), check())
+            .await;
+        assert_eq!(res, Access::Allow);
+        cache
+            .get_or_check(request(node_id, "1.1.1.1"), check())
+            .await;
+        assert_eq!(checks.load(Ordering::Relaxed), 1);
+
+        // A different remote IP is a different request.
+        cache
+            .get_or_check(request(node_id, "2.2.2.2"), check())
+            .await;
+        assert_eq!(checks.load(Ordering::Relaxed), 2);
+
+        // The decision expires.
+        tokio::time::advance(Duration::from_secs(11)).await;
+        cache
+            .get_or_check(request(node_id, "1.1.1.1"), check())
+            .await;
+        assert_eq!(checks.load(Ordering::Relaxed), 3);
+    }
+}
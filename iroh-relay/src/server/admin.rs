@@ -0,0 +1,257 @@
+//! The admin HTTP API of the relay server.
+//!
+//! This is served on its own socket, see [`AdminConfig`].  Every request must be
+//! authenticated with an `Authorization: Bearer {token}` header.  The API serves:
+//!
+//! - `GET /clients`: The connected clients, as JSON.
+//! - `POST /clients/{node_id}/disconnect`: Disconnects the client, it may reconnect.
+//! - `GET /bans`: The banned nodes with the seconds until their ban expires, as JSON.
+//! - `PUT /bans/{node_id}?duration_secs={secs}`: Bans the node and disconnects it.  The ban
+//!   lasts for one hour if no duration is given.
+//! - `DELETE /bans/{node_id}`: Lifts the ban of the node.
+//! - `GET /usage/{node_id}`: The [`QuotaUsage`] of the account of the node, as JSON.
+//!
+//! [`QuotaUsage`]: super::QuotaUsage
+
+use std::{future::Future, net::SocketAddr, pin::Pin, sync::Arc, time::Duration};
+
+use anyhow::Result;
+use http::{header::AUTHORIZATION, Method, Request, Response, StatusCode};
+use hyper::body::Incoming;
+use iroh_base::NodeId;
+use subtle::ConstantTimeEq;
+use tokio::{net::TcpListener, task::JoinSet};
+use tracing::{debug, error, info};
+
+use super::{body_empty, BytesBody, HyperError, RelayService, NOTFOUND};
+
+/// How long a node is banned if the request does not specify a duration.
+const DEFAULT_BAN_DURATION: Duration = Duration::from_secs(60 * 60);
+
+/// Configuration for the admin HTTP API.
+#[derive(derive_more::Debug, Clone)]
+pub struct AdminConfig {
+    /// The socket address to serve the admin API on.
+    ///
+    /// This should not be reachable from the public internet.
+    pub bind_addr: SocketAddr,
+    /// The bearer token required to use the admin API.
+    #[debug("[REDACTED]")]
+    pub bearer_token: String,
+}
+
+/// Runs the admin HTTP API.
+///
+/// When the future is dropped, the server stops.
+pub(super) async fn run_admin_service(
+    listener: TcpListener,
+    bearer_token: String,
+    relay: RelayService,
+) -> Result<()> {
+    info!("serving");
+    let service = AdminService(Arc::new(Inner {
+        bearer_token,
+        relay,
+    }));
+
+    // If this future is cancelled, this is dropped and all tasks are aborted.
+    let mut tasks = JoinSet::new();
+
+    loop {
+        tokio::select! {
+            biased;
+
+            Some(res) = tasks.join_next() => {
+                if let Err(err) = res {
+                    if err.is_panic() {
+                        panic!("task panicked: {:#?}", err);
+                    }
+                }
+            }
+
+            res = listener.accept() => {
+                match res {
+                    Ok((stream, peer_addr)) => {
+                        debug!(%peer_addr, "Connection opened",);
+                        let service = service.clone();
+                        tasks.spawn(async move {
+                            let stream = hyper_util::rt::TokioIo::new(stream);
+                            if let Err(err) = hyper::server::conn::http1::Builder::new()
+                                .serve_connection(stream, service)
+                                .await
+                            {
+                                debug!("Failed to serve connection: {err:?}");
+                            }
+                        });
+                    }
+                    Err(err) => {
+                        error!("[AdminService] failed to accept connection: {:#?}", err);
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct AdminService(Arc<Inner>);
+
+#[derive(derive_more::Debug)]
+struct Inner {
+    #[debug("[REDACTED]")]
+    bearer_token: String,
+    relay: RelayService,
+}
+
+impl AdminService {
+    fn is_authorized(&self, req: &Request<Incoming>) -> bool {
+        // Compare in constant time to not leak the token through response timings.
+        req.headers()
+            .get(AUTHORIZATION)
+            .and_then(|value| value.as_bytes().strip_prefix(b"Bearer "))
+            .is_some_and(|token| bool::from(token.ct_eq(self.0.bearer_token.as_bytes())))
+    }
+
+    fn handle(&self, req: Request<Incoming>) -> Result<Response<BytesBody>, http::Error> {
+        if !self.is_authorized(&req) {
+            return Response::builder()
+                .status(StatusCode::UNAUTHORIZED)
+                .body(b"Unauthorized".as_slice().into());
+        }
+        let segments: Vec<_> = req.uri().path().trim_matches('/').split('/').collect();
+        let relay = &self.0.relay;
+        match (req.method(), segments.as_slice()) {
+            (&Method::GET, ["clients"]) => json_response(&relay.clients()),
+            (&Method::POST, ["clients", node_id, "disconnect"]) => {
+                let Some(node_id) = parse_node_id(node_id) else {
+                    return bad_request("Invalid node id");
+                };
+                match relay.disconnect(&node_id) {
+                    true => {
+                        info!(node_id = node_id.fmt_short(), "disconnected");
+                        no_content()
+                    }
+                    false => not_found(),
+                }
+            }
+            (&Method::GET, ["bans"]) => {
+                let bans: Vec<_> = relay
+                    .bans()
+                    .into_iter()
+                    .map(|(node_id, remaining)| Ban {
+                        node_id,
+                        expires_in_secs: remaining.as_secs(),
+                    })
+                    .collect();
+                json_response(&bans)
+            }
+            (&Method::PUT, ["bans", node_id]) => {
+                let Some(node_id) = parse_node_id(node_id) else {
+                    return bad_request("Invalid node id");
+                };
+                let duration = match query_param(req.uri().query(), "duration_secs") {
+                    None => DEFAULT_BAN_DURATION,
+                    Some(secs) => match secs.parse() {
+                        Ok(secs) => Duration::from_secs(secs),
+                        Err(_) => return bad_request("Invalid duration_secs"),
+                    },
+                };
+                let Ok(was_connected) = relay.ban(node_id, duration) else {
+                    return bad_request("duration_secs too large");
+                };
+                info!(
+                    node_id = node_id.fmt_short(),
+                    ?duration,
+                    was_connected,
+                    "banned"
+                );
+                no_content()
+            }
+            (&Method::DELETE, ["bans", node_id]) => {
+                let Some(node_id) = parse_node_id(node_id) else {
+                    return bad_request("Invalid node id");
+                };
+                match relay.unban(&node_id) {
+                    true => {
+                        info!(node_id = node_id.fmt_short(), "ban lifted");
+                        no_content()
+                    }
+                    false => not_found(),
+                }
+            }
+            (&Method::GET, ["usage", node_id]) => {
+                let Some(node_id) = parse_node_id(node_id) else {
+                    return bad_request("Invalid node id");
+                };
+                match relay.quota_usage(node_id) {
+                    Some(usage) => json_response(&usage),
+                    None => not_found(),
+                }
+            }
+            _ => not_found(),
+        }
+    }
+}
+
+impl hyper::service::Service<Request<Incoming>> for AdminService {
+    type Response = Response<BytesBody>;
+    type Error = HyperError;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn call(&self, req: Request<Incoming>) -> Self::Future {
+        let res = self.handle(req).map_err(|err| Box::new(err) as HyperError);
+        Box::pin(async move { res })
+    }
+}
+
+/// A banned node, as reported by `GET /bans`.
+#[derive(Debug, serde::Serialize)]
+struct Ban {
+    node_id: NodeId,
+    expires_in_secs: u64,
+}
+
+fn parse_node_id(s: &str) -> Option<NodeId> {
+    s.parse().ok()
+}
+
+/// Returns the value of the query parameter `name`.
+fn query_param<'a>(query: Option<&'a str>, name: &str) -> Option<&'a str> {
+    query?
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find_map(|(key, value)| (key == name).then_some(value))
+}
+
+fn json_response(value: &impl serde::Serialize) -> Result<Response<BytesBody>, http::Error> {
+    match serde_json::to_vec(value) {
+        Ok(body) => Response::builder()
+            .status(StatusCode::OK)
+            .header(http::header::CONTENT_TYPE, "application/json")
+            .body(body.into()),
+        Err(err) => {
+            error!("failed to serialize response: {err:#}");
+            Response::builder()
+                .status(StatusCode::INTERNAL_SERVER_ERROR)
+                .body(b"Internal Server Error".as_slice().into())
+        }
+    }
+}
+
+fn no_content() -> Result<Response<BytesBody>, http::Error> {
+    Response::builder()
+        .status(StatusCode::NO_CONTENT)
+        .body(body_empty())
+}
+
+fn bad_request(msg: &'static str) -> Result<Response<BytesBody>, http::Error> {
+    Response::builder()
+        .status(StatusCode::BAD_REQUEST)
+        .body(msg.as_bytes().into())
+}
+
+fn not_found() -> Result<Response<BytesBody>, http::Error> {
+    Response::builder()
+        .status(StatusCode::NOT_FOUND)
+        .body(NOTFOUND.into())
+}
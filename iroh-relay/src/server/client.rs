@@ -1,8 +1,17 @@
 //! The server-side representation of an ongoing client relaying connection.
 
 use std::{
-    collections::HashSet, future::Future, num::NonZeroU32, pin::Pin, sync::Arc, task::Poll,
-    time::Duration,
+    collections::HashSet,
+    future::Future,
+    net::IpAddr,
+    num::NonZeroU32,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    task::Poll,
+    time::{Duration, SystemTime},
 };
 
 use anyhow::{bail, Context, Result};
@@ -23,7 +32,13 @@ use crate::{
         disco,
         relay::{write_frame, Frame, PING_INTERVAL},
     },
-    server::{clients::Clients, metrics::Metrics, streams::RelayedStream, ClientRateLimit},
+    server::{
+        clients::Clients,
+        metrics::Metrics,
+        quotas::{QuotaLimiter, QuotaPermit},
+        streams::RelayedStream,
+        AccessRequest, ClientRateLimit, RelayProtocol,
+    },
     PingTracker,
 };
 
@@ -44,6 +59,10 @@ pub(super) struct Config {
     pub(super) write_timeout: Duration,
     pub(super) channel_capacity: usize,
     pub(super) rate_limit: Option<ClientRateLimit>,
+    /// The quota this connection is accounted to, if any.
+    pub(super) quota: Option<QuotaPermit>,
+    /// The request the client was granted access with.
+    pub(super) access: AccessRequest,
 }
 
 /// The [`Server`] side representation of a [`Client`]'s connection.
@@ -66,6 +85,45 @@ pub(super) struct Client {
     disco_send_queue: mpsc::Sender<Packet>,
     /// Channel to notify the client that a previous sender has disconnected.
     peer_gone: mpsc::Sender<NodeId>,
+    /// Channel to notify a watching mesh peer that a node has connected.
+    peer_present: mpsc::Sender<NodeId>,
+    /// The request the client was granted access with.
+    access: AccessRequest,
+    /// Statistics of the connection, updated by the actor.
+    stats: Arc<ConnStats>,
+}
+
+/// Statistics of a client connection.
+#[derive(Debug)]
+struct ConnStats {
+    connected_at: SystemTime,
+    bytes_recv: AtomicU64,
+    bytes_sent: AtomicU64,
+}
+
+impl ConnStats {
+    fn new() -> Self {
+        Self {
+            connected_at: SystemTime::now(),
+            bytes_recv: AtomicU64::new(0),
+            bytes_sent: AtomicU64::new(0),
+        }
+    }
+}
+
+/// Information about a connected client, as reported by the admin API.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
+pub(super) struct ClientInfo {
+    pub(super) node_id: NodeId,
+    pub(super) protocol: RelayProtocol,
+    pub(super) remote_ip: Option<IpAddr>,
+    pub(super) server_name: Option<String>,
+    /// When the client connected, in seconds since the UNIX epoch.
+    pub(super) connected_at: u64,
+    /// Bytes relayed from the client.
+    pub(super) bytes_recv: u64,
+    /// Bytes relayed to the client.
+    pub(super) bytes_sent: u64,
 }
 
 impl Client {
@@ -84,25 +142,23 @@ impl Client {
             write_timeout,
             channel_capacity,
             rate_limit,
+            quota,
+            access,
         } = config;
 
-        let stream = match rate_limit {
-            Some(cfg) => {
-                let mut quota = governor::Quota::per_second(cfg.bytes_per_second);
-                if let Some(max_burst) = cfg.max_burst_bytes {
-                    quota = quota.allow_burst(max_burst);
-                }
-                let limiter = governor::RateLimiter::direct(quota);
-                RateLimitedRelayedStream::new(io, limiter, metrics.clone())
-            }
+        let mut stream = match rate_limit {
+            Some(cfg) => RateLimitedRelayedStream::new(io, cfg.limiter(), metrics.clone()),
             None => RateLimitedRelayedStream::unlimited(io, metrics.clone()),
         };
+        stream.quota_limiter = quota.as_ref().map(|quota| quota.limiter_handle());
 
         let done = CancellationToken::new();
         let (send_queue_s, send_queue_r) = mpsc::channel(channel_capacity);
 
         let (disco_send_queue_s, disco_send_queue_r) = mpsc::channel(channel_capacity);
         let (peer_gone_s, peer_gone_r) = mpsc::channel(channel_capacity);
+        let (peer_present_s, peer_present_r) = mpsc::channel(channel_capacity);
+        let stats = Arc::new(ConnStats::new());
 
         let actor = Actor {
             stream,
@@ -110,6 +166,9 @@ impl Client {
             send_queue: send_queue_r,
             disco_send_queue: disco_send_queue_r,
             node_gone: peer_gone_r,
+            node_present: peer_present_r,
+            quota,
+            stats: stats.clone(),
             node_id,
             connection_id,
             clients: clients.clone(),
@@ -134,6 +193,9 @@ impl Client {
             send_queue: send_queue_s,
             disco_send_queue: disco_send_queue_s,
             peer_gone: peer_gone_s,
+            peer_present: peer_present_s,
+            access,
+            stats,
         }
     }
 
@@ -141,6 +203,28 @@ impl Client {
         self.connection_id
     }
 
+    /// The request the client was granted access with.
+    pub(super) fn access_request(&self) -> &AccessRequest {
+        &self.access
+    }
+
+    pub(super) fn info(&self) -> ClientInfo {
+        ClientInfo {
+            node_id: self.node_id,
+            protocol: self.access.protocol,
+            remote_ip: self.access.remote_ip,
+            server_name: self.access.server_name.clone(),
+            connected_at: self
+                .stats
+                .connected_at
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or_default(),
+            bytes_recv: self.stats.bytes_recv.load(Ordering::Relaxed),
+            bytes_sent: self.stats.bytes_sent.load(Ordering::Relaxed),
+        }
+    }
+
     /// Shutdown the reader and writer loops and closes the connection.
     ///
     /// Any shutdown errors will be logged as warnings.
@@ -178,6 +262,10 @@ impl Client {
     pub(super) fn try_send_peer_gone(&self, key: NodeId) -> Result<(), TrySendError<NodeId>> {
         self.peer_gone.try_send(key)
     }
+
+    pub(super) fn try_send_peer_present(&self, key: NodeId) -> Result<(), TrySendError<NodeId>> {
+        self.peer_present.try_send(key)
+    }
 }
 
 /// Manages all the reads and writes to this client. It periodically sends a `KEEP_ALIVE`
@@ -209,6 +297,12 @@ struct Actor {
     disco_send_queue: mpsc::Receiver<Packet>,
     /// Notify the client that a previous sender has disconnected
     node_gone: mpsc::Receiver<NodeId>,
+    /// Notify a watching mesh peer that a node has connected
+    node_present: mpsc::Receiver<NodeId>,
+    /// The quota of this client, released when the actor is dropped
+    quota: Option<QuotaPermit>,
+    /// Statistics of this connection
+    stats: Arc<ConnStats>,
     /// [`NodeId`] of this client
     node_id: NodeId,
     /// Connection identifier.
@@ -255,6 +349,8 @@ impl Actor {
         ping_interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
         ping_interval.tick().await;
 
+        let mut rate_limit = self.clients.rate_limit_updates();
+
         loop {
             tokio::select! {
                 biased;
@@ -286,6 +382,16 @@ impl Actor {
                     trace!("node_id gone: {:?}", node_id);
                     self.write_frame(Frame::NodeGone { node_id }).await?;
                 }
+                node_id = self.node_present.recv() => {
+                    let node_id = node_id.context("Server.node_present dropped")?;
+                    trace!("node_id present: {:?}", node_id);
+                    self.write_frame(Frame::PeerPresent { node_id }).await?;
+                }
+                Ok(()) = rate_limit.changed() => {
+                    let limit = *rate_limit.borrow_and_update();
+                    trace!(?limit, "rate limit changed");
+                    self.stream.set_limiter(limit.map(|cfg| cfg.limiter()));
+                }
                 _ = self.ping_tracker.timeout() => {
                     trace!("pong timed out");
                     break;
@@ -321,6 +427,7 @@ impl Actor {
 
         if let Ok(len) = content.len().try_into() {
             self.metrics.bytes_sent.inc_by(len);
+            self.stats.bytes_sent.fetch_add(len, Ordering::Relaxed);
         }
         self.write_frame(Frame::RecvPacket { src_key, content })
             .await
@@ -365,12 +472,24 @@ impl Actor {
         match frame {
             Frame::SendPacket { dst_key, packet } => {
                 let packet_len = packet.len();
+                if let Some(ref quota) = self.quota {
+                    if !quota.record_recv(packet_len) {
+                        trace!(
+                            dst = dst_key.fmt_short(),
+                            "daily quota exceeded, dropping packet"
+                        );
+                        return Ok(());
+                    }
+                }
                 if let Err(err @ ForwardPacketError { .. }) =
                     self.handle_frame_send_packet(dst_key, packet)
                 {
                     warn!("failed to handle send packet frame: {err:#}");
                 }
                 self.metrics.bytes_recv.inc_by(packet_len as u64);
+                self.stats
+                    .bytes_recv
+                    .fetch_add(packet_len as u64, Ordering::Relaxed);
             }
             Frame::Ping { data } => {
                 self.metrics.got_ping.inc();
@@ -384,6 +503,23 @@ impl Actor {
             Frame::Health { problem } => {
                 bail!("server issue: {:?}", problem);
             }
+            Frame::WatchConns if self.clients.is_mesh_peer(&self.node_id) => {
+                for node_id in self.clients.watch(self.node_id, self.connection_id) {
+                    self.write_frame(Frame::PeerPresent { node_id }).await?;
+                }
+            }
+            Frame::ForwardPacket {
+                src_key,
+                dst_key,
+                packet,
+            } if self.clients.is_mesh_peer(&self.node_id) => {
+                self.metrics.mesh_packets_recv.inc();
+                if let Err(err @ ForwardPacketError { .. }) =
+                    self.handle_frame_forward_packet(src_key, dst_key, packet)
+                {
+                    warn!("failed to handle forward packet frame: {err:#}");
+                }
+            }
             _ => {
                 self.metrics.unknown_frames.inc();
             }
@@ -392,17 +528,42 @@ impl Actor {
     }
 
     fn handle_frame_send_packet(&self, dst: NodeId, data: Bytes) -> Result<(), ForwardPacketError> {
-        if disco::looks_like_disco_wrapper(&data) {
+        let is_disco = disco::looks_like_disco_wrapper(&data);
+        if is_disco {
             self.metrics.disco_packets_recv.inc();
+        } else {
+            self.metrics.send_packets_recv.inc();
+        }
+        if self.clients.forward_to_mesh(self.node_id, dst, &data) {
+            return Ok(());
+        }
+        if is_disco {
             self.clients
                 .send_disco_packet(dst, data, self.node_id, &self.metrics)?;
         } else {
-            self.metrics.send_packets_recv.inc();
             self.clients
                 .send_packet(dst, data, self.node_id, &self.metrics)?;
         }
         Ok(())
     }
+
+    /// Delivers a packet forwarded by a mesh peer to the local client.
+    ///
+    /// Forwarded packets are never forwarded again, which avoids loops in the mesh.
+    fn handle_frame_forward_packet(
+        &self,
+        src: NodeId,
+        dst: NodeId,
+        data: Bytes,
+    ) -> Result<(), ForwardPacketError> {
+        if disco::looks_like_disco_wrapper(&data) {
+            self.clients
+                .send_disco_packet(dst, data, src, &self.metrics)?;
+        } else {
+            self.clients.send_packet(dst, data, src, &self.metrics)?;
+        }
+        Ok(())
+    }
 }
 
 #[derive(Debug)]
@@ -440,6 +601,8 @@ impl ForwardPacketError {
 struct RateLimitedRelayedStream {
     inner: RelayedStream,
     limiter: Option<Arc<governor::DefaultDirectRateLimiter>>,
+    /// The rate-limiter of the quota, shared with other connections.
+    quota_limiter: Option<QuotaLimiter>,
     state: State,
     /// Keeps track if this stream was ever rate-limited.
     limited_once: bool,
@@ -467,6 +630,7 @@ impl RateLimitedRelayedStream {
         Self {
             inner,
             limiter: Some(Arc::new(limiter)),
+            quota_limiter: None,
             state: State::Ready,
             limited_once: false,
             metrics,
@@ -477,6 +641,7 @@ impl RateLimitedRelayedStream {
         Self {
             inner,
             limiter: None,
+            quota_limiter: None,
             state: State::Ready,
             limited_once: false,
             metrics,
@@ -485,6 +650,13 @@ impl RateLimitedRelayedStream {
 }
 
 impl RateLimitedRelayedStream {
+    /// Replaces the rate-limiter, `None` disables rate-limiting.
+    ///
+    /// A frame which is currently blocked still waits for the previous rate-limiter.
+    fn set_limiter(&mut self, limiter: Option<governor::DefaultDirectRateLimiter>) {
+        self.limiter = limiter.map(Arc::new);
+    }
+
     /// Records metrics about being rate-limited.
     fn record_rate_limited(&mut self) {
         // TODO: add a label for the frame type.
@@ -504,11 +676,14 @@ impl Stream for RateLimitedRelayedStream {
         mut self: Pin<&mut Self>,
         cx: &mut std::task::Context<'_>,
     ) -> Poll<Option<Self::Item>> {
-        let Some(ref limiter) = self.limiter else {
+        if self.limiter.is_none() && self.quota_limiter.is_none() {
             // If there is no rate-limiter directly poll the inner.
             return Pin::new(&mut self.inner).poll_next(cx);
-        };
-        let limiter = limiter.clone();
+        }
+        let limiters = [
+            self.limiter.clone(),
+            self.quota_limiter.as_ref().and_then(|quota| quota.get()),
+        ];
         loop {
             match &mut self.state {
                 State::Ready => {
@@ -527,30 +702,34 @@ impl Stream for RateLimitedRelayedStream {
                                         return Poll::Ready(Some(item));
                                     };
 
-                                    match limiter.check_n(frame_len) {
-                                        Ok(Ok(_)) => return Poll::Ready(Some(item)),
-                                        Ok(Err(_)) => {
-                                            // Item is rate-limited.
-                                            self.record_rate_limited();
-                                            let delay = Box::pin({
-                                                let limiter = limiter.clone();
-                                                async move {
-                                                    limiter.until_n_ready(frame_len).await.ok();
-                                                }
-                                            });
-                                            self.state = State::Blocked { delay, item };
-                                            continue;
-                                        }
-                                        Err(_insufficient_capacity) => {
-                                            error!(
-                                                "frame larger than bucket capacity: \
-                                                 configuration error: \
-                                                 max_burst_bytes < MAX_FRAME_SIZE?"
-                                            );
-                                            // Let this frame through so to not completely break.
-                                            return Poll::Ready(Some(item));
+                                    let mut blocked = Vec::new();
+                                    for limiter in limiters.iter().flatten() {
+                                        match limiter.check_n(frame_len) {
+                                            Ok(Ok(_)) => {}
+                                            Ok(Err(_)) => blocked.push(limiter.clone()),
+                                            Err(_insufficient_capacity) => {
+                                                error!(
+                                                    "frame larger than bucket capacity: \
+                                                     configuration error: \
+                                                     max_burst_bytes < MAX_FRAME_SIZE?"
+                                                );
+                                                // Let this frame through so to not completely
+                                                // break.
+                                            }
                                         }
                                     }
+                                    if blocked.is_empty() {
+                                        return Poll::Ready(Some(item));
+                                    }
+                                    // Item is rate-limited.
+                                    self.record_rate_limited();
+                                    let delay = Box::pin(async move {
+                                        for limiter in blocked {
+                                            limiter.until_n_ready(frame_len).await.ok();
+                                        }
+                                    });
+                                    self.state = State::Blocked { delay, item };
+                                    continue;
                                 }
                                 Err(_) => {
                                     // Yielding errors is not rate-limited.
@@ -664,6 +843,7 @@ mod tests {
         let (send_queue_s, send_queue_r) = mpsc::channel(10);
         let (disco_send_queue_s, disco_send_queue_r) = mpsc::channel(10);
         let (peer_gone_s, peer_gone_r) = mpsc::channel(10);
+        let (_peer_present_s, peer_present_r) = mpsc::channel(10);
 
         let node_id = SecretKey::generate(rand::thread_rng()).public();
         let (io, io_rw) = tokio::io::duplex(1024);
@@ -679,6 +859,9 @@ mod tests {
             send_queue: send_queue_r,
             disco_send_queue: disco_send_queue_r,
             node_gone: peer_gone_r,
+            node_present: peer_present_r,
+            quota: None,
+            stats: Arc::new(ConnStats::new()),
             connection_id: 0,
             node_id,
             clients: clients.clone(),
@@ -13,13 +13,15 @@ use anyhow::Result;
 use bytes::Bytes;
 use dashmap::DashMap;
 use iroh_base::NodeId;
-use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::{mpsc::error::TrySendError, watch};
 use tracing::{debug, trace};
 
-use super::client::{Client, Config, ForwardPacketError};
+use super::client::{Client, ClientInfo, Config, ForwardPacketError};
 use crate::server::{
     client::{PacketScope, SendError},
+    mesh::Mesh,
     metrics::Metrics,
+    AccessRequest, ClientRateLimit,
 };
 
 /// Manages the connections to all currently connected clients.
@@ -34,9 +36,79 @@ struct Inner {
     sent_to: DashMap<NodeId, HashSet<NodeId>>,
     /// Connection ID Counter
     next_connection_id: AtomicU64,
+    /// The mesh of peer relay servers, if any.
+    mesh: Option<Mesh>,
+    /// Mesh peers watching the connected clients, with their connection ID.
+    watchers: DashMap<NodeId, u64>,
+    /// Rate limit for incoming traffic of each client.
+    rate_limit: watch::Sender<Option<ClientRateLimit>>,
 }
 
 impl Clients {
+    pub(super) fn new(mesh: Option<Mesh>, rate_limit: Option<ClientRateLimit>) -> Self {
+        Self(Arc::new(Inner {
+            mesh,
+            rate_limit: watch::Sender::new(rate_limit),
+            ..Default::default()
+        }))
+    }
+
+    /// Returns the current rate limit for incoming traffic of each client.
+    pub(super) fn rate_limit(&self) -> Option<ClientRateLimit> {
+        *self.0.rate_limit.borrow()
+    }
+
+    /// Changes the rate limit of all current and future clients.
+    pub(super) fn set_rate_limit(&self, rate_limit: Option<ClientRateLimit>) {
+        self.0.rate_limit.send_replace(rate_limit);
+    }
+
+    /// Subscribes to changes of the rate limit.
+    pub(super) fn rate_limit_updates(&self) -> watch::Receiver<Option<ClientRateLimit>> {
+        self.0.rate_limit.subscribe()
+    }
+
+    /// Returns the [`NodeId`]s of all connected clients, excluding mesh peers.
+    pub(super) fn node_ids(&self) -> Vec<NodeId> {
+        self.0
+            .clients
+            .iter()
+            .map(|client| *client.key())
+            .filter(|node_id| !self.is_mesh_peer(node_id))
+            .collect()
+    }
+
+    /// Returns information about all connected clients, excluding mesh peers.
+    pub(super) fn infos(&self) -> Vec<ClientInfo> {
+        self.0
+            .clients
+            .iter()
+            .filter(|client| !self.is_mesh_peer(client.key()))
+            .map(|client| client.info())
+            .collect()
+    }
+
+    /// Returns the [`AccessRequest`]s of all connected clients, excluding mesh peers.
+    pub(super) fn access_requests(&self) -> Vec<AccessRequest> {
+        self.0
+            .clients
+            .iter()
+            .filter(|client| !self.is_mesh_peer(client.key()))
+            .map(|client| client.access_request().clone())
+            .collect()
+    }
+
+    /// Disconnects the client, returns `false` if it is not connected.
+    pub(super) fn disconnect(&self, node_id: &NodeId) -> bool {
+        match self.0.clients.get(node_id) {
+            Some(client) => {
+                client.start_shutdown();
+                true
+            }
+            None => false,
+        }
+    }
+
     pub async fn shutdown(&self) {
         let keys: Vec<_> = self.0.clients.iter().map(|x| *x.key()).collect();
         trace!("shutting down {} clients", keys.len());
@@ -53,7 +125,11 @@ impl Clients {
         trace!(remote_node = node_id.fmt_short(), "registering client");
 
         let client = Client::new(client_config, connection_id, self, metrics);
-        if let Some(old_client) = self.0.clients.insert(node_id, client) {
+        let old_client = self.0.clients.insert(node_id, client);
+        if !self.is_mesh_peer(&node_id) {
+            self.notify_watchers(node_id, Client::try_send_peer_present);
+        }
+        if let Some(old_client) = old_client {
             debug!(
                 remote_node = node_id.fmt_short(),
                 "multiple connections found, pruning old connection",
@@ -62,6 +138,59 @@ impl Clients {
         }
     }
 
+    /// Whether the node is a trusted peer relay server of the mesh.
+    pub(super) fn is_mesh_peer(&self, node_id: &NodeId) -> bool {
+        self.0
+            .mesh
+            .as_ref()
+            .is_some_and(|mesh| mesh.is_peer(node_id))
+    }
+
+    /// Adds a mesh peer to the watchers of the connected clients.
+    ///
+    /// Returns the currently connected clients, the watcher is notified about any clients
+    /// connecting or disconnecting from now on.
+    pub(super) fn watch(&self, node_id: NodeId, connection_id: u64) -> Vec<NodeId> {
+        trace!(node_id = node_id.fmt_short(), "mesh peer watching clients");
+        self.0.watchers.insert(node_id, connection_id);
+        self.node_ids()
+    }
+
+    /// Notifies all watching mesh peers about the node.
+    ///
+    /// A watcher which can not keep up is disconnected, it will resync when it reconnects.
+    fn notify_watchers(
+        &self,
+        node_id: NodeId,
+        notify: fn(&Client, NodeId) -> Result<(), TrySendError<NodeId>>,
+    ) {
+        for watcher in self.0.watchers.iter() {
+            let Some(client) = self.0.clients.get(watcher.key()) else {
+                continue;
+            };
+            if let Err(err) = notify(&client, node_id) {
+                debug!(
+                    watcher = watcher.key().fmt_short(),
+                    "mesh peer can not keep up with connection changes: {err}, disconnecting"
+                );
+                client.start_shutdown();
+            }
+        }
+    }
+
+    /// Forwards a packet to the mesh peer the `dst` node is connected to.
+    ///
+    /// Returns `false` if the node is connected to this server or not known in the mesh.
+    pub(super) fn forward_to_mesh(&self, src: NodeId, dst: NodeId, data: &Bytes) -> bool {
+        let Some(ref mesh) = self.0.mesh else {
+            return false;
+        };
+        if self.0.clients.contains_key(&dst) {
+            return false;
+        }
+        mesh.forward(src, dst, data.clone())
+    }
+
     fn get_connection_id(&self) -> u64 {
         self.0.next_connection_id.fetch_add(1, Ordering::Relaxed)
     }
@@ -78,11 +207,17 @@ impl Clients {
             "unregistering client"
         );
 
+        self.0
+            .watchers
+            .remove_if(&node_id, |_, id| *id == connection_id);
         if let Some((_, client)) = self
             .0
             .clients
             .remove_if(&node_id, |_, c| c.connection_id() == connection_id)
         {
+            if !self.is_mesh_peer(&node_id) {
+                self.notify_watchers(node_id, Client::try_send_peer_gone);
+            }
             if let Some((_, sent_to)) = self.0.sent_to.remove(&node_id) {
                 for key in sent_to {
                     match client.try_send_peer_gone(key) {
@@ -201,7 +336,10 @@ mod tests {
     use super::*;
     use crate::{
         protos::relay::{recv_frame, Frame, FrameType, RelayCodec},
-        server::streams::{MaybeTlsStream, RelayedStream},
+        server::{
+            streams::{MaybeTlsStream, RelayedStream},
+            RelayProtocol,
+        },
     };
 
     fn test_client_builder(key: NodeId) -> (Config, FramedRead<DuplexStream, RelayCodec>) {
@@ -216,6 +354,13 @@ mod tests {
                 write_timeout: Duration::from_secs(1),
                 channel_capacity: 10,
                 rate_limit: None,
+                quota: None,
+                access: AccessRequest {
+                    node_id: key,
+                    remote_ip: None,
+                    protocol: RelayProtocol::Relay,
+                    server_name: None,
+                },
             },
             FramedRead::new(test_io, RelayCodec::test()),
         )
@@ -255,6 +400,14 @@ mod tests {
             }
         );
 
+        // both packets are accounted to client a
+        let infos = clients.infos();
+        assert_eq!(infos.len(), 1);
+        assert_eq!(infos[0].node_id, a_key);
+        assert_eq!(infos[0].protocol, RelayProtocol::Relay);
+        assert_eq!(infos[0].bytes_sent, 2 * data.len() as u64);
+        assert_eq!(infos[0].bytes_recv, 0);
+
         {
             let client = clients.0.clients.get(&a_key).unwrap();
             // shutdown client a, this should trigger the removal from the clients list
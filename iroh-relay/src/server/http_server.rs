@@ -1,9 +1,15 @@
 use std::{
-    collections::HashMap, future::Future, net::SocketAddr, pin::Pin, sync::Arc, time::Duration,
+    collections::HashMap,
+    future::Future,
+    net::SocketAddr,
+    pin::Pin,
+    sync::{Arc, RwLock},
+    time::Duration,
 };
 
 use anyhow::{bail, ensure, Context as _, Result};
 use bytes::Bytes;
+use dashmap::DashMap;
 use derive_more::Debug;
 use http::{header::CONNECTION, response::Builder as ResponseBuilder};
 use hyper::{
@@ -13,19 +19,27 @@ use hyper::{
     upgrade::Upgraded,
     HeaderMap, Method, Request, Response, StatusCode,
 };
-use n0_future::{FutureExt, SinkExt};
+use iroh_base::NodeId;
+use n0_future::{time::Instant, BufferedStreamExt, FutureExt, SinkExt, StreamExt};
 use tokio::net::{TcpListener, TcpStream};
 use tokio_rustls_acme::AcmeAcceptor;
 use tokio_util::{codec::Framed, sync::CancellationToken, task::AbortOnDropHandle};
 use tracing::{debug, debug_span, error, info, info_span, trace, warn, Instrument};
 
-use super::{clients::Clients, AccessConfig};
+use super::{
+    client::ClientInfo,
+    clients::Clients,
+    mesh::Mesh,
+    quotas::{QuotaUsage, Quotas},
+    Access, AccessConfig, AccessRequest, RelayConfigUpdate, RelayProtocol,
+};
 use crate::{
     defaults::{timeouts::SERVER_WRITE_TIMEOUT, DEFAULT_KEY_CACHE_CAPACITY},
     http::{Protocol, LEGACY_RELAY_PATH, RELAY_PATH, SUPPORTED_WEBSOCKET_VERSION},
     protos::relay::{
         recv_client_key, Frame, RelayCodec, PER_CLIENT_SEND_QUEUE_DEPTH, PROTOCOL_VERSION,
     },
+    quic::QuicRelayStream,
     server::{
         client::Config,
         metrics::Metrics,
@@ -45,6 +59,12 @@ type HyperHandler = Box<
         + 'static,
 >;
 
+/// How long a client relaying over QUIC has to open the control stream.
+const QUIC_CONTROL_STREAM_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How many access re-checks run concurrently when the access config is updated.
+const ACCESS_RECHECK_CONCURRENCY: usize = 32;
+
 /// WebSocket GUID needed for accepting websocket connections, see RFC 6455 (https://www.rfc-editor.org/rfc/rfc6455) section 1.3
 const SEC_WEBSOCKET_ACCEPT_GUID: &[u8] = b"258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
 
@@ -90,6 +110,7 @@ pub(super) struct Server {
     addr: SocketAddr,
     http_server_task: AbortOnDropHandle<()>,
     cancel_server_loop: CancellationToken,
+    service: RelayService,
 }
 
 impl Server {
@@ -121,6 +142,11 @@ impl Server {
     pub(super) fn addr(&self) -> SocketAddr {
         self.addr
     }
+
+    /// Returns the service accepting relay connections.
+    pub(super) fn relay_service(&self) -> RelayService {
+        self.service.clone()
+    }
 }
 
 /// A handle for the [`Server`].
@@ -155,6 +181,8 @@ pub(super) struct TlsConfig {
 pub(super) struct ServerBuilder {
     /// The ip + port combination for this server.
     addr: SocketAddr,
+    /// An already bound listener to serve on instead of binding `addr`.
+    listener: Option<std::net::TcpListener>,
     /// Optional tls configuration/TlsAcceptor combination.
     ///
     /// When `None`, the server will serve HTTP, otherwise it will serve HTTPS.
@@ -175,6 +203,10 @@ pub(super) struct ServerBuilder {
     key_cache_capacity: usize,
     /// Access config for nodes.
     access: AccessConfig,
+    /// The mesh of peer relay servers, if any.
+    mesh: Option<Mesh>,
+    /// Per-node quotas, if any.
+    quotas: Option<Quotas>,
     metrics: Option<Arc<Metrics>>,
 }
 
@@ -183,16 +215,25 @@ impl ServerBuilder {
     pub(super) fn new(addr: SocketAddr) -> Self {
         Self {
             addr,
+            listener: None,
             tls_config: None,
             handlers: Default::default(),
             headers: HeaderMap::new(),
             client_rx_ratelimit: None,
             key_cache_capacity: DEFAULT_KEY_CACHE_CAPACITY,
             access: AccessConfig::Everyone,
+            mesh: None,
+            quotas: None,
             metrics: None,
         }
     }
 
+    /// Serves on an already bound listener instead of binding the address.
+    pub(super) fn listener(mut self, listener: Option<std::net::TcpListener>) -> Self {
+        self.listener = listener;
+        self
+    }
+
     /// Sets the metrics collector.
     pub(super) fn metrics(mut self, metrics: Arc<Metrics>) -> Self {
         self.metrics = Some(metrics);
@@ -205,6 +246,18 @@ impl ServerBuilder {
         self
     }
 
+    /// Sets the mesh of peer relay servers.
+    pub(super) fn mesh(mut self, mesh: Option<Mesh>) -> Self {
+        self.mesh = mesh;
+        self
+    }
+
+    /// Sets the per-node quotas.
+    pub(super) fn quotas(mut self, quotas: Option<Quotas>) -> Self {
+        self.quotas = quotas;
+        self
+    }
+
     /// Serves all requests content using TLS.
     pub(super) fn tls_config(mut self, config: Option<TlsConfig>) -> Self {
         self.tls_config = config;
@@ -255,6 +308,8 @@ impl ServerBuilder {
             self.client_rx_ratelimit,
             KeyCache::new(self.key_cache_capacity),
             self.access,
+            self.mesh,
+            self.quotas,
             self.metrics.unwrap_or_default(),
         );
 
@@ -263,15 +318,22 @@ impl ServerBuilder {
 
         // Bind a TCP listener on `addr` and handles content using HTTPS.
 
-        let listener = TcpListener::bind(&addr)
-            .await
-            .with_context(|| format!("failed to bind server socket to {addr}"))?;
+        let listener = match self.listener {
+            Some(listener) => {
+                listener.set_nonblocking(true)?;
+                TcpListener::from_std(listener)?
+            }
+            None => TcpListener::bind(&addr)
+                .await
+                .with_context(|| format!("failed to bind server socket to {addr}"))?,
+        };
 
         let addr = listener.local_addr()?;
         let http_str = tls_config.as_ref().map_or("HTTP/WS", |_| "HTTPS/WSS");
         info!("[{http_str}] relay: serving on {addr}");
 
         let cancel = cancel_token.clone();
+        let relay_service = service.clone();
         let task = tokio::task::spawn(
             async move {
                 // create a join set to track all our connection tasks
@@ -318,13 +380,16 @@ impl ServerBuilder {
             addr,
             http_server_task: AbortOnDropHandle::new(task),
             cancel_server_loop: cancel_token,
+            service: relay_service,
         })
     }
 }
 
 /// The hyper Service that serves the actual relay endpoints.
+///
+/// The QUIC server also hands connections relaying over QUIC to this service.
 #[derive(Clone, Debug)]
-struct RelayService(Arc<Inner>);
+pub(crate) struct RelayService(Arc<Inner>);
 
 #[derive(Debug)]
 struct Inner {
@@ -332,12 +397,24 @@ struct Inner {
     headers: HeaderMap,
     clients: Clients,
     write_timeout: Duration,
-    rate_limit: Option<ClientRateLimit>,
-    key_cache: KeyCache,
-    access: AccessConfig,
+    /// Replaced by [`RelayService::update_config`].
+    key_cache: RwLock<KeyCache>,
+    /// Replaced by [`RelayService::update_config`].
+    access: RwLock<Arc<AccessConfig>>,
+    quotas: Quotas,
+    /// Banned nodes, with the time their ban expires.
+    bans: DashMap<NodeId, Instant>,
     metrics: Arc<Metrics>,
 }
 
+/// What is known about a relay connection before the client's [`NodeId`] is received.
+#[derive(Debug)]
+struct ConnInfo {
+    remote_ip: Option<std::net::IpAddr>,
+    protocol: RelayProtocol,
+    server_name: Option<String>,
+}
+
 impl RelayService {
     /// Upgrades the HTTP connection to the relay protocol, runs relay client.
     fn call_client_conn(
@@ -524,10 +601,15 @@ impl Inner {
     /// [`AsyncWrite`]: tokio::io::AsyncWrite
     async fn accept(&self, protocol: Protocol, io: MaybeTlsStream) -> Result<()> {
         trace!(?protocol, "accept: start");
-        let mut io = match protocol {
+        let conn_info = ConnInfo {
+            remote_ip: io.remote_ip(),
+            protocol: protocol.into(),
+            server_name: io.server_name(),
+        };
+        let io = match protocol {
             Protocol::Relay => {
                 self.metrics.relay_accepts.inc();
-                RelayedStream::Relay(Framed::new(io, RelayCodec::new(self.key_cache.clone())))
+                RelayedStream::Relay(Framed::new(io, RelayCodec::new(self.key_cache())))
             }
             Protocol::Websocket => {
                 self.metrics.websocket_accepts.inc();
@@ -537,24 +619,93 @@ impl Inner {
                 let builder = tokio_websockets::ServerBuilder::new();
                 // Serve will create a WebSocketStream on an already upgraded connection
                 let websocket = builder.serve(io);
-                RelayedStream::Ws(websocket, self.key_cache.clone())
+                RelayedStream::Ws(websocket, self.key_cache())
             }
         };
+        self.accept_relayed(io, conn_info).await
+    }
+
+    /// Adds a new connection relaying over QUIC to the server and serves it.
+    ///
+    /// The client opens the control stream of the [`QuicRelayStream`], after that this
+    /// behaves like [`Inner::accept`].
+    async fn accept_quic(&self, conn: quinn::Connection) -> Result<()> {
+        trace!("accept: start quic");
+        self.metrics.quic_relay_accepts.inc();
+        let conn_info = ConnInfo {
+            remote_ip: Some(conn.remote_address().ip().to_canonical()),
+            protocol: RelayProtocol::Quic,
+            server_name: conn
+                .handshake_data()
+                .and_then(|data| data.downcast::<quinn::crypto::rustls::HandshakeData>().ok())
+                .and_then(|data| data.server_name),
+        };
+        let (send, recv) = tokio::time::timeout(QUIC_CONTROL_STREAM_TIMEOUT, conn.accept_bi())
+            .await
+            .context("control stream timeout")?
+            .context("unable to accept control stream")?;
+        let io = RelayedStream::Quic(QuicRelayStream::new(conn, send, recv, self.key_cache()));
+        self.accept_relayed(io, conn_info).await
+    }
+
+    fn key_cache(&self) -> KeyCache {
+        self.key_cache.read().expect("poisoned").clone()
+    }
+
+    fn access(&self) -> Arc<AccessConfig> {
+        self.access.read().expect("poisoned").clone()
+    }
+
+    /// Whether the node is banned, removes the ban if it expired.
+    fn is_banned(&self, node_id: &NodeId) -> bool {
+        let now = Instant::now();
+        self.bans.remove_if(node_id, |_, expires| *expires <= now);
+        self.bans.contains_key(node_id)
+    }
+
+    /// Performs the relay handshake on the stream and registers the client.
+    async fn accept_relayed(&self, mut io: RelayedStream, conn_info: ConnInfo) -> Result<()> {
         trace!("accept: recv client key");
         let (client_key, info) = recv_client_key(&mut io)
             .await
             .context("unable to receive client information")?;
+        let request = AccessRequest {
+            node_id: client_key,
+            remote_ip: conn_info.remote_ip,
+            protocol: conn_info.protocol,
+            server_name: conn_info.server_name,
+        };
 
-        trace!("accept: checking access: {:?}", self.access);
-        if !self.access.is_allowed(client_key).await {
+        // Mesh peers are trusted, they forward packets on behalf of their own clients.
+        let is_mesh_peer = self.clients.is_mesh_peer(&client_key);
+        if !is_mesh_peer && self.is_banned(&client_key) {
+            self.metrics.banned_conns_rejected.inc();
             io.send(Frame::Health {
-                problem: Bytes::from_static(b"not authenticated"),
+                problem: Bytes::from_static(b"banned"),
             })
             .await?;
             io.flush().await?;
 
-            bail!("client is not authenticated: {}", client_key);
+            bail!("client is banned: {}", client_key);
         }
+        let access = self.access();
+        trace!("accept: checking access: {:?}", access);
+        let limits = match is_mesh_peer {
+            true => None,
+            false => match access.check(request.clone()).await {
+                Access::Allow => None,
+                Access::AllowWithLimits(limits) => Some(limits),
+                Access::Deny => {
+                    io.send(Frame::Health {
+                        problem: Bytes::from_static(b"not authenticated"),
+                    })
+                    .await?;
+                    io.flush().await?;
+
+                    bail!("client is not authenticated: {}", client_key);
+                }
+            },
+        };
 
         if info.version != PROTOCOL_VERSION {
             bail!(
@@ -564,13 +715,31 @@ impl Inner {
             );
         }
 
+        let quota = match is_mesh_peer {
+            true => None,
+            false => match self.quotas.acquire(client_key, limits) {
+                Ok(quota) => quota,
+                Err(err) => {
+                    io.send(Frame::Health {
+                        problem: Bytes::from(format!("quota exceeded: {err}")),
+                    })
+                    .await?;
+                    io.flush().await?;
+
+                    bail!("client {} exceeded its quota: {err}", client_key);
+                }
+            },
+        };
+
         trace!("accept: build client conn");
         let client_conn_builder = Config {
             node_id: client_key,
             stream: io,
             write_timeout: self.write_timeout,
             channel_capacity: PER_CLIENT_SEND_QUEUE_DEPTH,
-            rate_limit: self.rate_limit,
+            rate_limit: self.clients.rate_limit(),
+            quota,
+            access: request,
         };
         trace!("accept: create client");
         let node_id = client_conn_builder.node_id;
@@ -596,30 +765,125 @@ pub(super) enum TlsAcceptor {
 }
 
 impl RelayService {
+    #[allow(clippy::too_many_arguments)]
     fn new(
         handlers: Handlers,
         headers: HeaderMap,
         rate_limit: Option<ClientRateLimit>,
         key_cache: KeyCache,
         access: AccessConfig,
+        mesh: Option<Mesh>,
+        quotas: Option<Quotas>,
         metrics: Arc<Metrics>,
     ) -> Self {
         Self(Arc::new(Inner {
             handlers,
             headers,
-            clients: Clients::default(),
+            clients: Clients::new(mesh, rate_limit),
             write_timeout: SERVER_WRITE_TIMEOUT,
-            rate_limit,
-            key_cache,
-            access,
+            key_cache: RwLock::new(key_cache),
+            access: RwLock::new(Arc::new(access)),
+            quotas: quotas.unwrap_or_else(|| Quotas::new(Default::default(), metrics.clone())),
+            bans: Default::default(),
             metrics,
         }))
     }
 
+    /// Applies a new configuration to the running service.
+    ///
+    /// The rate limit applies to connected clients immediately.  Connected clients are
+    /// checked against the new access configuration, clients which are no longer allowed
+    /// are disconnected.
+    pub(super) async fn update_config(&self, update: RelayConfigUpdate) {
+        let RelayConfigUpdate {
+            limits,
+            key_cache_capacity,
+            access,
+        } = update;
+        self.0.clients.set_rate_limit(limits.client_rx);
+        self.0
+            .key_cache
+            .write()
+            .expect("poisoned")
+            .set_capacity(key_cache_capacity.unwrap_or(DEFAULT_KEY_CACHE_CAPACITY));
+        let access = Arc::new(access);
+        *self.0.access.write().expect("poisoned") = access.clone();
+        let mut revoked = n0_future::stream::iter(self.0.clients.access_requests())
+            .map(|request| {
+                let access = access.clone();
+                async move {
+                    let node_id = request.node_id;
+                    (!access.is_allowed(request).await).then_some(node_id)
+                }
+            })
+            .buffered_unordered(ACCESS_RECHECK_CONCURRENCY);
+        while let Some(res) = revoked.next().await {
+            if let Some(node_id) = res {
+                debug!(
+                    node_id = node_id.fmt_short(),
+                    "access revoked, disconnecting"
+                );
+                self.0.clients.disconnect(&node_id);
+            }
+        }
+    }
+
     async fn shutdown(&self) {
         self.0.clients.shutdown().await;
     }
 
+    /// Returns information about all connected clients.
+    pub(super) fn clients(&self) -> Vec<ClientInfo> {
+        self.0.clients.infos()
+    }
+
+    /// Disconnects the client, returns `false` if it is not connected.
+    pub(super) fn disconnect(&self, node_id: &NodeId) -> bool {
+        let disconnected = self.0.clients.disconnect(node_id);
+        if disconnected {
+            self.0.metrics.admin_disconnects.inc();
+        }
+        disconnected
+    }
+
+    /// Bans the node for `duration` and disconnects it.
+    ///
+    /// Returns whether the node was connected, or an error if the ban would end too far in
+    /// the future to be represented.
+    pub(super) fn ban(&self, node_id: NodeId, duration: Duration) -> Result<bool> {
+        let until = Instant::now()
+            .checked_add(duration)
+            .context("ban duration too long")?;
+        self.0.bans.insert(node_id, until);
+        Ok(self.disconnect(&node_id))
+    }
+
+    /// Lifts the ban of the node, returns `false` if it was not banned.
+    pub(super) fn unban(&self, node_id: &NodeId) -> bool {
+        self.0.bans.remove(node_id).is_some()
+    }
+
+    /// Returns the banned nodes with the remaining time of their ban.
+    pub(super) fn bans(&self) -> Vec<(NodeId, Duration)> {
+        let now = Instant::now();
+        self.0.bans.retain(|_, expires| *expires > now);
+        self.0
+            .bans
+            .iter()
+            .map(|ban| (*ban.key(), ban.value().saturating_duration_since(now)))
+            .collect()
+    }
+
+    /// Returns the quota usage of the account of the node.
+    pub(super) fn quota_usage(&self, node_id: NodeId) -> Option<QuotaUsage> {
+        self.0.quotas.usage(node_id)
+    }
+
+    /// Adds a new connection relaying over QUIC to the server and serves it.
+    pub(crate) async fn accept_quic(&self, conn: quinn::Connection) -> Result<()> {
+        self.0.accept_quic(conn).await
+    }
+
     /// Handle the incoming connection.
     ///
     /// If a `tls_config` is given, will serve the connection using HTTPS.
@@ -965,6 +1229,8 @@ mod tests {
             None,
             KeyCache::test(),
             AccessConfig::Everyone,
+            None,
+            None,
             Default::default(),
         );
 
@@ -1053,6 +1319,8 @@ mod tests {
             None,
             KeyCache::test(),
             AccessConfig::Everyone,
+            None,
+            None,
             Default::default(),
         );
 
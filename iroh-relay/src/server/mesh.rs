@@ -0,0 +1,214 @@
+//! Meshing of relay servers.
+//!
+//! A mesh is a set of relay servers which trust each other.  Each server of the mesh
+//! connects to every other server as a relay client, authenticated by the
+//! [`MeshConfig::secret_key`] of the server.  Over this link it sends a `WatchConns` frame
+//! to learn which nodes are connected to the peer, the peer announces them using
+//! `PeerPresent` and `NodeGone` frames.  Packets for these nodes are sent over the link in
+//! `ForwardPacket` frames, which the peer delivers to its locally connected node.
+//!
+//! Forwarded packets are only ever delivered to local clients, never forwarded again, so
+//! packets can not loop between servers.  This makes a fleet of relay servers behave like a
+//! single relay server, clients do not need to dial the home relay of the node they talk to.
+//!
+//! [`MeshConfig::secret_key`]: crate::server::MeshConfig::secret_key
+
+use std::{collections::HashMap, sync::Arc};
+
+use anyhow::{bail, Context, Result};
+use bytes::Bytes;
+use dashmap::DashMap;
+use iroh_base::{NodeId, RelayUrl};
+use n0_future::{future::poll_fn, time::Duration, SinkExt};
+use tokio::{
+    sync::mpsc::{self, error::TrySendError},
+    task::JoinSet,
+};
+use tracing::{debug, info_span, trace, warn, Instrument};
+
+use crate::{
+    client::{conn::Conn, ClientBuilder},
+    dns::DnsResolver,
+    protos::relay::{Frame, PER_CLIENT_SEND_QUEUE_DEPTH},
+    server::{metrics::Metrics, MeshConfig},
+};
+
+/// Initial delay before reconnecting a link to a mesh peer.
+const INITIAL_RECONNECT_DELAY: Duration = Duration::from_millis(100);
+
+/// Maximum delay before reconnecting a link to a mesh peer.
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(10);
+
+/// The state of this relay server in the mesh.
+#[derive(Debug, Clone)]
+pub(super) struct Mesh(Arc<Inner>);
+
+#[derive(Debug)]
+struct Inner {
+    /// The mesh peers, which are trusted to watch and forward.
+    peers: HashMap<NodeId, RelayUrl>,
+    /// Which mesh peer each remote node is connected to.
+    routes: DashMap<NodeId, NodeId>,
+    /// Queues of `ForwardPacket` frames for each link to a mesh peer.
+    links: HashMap<NodeId, mpsc::Sender<Frame>>,
+    metrics: Arc<Metrics>,
+}
+
+impl Mesh {
+    /// Creates the mesh and spawns the links to all mesh peers onto `tasks`.
+    pub(super) fn spawn(
+        config: MeshConfig,
+        metrics: Arc<Metrics>,
+        tasks: &mut JoinSet<Result<()>>,
+    ) -> Self {
+        let mut links = HashMap::new();
+        let mut receivers = Vec::new();
+        for peer in config.peers.iter() {
+            let (tx, rx) = mpsc::channel(PER_CLIENT_SEND_QUEUE_DEPTH);
+            links.insert(peer.node_id, tx);
+            receivers.push((peer.clone(), rx));
+        }
+        let mesh = Self(Arc::new(Inner {
+            peers: config
+                .peers
+                .into_iter()
+                .map(|peer| (peer.node_id, peer.url))
+                .collect(),
+            routes: Default::default(),
+            links,
+            metrics,
+        }));
+        for (peer, rx) in receivers {
+            let link = Link {
+                mesh: mesh.clone(),
+                node_id: peer.node_id,
+                url: peer.url.clone(),
+                builder: ClientBuilder::new(
+                    peer.url.clone(),
+                    config.secret_key.clone(),
+                    DnsResolver::new(),
+                ),
+                forward_queue: rx,
+            };
+            tasks.spawn(
+                link.run()
+                    .instrument(info_span!("mesh-link", peer = %peer.url)),
+            );
+        }
+        mesh
+    }
+
+    /// Whether the node is a mesh peer.
+    pub(super) fn is_peer(&self, node_id: &NodeId) -> bool {
+        self.0.peers.contains_key(node_id)
+    }
+
+    /// Forwards a packet to the mesh peer the `dst` node is connected to.
+    ///
+    /// Returns `false` if the node is not known to be connected to any mesh peer.
+    pub(super) fn forward(&self, src: NodeId, dst: NodeId, packet: Bytes) -> bool {
+        let Some(peer) = self.0.routes.get(&dst).map(|peer| *peer) else {
+            return false;
+        };
+        let Some(link) = self.0.links.get(&peer) else {
+            return false;
+        };
+        let frame = Frame::ForwardPacket {
+            src_key: src,
+            dst_key: dst,
+            packet,
+        };
+        match link.try_send(frame) {
+            Ok(()) => {
+                self.0.metrics.mesh_packets_forwarded.inc();
+            }
+            Err(TrySendError::Full(_)) => {
+                debug!(
+                    dst = dst.fmt_short(),
+                    "mesh link too busy to forward packet, dropping packet"
+                );
+                self.0.metrics.mesh_packets_dropped.inc();
+            }
+            Err(TrySendError::Closed(_)) => {
+                debug!(dst = dst.fmt_short(), "mesh link closed, dropping packet");
+                self.0.metrics.mesh_packets_dropped.inc();
+            }
+        }
+        true
+    }
+}
+
+/// The link to a single mesh peer.
+///
+/// The link keeps reconnecting to the peer until it is aborted.
+#[derive(Debug)]
+struct Link {
+    mesh: Mesh,
+    /// The [`NodeId`] of the mesh peer.
+    node_id: NodeId,
+    url: RelayUrl,
+    builder: ClientBuilder,
+    forward_queue: mpsc::Receiver<Frame>,
+}
+
+impl Link {
+    /// Runs the link, this never returns.
+    async fn run(mut self) -> Result<()> {
+        let mut delay = INITIAL_RECONNECT_DELAY;
+        loop {
+            match self.builder.connect().await {
+                Ok(client) => {
+                    debug!("connected to mesh peer");
+                    delay = INITIAL_RECONNECT_DELAY;
+                    if let Err(err) = self.run_connected(client.into_conn()).await {
+                        warn!("link to mesh peer {} failed: {err:#}", self.url);
+                    }
+                }
+                Err(err) => {
+                    debug!("failed to connect to mesh peer: {err:#}");
+                }
+            }
+            // All nodes of the peer are unreachable until we are connected again.
+            let peer = self.node_id;
+            self.mesh.0.routes.retain(|_, route| *route != peer);
+            tokio::time::sleep(delay).await;
+            delay = (delay * 2).min(MAX_RECONNECT_DELAY);
+        }
+    }
+
+    async fn run_connected(&mut self, mut conn: Conn) -> Result<()> {
+        conn.send(Frame::WatchConns).await?;
+        loop {
+            tokio::select! {
+                frame = poll_fn(|cx| Pin::new(&mut conn).poll_next_frame(cx)) => {
+                    let frame = frame.context("mesh peer closed the connection")??;
+                    match frame {
+                        Frame::PeerPresent { node_id } => {
+                            trace!(node = node_id.fmt_short(), "node present on mesh peer");
+                            self.mesh.0.routes.insert(node_id, self.node_id);
+                        }
+                        Frame::NodeGone { node_id } => {
+                            trace!(node = node_id.fmt_short(), "node gone from mesh peer");
+                            let peer = self.node_id;
+                            self.mesh.0.routes.remove_if(&node_id, |_, route| *route == peer);
+                        }
+                        Frame::Ping { data } => {
+                            conn.send(Frame::Pong { data }).await?;
+                        }
+                        Frame::Health { problem } => {
+                            bail!("mesh peer unhealthy: {}", String::from_utf8_lossy(&problem));
+                        }
+                        frame => {
+                            trace!("ignoring {:?} frame from mesh peer", frame.typ());
+                        }
+                    }
+                }
+                frame = self.forward_queue.recv() => {
+                    // The sender lives in the mesh, which we hold on to.
+                    let frame = frame.context("forward queue closed")?;
+                    conn.send(frame).await?;
+                }
+            }
+        }
+    }
+}
@@ -77,6 +77,41 @@ pub struct Metrics {
     pub websocket_accepts: Counter,
     /// Number of accepted 'iroh derp http' connection upgrades
     pub relay_accepts: Counter,
+    /// Number of accepted connections relaying over QUIC
+    pub quic_relay_accepts: Counter,
+
+    /*
+     * Metrics about the mesh
+     */
+    /// Packets forwarded to a mesh peer in a `FrameType::ForwardPacket`
+    #[metrics(help = "Number of packets forwarded to mesh peers.")]
+    pub mesh_packets_forwarded: Counter,
+    /// `FrameType::ForwardPacket` received from a mesh peer
+    #[metrics(help = "Number of packets received from mesh peers.")]
+    pub mesh_packets_recv: Counter,
+    /// Packets which could not be forwarded to a mesh peer
+    #[metrics(help = "Number of packets dropped while forwarding to mesh peers.")]
+    pub mesh_packets_dropped: Counter,
+
+    /*
+     * Metrics about quotas
+     */
+    /// Connections rejected because the quota allows no more connections
+    #[metrics(help = "Number of connections rejected because of a quota.")]
+    pub quota_conns_rejected: Counter,
+    /// Packets dropped because the daily quota is exceeded
+    #[metrics(help = "Number of packets dropped because of a daily quota.")]
+    pub quota_packets_dropped: Counter,
+
+    /*
+     * Metrics about the admin API
+     */
+    /// Connections rejected because the node is banned
+    #[metrics(help = "Number of connections rejected because the node is banned.")]
+    pub banned_conns_rejected: Counter,
+    /// Clients disconnected using the admin API
+    #[metrics(help = "Number of clients disconnected using the admin API.")]
+    pub admin_disconnects: Counter,
     // TODO: enable when we can have multiple connections for one node id
     // pub duplicate_client_keys: Counter,
     // pub duplicate_client_conns: Counter,
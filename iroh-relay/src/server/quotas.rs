@@ -0,0 +1,534 @@
+//! Quotas limiting the usage of the relay server per node or per group of nodes.
+//!
+//! Each node is accounted either to the [`QuotaGroup`] it is a member of, or to its own
+//! account when a [`QuotaConfig::default`] quota is configured.  Nodes without an account
+//! are only subject to the [`Limits`] of the relay server.
+//!
+//! When the [`AccessConfig`] allows a node with [`Access::AllowWithLimits`], the node is
+//! accounted to its own account with the given quota instead.
+//!
+//! Usage is exported through [`Metrics`] only in aggregate, e.g. as
+//! [`Metrics::quota_packets_dropped`].  The metrics groups have a fixed set of counters,
+//! there is no way to add a counter per node or group at runtime, and doing so would give
+//! the metrics an unbounded cardinality.  The usage of a single node or group is available
+//! from [`Quotas::usage`], served by the admin API.
+//!
+//! [`Limits`]: crate::server::Limits
+//! [`AccessConfig`]: crate::server::AccessConfig
+//! [`Access::AllowWithLimits`]: crate::server::Access::AllowWithLimits
+
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use dashmap::{mapref::entry::Entry, DashMap};
+use iroh_base::NodeId;
+use serde::Serialize;
+use time::{Date, OffsetDateTime};
+use tracing::debug;
+
+use crate::server::{metrics::Metrics, ClientRateLimit};
+
+/// Quotas for a single node or a group of nodes.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Quota {
+    /// Rate limit for incoming traffic.
+    ///
+    /// For a group the rate is shared by all connections of the group.  This applies in
+    /// addition to [`Limits::client_rx`].
+    ///
+    /// [`Limits::client_rx`]: crate::server::Limits::client_rx
+    pub rx_rate_limit: Option<ClientRateLimit>,
+    /// Maximum number of bytes relayed from the node or group per day.
+    ///
+    /// Days start at midnight UTC.  Once exceeded, all packets sent by the node or group
+    /// are dropped until the next day.
+    pub bytes_per_day: Option<u64>,
+    /// Maximum number of nodes connected at the same time.
+    ///
+    /// A node only has a single connection to the relay server, reconnecting replaces the
+    /// previous connection, so this mostly makes sense for a group.
+    pub max_connections: Option<usize>,
+}
+
+/// A group of nodes sharing a single [`Quota`].
+#[derive(Debug, Clone)]
+pub struct QuotaGroup {
+    /// The name of the group, used when reporting usage.
+    pub name: String,
+    /// The nodes in this group.
+    pub nodes: Vec<NodeId>,
+    /// The quota shared by all nodes in this group.
+    pub quota: Quota,
+}
+
+/// Configuration of the per-node quotas.
+#[derive(Debug, Clone, Default)]
+pub struct QuotaConfig {
+    /// The quota for each node which is not a member of any group.
+    ///
+    /// If `None`, these nodes have no quota.
+    pub default: Option<Quota>,
+    /// Groups of nodes sharing a quota.
+    ///
+    /// A node which is listed in several groups belongs to the first one.
+    pub groups: Vec<QuotaGroup>,
+}
+
+/// The usage of the relay server by an account, a node or a group of nodes.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct QuotaUsage {
+    /// The name of the group, `None` if the node has its own account.
+    pub group: Option<String>,
+    /// The nodes of the account currently connected.
+    pub connected_nodes: Vec<NodeId>,
+    /// Bytes received from the account today, days start at midnight UTC.
+    pub bytes_today: u64,
+    /// Bytes received from the account since the server started.
+    pub bytes_total: u64,
+    /// The configured [`Quota::bytes_per_day`].
+    pub bytes_per_day: Option<u64>,
+    /// The configured [`Quota::max_connections`].
+    pub max_connections: Option<usize>,
+}
+
+/// The key of an account.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum AccountId {
+    /// A group, the index into [`Inner::groups`].
+    Group(usize),
+    /// A node with its own account.
+    Node(NodeId),
+}
+
+/// Tracks the usage of all accounts and enforces their quotas.
+#[derive(Debug, Clone)]
+pub(super) struct Quotas(Arc<Inner>);
+
+#[derive(Debug)]
+struct Inner {
+    default: Option<Quota>,
+    groups: Vec<QuotaGroup>,
+    /// The group index of each node in a group.
+    node_groups: HashMap<NodeId, usize>,
+    accounts: DashMap<AccountId, Arc<Account>>,
+    /// The day when accounts without connections were last pruned.
+    last_prune: Mutex<Date>,
+    metrics: Arc<Metrics>,
+}
+
+#[derive(Debug)]
+struct Account {
+    group: Option<String>,
+    /// The quota, updated in place when a connection brings different limits.
+    quota: Mutex<AccountQuota>,
+    /// The number of connections of each connected node.
+    ///
+    /// While a node reconnects it briefly has two connections.
+    nodes: Mutex<HashMap<NodeId, usize>>,
+    today: Mutex<DailyBytes>,
+    bytes_total: AtomicU64,
+}
+
+#[derive(derive_more::Debug)]
+struct AccountQuota {
+    quota: Quota,
+    /// The shared rate-limiter of the account.
+    #[debug("{}", limiter.is_some())]
+    limiter: Option<Arc<governor::DefaultDirectRateLimiter>>,
+}
+
+#[derive(Debug)]
+struct DailyBytes {
+    day: Date,
+    bytes: u64,
+}
+
+/// The connection limit of an account was reached.
+#[derive(Debug, thiserror::Error)]
+#[error("too many connections, at most {max} allowed")]
+pub(super) struct TooManyConnections {
+    max: usize,
+}
+
+impl Quotas {
+    pub(super) fn new(config: QuotaConfig, metrics: Arc<Metrics>) -> Self {
+        let mut node_groups = HashMap::new();
+        for (i, group) in config.groups.iter().enumerate() {
+            for node_id in group.nodes.iter() {
+                node_groups.entry(*node_id).or_insert(i);
+            }
+        }
+        Self(Arc::new(Inner {
+            default: config.default,
+            groups: config.groups,
+            node_groups,
+            accounts: Default::default(),
+            last_prune: Mutex::new(OffsetDateTime::now_utc().date()),
+            metrics,
+        }))
+    }
+
+    fn account_id(&self, node_id: NodeId) -> Option<AccountId> {
+        match self.0.node_groups.get(&node_id) {
+            Some(i) => Some(AccountId::Group(*i)),
+            None => self.0.default.as_ref().map(|_| AccountId::Node(node_id)),
+        }
+    }
+
+    /// The configured quota of an account.
+    fn quota(&self, id: AccountId) -> Quota {
+        match id {
+            AccountId::Group(i) => self.0.groups[i].quota.clone(),
+            AccountId::Node(_) => self.0.default.clone().unwrap_or_default(),
+        }
+    }
+
+    /// Returns the account, creating it if needed.
+    ///
+    /// If the account has a different quota, its quota is updated in place so the usage
+    /// and the connections already accounted to it are kept.
+    fn account(&self, id: AccountId, quota: Quota) -> Arc<Account> {
+        match self.0.accounts.entry(id) {
+            Entry::Occupied(entry) => {
+                entry.get().set_quota(quota);
+                entry.get().clone()
+            }
+            Entry::Vacant(entry) => {
+                let group = match id {
+                    AccountId::Group(i) => Some(self.0.groups[i].name.clone()),
+                    AccountId::Node(_) => None,
+                };
+                entry.insert(Arc::new(Account::new(group, quota))).clone()
+            }
+        }
+    }
+
+    /// Accounts a new connection of the node.
+    ///
+    /// If `limits` are given the node is accounted to its own account with these limits,
+    /// see [`Access::AllowWithLimits`].  Returns `None` if the node has no quota.  The
+    /// connection is accounted until the returned permit is dropped.
+    ///
+    /// [`Access::AllowWithLimits`]: crate::server::Access::AllowWithLimits
+    pub(super) fn acquire(
+        &self,
+        node_id: NodeId,
+        limits: Option<Quota>,
+    ) -> Result<Option<QuotaPermit>, TooManyConnections> {
+        self.prune();
+        let (id, quota) = match limits {
+            Some(quota) => (AccountId::Node(node_id), quota),
+            None => match self.account_id(node_id) {
+                Some(id) => (id, self.quota(id)),
+                None => return Ok(None),
+            },
+        };
+        let account = self.account(id, quota);
+        {
+            let mut nodes = account.nodes.lock().expect("poisoned");
+            if let Some(max) = account.quota().max_connections {
+                if !nodes.contains_key(&node_id) && nodes.len() >= max {
+                    self.0.metrics.quota_conns_rejected.inc();
+                    return Err(TooManyConnections { max });
+                }
+            }
+            *nodes.entry(node_id).or_default() += 1;
+        }
+        Ok(Some(QuotaPermit {
+            node_id,
+            account,
+            metrics: self.0.metrics.clone(),
+        }))
+    }
+
+    /// Returns the usage of the account of the node.
+    ///
+    /// Returns `None` if the node has no quota or has not connected yet.
+    pub(super) fn usage(&self, node_id: NodeId) -> Option<QuotaUsage> {
+        let id = match self.0.node_groups.get(&node_id) {
+            Some(i) => AccountId::Group(*i),
+            None => AccountId::Node(node_id),
+        };
+        let account = self.0.accounts.get(&id)?.clone();
+        Some(account.usage())
+    }
+
+    /// Removes the accounts of single nodes which are not connected, once a day.
+    ///
+    /// Their daily usage is reset anyway.
+    fn prune(&self) {
+        let today = OffsetDateTime::now_utc().date();
+        {
+            let mut last_prune = self.0.last_prune.lock().expect("poisoned");
+            if *last_prune == today {
+                return;
+            }
+            *last_prune = today;
+        }
+        self.0.accounts.retain(|id, account| {
+            matches!(id, AccountId::Group(_)) || !account.nodes.lock().expect("poisoned").is_empty()
+        });
+        debug!(accounts = self.0.accounts.len(), "pruned quota accounts");
+    }
+}
+
+impl Account {
+    fn new(group: Option<String>, quota: Quota) -> Self {
+        Self {
+            group,
+            quota: Mutex::new(AccountQuota::new(quota)),
+            nodes: Default::default(),
+            today: Mutex::new(DailyBytes {
+                day: OffsetDateTime::now_utc().date(),
+                bytes: 0,
+            }),
+            bytes_total: AtomicU64::new(0),
+        }
+    }
+
+    /// The current quota of the account.
+    fn quota(&self) -> Quota {
+        self.quota.lock().expect("poisoned").quota.clone()
+    }
+
+    /// The current rate-limiter of the account.
+    fn limiter(&self) -> Option<Arc<governor::DefaultDirectRateLimiter>> {
+        self.quota.lock().expect("poisoned").limiter.clone()
+    }
+
+    /// Changes the quota of the account.
+    ///
+    /// The rate-limiter is only replaced if the rate limit changed, connections pick up a
+    /// new rate-limiter with their next frame.
+    fn set_quota(&self, quota: Quota) {
+        let mut current = self.quota.lock().expect("poisoned");
+        if current.quota == quota {
+            return;
+        }
+        if current.quota.rx_rate_limit != quota.rx_rate_limit {
+            *current = AccountQuota::new(quota);
+        } else {
+            current.quota = quota;
+        }
+    }
+
+    fn usage(&self) -> QuotaUsage {
+        let mut connected_nodes: Vec<_> = self
+            .nodes
+            .lock()
+            .expect("poisoned")
+            .keys()
+            .copied()
+            .collect();
+        connected_nodes.sort();
+        let bytes_today = {
+            let mut today = self.today.lock().expect("poisoned");
+            today.roll_over();
+            today.bytes
+        };
+        let quota = self.quota();
+        QuotaUsage {
+            group: self.group.clone(),
+            connected_nodes,
+            bytes_today,
+            bytes_total: self.bytes_total.load(Ordering::Relaxed),
+            bytes_per_day: quota.bytes_per_day,
+            max_connections: quota.max_connections,
+        }
+    }
+}
+
+impl AccountQuota {
+    fn new(quota: Quota) -> Self {
+        Self {
+            limiter: quota.rx_rate_limit.map(|cfg| Arc::new(cfg.limiter())),
+            quota,
+        }
+    }
+}
+
+impl DailyBytes {
+    /// Resets the counter when a new day started.
+    fn roll_over(&mut self) {
+        let today = OffsetDateTime::now_utc().date();
+        if today != self.day {
+            self.day = today;
+            self.bytes = 0;
+        }
+    }
+}
+
+/// A connection accounted to the quota of a node.
+///
+/// Releases the connection when dropped.
+#[derive(Debug)]
+pub(super) struct QuotaPermit {
+    node_id: NodeId,
+    account: Arc<Account>,
+    metrics: Arc<Metrics>,
+}
+
+impl QuotaPermit {
+    /// A handle to look up the current rate-limiter, shared by all connections of the
+    /// account.
+    pub(super) fn limiter_handle(&self) -> QuotaLimiter {
+        QuotaLimiter(self.account.clone())
+    }
+
+    /// Accounts bytes received from the node.
+    ///
+    /// Returns `false` if the daily quota is exceeded, the packet must be dropped then.
+    pub(super) fn record_recv(&self, bytes: usize) -> bool {
+        let bytes = bytes as u64;
+        let mut today = self.account.today.lock().expect("poisoned");
+        today.roll_over();
+        if let Some(max) = self.account.quota().bytes_per_day {
+            if today.bytes.saturating_add(bytes) > max {
+                self.metrics.quota_packets_dropped.inc();
+                return false;
+            }
+        }
+        today.bytes += bytes;
+        self.account.bytes_total.fetch_add(bytes, Ordering::Relaxed);
+        true
+    }
+}
+
+/// Looks up the current rate-limiter of an account.
+///
+/// The rate-limiter is replaced when the rate limit of the account changes.
+#[derive(Debug, Clone)]
+pub(super) struct QuotaLimiter(Arc<Account>);
+
+impl QuotaLimiter {
+    pub(super) fn get(&self) -> Option<Arc<governor::DefaultDirectRateLimiter>> {
+        self.0.limiter()
+    }
+}
+
+impl Drop for QuotaPermit {
+    fn drop(&mut self) {
+        let mut nodes = self.account.nodes.lock().expect("poisoned");
+        if let Some(count) = nodes.get_mut(&self.node_id) {
+            *count -= 1;
+            if *count == 0 {
+                nodes.remove(&self.node_id);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::num::NonZeroU32;
+
+    use iroh_base::SecretKey;
+
+    use super::*;
+
+    #[test]
+    fn test_quotas() {
+        let a = SecretKey::generate(rand::thread_rng()).public();
+        let b = SecretKey::generate(rand::thread_rng()).public();
+        let c = SecretKey::generate(rand::thread_rng()).public();
+        let quotas = Quotas::new(
+            QuotaConfig {
+                default: None,
+                groups: vec![QuotaGroup {
+                    name: "tenant".to_string(),
+                    nodes: vec![a, b],
+                    quota: Quota {
+                        rx_rate_limit: Some(ClientRateLimit {
+                            bytes_per_second: NonZeroU32::new(1024).unwrap(),
+                            max_burst_bytes: None,
+                        }),
+                        bytes_per_day: Some(100),
+                        max_connections: Some(1),
+                    },
+                }],
+            },
+            Default::default(),
+        );
+
+        // c has no quota
+        assert!(quotas.acquire(c, None).unwrap().is_none());
+        assert!(quotas.usage(c).is_none());
+
+        // only one node of the group may connect, but it can reconnect
+        let permit_a = quotas.acquire(a, None).unwrap().unwrap();
+        assert!(permit_a.limiter_handle().get().is_some());
+        let permit_a2 = quotas.acquire(a, None).unwrap().unwrap();
+        assert!(quotas.acquire(b, None).is_err());
+        drop(permit_a);
+
+        assert!(permit_a2.record_recv(60));
+        assert!(!permit_a2.record_recv(60));
+        assert!(permit_a2.record_recv(40));
+        let usage = quotas.usage(b).unwrap();
+        assert_eq!(usage.group.as_deref(), Some("tenant"));
+        assert_eq!(usage.connected_nodes, vec![a]);
+        assert_eq!(usage.bytes_today, 100);
+        assert_eq!(usage.bytes_total, 100);
+
+        drop(permit_a2);
+        let _permit_b = quotas.acquire(b, None).unwrap().unwrap();
+
+        // limits from the access check override the group
+        let limits = Quota {
+            bytes_per_day: Some(10),
+            ..Default::default()
+        };
+        let permit_a = quotas.acquire(a, Some(limits.clone())).unwrap().unwrap();
+        assert!(permit_a.limiter_handle().get().is_none());
+        assert!(!permit_a.record_recv(20));
+        let permit_c = quotas.acquire(c, Some(limits)).unwrap().unwrap();
+        assert!(permit_c.record_recv(10));
+        let usage = quotas.usage(c).unwrap();
+        assert_eq!(usage.group, None);
+        assert_eq!(usage.bytes_per_day, Some(10));
+        assert_eq!(usage.bytes_today, 10);
+    }
+
+    #[test]
+    fn test_quota_change_keeps_usage() {
+        let a = SecretKey::generate(rand::thread_rng()).public();
+        let quotas = Quotas::new(Default::default(), Default::default());
+        let limits = Quota {
+            bytes_per_day: Some(100),
+            max_connections: Some(1),
+            ..Default::default()
+        };
+        let permit = quotas.acquire(a, Some(limits)).unwrap().unwrap();
+        let limiter = permit.limiter_handle();
+        assert!(limiter.get().is_none());
+        assert!(permit.record_recv(60));
+
+        // reconnecting with different limits updates the account of the open connection
+        let limits = Quota {
+            rx_rate_limit: Some(ClientRateLimit {
+                bytes_per_second: NonZeroU32::new(1024).unwrap(),
+                max_burst_bytes: None,
+            }),
+            bytes_per_day: Some(80),
+            max_connections: Some(1),
+        };
+        let permit2 = quotas.acquire(a, Some(limits)).unwrap().unwrap();
+        assert!(limiter.get().is_some());
+        assert!(!permit.record_recv(30));
+        assert!(permit2.record_recv(20));
+
+        let usage = quotas.usage(a).unwrap();
+        assert_eq!(usage.connected_nodes, vec![a]);
+        assert_eq!(usage.bytes_today, 80);
+        assert_eq!(usage.bytes_per_day, Some(80));
+
+        drop(permit);
+        drop(permit2);
+        assert!(quotas.usage(a).unwrap().connected_nodes.is_empty());
+    }
+}
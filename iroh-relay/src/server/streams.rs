@@ -1,6 +1,7 @@
 //! Streams used in the server-side implementation of iroh relays.
 
 use std::{
+    net::IpAddr,
     pin::Pin,
     task::{Context, Poll},
 };
@@ -13,6 +14,7 @@ use tokio_websockets::WebSocketStream;
 
 use crate::{
     protos::relay::{Frame, RelayCodec},
+    quic::QuicRelayStream,
     KeyCache,
 };
 
@@ -23,6 +25,7 @@ use crate::{
 pub(crate) enum RelayedStream {
     Relay(Framed<MaybeTlsStream, RelayCodec>),
     Ws(WebSocketStream<MaybeTlsStream>, KeyCache),
+    Quic(QuicRelayStream),
 }
 
 fn ws_to_io_err(e: tokio_websockets::Error) -> std::io::Error {
@@ -39,6 +42,7 @@ impl Sink<Frame> for RelayedStream {
         match *self {
             Self::Relay(ref mut framed) => Pin::new(framed).poll_ready(cx),
             Self::Ws(ref mut ws, _) => Pin::new(ws).poll_ready(cx).map_err(ws_to_io_err),
+            Self::Quic(ref mut quic) => Pin::new(quic).poll_ready(cx),
         }
     }
 
@@ -50,6 +54,7 @@ impl Sink<Frame> for RelayedStream {
                     tokio_websockets::Payload::from(item.encode_for_ws_msg()),
                 ))
                 .map_err(ws_to_io_err),
+            Self::Quic(ref mut quic) => Pin::new(quic).start_send(item),
         }
     }
 
@@ -57,6 +62,7 @@ impl Sink<Frame> for RelayedStream {
         match *self {
             Self::Relay(ref mut framed) => Pin::new(framed).poll_flush(cx),
             Self::Ws(ref mut ws, _) => Pin::new(ws).poll_flush(cx).map_err(ws_to_io_err),
+            Self::Quic(ref mut quic) => Pin::new(quic).poll_flush(cx),
         }
     }
 
@@ -64,6 +70,7 @@ impl Sink<Frame> for RelayedStream {
         match *self {
             Self::Relay(ref mut framed) => Pin::new(framed).poll_close(cx),
             Self::Ws(ref mut ws, _) => Pin::new(ws).poll_close(cx).map_err(ws_to_io_err),
+            Self::Quic(ref mut quic) => Pin::new(quic).poll_close(cx),
         }
     }
 }
@@ -97,6 +104,7 @@ impl Stream for RelayedStream {
                 Poll::Ready(None) => Poll::Ready(None),
                 Poll::Pending => Poll::Pending,
             },
+            Self::Quic(ref mut quic) => Pin::new(quic).poll_next(cx),
         }
     }
 }
@@ -115,6 +123,27 @@ pub enum MaybeTlsStream {
     Test(tokio::io::DuplexStream),
 }
 
+impl MaybeTlsStream {
+    /// The IP address of the remote end of the stream.
+    pub(crate) fn remote_ip(&self) -> Option<IpAddr> {
+        let tcp = match self {
+            Self::Plain(tcp) => tcp,
+            Self::Tls(tls) => tls.get_ref().0,
+            #[cfg(test)]
+            Self::Test(_) => return None,
+        };
+        tcp.peer_addr().ok().map(|addr| addr.ip().to_canonical())
+    }
+
+    /// The server name the client requested using TLS SNI.
+    pub(crate) fn server_name(&self) -> Option<String> {
+        match self {
+            Self::Tls(tls) => tls.get_ref().1.server_name().map(ToString::to_string),
+            _ => None,
+        }
+    }
+}
+
 impl AsyncRead for MaybeTlsStream {
     fn poll_read(
         mut self: Pin<&mut Self>,
@@ -70,6 +70,7 @@ pub fn relay_config() -> RelayConfig<()> {
         limits: Default::default(),
         key_cache_capacity: Some(1024),
         access: AccessConfig::Everyone,
+        ..Default::default()
     }
 }
 
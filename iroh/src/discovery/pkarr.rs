@@ -30,6 +30,9 @@
 //!
 //! - [`PkarrResolver`], which resolves from a pkarr relay server using HTTP.
 //!
+//! - [`PkarrWatcher`], which subscribes to updates of a set of nodes from a pkarr relay
+//!   server using HTTP.
+//!
 //! - [`DnsDiscovery`], which resolves from a DNS server.
 //!
 //! - [`DhtDiscovery`], which resolves and publishes from both pkarr relay servers and well
@@ -44,7 +47,10 @@
 //! [`DnsDiscovery`]: crate::discovery::dns::DnsDiscovery
 //! [`DhtDiscovery`]: dht::DhtDiscovery
 
-use std::sync::Arc;
+use std::{
+    collections::BTreeSet,
+    sync::{Arc, Mutex},
+};
 
 use anyhow::{anyhow, bail, Result};
 use iroh_base::{NodeId, SecretKey};
@@ -53,8 +59,10 @@ use n0_future::{
     boxed::BoxStream,
     task::{self, AbortOnDropHandle},
     time::{self, Duration, Instant},
+    StreamExt,
 };
 use pkarr::SignedPacket;
+use tokio::sync::broadcast;
 use tracing::{debug, error_span, warn, Instrument};
 use url::Url;
 
@@ -335,6 +343,176 @@ impl Discovery for PkarrResolver {
     }
 }
 
+/// Watcher of node discovery information on a [pkarr] relay.
+///
+/// The watcher keeps a connection to the watch endpoint of a pkarr relay server, which streams
+/// the signed packets of the watched nodes whenever they are published, see
+/// [`PkarrRelayClient::watch`]. This is supported by `iroh-dns-server`, but not by all pkarr
+/// relays.  `iroh-dns-server` allows to watch at most 256 nodes with a single watcher, and
+/// rejects watch requests while it serves too many of them.
+///
+/// This implements the [`Discovery`] trait to be used as a node discovery service. It only
+/// provides [`Discovery::subscribe`], use it together with a [`PkarrResolver`] in a
+/// [`ConcurrentDiscovery`] to also resolve nodes.
+///
+/// [pkarr]: https://pkarr.org
+/// [`ConcurrentDiscovery`]: super::ConcurrentDiscovery
+#[derive(derive_more::Debug, Clone)]
+pub struct PkarrWatcher {
+    /// The watched nodes, published to `nodes` while the lock is held.
+    watched: Arc<Mutex<BTreeSet<NodeId>>>,
+    nodes: Watchable<BTreeSet<NodeId>>,
+    sender: broadcast::Sender<DiscoveryItem>,
+    _drop_guard: Arc<AbortOnDropHandle<()>>,
+}
+
+impl PkarrWatcher {
+    /// Creates a new watcher using the pkarr relay server at the URL.
+    ///
+    /// The watcher starts without watched nodes, add them with [`Self::watch`].
+    pub fn new(pkarr_relay: Url) -> Self {
+        debug!("creating pkarr watcher that watches {pkarr_relay}");
+        let nodes = Watchable::default();
+        let (sender, _) = broadcast::channel(64);
+        let service = WatcherService {
+            pkarr_client: PkarrRelayClient::new(pkarr_relay),
+            nodes: nodes.watch(),
+            sender: sender.clone(),
+        };
+        let join_handle = task::spawn(service.run().instrument(error_span!("pkarr_watch")));
+        Self {
+            watched: Default::default(),
+            nodes,
+            sender,
+            _drop_guard: Arc::new(AbortOnDropHandle::new(join_handle)),
+        }
+    }
+
+    /// Creates a pkarr watcher which uses the [number 0] pkarr relay server.
+    ///
+    /// This uses the pkarr relay server operated by [number 0], at
+    /// [`N0_DNS_PKARR_RELAY_PROD`].
+    ///
+    /// When running with the environment variable
+    /// `IROH_FORCE_STAGING_RELAYS` set to any non empty value [`N0_DNS_PKARR_RELAY_STAGING`]
+    /// server is used instead.
+    ///
+    /// [number 0]: https://n0.computer
+    pub fn n0_dns() -> Self {
+        let pkarr_relay = match force_staging_infra() {
+            true => N0_DNS_PKARR_RELAY_STAGING,
+            false => N0_DNS_PKARR_RELAY_PROD,
+        };
+
+        let pkarr_relay: Url = pkarr_relay.parse().expect("url is valid");
+        Self::new(pkarr_relay)
+    }
+
+    /// Starts watching a node.
+    pub fn watch(&self, node_id: NodeId) {
+        let mut watched = self.watched.lock().expect("poisoned");
+        if watched.insert(node_id) {
+            self.nodes.set(watched.clone()).ok();
+        }
+    }
+
+    /// Stops watching a node.
+    pub fn unwatch(&self, node_id: NodeId) {
+        let mut watched = self.watched.lock().expect("poisoned");
+        if watched.remove(&node_id) {
+            self.nodes.set(watched.clone()).ok();
+        }
+    }
+}
+
+impl Discovery for PkarrWatcher {
+    fn subscribe(&self) -> Option<BoxStream<DiscoveryItem>> {
+        let recv = self.sender.subscribe();
+        let stream =
+            tokio_stream::wrappers::BroadcastStream::new(recv).filter_map(|item| item.ok());
+        Some(Box::pin(stream))
+    }
+}
+
+/// Watch node info on a pkarr relay.
+#[derive(derive_more::Debug)]
+struct WatcherService {
+    #[debug("PkarrClient")]
+    pkarr_client: PkarrRelayClient,
+    nodes: Watcher<BTreeSet<NodeId>>,
+    sender: broadcast::Sender<DiscoveryItem>,
+}
+
+impl WatcherService {
+    async fn run(self) {
+        let Self {
+            pkarr_client,
+            mut nodes,
+            sender,
+        } = self;
+        let mut failed_attempts = 0;
+        while let Ok(watched) = nodes.get() {
+            if watched.is_empty() {
+                match nodes.updated().await {
+                    Ok(_) => continue,
+                    Err(Disconnected) => break,
+                }
+            }
+            let res = tokio::select! {
+                res = watch_nodes(&pkarr_client, &watched, &sender, &mut failed_attempts) => res,
+                res = nodes.updated() => match res {
+                    Ok(_) => {
+                        debug!("Reconnect to pkarr watch (nodes changed)");
+                        continue;
+                    }
+                    Err(Disconnected) => break,
+                },
+            };
+            let err = res.err().unwrap_or_else(|| anyhow!("stream closed"));
+            failed_attempts += 1;
+            // Retry after increasing timeout
+            let retry_after = Duration::from_secs(failed_attempts.min(60));
+            warn!(
+                err = %format!("{err:#}"),
+                url = %pkarr_client.pkarr_relay_url,
+                ?retry_after,
+                %failed_attempts,
+                "Failed to watch pkarr relay",
+            );
+            // Wait until either the retry timeout is reached, or the watched nodes changed.
+            tokio::select! {
+                res = nodes.updated() => match res {
+                    Ok(_) => debug!("Reconnect to pkarr watch (nodes changed)"),
+                    Err(Disconnected) => break,
+                },
+                _ = time::sleep(retry_after) => debug!("Reconnect to pkarr watch (retry)"),
+            }
+        }
+    }
+}
+
+/// Forwards the packets of `nodes` to `sender` until the watch stream ends.
+async fn watch_nodes(
+    pkarr_client: &PkarrRelayClient,
+    nodes: &BTreeSet<NodeId>,
+    sender: &broadcast::Sender<DiscoveryItem>,
+    failed_attempts: &mut u64,
+) -> Result<()> {
+    let mut stream = pkarr_client.watch(nodes.iter().copied()).await?;
+    debug!(nodes = nodes.len(), "Watching pkarr relay");
+    *failed_attempts = 0;
+    while let Some(signed_packet) = stream.next().await {
+        let signed_packet = signed_packet?;
+        let info = NodeInfo::from_pkarr_signed_packet(&signed_packet)?;
+        let last_updated = signed_packet.timestamp().as_u64();
+        // fails only if there are no subscribers
+        sender
+            .send(DiscoveryItem::new(info, "pkarr", Some(last_updated)))
+            .ok();
+    }
+    Ok(())
+}
+
 /// A [pkarr] client to publish [`pkarr::SignedPacket`]s to a pkarr relay.
 ///
 /// [pkarr]: https://pkarr.org
@@ -401,4 +579,126 @@ impl PkarrRelayClient {
 
         Ok(())
     }
+
+    /// Watches the [`SignedPacket`]s for the given [`NodeId`]s.
+    ///
+    /// The returned stream yields the current packets of the nodes, followed by every newer
+    /// packet published to the relay. It uses the `watch` endpoint of the relay, which streams
+    /// the packets as server-sent events. This is supported by `iroh-dns-server`.
+    pub async fn watch(
+        &self,
+        node_ids: impl IntoIterator<Item = NodeId>,
+    ) -> anyhow::Result<BoxStream<anyhow::Result<SignedPacket>>> {
+        let keys = node_ids
+            .into_iter()
+            .map(|node_id| z32::encode(node_id.as_bytes()))
+            .collect::<Vec<_>>();
+        let mut url = self.pkarr_relay_url.clone();
+        url.path_segments_mut()
+            .map_err(|_| anyhow!("Failed to watch: Invalid relay URL"))?
+            .push("watch");
+        url.query_pairs_mut().append_pair("keys", &keys.join(","));
+
+        let response = self
+            .http_client
+            .get(url)
+            .header(reqwest::header::ACCEPT, "text/event-stream")
+            .send()
+            .await?;
+
+        if !response.status().is_success() {
+            bail!(format!(
+                "Watch request failed with status {}",
+                response.status()
+            ))
+        }
+
+        let mut decoder = SseDecoder::default();
+        let stream = response
+            .bytes_stream()
+            .map(move |chunk| match chunk {
+                Ok(chunk) => decoder
+                    .push(&chunk)
+                    .into_iter()
+                    .filter(|(event, _data)| event == "packet")
+                    .map(|(_event, data)| parse_watch_event(&data))
+                    .collect(),
+                Err(err) => vec![Err(err.into())],
+            })
+            .flat_map(n0_future::stream::iter);
+        Ok(Box::pin(stream))
+    }
+}
+
+/// Parses the data of a watch event, `<z32 key> <base64url relay payload>`.
+fn parse_watch_event(data: &str) -> Result<SignedPacket> {
+    let (key, payload) = data
+        .split_once(' ')
+        .ok_or_else(|| anyhow!("invalid watch event"))?;
+    // We map the error to string, as in browsers the error is !Send
+    let public_key = pkarr::PublicKey::try_from(key).map_err(|e| anyhow::anyhow!(e.to_string()))?;
+    let payload = data_encoding::BASE64URL_NOPAD.decode(payload.as_bytes())?;
+    SignedPacket::from_relay_payload(&public_key, &payload.into())
+        .map_err(|e| anyhow::anyhow!(e.to_string()))
+}
+
+/// Minimal decoder for [server-sent events].
+///
+/// [server-sent events]: https://html.spec.whatwg.org/multipage/server-sent-events.html
+#[derive(Debug, Default)]
+struct SseDecoder {
+    buf: Vec<u8>,
+    event: String,
+    data: String,
+}
+
+impl SseDecoder {
+    /// Feeds a chunk of the response body, returns the completed events as `(event, data)`.
+    fn push(&mut self, chunk: &[u8]) -> Vec<(String, String)> {
+        self.buf.extend_from_slice(chunk);
+        let mut events = Vec::new();
+        while let Some(pos) = self.buf.iter().position(|b| *b == b'\n') {
+            let line = self.buf.drain(..=pos).collect::<Vec<_>>();
+            let line = String::from_utf8_lossy(&line);
+            let line = line.trim_end_matches(['\n', '\r']);
+            if line.is_empty() {
+                // an empty line dispatches the event
+                let event = std::mem::take(&mut self.event);
+                let data = std::mem::take(&mut self.data);
+                if !data.is_empty() {
+                    events.push((event, data));
+                }
+                continue;
+            }
+            let (field, value) = line.split_once(':').unwrap_or((line, ""));
+            let value = value.strip_prefix(' ').unwrap_or(value);
+            match field {
+                "event" => self.event = value.to_string(),
+                "data" => {
+                    if !self.data.is_empty() {
+                        self.data.push('\n');
+                    }
+                    self.data.push_str(value);
+                }
+                // comments, ids and retry intervals are not used
+                _ => {}
+            }
+        }
+        events
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SseDecoder;
+
+    #[test]
+    fn sse_decoder() {
+        let mut decoder = SseDecoder::default();
+        assert!(decoder.push(b": keep-alive\n\nevent: pac").is_empty());
+        let events = decoder.push(b"ket\ndata: foo\r\ndata: bar\n\ndata:baz\n");
+        assert_eq!(events, vec![("packet".to_string(), "foo\nbar".to_string())]);
+        let events = decoder.push(b"\n");
+        assert_eq!(events, vec![(String::new(), "baz".to_string())]);
+    }
 }
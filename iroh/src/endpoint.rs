@@ -13,11 +13,11 @@
 
 use std::{
     any::Any,
-    collections::BTreeSet,
+    collections::{BTreeSet, HashMap},
     future::{Future, IntoFuture},
     net::{IpAddr, SocketAddr, SocketAddrV4, SocketAddrV6},
     pin::Pin,
-    sync::Arc,
+    sync::{Arc, Mutex, Weak},
     task::Poll,
 };
 
@@ -25,7 +25,10 @@ use anyhow::{bail, Context, Result};
 use ed25519_dalek::{pkcs8::DecodePublicKey, VerifyingKey};
 use iroh_base::{NodeAddr, NodeId, RelayUrl, SecretKey};
 use iroh_relay::RelayMap;
-use n0_future::{time::Duration, Stream};
+use n0_future::{
+    time::{self, Duration, Instant},
+    Stream,
+};
 use pin_project::pin_project;
 use tracing::{debug, instrument, trace, warn};
 use url::Url;
@@ -43,10 +46,12 @@ use crate::{
     metrics::EndpointMetrics,
     net_report::Report,
     tls,
-    watchable::Watcher,
+    watchable::{Watchable, Watcher},
     RelayProtocol,
 };
 
+pub mod address_book;
+pub mod pool;
 mod rtt_actor;
 
 // Missing still: SendDatagram and ConnectionClose::frame_type's Type.
@@ -66,10 +71,15 @@ pub use quinn_proto::{
     FrameStats, PathStats, TransportError, TransportErrorCode, UdpStats, Written,
 };
 
-use self::rtt_actor::RttMessage;
+use self::{
+    address_book::{AddressBook, AddressBookSaver, SavedNode},
+    rtt_actor::RttMessage,
+};
 pub use super::magicsock::{
-    ConnectionType, ControlMsg, DirectAddr, DirectAddrInfo, DirectAddrType, RemoteInfo, Source,
+    ClearReason, ConnectionType, ControlMsg, DirectAddr, DirectAddrInfo, DirectAddrType,
+    MultipathMode, NetworkPath, PathEvent, PathEventKind, RemoteInfo, Source, UntrustReason,
 };
+pub use crate::tls::{AsyncAuthorization, AuthorizedNodes};
 
 /// The delay to fall back to discovery when direct addresses fail.
 ///
@@ -78,6 +88,21 @@ pub use super::magicsock::{
 /// is still no connection the configured [`Discovery`] will be used however.
 const DISCOVERY_WAIT_PERIOD: Duration = Duration::from_millis(500);
 
+/// The error code of connections closed by an [`Endpoint`] that was draining.
+///
+/// See [`Endpoint::drain`].  Remote nodes seeing this code know that the node shuts down on
+/// purpose, and can reconnect to another node instead of treating it as a failure.
+pub const DRAIN_CLOSE_CODE: VarInt = VarInt::from_u32(0x6472_6169);
+
+/// The reason of connections closed by an [`Endpoint`] that was draining.
+pub const DRAIN_CLOSE_REASON: &[u8] = b"draining";
+
+/// How often the open connections are checked while draining.
+const DRAIN_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How long a connection has to be without stream activity to be closed while draining.
+const DRAIN_IDLE_TIMEOUT: Duration = Duration::from_secs(1);
+
 type DiscoveryBuilder = Box<dyn FnOnce(&SecretKey) -> Option<Box<dyn Discovery>> + Send + Sync>;
 
 /// Defines the mode of path selection for all traffic flowing through
@@ -112,6 +137,7 @@ pub struct Builder {
     proxy_url: Option<Url>,
     /// List of known nodes. See [`Builder::known_nodes`].
     node_map: Option<Vec<NodeAddr>>,
+    address_book: Option<Arc<dyn AddressBook>>,
     #[cfg(not(wasm_browser))]
     dns_resolver: Option<DnsResolver>,
     #[cfg(any(test, feature = "test-utils"))]
@@ -120,7 +146,9 @@ pub struct Builder {
     addr_v6: Option<SocketAddrV6>,
     #[cfg(any(test, feature = "test-utils"))]
     path_selection: PathSelection,
+    multipath: MultipathMode,
     tls_auth: tls::Authentication,
+    authorized_nodes: Option<AuthorizedNodes>,
 }
 
 impl Default for Builder {
@@ -138,6 +166,7 @@ impl Default for Builder {
             discovery_user_data: Default::default(),
             proxy_url: None,
             node_map: None,
+            address_book: None,
             #[cfg(not(wasm_browser))]
             dns_resolver: None,
             #[cfg(any(test, feature = "test-utils"))]
@@ -146,7 +175,9 @@ impl Default for Builder {
             addr_v6: None,
             #[cfg(any(test, feature = "test-utils"))]
             path_selection: PathSelection::default(),
+            multipath: MultipathMode::default(),
             tls_auth: tls::Authentication::RawPublicKey,
+            authorized_nodes: None,
         }
     }
 }
@@ -163,9 +194,13 @@ impl Builder {
         let secret_key = self
             .secret_key
             .unwrap_or_else(|| SecretKey::generate(rand::rngs::OsRng));
+        let mut tls_config = tls::TlsConfig::new(self.tls_auth, secret_key.clone());
+        if let Some(authorized_nodes) = self.authorized_nodes {
+            tls_config = tls_config.with_authorized_nodes(authorized_nodes);
+        }
         let static_config = StaticConfig {
             transport_config: Arc::new(self.transport_config),
-            tls_config: tls::TlsConfig::new(self.tls_auth, secret_key.clone()),
+            tls_config,
             keylog: self.keylog,
         };
         #[cfg(not(wasm_browser))]
@@ -184,13 +219,25 @@ impl Builder {
 
         let metrics = EndpointMetrics::default();
 
+        let mut node_map = self.node_map;
+        let address_book = match self.address_book {
+            Some(book) => {
+                let saved = address_book::load(book.as_ref()).await;
+                node_map
+                    .get_or_insert_with(Vec::new)
+                    .extend(address_book::restore(&saved));
+                Some((book, saved))
+            }
+            None => None,
+        };
+
         let msock_opts = magicsock::Options {
             addr_v4: self.addr_v4,
             addr_v6: self.addr_v6,
             secret_key,
             relay_map,
             relay_protocol: self.relay_protocol,
-            node_map: self.node_map,
+            node_map,
             discovery,
             discovery_user_data: self.discovery_user_data,
             proxy_url: self.proxy_url,
@@ -201,9 +248,10 @@ impl Builder {
             insecure_skip_relay_cert_verify: self.insecure_skip_relay_cert_verify,
             #[cfg(any(test, feature = "test-utils"))]
             path_selection: self.path_selection,
+            multipath: self.multipath,
             metrics,
         };
-        Endpoint::bind(static_config, msock_opts).await
+        Endpoint::bind(static_config, msock_opts, address_book).await
     }
 
     // # The very common methods everyone basically needs.
@@ -390,6 +438,19 @@ impl Builder {
         self
     }
 
+    /// Only completes TLS handshakes with the authorized nodes.
+    ///
+    /// Incoming connections from nodes which are not authorized fail during the handshake,
+    /// so protocols never see them.  Connecting to such nodes fails right away.  See
+    /// [`AuthorizedNodes`] for details.
+    ///
+    /// Since resumed TLS sessions skip the verification of the remote node, session
+    /// resumption and with it 0-RTT are disabled when this is set.
+    pub fn authorized_nodes(mut self, nodes: AuthorizedNodes) -> Self {
+        self.authorized_nodes = Some(nodes);
+        self
+    }
+
     #[cfg(feature = "discovery-pkarr-dht")]
     /// Configures the endpoint to also use the mainline DHT with default settings.
     ///
@@ -450,6 +511,18 @@ impl Builder {
         self
     }
 
+    /// Sets an [`AddressBook`] to persist the addressing information of remote nodes.
+    ///
+    /// The stored nodes are loaded when the endpoint is bound and added with
+    /// [`Source::Saved`].  A snapshot of all known nodes is stored periodically and when
+    /// the endpoint is closed using [`Endpoint::close`].
+    ///
+    /// See the [`address_book`] module for details.
+    pub fn address_book(mut self, address_book: impl AddressBook) -> Self {
+        self.address_book = Some(Arc::new(address_book));
+        self
+    }
+
     // # Methods for more specialist customisation.
 
     /// Sets a custom [`quinn::TransportConfig`] for this endpoint.
@@ -468,6 +541,20 @@ impl Builder {
         self
     }
 
+    /// Sets whether to duplicate datagrams over the relay while using a direct path.
+    ///
+    /// For latency-critical traffic this trades bandwidth for lower tail latency: during
+    /// path transitions or loss on the direct path the datagrams also take the relay path,
+    /// so that a single lost datagram does not have to wait for a QUIC retransmit.  The
+    /// receiving side drops the duplicates.
+    ///
+    /// This is the default for all remote nodes, use [`Endpoint::set_multipath`] to change
+    /// it for a single node.  Defaults to [`MultipathMode::Disabled`].
+    pub fn multipath(mut self, mode: MultipathMode) -> Self {
+        self.multipath = mode;
+        self
+    }
+
     /// Optionally sets a custom DNS resolver to use for this endpoint.
     ///
     /// The DNS resolver is used to resolve relay hostnames, and node addresses if
@@ -584,6 +671,12 @@ pub struct Endpoint {
     rtt_actor: Arc<rtt_actor::RttHandle>,
     /// Configuration structs for quinn, holds the transport config, certificate setup, secret key etc.
     static_config: Arc<StaticConfig>,
+    /// Stores the known nodes in the configured [`AddressBook`], if any.
+    address_book: Option<Arc<AddressBookSaver>>,
+    /// Progress of draining the endpoint.
+    drain: Watchable<DrainState>,
+    /// The connections of the endpoint, to close idle connections while draining.
+    connections: Arc<Mutex<Vec<Weak<quinn::Connection>>>>,
 }
 
 impl Endpoint {
@@ -603,15 +696,24 @@ impl Endpoint {
     /// This is for internal use, the public interface is the [`Builder`] obtained from
     /// [Self::builder]. See the methods on the builder for documentation of the parameters.
     #[instrument("ep", skip_all, fields(me = %static_config.tls_config.secret_key.public().fmt_short()))]
-    async fn bind(static_config: StaticConfig, msock_opts: magicsock::Options) -> Result<Self> {
+    async fn bind(
+        static_config: StaticConfig,
+        msock_opts: magicsock::Options,
+        address_book: Option<(Arc<dyn AddressBook>, Vec<SavedNode>)>,
+    ) -> Result<Self> {
         let msock = magicsock::MagicSock::spawn(msock_opts).await?;
         trace!("created magicsock");
         debug!(version = env!("CARGO_PKG_VERSION"), "iroh Endpoint created");
 
+        let address_book = address_book
+            .map(|(book, saved)| Arc::new(AddressBookSaver::new(book, saved, msock.clone())));
         let ep = Self {
             msock: msock.clone(),
             rtt_actor: Arc::new(rtt_actor::RttHandle::new(msock.metrics.magicsock.clone())),
             static_config: Arc::new(static_config),
+            address_book,
+            drain: Watchable::new(DrainState::Running),
+            connections: Default::default(),
         };
         Ok(ep)
     }
@@ -703,6 +805,16 @@ impl Endpoint {
             );
         }
 
+        // Fail early instead of in the handshake, and wait for an async authorization.
+        if let Some(authorized) = self.static_config.tls_config.authorized_nodes() {
+            if !authorized.authorize(node_addr.node_id).await {
+                bail!(
+                    "Connecting to unauthorized node {} is not allowed",
+                    node_addr.node_id.fmt_short()
+                );
+            }
+        }
+
         if !node_addr.is_empty() {
             self.add_node_addr(node_addr.clone())?;
         }
@@ -1097,6 +1209,49 @@ impl Endpoint {
         self.msock.conn_type(node_id)
     }
 
+    /// Returns a stream of [`PathEvent`]s for the given remote node.
+    ///
+    /// Where [`Endpoint::conn_type`] only exposes the latest [`ConnectionType`], this stream
+    /// explains how it came about: holepunching attempts, call-me-maybe messages, pongs and
+    /// ping timeouts per path, changes of the selected direct address and the reasons paths
+    /// were dropped.  Unlike the [`Watcher`] every event is yielded, unless the stream is not
+    /// polled fast enough in which case [`Lagged`] is yielded to indicate missed events.
+    ///
+    /// Only events which happen after subscribing are yielded, use
+    /// [`Endpoint::path_event_history`] to retrieve the recent past.  The stream ends when
+    /// the endpoint forgets about the remote node.
+    ///
+    /// # Errors
+    ///
+    /// Will error if we do not have any address information for the given `node_id`.
+    pub fn path_events(
+        &self,
+        node_id: NodeId,
+    ) -> Result<impl Stream<Item = Result<PathEvent, Lagged>>> {
+        self.msock.path_events(node_id)
+    }
+
+    /// Sets the [`MultipathMode`] for a single remote node.
+    ///
+    /// Overrides the mode configured using [`Builder::multipath`] for this node, passing
+    /// `None` reverts to that default.  Enabling multipath for a node also enables dropping
+    /// duplicate datagrams received by this endpoint.
+    ///
+    /// # Errors
+    ///
+    /// Will error if we do not have any address information for the given `node_id`.
+    pub fn set_multipath(&self, node_id: NodeId, mode: Option<MultipathMode>) -> Result<()> {
+        self.msock.set_multipath(node_id, mode)
+    }
+
+    /// Returns the most recent [`PathEvent`]s for the given remote node, oldest first.
+    ///
+    /// Only a limited number of events is kept for each node.  Returns `None` if we do not
+    /// have any address information for the given `node_id`.
+    pub fn path_event_history(&self, node_id: NodeId) -> Option<Vec<PathEvent>> {
+        self.msock.path_event_history(node_id)
+    }
+
     /// Returns the DNS resolver used in this [`Endpoint`].
     ///
     /// See [`Builder::dns_resolver`].
@@ -1289,6 +1444,9 @@ impl Endpoint {
     /// while TCP sockets usually get closed and drained by the operating system in the
     /// kernel during the "Time-Wait" period of the TCP socket.
     ///
+    /// If an [`AddressBook`] is configured, a final snapshot of the known nodes is stored
+    /// before any connections are closed.
+    ///
     /// Be aware however that the underlying UDP sockets are only closed once all clones of
     /// the the respective [`Endpoint`] are dropped.
     pub async fn close(&self) {
@@ -1296,8 +1454,155 @@ impl Endpoint {
             return;
         }
 
+        if let Some(ref address_book) = self.address_book {
+            address_book.save_now().await;
+        }
+
         tracing::debug!("Connections closed");
         self.msock.close().await;
+        self.drain.set(DrainState::Closed).ok();
+    }
+
+    /// Starts draining the endpoint.
+    ///
+    /// A draining endpoint refuses new incoming connections in [`Endpoint::accept`] and no
+    /// longer publishes its address to discovery, while existing connections keep working.
+    /// When the endpoint is closed afterwards, remaining connections are closed with
+    /// [`DRAIN_CLOSE_CODE`] instead of `0`.
+    ///
+    /// Only [`Endpoint::drain`] closes idle connections early, starting to drain does not
+    /// close any connection.
+    ///
+    /// Use [`Endpoint::drain`] to also wait for the connections to finish and close the
+    /// endpoint.
+    pub fn start_draining(&self) {
+        if self.is_closed() || !self.msock.start_draining() {
+            return;
+        }
+        debug!("Draining endpoint");
+        let connections = self.msock.endpoint().open_connections();
+        self.drain.set(DrainState::Draining { connections }).ok();
+    }
+
+    /// Drains the endpoint, then closes it.
+    ///
+    /// This is a graceful alternative to [`Endpoint::close`], e.g. for rolling deploys.  It
+    /// starts draining with [`Endpoint::start_draining`] and waits up to `timeout` for all
+    /// connections to be closed, by either side, before closing the endpoint and any
+    /// remaining connections.  The progress can be observed with [`Endpoint::drain_state`].
+    ///
+    /// While waiting, connections on which no stream data was sent or received for one
+    /// second are closed with [`DRAIN_CLOSE_CODE`], so idle connections do not hold up the
+    /// drain until the timeout.
+    pub async fn drain(&self, timeout: Duration) {
+        self.start_draining();
+        self.wait_drained(timeout).await;
+        self.close().await;
+    }
+
+    /// Waits up to `timeout` for all connections to be closed, updating the [`DrainState`].
+    ///
+    /// Closes idle connections, see [`Endpoint::close_idle_connections`].  Returns early
+    /// once the endpoint is closed.  A `timeout` too large to be represented waits without a
+    /// deadline.
+    pub(crate) async fn wait_drained(&self, timeout: Duration) {
+        let deadline = Instant::now().checked_add(timeout);
+        let mut activity = HashMap::new();
+        // Accepting refuses incoming connections while draining, and only returns once the
+        // endpoint is closed.
+        let mut accept = std::pin::pin!(self.accept());
+        loop {
+            if self.is_closed() {
+                break;
+            }
+            self.close_idle_connections(&mut activity);
+            let connections = self.msock.endpoint().open_connections();
+            self.drain.set(DrainState::Draining { connections }).ok();
+            let now = Instant::now();
+            if connections == 0 || deadline.is_some_and(|deadline| now >= deadline) {
+                break;
+            }
+            let interval = match deadline {
+                Some(deadline) => DRAIN_POLL_INTERVAL.min(deadline - now),
+                None => DRAIN_POLL_INTERVAL,
+            };
+            tokio::select! {
+                _ = time::sleep(interval) => {}
+                _ = &mut accept => break,
+            }
+        }
+    }
+
+    /// Closes the connections without stream activity for [`DRAIN_IDLE_TIMEOUT`] with
+    /// [`DRAIN_CLOSE_CODE`].
+    ///
+    /// `activity` keeps the number of stream frames of each connection, and when it last
+    /// changed, between calls.  Connections are only tracked while a [`Connection`] handle
+    /// exists, connections only kept alive by their streams wait for the drain timeout.
+    fn close_idle_connections(&self, activity: &mut HashMap<usize, (u64, Instant)>) {
+        let now = Instant::now();
+        let connections: Vec<_> = {
+            let mut connections = self.connections.lock().expect("poisoned");
+            connections.retain(|conn| conn.strong_count() > 0);
+            connections.iter().filter_map(Weak::upgrade).collect()
+        };
+        activity.retain(|id, _| connections.iter().any(|conn| conn.stable_id() == *id));
+        for conn in connections {
+            if conn.close_reason().is_some() {
+                continue;
+            }
+            let stats = conn.stats();
+            let frames = [stats.frame_rx, stats.frame_tx]
+                .iter()
+                .map(|frames| frames.stream + frames.reset_stream + frames.stop_sending)
+                .sum();
+            let (last_frames, last_active) =
+                activity.entry(conn.stable_id()).or_insert((frames, now));
+            if *last_frames != frames {
+                *last_frames = frames;
+                *last_active = now;
+            } else if now.duration_since(*last_active) >= DRAIN_IDLE_TIMEOUT {
+                debug!(remote = ?conn.remote_address(), "Closing idle connection while draining");
+                conn.close(DRAIN_CLOSE_CODE, DRAIN_CLOSE_REASON);
+            }
+        }
+    }
+
+    /// Starts an async authorization of the node an incoming connection attempt likely comes
+    /// from, so that its result is ready for the handshake.
+    ///
+    /// See [`AuthorizedNodes::from_async_fn`].
+    fn prepare_authorization(&self, remote_addr: SocketAddr) {
+        if let Some(authorized @ AuthorizedNodes::Async(_)) =
+            self.static_config.tls_config.authorized_nodes()
+        {
+            if let Some(node_id) = self.msock.mapped_node_id(remote_addr) {
+                // Refreshes the cached result if needed.
+                authorized.is_authorized(&node_id);
+            }
+        }
+    }
+
+    /// Closes all connections with [`DRAIN_CLOSE_CODE`], without closing the endpoint.
+    ///
+    /// Used to close connections before their handles are dropped, which would close them
+    /// with `0`.
+    pub(crate) fn close_drained_connections(&self) {
+        self.msock
+            .endpoint()
+            .close(DRAIN_CLOSE_CODE, DRAIN_CLOSE_REASON);
+    }
+
+    /// Returns a [`Watcher`] for the progress of draining the endpoint.
+    ///
+    /// See [`Endpoint::drain`].
+    pub fn drain_state(&self) -> Watcher<DrainState> {
+        self.drain.watch()
+    }
+
+    /// Checks if this endpoint is draining or closed after draining.
+    pub fn is_draining(&self) -> bool {
+        self.msock.is_draining()
     }
 
     /// Check if this endpoint is still alive, or already closed.
@@ -1384,6 +1689,26 @@ impl Endpoint {
     }
 }
 
+/// The progress of draining an [`Endpoint`] or a [`Router`].
+///
+/// See [`Endpoint::drain`] and [`Router::drain`].
+///
+/// [`Router`]: crate::protocol::Router
+/// [`Router::drain`]: crate::protocol::Router::drain
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DrainState {
+    /// Not draining, new connections are accepted.
+    #[default]
+    Running,
+    /// New connections are refused while waiting for the open connections to finish.
+    Draining {
+        /// The number of connections still open.
+        connections: usize,
+    },
+    /// Closed, either after draining or without.
+    Closed,
+}
+
 /// Options for the [`Endpoint::connect_with_opts`] function.
 #[derive(Default, Debug, Clone)]
 pub struct ConnectOptions {
@@ -1446,14 +1771,23 @@ impl Future for Accept<'_> {
     type Output = Option<Incoming>;
 
     fn poll(self: Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> Poll<Self::Output> {
-        let this = self.project();
-        match this.inner.poll(cx) {
-            Poll::Pending => Poll::Pending,
-            Poll::Ready(None) => Poll::Ready(None),
-            Poll::Ready(Some(inner)) => Poll::Ready(Some(Incoming {
-                inner,
-                ep: this.ep.clone(),
-            })),
+        let mut this = self.project();
+        loop {
+            match this.inner.as_mut().poll(cx) {
+                Poll::Pending => return Poll::Pending,
+                Poll::Ready(None) => return Poll::Ready(None),
+                Poll::Ready(Some(inner)) if this.ep.is_draining() => {
+                    trace!("Refusing incoming connection: endpoint is draining");
+                    inner.refuse();
+                }
+                Poll::Ready(Some(inner)) => {
+                    this.ep.prepare_authorization(inner.remote_address());
+                    return Poll::Ready(Some(Incoming {
+                        inner,
+                        ep: this.ep.clone(),
+                    }));
+                }
+            }
         }
     }
 }
@@ -1530,10 +1864,29 @@ impl Incoming {
     }
 
     /// Returns the peer's UDP address.
+    ///
+    /// For iroh connections this is the address used on the QUIC layer, which is not the
+    /// peer's real address.  Use [`Incoming::remote_path`] for the path the connection
+    /// attempt was received on.
     pub fn remote_address(&self) -> SocketAddr {
         self.inner.remote_address()
     }
 
+    /// Returns the path this connection attempt was received on.
+    ///
+    /// This is the peer's UDP address if the attempt was received directly, or the relay URL
+    /// if it was received via a relay server.  It is determined from the last datagram
+    /// received from the peer before the handshake, so it can not be trusted in the same way
+    /// as the peer's [`NodeId`] once the handshake completed.
+    ///
+    /// Returns `None` if the path is not known.
+    pub fn remote_path(&self) -> Option<NetworkPath> {
+        self.ep
+            .msock
+            .last_received_path(self.inner.remote_address())
+            .map(Into::into)
+    }
+
     /// Whether the socket address that is initiating this connection has been validated.
     ///
     /// This means that the sender of the initial packet has proved that they can receive
@@ -1573,10 +1926,7 @@ impl Future for IncomingFuture {
             Poll::Pending => Poll::Pending,
             Poll::Ready(Err(err)) => Poll::Ready(Err(err)),
             Poll::Ready(Ok(inner)) => {
-                let conn = Connection {
-                    inner,
-                    tls_auth: this.ep.static_config.tls_config.auth,
-                };
+                let conn = Connection::new(inner, this.ep);
                 try_send_rtt_msg(&conn, this.ep, None);
                 Poll::Ready(Ok(conn))
             }
@@ -1646,10 +1996,7 @@ impl Connecting {
     pub fn into_0rtt(self) -> Result<(Connection, ZeroRttAccepted), Self> {
         match self.inner.into_0rtt() {
             Ok((inner, zrtt_accepted)) => {
-                let conn = Connection {
-                    inner,
-                    tls_auth: self.ep.static_config.tls_config.auth,
-                };
+                let conn = Connection::new(inner, &self.ep);
                 let zrtt_accepted = ZeroRttAccepted {
                     inner: zrtt_accepted,
                     _discovery_drop_guard: self._discovery_drop_guard,
@@ -1701,10 +2048,7 @@ impl Future for Connecting {
             Poll::Pending => Poll::Pending,
             Poll::Ready(Err(err)) => Poll::Ready(Err(err)),
             Poll::Ready(Ok(inner)) => {
-                let conn = Connection {
-                    inner,
-                    tls_auth: this.ep.static_config.tls_config.auth,
-                };
+                let conn = Connection::new(inner, this.ep);
                 try_send_rtt_msg(&conn, this.ep, *this.remote_node_id);
                 Poll::Ready(Ok(conn))
             }
@@ -1752,11 +2096,24 @@ impl Future for ZeroRttAccepted {
 /// May be cloned to obtain another handle to the same connection.
 #[derive(Debug, Clone)]
 pub struct Connection {
-    inner: quinn::Connection,
+    inner: Arc<quinn::Connection>,
     tls_auth: tls::Authentication,
 }
 
 impl Connection {
+    /// Wraps a new connection, and tracks it in the endpoint to close it when idle while
+    /// draining.
+    fn new(inner: quinn::Connection, ep: &Endpoint) -> Self {
+        let inner = Arc::new(inner);
+        let mut connections = ep.connections.lock().expect("poisoned");
+        connections.retain(|conn| conn.strong_count() > 0);
+        connections.push(Arc::downgrade(&inner));
+        Self {
+            inner,
+            tls_auth: ep.static_config.tls_config.auth,
+        }
+    }
+
     /// Initiates a new outgoing unidirectional stream.
     ///
     /// Streams are cheap and instantaneous to open unless blocked by flow control. As a
@@ -2175,7 +2532,10 @@ fn is_cgi() -> bool {
 #[cfg(test)]
 mod tests {
 
-    use std::time::Instant;
+    use std::{
+        sync::atomic::{AtomicUsize, Ordering},
+        time::Instant,
+    };
 
     use iroh_metrics::MetricsSource;
     use iroh_relay::http::Protocol;
@@ -2357,6 +2717,82 @@ mod tests {
         assert_eq!(conn_addr, direct_addr);
     }
 
+    /// Test that peers are restored from an [`AddressBook`].
+    #[tokio::test]
+    #[traced_test]
+    async fn restore_peers_address_book() -> TestResult {
+        let secret_key = SecretKey::generate(rand::thread_rng());
+        let address_book = address_book::MemoryAddressBook::new();
+
+        let peer_id = SecretKey::generate(rand::thread_rng()).public();
+        let direct_addr: SocketAddr =
+            (std::net::IpAddr::V4(std::net::Ipv4Addr::LOCALHOST), 8758u16).into();
+        let node_addr = NodeAddr::new(peer_id).with_direct_addresses([direct_addr]);
+
+        let endpoint = Endpoint::builder()
+            .secret_key(secret_key.clone())
+            .address_book(address_book.clone())
+            .bind()
+            .await?;
+        endpoint.add_node_addr(node_addr)?;
+        endpoint.close().await;
+        assert_eq!(address_book.nodes().len(), 1);
+
+        let endpoint = Endpoint::builder()
+            .secret_key(secret_key)
+            .address_book(address_book.clone())
+            .bind()
+            .await?;
+        let info = endpoint.remote_info(peer_id).context("peer not restored")?;
+        assert_eq!(info.addrs.len(), 1);
+        assert_eq!(info.addrs[0].addr, direct_addr);
+        assert!(info.addrs[0].sources.contains_key(&Source::Saved));
+        endpoint.close().await;
+        Ok(())
+    }
+
+    /// Test that endpoints relay over QUIC once UDP is known to work.
+    #[tokio::test]
+    #[traced_test]
+    async fn endpoint_relay_over_quic() -> TestResult {
+        let (relay_map, relay_url, server) = run_relay_server().await?;
+        let new_ep = || {
+            Endpoint::builder()
+                .insecure_skip_relay_cert_verify(true)
+                .alpns(vec![TEST_ALPN.to_vec()])
+                .relay_mode(RelayMode::Custom(relay_map.clone()))
+                .bind()
+        };
+        let ep1 = new_ep().await?;
+        let ep2 = new_ep().await?;
+        ep1.home_relay().initialized().await?;
+        ep2.home_relay().initialized().await?;
+
+        let accept = tokio::spawn({
+            let ep1 = ep1.clone();
+            async move {
+                let conn = ep1.accept().await.context("no incoming")?.await?;
+                let (mut send, mut recv) = conn.accept_bi().await?;
+                let msg = recv.read_to_end(100).await?;
+                send.write_all(&msg).await?;
+                send.finish()?;
+                conn.closed().await;
+                anyhow::Ok(())
+            }
+        });
+        let addr = NodeAddr::new(ep1.node_id()).with_relay_url(relay_url);
+        let conn = ep2.connect(addr, TEST_ALPN).await?;
+        let (mut send, mut recv) = conn.open_bi().await?;
+        send.write_all(b"hello").await?;
+        send.finish()?;
+        assert_eq!(recv.read_to_end(100).await?, b"hello");
+        conn.close(0u32.into(), b"done");
+        accept.await??;
+
+        assert!(server.metrics().server.quic_relay_accepts.get() >= 2);
+        Ok(())
+    }
+
     #[tokio::test]
     #[traced_test]
     async fn endpoint_relay_connect_loop() {
@@ -3047,6 +3483,252 @@ mod tests {
         Ok(server_alpn)
     }
 
+    /// Accepts all connection attempts, returning the results of their handshakes.
+    fn accept_all(
+        ep: Endpoint,
+    ) -> tokio::sync::mpsc::UnboundedReceiver<Result<Connection, ConnectionError>> {
+        let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            while let Some(incoming) = ep.accept().await {
+                tx.send(incoming.await).ok();
+            }
+        });
+        rx
+    }
+
+    /// Returns the error of a connection whose remote did not authorize this node.
+    async fn rejected(res: Result<Connection>) -> ConnectionError {
+        match res {
+            // The client completes its side of the handshake before the server verifies it.
+            Ok(conn) => conn.closed().await,
+            Err(err) => err.downcast().expect("not a connection error"),
+        }
+    }
+
+    /// Returns whether the handshake failed with the TLS `access_denied` alert, which is sent
+    /// for unauthorized nodes.
+    fn is_access_denied(err: &ConnectionError) -> bool {
+        let code = match err {
+            ConnectionError::ConnectionClosed(close) => close.error_code,
+            ConnectionError::TransportError(err) => err.code,
+            _ => return false,
+        };
+        code == TransportErrorCode::crypto(0x31)
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn endpoint_authorized_nodes() -> TestResult {
+        const ALPN: &[u8] = b"authorized/1";
+        let allowed_key = SecretKey::generate(rand::rngs::OsRng);
+        let server = Endpoint::builder()
+            .alpns(vec![ALPN.to_vec()])
+            .relay_mode(RelayMode::Disabled)
+            .authorized_nodes(AuthorizedNodes::from_nodes([allowed_key.public()]))
+            .bind()
+            .await?;
+        let allowed = Endpoint::builder()
+            .secret_key(allowed_key)
+            .relay_mode(RelayMode::Disabled)
+            .bind()
+            .await?;
+        let other = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .bind()
+            .await?;
+        let server_addr = server.node_addr().await?;
+        let mut accepted = accept_all(server.clone());
+
+        let conn = allowed.connect(server_addr.clone(), ALPN).await?;
+        let server_conn = accepted.recv().await.unwrap()?;
+        assert_eq!(server_conn.remote_node_id()?, allowed.node_id());
+        conn.close(0u32.into(), b"done");
+
+        let err = rejected(other.connect(server_addr.clone(), ALPN).await).await;
+        assert!(is_access_denied(&err), "{err:?}");
+        let err = accepted.recv().await.unwrap().unwrap_err();
+        assert!(is_access_denied(&err), "{err:?}");
+
+        // Nodes authorized by a function can change.
+        let nodes = Arc::new(std::sync::Mutex::new(BTreeSet::new()));
+        let dynamic = Endpoint::builder()
+            .alpns(vec![ALPN.to_vec()])
+            .relay_mode(RelayMode::Disabled)
+            .authorized_nodes(AuthorizedNodes::from_fn({
+                let nodes = nodes.clone();
+                move |node_id| nodes.lock().unwrap().contains(node_id)
+            }))
+            .bind()
+            .await?;
+        let dynamic_addr = dynamic.node_addr().await?;
+        let mut accepted = accept_all(dynamic.clone());
+
+        let err = rejected(other.connect(dynamic_addr.clone(), ALPN).await).await;
+        assert!(is_access_denied(&err), "{err:?}");
+        assert!(is_access_denied(
+            &accepted.recv().await.unwrap().unwrap_err()
+        ));
+        nodes.lock().unwrap().insert(other.node_id());
+        let conn = other.connect(dynamic_addr, ALPN).await?;
+        let server_conn = accepted.recv().await.unwrap()?;
+        assert_eq!(server_conn.remote_node_id()?, other.node_id());
+        conn.close(0u32.into(), b"done");
+
+        // The results of an async function are cached, and can be prepared before a node
+        // connects.
+        let calls = Arc::new(AtomicUsize::new(0));
+        let authorized = AuthorizedNodes::from_async_fn(Duration::from_secs(60), {
+            let calls = calls.clone();
+            move |node_id| {
+                calls.fetch_add(1, Ordering::Relaxed);
+                let authorized = nodes.lock().unwrap().contains(&node_id);
+                async move { authorized }
+            }
+        });
+        let async_server = Endpoint::builder()
+            .alpns(vec![ALPN.to_vec()])
+            .relay_mode(RelayMode::Disabled)
+            .authorized_nodes(authorized.clone())
+            .bind()
+            .await?;
+        let async_addr = async_server.node_addr().await?;
+        let mut accepted = accept_all(async_server.clone());
+
+        assert!(authorized.authorize(other.node_id()).await);
+        let conn = other.connect(async_addr.clone(), ALPN).await?;
+        let server_conn = accepted.recv().await.unwrap()?;
+        assert_eq!(server_conn.remote_node_id()?, other.node_id());
+        conn.close(0u32.into(), b"done");
+
+        assert!(!authorized.authorize(allowed.node_id()).await);
+        let err = rejected(allowed.connect(async_addr.clone(), ALPN).await).await;
+        assert!(is_access_denied(&err), "{err:?}");
+        assert!(is_access_denied(
+            &accepted.recv().await.unwrap().unwrap_err()
+        ));
+        assert_eq!(calls.load(Ordering::Relaxed), 2);
+
+        // Outgoing connections to unauthorized nodes fail before connecting.
+        let client = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .authorized_nodes(AuthorizedNodes::from_async_fn(Duration::ZERO, |_| async {
+                false
+            }))
+            .bind()
+            .await?;
+        let err = client.connect(async_addr, ALPN).await.unwrap_err();
+        assert!(format!("{err:#}").contains("unauthorized node"), "{err:#}");
+
+        client.close().await;
+        other.close().await;
+        allowed.close().await;
+        server.close().await;
+        dynamic.close().await;
+        async_server.close().await;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn endpoint_drain() -> TestResult {
+        const ALPN: &[u8] = b"drain/1";
+        let server = Endpoint::builder()
+            .alpns(vec![ALPN.to_vec()])
+            .relay_mode(RelayMode::Disabled)
+            .bind()
+            .await?;
+        let client = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .bind()
+            .await?;
+        let server_addr = server.node_addr().await?;
+        let server_task = tokio::spawn({
+            let server = server.clone();
+            async move {
+                let conn = server.accept().await.unwrap().await?;
+                // Keep the connection open while draining.
+                server.drain(Duration::from_millis(500)).await;
+                drop(conn);
+                TestResult::Ok(())
+            }
+        });
+
+        let conn = client.connect(server_addr.clone(), ALPN).await?;
+        let mut drain = server.drain_state();
+        while !matches!(drain.updated().await?, DrainState::Draining { .. }) {}
+        assert!(server.is_draining());
+
+        // New connections are refused while draining.
+        let err = client.connect(server_addr, ALPN).await.unwrap_err();
+        assert!(format!("{err:#}").contains("refused"), "{err:#}");
+
+        // The open connection is closed with the drain code.
+        let ConnectionError::ApplicationClosed(close) = conn.closed().await else {
+            panic!("connection not closed by application");
+        };
+        assert_eq!(close.error_code, DRAIN_CLOSE_CODE);
+        assert_eq!(&close.reason[..], DRAIN_CLOSE_REASON);
+
+        server_task.await??;
+        assert_eq!(server.drain_state().get()?, DrainState::Closed);
+
+        // Draining without a deadline finishes once the connections are gone.
+        client.drain(Duration::MAX).await;
+        assert_eq!(client.drain_state().get()?, DrainState::Closed);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn endpoint_drain_idle_connection() -> TestResult {
+        const ALPN: &[u8] = b"drain/1";
+        let server = Endpoint::builder()
+            .alpns(vec![ALPN.to_vec()])
+            .relay_mode(RelayMode::Disabled)
+            .bind()
+            .await?;
+        let client = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .bind()
+            .await?;
+        let server_addr = server.node_addr().await?;
+        let server_task = tokio::spawn({
+            let server = server.clone();
+            async move {
+                let conn = server.accept().await.unwrap().await?;
+                let (mut send, mut recv) = conn.accept_bi().await?;
+                let data = recv.read_to_end(100).await?;
+                send.write_all(&data).await?;
+                send.finish()?;
+                // Keep the now idle connection open while draining.
+                let start = Instant::now();
+                server.drain(Duration::from_secs(30)).await;
+                drop(conn);
+                TestResult::Ok(start.elapsed())
+            }
+        });
+
+        let conn = client.connect(server_addr, ALPN).await?;
+        let (mut send, mut recv) = conn.open_bi().await?;
+        send.write_all(b"hello").await?;
+        send.finish()?;
+        assert_eq!(recv.read_to_end(100).await?, b"hello");
+
+        // The idle connection is closed with the drain code, ending the drain early.
+        let ConnectionError::ApplicationClosed(close) = conn.closed().await else {
+            panic!("connection not closed by application");
+        };
+        assert_eq!(close.error_code, DRAIN_CLOSE_CODE);
+        let elapsed = server_task.await??;
+        assert!(elapsed < Duration::from_secs(10), "drain took {elapsed:?}");
+        assert_eq!(server.drain_state().get()?, DrainState::Closed);
+
+        client.close().await;
+        Ok(())
+    }
+
     #[tokio::test]
     #[traced_test]
     async fn connect_multiple_alpn_negotiated() -> testresult::TestResult {
@@ -0,0 +1,511 @@
+//! Persisting the addressing information of remote nodes across restarts.
+//!
+//! An [`Endpoint`] learns direct addresses and relay URLs of remote nodes while it is
+//! running.  Without an [`AddressBook`] all of this is forgotten once the endpoint is
+//! closed, and the next run has to go through discovery again before it can dial any of
+//! these nodes.
+//!
+//! When an [`AddressBook`] is configured using [`Builder::address_book`], the endpoint
+//! will:
+//!
+//! - Load the stored nodes on [`Builder::bind`] and add them to its internal address book
+//!   with [`Source::Saved`] as provenance.  Addresses that were last seen longer than
+//!   [`MAX_SAVED_ADDR_AGE`] ago are not restored.
+//! - Store a snapshot of all known nodes every [`SAVE_INTERVAL`].
+//! - Store a final snapshot when [`Endpoint::close`] is called.
+//!
+//! The [`FsAddressBook`] stores the snapshot in a file, the [`MemoryAddressBook`] keeps it
+//! in memory which is mostly useful for tests.
+//!
+//! [`Endpoint`]: crate::Endpoint
+//! [`Endpoint::close`]: crate::Endpoint::close
+//! [`Builder::address_book`]: super::Builder::address_book
+//! [`Builder::bind`]: super::Builder::bind
+//! [`Source::Saved`]: super::Source::Saved
+
+#[cfg(not(wasm_browser))]
+use std::path::PathBuf;
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{Arc, Mutex, RwLock},
+};
+
+use anyhow::Result;
+use iroh_base::{NodeAddr, NodeId, RelayUrl};
+use n0_future::{
+    boxed::BoxFuture,
+    task::{self, AbortOnDropHandle},
+    time::{self, Duration, SystemTime},
+};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, info_span, trace, warn, Instrument};
+
+use super::{RemoteInfo, Source};
+use crate::magicsock::Handle;
+
+/// How often the endpoint stores a snapshot of the known nodes.
+pub const SAVE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Direct addresses last seen longer ago than this are not restored.
+///
+/// NAT mappings and DHCP leases change, so old addresses are more likely to cost a failed
+/// holepunching attempt than to be useful.
+pub const MAX_SAVED_ADDR_AGE: Duration = Duration::from_secs(60 * 60 * 24);
+
+/// Nodes not seen longer ago than this are not restored at all, including their relay URL.
+pub const MAX_SAVED_NODE_AGE: Duration = Duration::from_secs(60 * 60 * 24 * 7);
+
+/// Storage for the addressing information of remote nodes.
+///
+/// See the [module docs](self) for how an [`Endpoint`] uses this.
+///
+/// [`Endpoint`]: crate::Endpoint
+pub trait AddressBook: std::fmt::Debug + Send + Sync + 'static {
+    /// Loads the previously stored nodes.
+    ///
+    /// If nothing was stored yet this should return an empty list, not an error.
+    fn load(&self) -> BoxFuture<Result<Vec<SavedNode>>>;
+
+    /// Stores the given nodes, replacing any previously stored nodes.
+    fn save(&self, nodes: Vec<SavedNode>) -> BoxFuture<Result<()>>;
+}
+
+impl<T: AddressBook> AddressBook for Arc<T> {
+    fn load(&self) -> BoxFuture<Result<Vec<SavedNode>>> {
+        self.as_ref().load()
+    }
+
+    fn save(&self, nodes: Vec<SavedNode>) -> BoxFuture<Result<()>> {
+        self.as_ref().save(nodes)
+    }
+}
+
+/// The stored addressing information of a single remote node.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SavedNode {
+    /// The ID of the remote node.
+    pub node_id: NodeId,
+    /// The home relay of the remote node, if known.
+    pub relay_url: Option<RelayUrl>,
+    /// The direct addresses of the remote node.
+    pub direct_addrs: Vec<SavedAddr>,
+    /// The latency of the path in use at the time of the snapshot.
+    pub latency: Option<Duration>,
+    /// When the node was last used, or when its addressing information was last learned if
+    /// it was never used.
+    #[serde(with = "unix_secs")]
+    pub last_seen: SystemTime,
+}
+
+/// A stored direct address of a remote node.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SavedAddr {
+    /// The UDP address.
+    pub addr: SocketAddr,
+    /// The latency over this address, if there ever was connectivity.
+    pub latency: Option<Duration>,
+    /// When this address was last confirmed to exist.
+    #[serde(with = "unix_secs")]
+    pub last_seen: SystemTime,
+}
+
+impl SavedNode {
+    /// Creates a [`SavedNode`] from the current information about a remote node.
+    ///
+    /// Restoring information from an address book does not count as seeing it: timestamps
+    /// which are only known from [`Source::Saved`] are taken from `previous`, the record the
+    /// node was restored from.  Direct addresses without any timestamp are dropped.
+    ///
+    /// Returns `None` if there is no way to reach the node.
+    pub fn from_remote_info(
+        info: RemoteInfo,
+        previous: Option<&SavedNode>,
+        now: SystemTime,
+    ) -> Option<Self> {
+        if !info.has_send_address() {
+            return None;
+        }
+        let since = |elapsed: Duration| now.checked_sub(elapsed).unwrap_or(now);
+        let mut learned: Option<Duration> = info.relay_url.as_ref().and_then(|r| r.last_alive);
+        let direct_addrs = info
+            .addrs
+            .into_iter()
+            .filter_map(|addr| {
+                let elapsed = addr.last_alive.or_else(|| {
+                    addr.sources
+                        .iter()
+                        .filter(|(source, _)| **source != Source::Saved)
+                        .map(|(_, elapsed)| *elapsed)
+                        .min()
+                });
+                let last_seen = match elapsed {
+                    Some(elapsed) => {
+                        learned = Some(learned.map_or(elapsed, |l| l.min(elapsed)));
+                        since(elapsed)
+                    }
+                    None => {
+                        previous?
+                            .direct_addrs
+                            .iter()
+                            .find(|saved| saved.addr == addr.addr)?
+                            .last_seen
+                    }
+                };
+                Some(SavedAddr {
+                    addr: addr.addr,
+                    latency: addr.latency,
+                    last_seen,
+                })
+            })
+            .collect();
+        // A node that was never used keeps the timestamp it was restored with, unless new
+        // addressing information was learned since.  Only a node which was neither used nor
+        // restored and came without any timestamps counts as seen now.
+        let last_seen = match (info.last_used.or(learned), previous) {
+            (Some(elapsed), _) => since(elapsed),
+            (None, Some(previous)) => previous.last_seen,
+            (None, None) => now,
+        };
+        Some(Self {
+            node_id: info.node_id,
+            relay_url: info.relay_url.map(Into::into),
+            direct_addrs,
+            latency: info.latency,
+            last_seen,
+        })
+    }
+
+    /// Converts this into a [`NodeAddr`], dropping information that is too old.
+    ///
+    /// Direct addresses older than `max_addr_age` are removed.  If the node itself is older
+    /// than `max_node_age`, or no addressing information remains, `None` is returned.
+    pub fn into_node_addr(
+        self,
+        now: SystemTime,
+        max_addr_age: Duration,
+        max_node_age: Duration,
+    ) -> Option<NodeAddr> {
+        let age = |t: SystemTime| now.duration_since(t).unwrap_or_default();
+        if age(self.last_seen) > max_node_age {
+            return None;
+        }
+        let addr = NodeAddr::from_parts(
+            self.node_id,
+            self.relay_url,
+            self.direct_addrs
+                .into_iter()
+                .filter(|addr| age(addr.last_seen) <= max_addr_age)
+                .map(|addr| addr.addr),
+        );
+        (!addr.is_empty()).then_some(addr)
+    }
+}
+
+/// An [`AddressBook`] storing the nodes in a file.
+///
+/// The file is replaced atomically on every save, so a crash while saving leaves the
+/// previous snapshot intact.
+#[cfg(not(wasm_browser))]
+#[derive(Debug, Clone)]
+pub struct FsAddressBook {
+    path: PathBuf,
+}
+
+#[cfg(not(wasm_browser))]
+impl FsAddressBook {
+    /// Creates a new address book stored at `path`.
+    ///
+    /// The file does not need to exist yet, it will be created on the first save.
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+
+    /// Returns the path of the file the nodes are stored in.
+    pub fn path(&self) -> &std::path::Path {
+        &self.path
+    }
+}
+
+#[cfg(not(wasm_browser))]
+impl AddressBook for FsAddressBook {
+    fn load(&self) -> BoxFuture<Result<Vec<SavedNode>>> {
+        let path = self.path.clone();
+        Box::pin(async move {
+            let bytes = match tokio::fs::read(&path).await {
+                Ok(bytes) => bytes,
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+                Err(err) => return Err(err.into()),
+            };
+            let nodes = postcard::from_bytes(&bytes)?;
+            Ok(nodes)
+        })
+    }
+
+    fn save(&self, nodes: Vec<SavedNode>) -> BoxFuture<Result<()>> {
+        let path = self.path.clone();
+        Box::pin(async move {
+            let bytes = postcard::to_stdvec(&nodes)?;
+            if let Some(parent) = path.parent() {
+                tokio::fs::create_dir_all(parent).await?;
+            }
+            let tmp_path = path.with_extension("tmp");
+            tokio::fs::write(&tmp_path, bytes).await?;
+            tokio::fs::rename(&tmp_path, &path).await?;
+            Ok(())
+        })
+    }
+}
+
+/// An [`AddressBook`] keeping the nodes in memory.
+///
+/// Clones share the same storage, so this can be used to carry nodes from one
+/// [`Endpoint`] to the next within the same process.
+///
+/// [`Endpoint`]: crate::Endpoint
+#[derive(Debug, Default, Clone)]
+pub struct MemoryAddressBook {
+    nodes: Arc<RwLock<Vec<SavedNode>>>,
+}
+
+impl MemoryAddressBook {
+    /// Creates a new, empty address book.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the currently stored nodes.
+    pub fn nodes(&self) -> Vec<SavedNode> {
+        self.nodes.read().expect("poisoned").clone()
+    }
+}
+
+impl AddressBook for MemoryAddressBook {
+    fn load(&self) -> BoxFuture<Result<Vec<SavedNode>>> {
+        let nodes = self.nodes();
+        Box::pin(async move { Ok(nodes) })
+    }
+
+    fn save(&self, nodes: Vec<SavedNode>) -> BoxFuture<Result<()>> {
+        *self.nodes.write().expect("poisoned") = nodes;
+        Box::pin(async move { Ok(()) })
+    }
+}
+
+/// Loads the nodes from `book`.
+pub(super) async fn load(book: &dyn AddressBook) -> Vec<SavedNode> {
+    match book.load().await {
+        Ok(nodes) => nodes,
+        Err(err) => {
+            warn!("failed to load address book: {err:#}");
+            Vec::new()
+        }
+    }
+}
+
+/// Returns the addresses to restore from the loaded `nodes`, dropping any information that
+/// is too old.
+pub(super) fn restore(nodes: &[SavedNode]) -> Vec<NodeAddr> {
+    let now = SystemTime::now();
+    let addrs: Vec<_> = nodes
+        .iter()
+        .cloned()
+        .filter_map(|node| node.into_node_addr(now, MAX_SAVED_ADDR_AGE, MAX_SAVED_NODE_AGE))
+        .collect();
+    debug!(
+        stored = nodes.len(),
+        restored = addrs.len(),
+        "loaded address book"
+    );
+    addrs
+}
+
+/// Periodically stores the nodes known to the magic socket in an [`AddressBook`].
+#[derive(Debug)]
+pub(super) struct AddressBookSaver {
+    book: Arc<dyn AddressBook>,
+    msock: Handle,
+    /// The last stored snapshot, starting with the loaded nodes.
+    saved: Arc<Mutex<HashMap<NodeId, SavedNode>>>,
+    _task: AbortOnDropHandle<()>,
+}
+
+impl AddressBookSaver {
+    /// Creates a saver for `book`, which was loaded with the `loaded` nodes.
+    pub(super) fn new(book: Arc<dyn AddressBook>, loaded: Vec<SavedNode>, msock: Handle) -> Self {
+        let saved = Arc::new(Mutex::new(
+            loaded
+                .into_iter()
+                .map(|node| (node.node_id, node))
+                .collect(),
+        ));
+        let task = task::spawn({
+            let book = book.clone();
+            let msock = msock.clone();
+            let saved = saved.clone();
+            async move {
+                let mut interval =
+                    time::interval_at(time::Instant::now() + SAVE_INTERVAL, SAVE_INTERVAL);
+                loop {
+                    interval.tick().await;
+                    save(book.as_ref(), &msock, &saved).await;
+                }
+            }
+            .instrument(info_span!("address-book"))
+        });
+        Self {
+            book,
+            msock,
+            saved,
+            _task: AbortOnDropHandle::new(task),
+        }
+    }
+
+    /// Stores a snapshot right away.
+    pub(super) async fn save_now(&self) {
+        save(self.book.as_ref(), &self.msock, &self.saved).await
+    }
+}
+
+async fn save(book: &dyn AddressBook, msock: &Handle, saved: &Mutex<HashMap<NodeId, SavedNode>>) {
+    let now = SystemTime::now();
+    let nodes: Vec<_> = {
+        let mut saved = saved.lock().expect("poisoned");
+        let nodes: Vec<_> = msock
+            .list_remote_infos()
+            .into_iter()
+            .filter_map(|info| {
+                let previous = saved.get(&info.node_id);
+                SavedNode::from_remote_info(info, previous, now)
+            })
+            .collect();
+        *saved = nodes
+            .iter()
+            .map(|node| (node.node_id, node.clone()))
+            .collect();
+        nodes
+    };
+    let count = nodes.len();
+    match book.save(nodes).await {
+        Ok(()) => trace!(count, "saved address book"),
+        Err(err) => warn!("failed to save address book: {err:#}"),
+    }
+}
+
+/// Serializes a [`SystemTime`] as seconds since the unix epoch.
+mod unix_secs {
+    use n0_future::time::{Duration, SystemTime};
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    pub(super) fn serialize<S: Serializer>(time: &SystemTime, s: S) -> Result<S::Ok, S::Error> {
+        time.duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs()
+            .serialize(s)
+    }
+
+    pub(super) fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<SystemTime, D::Error> {
+        let secs = u64::deserialize(d)?;
+        Ok(SystemTime::UNIX_EPOCH + Duration::from_secs(secs))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use iroh_base::SecretKey;
+    use testresult::TestResult;
+
+    use super::*;
+    use crate::endpoint::{ConnectionType, DirectAddrInfo};
+
+    fn saved_node(last_seen: SystemTime, addrs: &[(u16, SystemTime)]) -> SavedNode {
+        SavedNode {
+            node_id: SecretKey::generate(rand::thread_rng()).public(),
+            relay_url: Some("https://relay.example.com".parse().unwrap()),
+            direct_addrs: addrs
+                .iter()
+                .map(|(port, last_seen)| SavedAddr {
+                    addr: (std::net::Ipv4Addr::LOCALHOST, *port).into(),
+                    latency: None,
+                    last_seen: *last_seen,
+                })
+                .collect(),
+            latency: Some(Duration::from_millis(10)),
+            last_seen,
+        }
+    }
+
+    #[test]
+    fn test_aging() {
+        let now = SystemTime::now();
+        let old = now - MAX_SAVED_ADDR_AGE - Duration::from_secs(1);
+        let ancient = now - MAX_SAVED_NODE_AGE - Duration::from_secs(1);
+
+        let node = saved_node(now, &[(1, now), (2, old)]);
+        let addr = node
+            .into_node_addr(now, MAX_SAVED_ADDR_AGE, MAX_SAVED_NODE_AGE)
+            .unwrap();
+        assert_eq!(addr.direct_addresses.len(), 1);
+        assert!(addr.relay_url.is_some());
+
+        let node = saved_node(ancient, &[(1, now)]);
+        assert!(node
+            .into_node_addr(now, MAX_SAVED_ADDR_AGE, MAX_SAVED_NODE_AGE)
+            .is_none());
+    }
+
+    #[test]
+    fn test_restored_keeps_timestamps() {
+        let now = SystemTime::now();
+        let old = now - Duration::from_secs(60 * 60);
+        let previous = saved_node(old, &[(1, old)]);
+        let addr = previous.direct_addrs[0].addr;
+        let mut info = RemoteInfo {
+            node_id: previous.node_id,
+            relay_url: None,
+            addrs: vec![DirectAddrInfo {
+                addr,
+                latency: None,
+                last_control: None,
+                last_payload: None,
+                last_alive: None,
+                sources: [(Source::Saved, Duration::ZERO)].into(),
+            }],
+            conn_type: ConnectionType::None,
+            latency: None,
+            last_used: None,
+        };
+
+        // Restoring the node does not refresh its timestamps.
+        let node = SavedNode::from_remote_info(info.clone(), Some(&previous), now).unwrap();
+        assert_eq!(node.last_seen, old);
+        assert_eq!(node.direct_addrs[0].last_seen, old);
+
+        // Without a previous record the restored-only address has no timestamp.
+        let node = SavedNode::from_remote_info(info.clone(), None, now).unwrap();
+        assert!(node.direct_addrs.is_empty());
+
+        // Learning the address again refreshes both.
+        info.addrs[0].sources.insert(Source::App, Duration::ZERO);
+        let node = SavedNode::from_remote_info(info, Some(&previous), now).unwrap();
+        assert_eq!(node.last_seen, now);
+        assert_eq!(node.direct_addrs[0].last_seen, now);
+    }
+
+    #[cfg(not(wasm_browser))]
+    #[tokio::test]
+    async fn test_fs_address_book_roundtrip() -> TestResult {
+        let dir = std::env::temp_dir().join(format!("iroh-address-book-{}", rand::random::<u64>()));
+        let book = FsAddressBook::new(dir.join("nodes.bin"));
+        assert!(book.load().await?.is_empty());
+
+        // The on-disk format only keeps second precision.
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let nodes = vec![saved_node(now, &[(1, now), (2, now)])];
+        book.save(nodes.clone()).await?;
+        assert_eq!(book.load().await?, nodes);
+
+        tokio::fs::remove_dir_all(dir).await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,590 @@
+//! A pool of shared connections on top of [`Endpoint::connect`].
+//!
+//! Every call to [`Endpoint::connect`] establishes a new QUIC connection, including a new
+//! handshake.  Services talking to the same remote node from many places usually only need
+//! a single connection per remote node and [ALPN], opening streams on it as needed.
+//!
+//! The [`ConnectionPool`] hands out [`PooledConnection`]s keyed by [`NodeId`] and [ALPN]:
+//!
+//! - Concurrent requests for the same key share a single connection attempt.
+//! - If the pooled connection was closed, the next request transparently reconnects.
+//! - Connections which have no outstanding [`PooledConnection`] handles for longer than
+//!   [`PoolOptions::idle_timeout`] are closed and removed from the pool.
+//! - The number of connections per remote node and in total is limited by
+//!   [`PoolOptions::max_connections_per_node`] and [`PoolOptions::max_connections`].
+//!
+//! # Examples
+//!
+//! ```no_run
+//! use iroh::{
+//!     endpoint::pool::{ConnectionPool, PoolOptions},
+//!     Endpoint, NodeAddr,
+//! };
+//!
+//! # async fn wrapper(node_addr: NodeAddr) -> anyhow::Result<()> {
+//! let endpoint = Endpoint::builder().bind().await?;
+//! let pool = ConnectionPool::new(endpoint, PoolOptions::default());
+//!
+//! // Both calls return a handle to the same connection.
+//! let conn_a = pool.get(node_addr.clone(), b"my-alpn").await?;
+//! let conn_b = pool.get(node_addr, b"my-alpn").await?;
+//! assert_eq!(conn_a.stable_id(), conn_b.stable_id());
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! [ALPN]: https://en.wikipedia.org/wiki/Application-Layer_Protocol_Negotiation
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, Weak},
+};
+
+use anyhow::{bail, Result};
+use iroh_base::{NodeAddr, NodeId};
+use iroh_metrics::{Counter, MetricsGroup};
+use n0_future::{
+    task::{self, AbortOnDropHandle},
+    time::{self, Duration, Instant},
+};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, info_span, trace, Instrument};
+
+use super::{Connection, Endpoint};
+
+/// The error code used when closing connections which were idle for too long.
+pub const IDLE_CLOSE_CODE: u32 = 0;
+
+/// Options for a [`ConnectionPool`].
+#[derive(Debug, Clone)]
+pub struct PoolOptions {
+    /// How long a connection may have no outstanding handles before it is closed.
+    ///
+    /// Defaults to 60 seconds.
+    pub idle_timeout: Duration,
+    /// Maximum number of pooled connections to a single remote node, across all ALPNs.
+    ///
+    /// Defaults to 16.
+    pub max_connections_per_node: usize,
+    /// Maximum number of pooled connections in total.
+    ///
+    /// Defaults to 1024.
+    pub max_connections: usize,
+}
+
+impl Default for PoolOptions {
+    fn default() -> Self {
+        Self {
+            idle_timeout: Duration::from_secs(60),
+            max_connections_per_node: 16,
+            max_connections: 1024,
+        }
+    }
+}
+
+/// Metrics collected by a [`ConnectionPool`].
+#[derive(Debug, Default, Serialize, Deserialize, MetricsGroup)]
+#[non_exhaustive]
+#[metrics(name = "pool")]
+pub struct Metrics {
+    /// Number of connections established by the pool.
+    pub connections_opened: Counter,
+    /// Number of requests served with an already pooled connection.
+    pub connections_reused: Counter,
+    /// Number of pooled connections found to be closed.
+    pub connections_closed: Counter,
+    /// Number of connections closed because they were idle.
+    pub connections_idle_evicted: Counter,
+    /// Number of failed connection attempts.
+    pub connect_errors: Counter,
+    /// Number of requests rejected because of the per-node or total connection limit.
+    pub limit_exceeded: Counter,
+}
+
+/// Statistics about the pooled connections to a single remote node.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct NodeStats {
+    /// Number of connections currently in the pool, across all ALPNs.
+    pub connections: usize,
+    /// Number of connections established to this node.
+    pub opened: u64,
+    /// Number of requests served with an already pooled connection.
+    pub reused: u64,
+}
+
+type Key = (NodeId, Vec<u8>);
+
+/// A pool of shared [`Connection`]s keyed by remote [`NodeId`] and ALPN.
+///
+/// See the [module docs](self) for details.
+///
+/// Cloning the pool is cheap and all clones share the same connections.  The idle
+/// connections are only evicted while at least one clone of the pool is alive.
+#[derive(Debug, Clone)]
+pub struct ConnectionPool {
+    inner: Arc<Inner>,
+    _sweeper: Arc<AbortOnDropHandle<()>>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    endpoint: Endpoint,
+    options: PoolOptions,
+    state: Mutex<State>,
+    metrics: Arc<Metrics>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    slots: HashMap<Key, Arc<tokio::sync::Mutex<Slot>>>,
+    nodes: HashMap<NodeId, NodeStats>,
+    /// Set by [`ConnectionPool::close`], no new slots are created afterwards.
+    closed: bool,
+}
+
+#[derive(Debug)]
+struct Slot {
+    conn: Option<Connection>,
+    usage: Arc<Usage>,
+    /// Set once the slot was removed from the pool, it must not be used anymore.
+    removed: bool,
+}
+
+/// Shared between a [`Slot`] and all [`PooledConnection`]s handed out for it.
+#[derive(Debug)]
+struct Usage {
+    last_used: Mutex<Instant>,
+}
+
+impl Slot {
+    fn new() -> Self {
+        Self {
+            conn: None,
+            usage: Arc::new(Usage {
+                last_used: Mutex::new(Instant::now()),
+            }),
+            removed: false,
+        }
+    }
+
+    /// Returns the connection if it is still open.
+    fn open_conn(&self) -> Option<&Connection> {
+        self.conn
+            .as_ref()
+            .filter(|conn| conn.close_reason().is_none())
+    }
+
+    /// Whether no [`PooledConnection`] handles are outstanding.
+    fn is_unused(&self) -> bool {
+        Arc::strong_count(&self.usage) == 1
+    }
+
+    fn is_idle(&self, idle_timeout: Duration) -> bool {
+        let last_used = *self.usage.last_used.lock().expect("poisoned");
+        self.is_unused() && last_used.elapsed() >= idle_timeout
+    }
+}
+
+impl ConnectionPool {
+    /// Creates a new pool dialing connections from `endpoint`.
+    pub fn new(endpoint: Endpoint, options: PoolOptions) -> Self {
+        let sweep_interval = (options.idle_timeout / 2).max(Duration::from_millis(100));
+        let inner = Arc::new(Inner {
+            endpoint,
+            options,
+            state: Default::default(),
+            metrics: Default::default(),
+        });
+        let sweeper = task::spawn(
+            sweep_loop(Arc::downgrade(&inner), sweep_interval).instrument(info_span!("pool")),
+        );
+        Self {
+            inner,
+            _sweeper: Arc::new(AbortOnDropHandle::new(sweeper)),
+        }
+    }
+
+    /// Returns a connection to `node_addr` using `alpn`.
+    ///
+    /// If there is an open pooled connection for this node and ALPN it is returned,
+    /// otherwise a new connection is established using [`Endpoint::connect`].
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if a new connection is needed but either
+    /// [`PoolOptions::max_connections_per_node`] or [`PoolOptions::max_connections`] is
+    /// reached and no idle connection can be evicted, if connecting fails, or if the pool
+    /// was closed.
+    pub async fn get(
+        &self,
+        node_addr: impl Into<NodeAddr>,
+        alpn: &[u8],
+    ) -> Result<PooledConnection> {
+        let node_addr = node_addr.into();
+        let key = (node_addr.node_id, alpn.to_vec());
+        loop {
+            let slot = self.inner.slot(&key)?;
+            let mut slot = slot.lock().await;
+            if slot.removed {
+                // The slot was evicted while we were waiting for it.
+                continue;
+            }
+            if let Some(conn) = slot.open_conn() {
+                let conn = conn.clone();
+                self.inner.metrics.connections_reused.inc();
+                self.inner.update_stats(key.0, |stats| stats.reused += 1);
+                return Ok(PooledConnection::new(conn, slot.usage.clone()));
+            }
+            if slot.conn.take().is_some() {
+                trace!(node = %key.0.fmt_short(), "pooled connection closed, reconnecting");
+                self.inner.metrics.connections_closed.inc();
+            }
+            return match self.inner.endpoint.connect(node_addr, alpn).await {
+                Ok(conn) => {
+                    self.inner.metrics.connections_opened.inc();
+                    self.inner.update_stats(key.0, |stats| stats.opened += 1);
+                    slot.conn = Some(conn.clone());
+                    Ok(PooledConnection::new(conn, slot.usage.clone()))
+                }
+                Err(err) => {
+                    self.inner.metrics.connect_errors.inc();
+                    slot.removed = true;
+                    self.inner.remove_slot(&key);
+                    Err(err)
+                }
+            };
+        }
+    }
+
+    /// Returns statistics about the pooled connections to `node_id`.
+    ///
+    /// Returns `None` if there are no pooled connections to this node.
+    pub fn node_stats(&self, node_id: NodeId) -> Option<NodeStats> {
+        let state = self.inner.state.lock().expect("poisoned");
+        let mut stats = state.nodes.get(&node_id)?.clone();
+        stats.connections = state.slots.keys().filter(|(id, _)| *id == node_id).count();
+        Some(stats)
+    }
+
+    /// Returns the number of connections currently in the pool.
+    pub fn len(&self) -> usize {
+        self.inner.state.lock().expect("poisoned").slots.len()
+    }
+
+    /// Whether the pool currently holds no connections.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns the metrics collected by this pool.
+    pub fn metrics(&self) -> &Arc<Metrics> {
+        &self.inner.metrics
+    }
+
+    /// Closes all pooled connections and empties the pool.
+    ///
+    /// Outstanding [`PooledConnection`] handles will observe the connection as closed.
+    /// Connection attempts in progress are waited for and their connections closed as well.
+    /// Afterwards the pool is unusable, [`ConnectionPool::get`] returns an error.
+    pub async fn close(&self, error_code: u32, reason: &[u8]) {
+        let slots: Vec<_> = {
+            let mut state = self.inner.state.lock().expect("poisoned");
+            state.closed = true;
+            state.nodes.clear();
+            state.slots.drain().map(|(_, slot)| slot).collect()
+        };
+        for slot in slots {
+            let mut slot = slot.lock().await;
+            slot.removed = true;
+            if let Some(conn) = slot.conn.take() {
+                conn.close(error_code.into(), reason);
+            }
+        }
+    }
+}
+
+impl Inner {
+    /// Returns the slot for `key`, creating it if needed.
+    fn slot(&self, key: &Key) -> Result<Arc<tokio::sync::Mutex<Slot>>> {
+        let mut state = self.state.lock().expect("poisoned");
+        if state.closed {
+            bail!("connection pool closed");
+        }
+        if let Some(slot) = state.slots.get(key) {
+            return Ok(slot.clone());
+        }
+        let node_id = key.0;
+        if state.slots.keys().filter(|(id, _)| *id == node_id).count()
+            >= self.options.max_connections_per_node
+            && !state.evict_unused(|(id, _)| *id == node_id)
+        {
+            self.metrics.limit_exceeded.inc();
+            bail!(
+                "connection limit per node reached for {}",
+                node_id.fmt_short()
+            );
+        }
+        if state.slots.len() >= self.options.max_connections && !state.evict_unused(|_| true) {
+            self.metrics.limit_exceeded.inc();
+            bail!("connection pool limit reached");
+        }
+        let slot = Arc::new(tokio::sync::Mutex::new(Slot::new()));
+        state.slots.insert(key.clone(), slot.clone());
+        state.nodes.entry(node_id).or_default();
+        Ok(slot)
+    }
+
+    fn remove_slot(&self, key: &Key) {
+        let mut state = self.state.lock().expect("poisoned");
+        state.slots.remove(key);
+        state.remove_unused_stats();
+    }
+
+    fn update_stats(&self, node_id: NodeId, f: impl FnOnce(&mut NodeStats)) {
+        let mut state = self.state.lock().expect("poisoned");
+        f(state.nodes.entry(node_id).or_default());
+    }
+
+    /// Closes and removes closed and idle connections.
+    fn sweep(&self) {
+        let mut state = self.state.lock().expect("poisoned");
+        state.slots.retain(|(node_id, _), slot| {
+            // A locked slot is currently in use by `get`.
+            let Ok(mut slot) = slot.try_lock() else {
+                return true;
+            };
+            if slot.open_conn().is_none() {
+                if slot.conn.is_some() {
+                    self.metrics.connections_closed.inc();
+                }
+                slot.removed = true;
+                return false;
+            }
+            if slot.is_idle(self.options.idle_timeout) {
+                debug!(node = %node_id.fmt_short(), "closing idle pooled connection");
+                if let Some(conn) = slot.conn.take() {
+                    conn.close(IDLE_CLOSE_CODE.into(), b"idle");
+                }
+                self.metrics.connections_idle_evicted.inc();
+                slot.removed = true;
+                return false;
+            }
+            true
+        });
+        state.remove_unused_stats();
+    }
+}
+
+impl State {
+    /// Evicts one connection without outstanding handles matching `filter`.
+    ///
+    /// Returns whether a connection was evicted.
+    fn evict_unused(&mut self, filter: impl Fn(&Key) -> bool) -> bool {
+        let evicted = self.slots.iter().find_map(|(key, slot)| {
+            if !filter(key) {
+                return None;
+            }
+            let mut slot = slot.try_lock().ok()?;
+            if !slot.is_unused() {
+                return None;
+            }
+            if let Some(conn) = slot.conn.take() {
+                conn.close(IDLE_CLOSE_CODE.into(), b"idle");
+            }
+            slot.removed = true;
+            Some(key.clone())
+        });
+        match evicted {
+            Some(key) => {
+                self.slots.remove(&key);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn remove_unused_stats(&mut self) {
+        let slots = &self.slots;
+        self.nodes
+            .retain(|node_id, _| slots.keys().any(|(id, _)| id == node_id));
+    }
+}
+
+async fn sweep_loop(inner: Weak<Inner>, interval: Duration) {
+    let mut interval = time::interval(interval);
+    loop {
+        interval.tick().await;
+        let Some(inner) = inner.upgrade() else {
+            break;
+        };
+        inner.sweep();
+    }
+}
+
+/// A handle to a pooled [`Connection`].
+///
+/// Dereferences to the [`Connection`].  While any handle for a connection is alive, the
+/// connection is not considered idle by the [`ConnectionPool`].
+///
+/// Closing the connection through this handle closes it for all users of the pool, the
+/// next [`ConnectionPool::get`] will then establish a new connection.
+#[derive(Debug, Clone, derive_more::Deref)]
+pub struct PooledConnection {
+    #[deref]
+    conn: Connection,
+    usage: Arc<Usage>,
+}
+
+impl PooledConnection {
+    fn new(conn: Connection, usage: Arc<Usage>) -> Self {
+        *usage.last_used.lock().expect("poisoned") = Instant::now();
+        Self { conn, usage }
+    }
+
+    /// Returns a clone of the underlying [`Connection`].
+    ///
+    /// The returned [`Connection`] does not keep the pooled connection from being
+    /// considered idle.
+    pub fn connection(&self) -> Connection {
+        self.conn.clone()
+    }
+}
+
+impl Drop for PooledConnection {
+    fn drop(&mut self) {
+        *self.usage.last_used.lock().expect("poisoned") = Instant::now();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use testresult::TestResult;
+    use tracing_test::traced_test;
+
+    use super::*;
+    use crate::RelayMode;
+
+    const TEST_ALPN: &[u8] = b"n0/iroh/test/pool";
+
+    /// Spawns an endpoint which accepts connections and keeps them open until closed.
+    async fn spawn_server() -> Result<(Endpoint, AbortOnDropHandle<()>)> {
+        let ep = Endpoint::builder()
+            .alpns(vec![TEST_ALPN.to_vec()])
+            .relay_mode(RelayMode::Disabled)
+            .bind()
+            .await?;
+        let task = task::spawn({
+            let ep = ep.clone();
+            async move {
+                while let Some(incoming) = ep.accept().await {
+                    task::spawn(async move {
+                        if let Ok(conn) = incoming.await {
+                            conn.closed().await;
+                        }
+                    });
+                }
+            }
+        });
+        Ok((ep, AbortOnDropHandle::new(task)))
+    }
+
+    async fn client() -> Result<Endpoint> {
+        Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .bind()
+            .await
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_reuse_and_reconnect() -> TestResult {
+        let (server, _task) = spawn_server().await?;
+        let server_addr = server.node_addr().await?;
+        let pool = ConnectionPool::new(client().await?, PoolOptions::default());
+
+        let conn_a = pool.get(server_addr.clone(), TEST_ALPN).await?;
+        let conn_b = pool.get(server_addr.clone(), TEST_ALPN).await?;
+        assert_eq!(conn_a.stable_id(), conn_b.stable_id());
+        assert_eq!(pool.len(), 1);
+
+        conn_a.close(0u32.into(), b"done");
+        let conn_c = pool.get(server_addr.clone(), TEST_ALPN).await?;
+        assert_ne!(conn_a.stable_id(), conn_c.stable_id());
+
+        let stats = pool.node_stats(server_addr.node_id).expect("stats");
+        assert_eq!(stats.connections, 1);
+        assert_eq!(stats.opened, 2);
+        assert_eq!(stats.reused, 1);
+        assert_eq!(pool.metrics().connections_closed.get(), 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_idle_eviction() -> TestResult {
+        let (server, _task) = spawn_server().await?;
+        let server_addr = server.node_addr().await?;
+        let options = PoolOptions {
+            idle_timeout: Duration::from_millis(200),
+            ..Default::default()
+        };
+        let pool = ConnectionPool::new(client().await?, options);
+
+        let conn = pool.get(server_addr.clone(), TEST_ALPN).await?;
+        let inner = conn.connection();
+        time::sleep(Duration::from_millis(500)).await;
+        // The handle is still alive, so the connection must not be evicted.
+        assert_eq!(pool.len(), 1);
+
+        drop(conn);
+        time::timeout(Duration::from_secs(5), inner.closed()).await?;
+        assert!(pool.is_empty());
+        assert_eq!(pool.metrics().connections_idle_evicted.get(), 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_node_limit() -> TestResult {
+        let (server, _task) = spawn_server().await?;
+        let server_addr = server.node_addr().await?;
+        let options = PoolOptions {
+            max_connections_per_node: 1,
+            ..Default::default()
+        };
+        let pool = ConnectionPool::new(client().await?, options);
+
+        let _conn = pool.get(server_addr.clone(), TEST_ALPN).await?;
+        let res = pool.get(server_addr.clone(), b"n0/iroh/test/other").await;
+        assert!(res.is_err());
+        assert_eq!(pool.metrics().limit_exceeded.get(), 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_close_during_connect() -> TestResult {
+        let (server, _task) = spawn_server().await?;
+        let server_addr = server.node_addr().await?;
+        let pool = ConnectionPool::new(client().await?, PoolOptions::default());
+
+        let get = task::spawn({
+            let pool = pool.clone();
+            async move { pool.get(server_addr, TEST_ALPN).await }
+        });
+        // Wait for the connection attempt to take its slot.
+        while pool.is_empty() {
+            time::sleep(Duration::from_millis(1)).await;
+        }
+        pool.close(0, b"closed").await;
+
+        let conn = get.await??;
+        time::timeout(Duration::from_secs(5), conn.closed()).await?;
+        assert!(pool.is_empty());
+        assert!(pool
+            .get(server.node_addr().await?, TEST_ALPN)
+            .await
+            .is_err());
+        Ok(())
+    }
+}
@@ -39,7 +39,7 @@ use n0_future::{
     boxed::BoxStream,
     task::{self, JoinSet},
     time::{self, Duration, Instant},
-    FutureExt, StreamExt,
+    FutureExt, Stream, StreamExt,
 };
 use netwatch::{interfaces, netmon};
 #[cfg(not(wasm_browser))]
@@ -60,6 +60,7 @@ use url::Url;
 use self::udp_conn::UdpConn;
 use self::{
     metrics::Metrics as MagicsockMetrics,
+    multipath::DuplicateFilter,
     node_map::{NodeMap, PingAction, PingRole, SendPing},
     relay_actor::{RelayActor, RelayActorMessage, RelayRecvDatagram},
 };
@@ -72,7 +73,8 @@ use crate::net_report::{IpMappedAddr, QuicConfig};
 use crate::{
     defaults::timeouts::NET_REPORT_TIMEOUT,
     disco::{self, CallMeMaybe, SendAddr},
-    discovery::{Discovery, DiscoveryItem, DiscoverySubscribers, NodeData, UserData},
+    discovery::{Discovery, DiscoveryItem, DiscoverySubscribers, Lagged, NodeData, UserData},
+    endpoint::{DRAIN_CLOSE_CODE, DRAIN_CLOSE_REASON},
     key::{public_ed_box, secret_ed_box, DecryptionError, SharedSecret},
     metrics::EndpointMetrics,
     net_report::{self, IpMappedAddresses, Report},
@@ -80,6 +82,7 @@ use crate::{
 };
 
 mod metrics;
+mod multipath;
 mod node_map;
 mod relay_actor;
 #[cfg(not(wasm_browser))]
@@ -89,7 +92,11 @@ pub use node_map::Source;
 
 pub use self::{
     metrics::Metrics,
-    node_map::{ConnectionType, ControlMsg, DirectAddrInfo, RemoteInfo},
+    multipath::MultipathMode,
+    node_map::{
+        ClearReason, ConnectionType, ControlMsg, DirectAddrInfo, NetworkPath, PathEvent,
+        PathEventKind, RemoteInfo, UntrustReason,
+    },
 };
 
 /// How long we consider a STUN-derived endpoint valid for. UDP NAT mappings typically
@@ -151,6 +158,9 @@ pub(crate) struct Options {
     #[cfg(any(test, feature = "test-utils"))]
     pub(crate) path_selection: PathSelection,
 
+    /// Whether to duplicate datagrams over the relay when using a direct path.
+    pub(crate) multipath: MultipathMode,
+
     pub(crate) metrics: EndpointMetrics,
 }
 
@@ -216,6 +226,8 @@ pub(crate) struct MagicSock {
     closing: AtomicBool,
     /// Close was called.
     closed: AtomicBool,
+    /// The endpoint is draining, our address is no longer published to discovery.
+    draining: AtomicBool,
     /// If the last net_report report, reports IPv6 to be available.
     ipv6_reported: Arc<AtomicBool>,
 
@@ -263,6 +275,11 @@ pub(crate) struct MagicSock {
     /// Broadcast channel for listening to discovery updates.
     discovery_subscribers: DiscoverySubscribers,
 
+    /// Default [`MultipathMode`] for nodes without their own mode.
+    multipath: MultipathMode,
+    /// Drops QUIC datagrams received over both the direct path and the relay.
+    duplicate_filter: DuplicateFilter,
+
     pub(crate) metrics: EndpointMetrics,
 }
 
@@ -313,6 +330,17 @@ impl MagicSock {
         self.closed.load(Ordering::SeqCst)
     }
 
+    /// Marks the socket as draining, which stops publishing our address to discovery.
+    ///
+    /// Returns `false` if it was already draining.
+    pub(crate) fn start_draining(&self) -> bool {
+        !self.draining.swap(true, Ordering::SeqCst)
+    }
+
+    pub(crate) fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::SeqCst)
+    }
+
     fn public_key(&self) -> PublicKey {
         self.secret_key.public()
     }
@@ -340,6 +368,27 @@ impl MagicSock {
         self.node_map.remote_info(node_id)
     }
 
+    /// Returns the path of the last payload datagram received from a QUIC remote address.
+    ///
+    /// Returns `None` if the address is not a [`NodeIdMappedAddr`] of a known node.
+    pub(crate) fn last_received_path(&self, addr: SocketAddr) -> Option<SendAddr> {
+        match MappedAddr::from(addr) {
+            MappedAddr::NodeId(addr) => self.node_map.last_received_path(addr),
+            _ => None,
+        }
+    }
+
+    /// Returns the node a QUIC remote address is mapped to.
+    ///
+    /// This is the node the datagrams from the address are believed to come from, it is not
+    /// verified.  Returns `None` if the address is not a [`NodeIdMappedAddr`] of a known node.
+    pub(crate) fn mapped_node_id(&self, addr: SocketAddr) -> Option<NodeId> {
+        match MappedAddr::from(addr) {
+            MappedAddr::NodeId(addr) => self.node_map.node_id(addr),
+            _ => None,
+        }
+    }
+
     /// Returns a [`Watcher`] for this socket's direct addresses.
     ///
     /// The [`MagicSock`] continuously monitors the direct addresses, the network addresses
@@ -393,6 +442,38 @@ impl MagicSock {
         self.node_map.conn_type(node_id)
     }
 
+    /// Returns a stream of [`PathEvent`]s for the given `node_id`.
+    ///
+    /// # Errors
+    ///
+    /// Will return an error if there is no address information known about the
+    /// given `node_id`.
+    pub(crate) fn path_events(
+        &self,
+        node_id: NodeId,
+    ) -> Result<impl Stream<Item = Result<PathEvent, Lagged>>> {
+        self.node_map.path_events(node_id)
+    }
+
+    /// Overrides the [`MultipathMode`] for the given `node_id`.
+    ///
+    /// # Errors
+    ///
+    /// Will return an error if there is no address information known about the
+    /// given `node_id`.
+    pub(crate) fn set_multipath(&self, node_id: NodeId, mode: Option<MultipathMode>) -> Result<()> {
+        self.node_map.set_multipath(node_id, mode)?;
+        if mode.is_some_and(|mode| mode != MultipathMode::Disabled) {
+            self.duplicate_filter.enable();
+        }
+        Ok(())
+    }
+
+    /// Returns the recent [`PathEvent`]s for the given `node_id`, oldest first.
+    pub(crate) fn path_event_history(&self, node_id: NodeId) -> Option<Vec<PathEvent>> {
+        self.node_map.path_event_history(node_id)
+    }
+
     /// Returns the socket address which can be used by the QUIC layer to dial this node.
     pub(crate) fn get_mapping_addr(&self, node_id: NodeId) -> Option<NodeIdMappedAddr> {
         self.node_map.get_quic_mapped_addr_for_node_key(node_id)
@@ -527,6 +608,7 @@ impl MagicSock {
                 match self.node_map.get_send_addrs(
                     dest,
                     self.ipv6_reported.load(Ordering::Relaxed),
+                    self.multipath,
                     &self.metrics.magicsock,
                 ) {
                     Some((node_id, udp_addr, relay_url, msgs)) => {
@@ -926,6 +1008,10 @@ impl MagicSock {
                         DiscoMessageSource::Udp(meta.addr),
                     );
                     datagram[0] = 0u8;
+                } else if self.duplicate_filter.is_duplicate(datagram) {
+                    trace!(src = %meta.addr, len = %meta.stride, "UDP recv: duplicate packet");
+                    self.metrics.magicsock.recv_duplicates_dropped.inc();
+                    datagram[0] = 0u8;
                 } else {
                     trace!(src = %meta.addr, len = %meta.stride, "UDP recv: quic packet");
                     if from_ipv4 {
@@ -1088,6 +1174,12 @@ impl MagicSock {
             return None;
         }
 
+        if self.duplicate_filter.is_duplicate(&dm.buf) {
+            trace!(src = %dm.src.fmt_short(), "relay recv: duplicate packet");
+            self.metrics.magicsock.recv_duplicates_dropped.inc();
+            return None;
+        }
+
         let quic_mapped_addr = self.node_map.receive_relay(&dm.url, dm.src);
 
         // Normalize local_ip
@@ -1562,8 +1654,12 @@ impl MagicSock {
 
     /// Publishes our address to a discovery service, if configured.
     ///
-    /// Called whenever our addresses or home relay node changes.
+    /// Called whenever our addresses or home relay node changes.  Nothing is published
+    /// while draining.
     fn publish_my_addr(&self) {
+        if self.is_draining() {
+            return;
+        }
         if let Some(ref discovery) = self.discovery {
             let relay_url = self.my_relay();
             let direct_addrs = self.direct_addrs.sockaddrs();
@@ -1725,6 +1821,7 @@ impl Handle {
             insecure_skip_relay_cert_verify,
             #[cfg(any(test, feature = "test-utils"))]
             path_selection,
+            multipath,
             metrics,
         } = opts;
 
@@ -1770,6 +1867,7 @@ impl Handle {
             sockets,
             closing: AtomicBool::new(false),
             closed: AtomicBool::new(false),
+            draining: AtomicBool::new(false),
             relay_datagram_recv_queue: relay_datagram_recv_queue.clone(),
             relay_datagram_send_channel: relay_datagram_send_tx,
             poll_recv_counter: AtomicUsize::new(0),
@@ -1793,6 +1891,8 @@ impl Handle {
             #[cfg(any(test, feature = "test-utils"))]
             insecure_skip_relay_cert_verify,
             discovery_subscribers: DiscoverySubscribers::new(),
+            multipath,
+            duplicate_filter: DuplicateFilter::new(multipath != MultipathMode::Disabled),
             metrics,
         });
 
@@ -1917,7 +2017,11 @@ impl Handle {
     pub(crate) async fn close(&self) {
         trace!("magicsock closing...");
         // Initiate closing all connections, and refuse future connections.
-        self.endpoint.close(0u16.into(), b"");
+        if self.msock.is_draining() {
+            self.endpoint.close(DRAIN_CLOSE_CODE, DRAIN_CLOSE_REASON);
+        } else {
+            self.endpoint.close(0u16.into(), b"");
+        }
 
         // In the history of this code, this call had been
         // - removed: https://github.com/n0-computer/iroh/pull/1753
@@ -3470,6 +3574,7 @@ mod tests {
                 insecure_skip_relay_cert_verify: false,
                 #[cfg(any(test, feature = "test-utils"))]
                 path_selection: PathSelection::default(),
+                multipath: MultipathMode::default(),
                 discovery_user_data: None,
                 metrics: Default::default(),
             }
@@ -4082,6 +4187,7 @@ mod tests {
             server_config,
             insecure_skip_relay_cert_verify: true,
             path_selection: PathSelection::default(),
+            multipath: MultipathMode::default(),
             metrics: Default::default(),
         };
         let msock = MagicSock::spawn(opts).await?;
@@ -27,6 +27,10 @@ pub struct Metrics {
     pub recv_datagrams: Counter,
     /// Number of datagrams received using GRO
     pub recv_gro_datagrams: Counter,
+    /// Number of datagrams additionally sent via the relay because of multipath.
+    pub send_multipath_duplicated: Counter,
+    /// Number of received QUIC datagrams dropped because they were already received.
+    pub recv_duplicates_dropped: Counter,
 
     // Disco packets
     pub send_disco_udp: Counter,
@@ -0,0 +1,167 @@
+//! Duplicating datagrams over the direct and relay paths.
+//!
+//! Normally once a direct path to a node is confirmed all datagrams are only sent over this
+//! path.  When the [`MultipathMode`] allows it datagrams are additionally sent via the home
+//! relay of the node, trading bandwidth for lower tail latency while the direct path is
+//! unreliable.  The receiving side drops the second copy using a [`DuplicateFilter`].
+
+use std::{
+    collections::{hash_map::RandomState, HashSet, VecDeque},
+    hash::BuildHasher,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+};
+
+use n0_future::time::{Duration, Instant};
+
+/// How long datagrams are duplicated after a path transition or loss on the direct path,
+/// when using [`MultipathMode::Adaptive`].
+pub(super) const TRANSITION_DURATION: Duration = Duration::from_secs(5);
+
+/// How long a received datagram is remembered to detect a duplicate.
+///
+/// The copy sent via the relay usually arrives within a few hundred milliseconds of the
+/// direct copy, this leaves plenty of margin.
+const DEDUP_WINDOW: Duration = Duration::from_secs(2);
+
+/// Maximum number of received datagrams remembered to detect duplicates.
+const DEDUP_CAPACITY: usize = 8192;
+
+/// Whether to send datagrams over both the direct path and the relay path.
+///
+/// This can be configured for all nodes using [`Builder::multipath`], and overridden for a
+/// single node using [`Endpoint::set_multipath`].
+///
+/// Enabling multipath also makes the endpoint drop datagrams it received twice, once over
+/// the direct path and once via the relay.  QUIC would discard these duplicates anyway, but
+/// only after spending the effort of decrypting them.
+///
+/// [`Builder::multipath`]: crate::endpoint::Builder::multipath
+/// [`Endpoint::set_multipath`]: crate::endpoint::Endpoint::set_multipath
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum MultipathMode {
+    /// Send on a single path once a direct path is confirmed.
+    ///
+    /// This is the default.
+    #[default]
+    Disabled,
+    /// Duplicate datagrams over the relay during path transitions and loss spikes.
+    ///
+    /// Datagrams are duplicated for a few seconds after the connection type changed, after
+    /// a ping on the direct path was not answered and after the network changed.
+    Adaptive,
+    /// Always duplicate datagrams over the relay when a direct path is used.
+    Always,
+}
+
+impl MultipathMode {
+    /// Whether datagrams should be duplicated, given the deadline of the last transition.
+    pub(super) fn should_duplicate(&self, transition_until: Option<Instant>, now: Instant) -> bool {
+        match self {
+            Self::Disabled => false,
+            Self::Adaptive => transition_until.is_some_and(|until| now < until),
+            Self::Always => true,
+        }
+    }
+}
+
+/// Detects QUIC datagrams which were received more than once.
+///
+/// Encrypted QUIC packets are unique, so a datagram with the same contents as one received
+/// shortly before is a copy sent over another path.  Only a hash of each datagram is kept.
+///
+/// The filter is disabled until [`DuplicateFilter::enable`] is called, so endpoints which
+/// do not use multipath do not pay for it.
+#[derive(Debug)]
+pub(super) struct DuplicateFilter {
+    enabled: AtomicBool,
+    hasher: RandomState,
+    seen: Mutex<Seen>,
+}
+
+#[derive(Debug, Default)]
+struct Seen {
+    /// Hashes in the order they were received.
+    order: VecDeque<(u64, Instant)>,
+    /// The same hashes as in `order`, for lookups.
+    hashes: HashSet<u64>,
+}
+
+impl DuplicateFilter {
+    pub(super) fn new(enabled: bool) -> Self {
+        Self {
+            enabled: AtomicBool::new(enabled),
+            hasher: RandomState::new(),
+            seen: Default::default(),
+        }
+    }
+
+    /// Starts detecting duplicates.
+    pub(super) fn enable(&self) {
+        self.enabled.store(true, Ordering::Relaxed);
+    }
+
+    /// Records the datagram, returns `true` if it was already received recently.
+    pub(super) fn is_duplicate(&self, datagram: &[u8]) -> bool {
+        if !self.enabled.load(Ordering::Relaxed) {
+            return false;
+        }
+        let hash = self.hasher.hash_one(datagram);
+        let now = Instant::now();
+        let mut seen = self.seen.lock().expect("poisoned");
+        seen.expire(now);
+        if !seen.hashes.insert(hash) {
+            return true;
+        }
+        seen.order.push_back((hash, now));
+        false
+    }
+}
+
+impl Seen {
+    fn expire(&mut self, now: Instant) {
+        while let Some(&(hash, at)) = self.order.front() {
+            if self.order.len() < DEDUP_CAPACITY && now.duration_since(at) < DEDUP_WINDOW {
+                break;
+            }
+            self.order.pop_front();
+            self.hashes.remove(&hash);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_duplicate_filter() {
+        let filter = DuplicateFilter::new(false);
+        assert!(!filter.is_duplicate(b"hello"));
+        assert!(!filter.is_duplicate(b"hello"));
+
+        filter.enable();
+        assert!(!filter.is_duplicate(b"hello"));
+        assert!(filter.is_duplicate(b"hello"));
+        assert!(!filter.is_duplicate(b"world"));
+
+        // Old entries are evicted once the capacity is reached.
+        for i in 0..DEDUP_CAPACITY as u32 {
+            assert!(!filter.is_duplicate(&i.to_be_bytes()));
+        }
+        assert!(!filter.is_duplicate(b"hello"));
+    }
+
+    #[test]
+    fn test_should_duplicate() {
+        let now = Instant::now();
+        let later = now + TRANSITION_DURATION;
+        assert!(!MultipathMode::Disabled.should_duplicate(Some(later), now));
+        assert!(MultipathMode::Adaptive.should_duplicate(Some(later), now));
+        assert!(!MultipathMode::Adaptive.should_duplicate(Some(later), later));
+        assert!(!MultipathMode::Adaptive.should_duplicate(None, now));
+        assert!(MultipathMode::Always.should_duplicate(None, now));
+    }
+}
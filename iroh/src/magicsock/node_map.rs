@@ -6,30 +6,33 @@ use std::{
 };
 
 use iroh_base::{NodeAddr, NodeId, PublicKey, RelayUrl};
-use n0_future::time::Instant;
+use n0_future::{time::Instant, Stream};
 use serde::{Deserialize, Serialize};
 use stun_rs::TransactionId;
 use tracing::{debug, info, instrument, trace, warn};
 
-use self::{
-    best_addr::ClearReason,
-    node_state::{NodeState, Options, PingHandled},
+use self::node_state::{NodeState, Options, PingHandled};
+use super::{
+    metrics::Metrics, multipath::MultipathMode, ActorMessage, DiscoMessageSource, NodeIdMappedAddr,
 };
-use super::{metrics::Metrics, ActorMessage, DiscoMessageSource, NodeIdMappedAddr};
 #[cfg(any(test, feature = "test-utils"))]
 use crate::endpoint::PathSelection;
 use crate::{
     disco::{CallMeMaybe, Pong, SendAddr},
+    discovery::Lagged,
     watchable::Watcher,
 };
 
 mod best_addr;
 mod node_state;
+mod path_events;
 mod path_state;
 mod udp_paths;
 
+pub use best_addr::{ClearReason, UntrustReason};
 pub use node_state::{ConnectionType, ControlMsg, DirectAddrInfo, RemoteInfo};
 pub(super) use node_state::{DiscoPingPurpose, PingAction, PingRole, SendPing};
+pub use path_events::{NetworkPath, PathEvent, PathEventKind};
 
 /// Number of nodes that are inactive for which we keep info about. This limit is enforced
 /// periodically via [`NodeMap::prune_inactive`].
@@ -253,6 +256,7 @@ impl NodeMap {
         &self,
         addr: NodeIdMappedAddr,
         have_ipv6: bool,
+        multipath: MultipathMode,
         metrics: &Metrics,
     ) -> Option<(
         PublicKey,
@@ -264,7 +268,7 @@ impl NodeMap {
         let ep = inner.get_mut(NodeStateKey::NodeIdMappedAddr(addr))?;
         let public_key = *ep.public_key();
         trace!(dest = %addr, node_id = %public_key.fmt_short(), "dst mapped to NodeId");
-        let (udp_addr, relay_url, msgs) = ep.get_send_addrs(have_ipv6, metrics);
+        let (udp_addr, relay_url, msgs) = ep.get_send_addrs(have_ipv6, multipath, metrics);
         Some((public_key, udp_addr, relay_url, msgs))
     }
 
@@ -313,11 +317,71 @@ impl NodeMap {
         self.inner.lock().expect("poisoned").conn_type(node_id)
     }
 
+    /// Returns a stream of [`PathEvent`]s for the given node.
+    ///
+    /// # Errors
+    ///
+    /// Will return an error if there is not an entry in the [`NodeMap`] for
+    /// the `node_id`
+    pub(super) fn path_events(
+        &self,
+        node_id: NodeId,
+    ) -> anyhow::Result<impl Stream<Item = Result<PathEvent, Lagged>>> {
+        self.inner.lock().expect("poisoned").path_events(node_id)
+    }
+
+    /// Returns the recent [`PathEvent`]s for the given node, oldest first.
+    pub(super) fn path_event_history(&self, node_id: NodeId) -> Option<Vec<PathEvent>> {
+        self.inner
+            .lock()
+            .expect("poisoned")
+            .path_event_history(node_id)
+    }
+
+    /// Overrides the [`MultipathMode`] for the given node, `None` restores the default.
+    ///
+    /// # Errors
+    ///
+    /// Will return an error if there is not an entry in the [`NodeMap`] for
+    /// the `node_id`
+    pub(super) fn set_multipath(
+        &self,
+        node_id: NodeId,
+        mode: Option<MultipathMode>,
+    ) -> anyhow::Result<()> {
+        let mut inner = self.inner.lock().expect("poisoned");
+        match inner.get_mut(NodeStateKey::NodeId(node_id)) {
+            Some(ep) => {
+                ep.set_multipath(mode);
+                Ok(())
+            }
+            None => anyhow::bail!("No endpoint for {node_id:?} found"),
+        }
+    }
+
     /// Get the [`RemoteInfo`]s for the node identified by [`NodeId`].
     pub(super) fn remote_info(&self, node_id: NodeId) -> Option<RemoteInfo> {
         self.inner.lock().expect("poisoned").remote_info(node_id)
     }
 
+    /// Returns the path of the last payload datagram received for a [`NodeIdMappedAddr`].
+    pub(super) fn last_received_path(&self, addr: NodeIdMappedAddr) -> Option<SendAddr> {
+        self.inner
+            .lock()
+            .expect("poisoned")
+            .get(NodeStateKey::NodeIdMappedAddr(addr))
+            .and_then(|ep| ep.last_received_path().cloned())
+    }
+
+    /// Returns the node of a QUIC mapped address.
+    pub(super) fn node_id(&self, addr: NodeIdMappedAddr) -> Option<NodeId> {
+        self.inner
+            .lock()
+            .expect("poisoned")
+            .get(NodeStateKey::NodeIdMappedAddr(addr))
+            .map(|ep| *ep.public_key())
+    }
+
     /// Prunes nodes without recent activity so that at most [`MAX_INACTIVE_NODES`] are kept.
     pub(super) fn prune_inactive(&self) {
         self.inner.lock().expect("poisoned").prune_inactive();
@@ -512,6 +576,29 @@ impl NodeMapInner {
         }
     }
 
+    /// Returns a stream of [`PathEvent`]s.
+    ///
+    /// The stream ends when the node is removed from the [`NodeMap`].
+    ///
+    /// # Errors
+    ///
+    /// Will return an error if there is not an entry in the [`NodeMap`] for
+    /// the `node_id`
+    fn path_events(
+        &self,
+        node_id: NodeId,
+    ) -> anyhow::Result<impl Stream<Item = Result<PathEvent, Lagged>>> {
+        match self.get(NodeStateKey::NodeId(node_id)) {
+            Some(ep) => Ok(ep.path_events().subscribe()),
+            None => anyhow::bail!("No endpoint for {node_id:?} found"),
+        }
+    }
+
+    fn path_event_history(&self, node_id: NodeId) -> Option<Vec<PathEvent>> {
+        self.get(NodeStateKey::NodeId(node_id))
+            .map(|ep| ep.path_events().history())
+    }
+
     fn handle_pong(&mut self, sender: NodeId, src: &DiscoMessageSource, pong: Pong) {
         if let Some(ns) = self.get_mut(NodeStateKey::NodeId(sender)).as_mut() {
             let insert = ns.handle_pong(&pong, src.into());
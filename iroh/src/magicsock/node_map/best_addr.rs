@@ -5,11 +5,15 @@ use std::net::SocketAddr;
 use n0_future::time::{Duration, Instant};
 use tracing::{debug, info};
 
+use super::path_events::{PathEventKind, PathEvents};
+
 /// How long we trust a UDP address as the exclusive path (without using relay) without having heard a Pong reply.
 const TRUST_UDP_ADDR_DURATION: Duration = Duration::from_millis(6500);
 
 #[derive(Debug, Default)]
-pub(super) struct BestAddr(Option<BestAddrInner>);
+pub(super) struct BestAddr {
+    inner: Option<BestAddrInner>,
+}
 
 #[derive(Debug)]
 struct BestAddrInner {
@@ -55,14 +59,30 @@ pub(super) enum State<'a> {
     Empty,
 }
 
-#[derive(Debug, Clone, Copy)]
+/// Why a direct address was cleared.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
 pub enum ClearReason {
+    /// All paths were reset, e.g. because the node was pinged from scratch.
     Reset,
+    /// The address has not been used for too long.
     Inactive,
+    /// A ping sent to the address was not answered in time.
     PongTimeout,
+    /// The address is one of our own local addresses.
     MatchesOurLocalAddr,
 }
 
+/// Why the trust in the best direct address was cleared.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum UntrustReason {
+    /// The network connectivity of this node changed.
+    ConnectivityChanged,
+    /// The node sent a call-me-maybe which did not include the address.
+    NotInCallMeMaybe,
+}
+
 impl BestAddr {
     #[cfg(test)]
     pub fn from_parts(
@@ -76,36 +96,50 @@ impl BestAddr {
             confirmed_at,
             trust_until: Some(trust_until),
         };
-        Self(Some(inner))
+        Self { inner: Some(inner) }
     }
 
     pub fn is_empty(&self) -> bool {
-        self.0.is_none()
+        self.inner.is_none()
     }
 
     /// Unconditionally clears the best address.
-    pub fn clear(&mut self, reason: ClearReason, has_relay: bool) {
-        let old = self.0.take();
+    pub fn clear(&mut self, reason: ClearReason, has_relay: bool, events: &PathEvents) {
+        let old = self.inner.take();
         if let Some(old_addr) = old.as_ref().map(BestAddrInner::addr) {
             info!(?reason, ?has_relay, %old_addr, "clearing best_addr");
+            events.record(PathEventKind::BestAddrCleared {
+                addr: old_addr,
+                reason,
+            });
         }
     }
 
     /// Clears the best address if equal to `addr`.
-    pub fn clear_if_equals(&mut self, addr: SocketAddr, reason: ClearReason, has_relay: bool) {
+    pub fn clear_if_equals(
+        &mut self,
+        addr: SocketAddr,
+        reason: ClearReason,
+        has_relay: bool,
+        events: &PathEvents,
+    ) {
         if self.addr() == Some(addr) {
-            self.clear(reason, has_relay)
+            self.clear(reason, has_relay, events)
         }
     }
 
-    pub fn clear_trust(&mut self, why: &'static str) {
-        if let Some(state) = self.0.as_mut() {
+    pub fn clear_trust(&mut self, reason: UntrustReason, events: &PathEvents) {
+        if let Some(state) = self.inner.as_mut() {
             info!(
-                %why,
+                ?reason,
                 prev_trust_until = ?state.trust_until,
                 "clearing best_addr trust",
             );
             state.trust_until = None;
+            events.record(PathEventKind::BestAddrUntrusted {
+                addr: state.addr(),
+                reason,
+            });
         }
     }
 
@@ -115,15 +149,16 @@ impl BestAddr {
         latency: Duration,
         source: Source,
         confirmed_at: Instant,
+        events: &PathEvents,
     ) {
-        match self.0.as_mut() {
+        match self.inner.as_mut() {
             None => {
-                self.insert(addr, latency, source, confirmed_at);
+                self.insert(addr, latency, source, confirmed_at, events);
             }
             Some(state) => {
                 let candidate = AddrLatency { addr, latency };
                 if !state.is_trusted(confirmed_at) || candidate.is_better_than(&state.addr) {
-                    self.insert(addr, latency, source, confirmed_at);
+                    self.insert(addr, latency, source, confirmed_at, events);
                 } else if state.addr.addr == addr {
                     state.confirmed_at = confirmed_at;
                     state.trust_until = Some(source.trust_until(confirmed_at));
@@ -135,7 +170,7 @@ impl BestAddr {
     /// Reset the expiry, if the passed in addr matches the currently used one.
     #[cfg(not(wasm_browser))]
     pub fn reconfirm_if_used(&mut self, addr: SocketAddr, source: Source, confirmed_at: Instant) {
-        if let Some(state) = self.0.as_mut() {
+        if let Some(state) = self.inner.as_mut() {
             if state.addr.addr == addr {
                 state.confirmed_at = confirmed_at;
                 state.trust_until = Some(source.trust_until(confirmed_at));
@@ -149,15 +184,12 @@ impl BestAddr {
         latency: Duration,
         source: Source,
         confirmed_at: Instant,
+        events: &PathEvents,
     ) {
         let trust_until = source.trust_until(confirmed_at);
 
-        if self
-            .0
-            .as_ref()
-            .map(|prev| prev.addr.addr == addr)
-            .unwrap_or_default()
-        {
+        let previous = self.addr();
+        if previous == Some(addr) {
             debug!(
                 %addr,
                 latency = ?latency,
@@ -171,17 +203,22 @@ impl BestAddr {
                trust_for = ?trust_until.duration_since(Instant::now()),
                "selecting new direct path for node"
             );
+            events.record(PathEventKind::BestAddrSelected {
+                addr,
+                latency,
+                previous,
+            });
         }
         let inner = BestAddrInner {
             addr: AddrLatency { addr, latency },
             trust_until: Some(trust_until),
             confirmed_at,
         };
-        self.0 = Some(inner);
+        self.inner = Some(inner);
     }
 
     pub fn state(&self, now: Instant) -> State {
-        match &self.0 {
+        match &self.inner {
             None => State::Empty,
             Some(state) => match state.trust_until {
                 Some(expiry) if now < expiry => State::Valid(&state.addr),
@@ -191,7 +228,7 @@ impl BestAddr {
     }
 
     pub fn addr(&self) -> Option<SocketAddr> {
-        self.0.as_ref().map(BestAddrInner::addr)
+        self.inner.as_ref().map(BestAddrInner::addr)
     }
 }
 
@@ -16,7 +16,8 @@ use tokio::sync::mpsc;
 use tracing::{debug, event, info, instrument, trace, warn, Level};
 
 use super::{
-    best_addr::{self, ClearReason, Source as BestAddrSource},
+    best_addr::{self, ClearReason, Source as BestAddrSource, UntrustReason},
+    path_events::{PathEventKind, PathEvents},
     path_state::{summarize_node_paths, PathState},
     udp_paths::{NodeUdpPaths, UdpSendAddr},
     IpPort, Source,
@@ -25,7 +26,10 @@ use super::{
 use crate::endpoint::PathSelection;
 use crate::{
     disco::{self, SendAddr},
-    magicsock::{ActorMessage, MagicsockMetrics, NodeIdMappedAddr, HEARTBEAT_INTERVAL},
+    magicsock::{
+        multipath::{self, MultipathMode},
+        ActorMessage, MagicsockMetrics, NodeIdMappedAddr, HEARTBEAT_INTERVAL,
+    },
     watchable::{Watchable, Watcher},
 };
 
@@ -132,12 +136,24 @@ pub(super) struct NodeState {
     /// the [`NodeState::stayin_alive`] function is called, which will trigger new
     /// call-me-maybe messages as backup.
     last_call_me_maybe: Option<Instant>,
+    /// The path the last payload datagram was received on.
+    last_received_path: Option<SendAddr>,
     /// The type of connection we have to the node, either direct, relay, mixed, or none.
     conn_type: Watchable<ConnectionType>,
+    /// The [`PathEvent`]s recorded for this node.
+    ///
+    /// [`PathEvent`]: super::PathEvent
+    path_events: PathEvents,
     /// Whether the conn_type was ever observed to be `Direct` at some point.
     ///
     /// Used for metric reporting.
     has_been_direct: bool,
+    /// The [`MultipathMode`] for this node, overriding the endpoint's default.
+    multipath: Option<MultipathMode>,
+    /// Until when datagrams are duplicated in [`MultipathMode::Adaptive`].
+    ///
+    /// Set after path transitions and when pings on the direct path go unanswered.
+    multipath_transition_until: Option<Instant>,
     /// Configuration for what path selection to use
     #[cfg(any(test, feature = "test-utils"))]
     path_selection: PathSelection,
@@ -183,8 +199,12 @@ impl NodeState {
             sent_pings: HashMap::new(),
             last_used: options.active.then(Instant::now),
             last_call_me_maybe: None,
+            last_received_path: None,
             conn_type: Watchable::new(ConnectionType::None),
+            path_events: Default::default(),
             has_been_direct: false,
+            multipath: None,
+            multipath_transition_until: None,
             #[cfg(any(test, feature = "test-utils"))]
             path_selection: options.path_selection,
         }
@@ -206,6 +226,21 @@ impl NodeState {
         self.conn_type.watch()
     }
 
+    /// Overrides the endpoint's [`MultipathMode`] for this node.
+    pub(super) fn set_multipath(&mut self, mode: Option<MultipathMode>) {
+        self.multipath = mode;
+    }
+
+    /// Starts duplicating datagrams for a while, if the [`MultipathMode`] is adaptive.
+    fn note_multipath_transition(&mut self, now: Instant) {
+        self.multipath_transition_until = Some(now + multipath::TRANSITION_DURATION);
+    }
+
+    /// The [`PathEvents`] recorded for this node.
+    pub(super) fn path_events(&self) -> &PathEvents {
+        &self.path_events
+    }
+
     /// Returns info about this node.
     pub(super) fn info(&self, now: Instant) -> RemoteInfo {
         let conn_type = self.conn_type.get();
@@ -276,11 +311,13 @@ impl NodeState {
 
     /// Returns the address(es) that should be used for sending the next packet.
     ///
-    /// This may return to send on one, both or no paths.
+    /// This may return to send on one, both or no paths.  The `multipath` mode is used
+    /// unless this node has its own [`MultipathMode`] configured.
     fn addr_for_send(
         &mut self,
         now: &Instant,
         have_ipv6: bool,
+        multipath: MultipathMode,
         metrics: &MagicsockMetrics,
     ) -> (Option<SocketAddr>, Option<RelayUrl>) {
         #[cfg(any(test, feature = "test-utils"))]
@@ -288,28 +325,29 @@ impl NodeState {
             debug!("in `RelayOnly` mode, giving the relay address as the only viable address for this endpoint");
             return (None, self.relay_url());
         }
-        let (best_addr, relay_url) = match self.udp_paths.send_addr(*now, have_ipv6) {
-            UdpSendAddr::Valid(addr) => {
-                // If we have a valid address we use it.
-                trace!(%addr, "UdpSendAddr is valid, use it");
-                (Some(addr), None)
-            }
-            UdpSendAddr::Outdated(addr) => {
-                // If the address is outdated we use it, but send via relay at the same time.
-                // We also send disco pings so that it will become valid again if it still
-                // works (i.e. we don't need to holepunch again).
-                trace!(%addr, "UdpSendAddr is outdated, use it together with relay");
-                (Some(addr), self.relay_url())
-            }
-            UdpSendAddr::Unconfirmed(addr) => {
-                trace!(%addr, "UdpSendAddr is unconfirmed, use it together with relay");
-                (Some(addr), self.relay_url())
-            }
-            UdpSendAddr::None => {
-                trace!("No UdpSendAddr, use relay");
-                (None, self.relay_url())
-            }
-        };
+        let (best_addr, relay_url) =
+            match self.udp_paths.send_addr(*now, have_ipv6, &self.path_events) {
+                UdpSendAddr::Valid(addr) => {
+                    // If we have a valid address we use it.
+                    trace!(%addr, "UdpSendAddr is valid, use it");
+                    (Some(addr), None)
+                }
+                UdpSendAddr::Outdated(addr) => {
+                    // If the address is outdated we use it, but send via relay at the same time.
+                    // We also send disco pings so that it will become valid again if it still
+                    // works (i.e. we don't need to holepunch again).
+                    trace!(%addr, "UdpSendAddr is outdated, use it together with relay");
+                    (Some(addr), self.relay_url())
+                }
+                UdpSendAddr::Unconfirmed(addr) => {
+                    trace!(%addr, "UdpSendAddr is unconfirmed, use it together with relay");
+                    (Some(addr), self.relay_url())
+                }
+                UdpSendAddr::None => {
+                    trace!("No UdpSendAddr, use relay");
+                    (None, self.relay_url())
+                }
+            };
         let typ = match (best_addr, relay_url.clone()) {
             (Some(best_addr), Some(relay_url)) => ConnectionType::Mixed(best_addr, relay_url),
             (Some(best_addr), None) => ConnectionType::Direct(best_addr),
@@ -329,6 +367,12 @@ impl NodeState {
                 conn_type = ?typ,
             );
             info!(%typ, "new connection type");
+            self.note_multipath_transition(*now);
+            self.path_events()
+                .record(PathEventKind::ConnectionTypeChanged {
+                    previous: prev_typ.clone(),
+                    current: typ.clone(),
+                });
 
             // Update some metrics
             match (prev_typ, typ) {
@@ -359,6 +403,19 @@ impl NodeState {
                 _ => (),
             }
         }
+
+        // Duplicating over the relay does not change the connection type, the relay copy is
+        // only a backup for the direct path.
+        if let (Some(addr), None) = (best_addr, &relay_url) {
+            let multipath = self.multipath.unwrap_or(multipath);
+            if multipath.should_duplicate(self.multipath_transition_until, *now) {
+                if let Some(relay_url) = self.relay_url() {
+                    trace!(%addr, %relay_url, ?multipath, "duplicating via relay");
+                    metrics.send_multipath_duplicated.inc();
+                    return (best_addr, Some(relay_url));
+                }
+            }
+        }
         (best_addr, relay_url)
     }
 
@@ -374,11 +431,16 @@ impl NodeState {
             Some(last_alive) => debug!(%ip_port, ?last_alive, ?reason, "pruning address"),
             None => debug!(%ip_port, last_seen=%"never", ?reason, "pruning address"),
         }
+        self.path_events().record(PathEventKind::PathRemoved {
+            addr: (*ip_port).into(),
+            reason,
+        });
 
         self.udp_paths.best_addr.clear_if_equals(
             (*ip_port).into(),
             reason,
             self.relay_url.is_some(),
+            &self.path_events,
         );
     }
 
@@ -432,6 +494,9 @@ impl NodeState {
     pub(super) fn ping_timeout(&mut self, txid: stun::TransactionId) {
         if let Some(sp) = self.sent_pings.remove(&txid) {
             debug!(tx = %HEXLOWER.encode(&txid), addr = %sp.to, "pong not received in timeout");
+            self.path_events().record(PathEventKind::PingTimeout {
+                path: sp.to.clone().into(),
+            });
             match sp.to {
                 SendAddr::Udp(addr) => {
                     if let Some(path_state) = self.udp_paths.paths.get_mut(&addr.into()) {
@@ -446,10 +511,14 @@ impl NodeState {
                             // pong.  Both are used to select this path again, but we know
                             // it's not a usable path now.
                             path_state.recent_pong = None;
+                            if self.udp_paths.best_addr.addr() == Some(addr) {
+                                self.note_multipath_transition(Instant::now());
+                            }
                             self.udp_paths.best_addr.clear_if_equals(
                                 addr,
                                 ClearReason::PongTimeout,
                                 self.relay_url().is_some(),
+                                &self.path_events,
                             )
                         }
                     } else {
@@ -459,6 +528,7 @@ impl NodeState {
                             addr,
                             ClearReason::PongTimeout,
                             self.relay_url.is_some(),
+                            &self.path_events,
                         );
                     }
                 }
@@ -595,6 +665,9 @@ impl NodeState {
 
         if let Some(url) = self.relay_url() {
             debug!(%url, "queue call-me-maybe");
+            self.path_events().record(PathEventKind::CallMeMaybeSent {
+                relay_url: url.clone(),
+            });
             msgs.push(PingAction::SendCallMeMaybe {
                 relay_url: url,
                 dst_node: self.node_id,
@@ -637,6 +710,7 @@ impl NodeState {
 
         self.prune_direct_addresses();
         let mut ping_dsts = String::from("[");
+        let mut pinged_addrs = Vec::new();
         self.udp_paths
             .paths
             .iter()
@@ -647,6 +721,9 @@ impl NodeState {
             .for_each(|msg| {
                 use std::fmt::Write;
                 write!(&mut ping_dsts, " {} ", msg.dst).ok();
+                if let SendAddr::Udp(addr) = msg.dst {
+                    pinged_addrs.push(addr);
+                }
                 ping_msgs.push(PingAction::SendPing(msg));
             });
         ping_dsts.push(']');
@@ -656,6 +733,11 @@ impl NodeState {
             paths = %summarize_node_paths(&self.udp_paths.paths),
             "sending pings to node",
         );
+        if !pinged_addrs.is_empty() {
+            self.path_events().record(PathEventKind::HolepunchStarted {
+                addrs: pinged_addrs,
+            });
+        }
         self.last_full_ping.replace(now);
         ping_msgs
     }
@@ -713,9 +795,11 @@ impl NodeState {
     #[instrument(skip_all, fields(node = %self.node_id.fmt_short()))]
     pub(super) fn reset(&mut self) {
         self.last_full_ping = None;
-        self.udp_paths
-            .best_addr
-            .clear(ClearReason::Reset, self.relay_url.is_some());
+        self.udp_paths.best_addr.clear(
+            ClearReason::Reset,
+            self.relay_url.is_some(),
+            &self.path_events,
+        );
 
         for es in self.udp_paths.paths.values_mut() {
             es.last_ping = None;
@@ -876,7 +960,10 @@ impl NodeState {
     /// assumptions about which paths work.
     #[instrument("disco", skip_all, fields(node = %self.node_id.fmt_short()))]
     pub(super) fn note_connectivity_change(&mut self) {
-        self.udp_paths.best_addr.clear_trust("connectivity changed");
+        self.note_multipath_transition(Instant::now());
+        self.udp_paths
+            .best_addr
+            .clear_trust(UntrustReason::ConnectivityChanged, &self.path_events);
         for es in self.udp_paths.paths.values_mut() {
             es.clear();
         }
@@ -922,6 +1009,10 @@ impl NodeState {
                     latency = %latency.as_millis(),
                     "received pong",
                 );
+                self.path_events().record(PathEventKind::PongReceived {
+                    path: src.clone().into(),
+                    latency,
+                });
 
                 match src {
                     SendAddr::Udp(addr) => {
@@ -978,6 +1069,7 @@ impl NodeState {
                         latency,
                         best_addr::Source::ReceivedPong,
                         now,
+                        &self.path_events,
                     );
                 }
 
@@ -999,6 +1091,10 @@ impl NodeState {
     pub(super) fn handle_call_me_maybe(&mut self, m: disco::CallMeMaybe) -> Vec<PingAction> {
         let now = Instant::now();
         let mut call_me_maybe_ipps = BTreeSet::new();
+        self.path_events()
+            .record(PathEventKind::CallMeMaybeReceived {
+                addrs: m.my_numbers.clone(),
+            });
 
         for peer_sockaddr in &m.my_numbers {
             if let IpAddr::V6(ip) = peer_sockaddr.ip() {
@@ -1046,7 +1142,7 @@ impl NodeState {
             if !call_me_maybe_ipps.contains(&ipp) {
                 self.udp_paths
                     .best_addr
-                    .clear_trust("best_addr not in new call-me-maybe");
+                    .clear_trust(UntrustReason::NotInCallMeMaybe, &self.path_events);
                 self.last_call_me_maybe = None;
             }
         }
@@ -1066,6 +1162,7 @@ impl NodeState {
         };
         state.last_payload_msg = Some(now);
         self.last_used = Some(now);
+        self.last_received_path = Some(SendAddr::Udp(addr.into()));
         self.udp_paths
             .best_addr
             .reconfirm_if_used(addr.into(), BestAddrSource::Udp, now);
@@ -1093,6 +1190,12 @@ impl NodeState {
             }
         }
         self.last_used = Some(now);
+        self.last_received_path = Some(SendAddr::Relay(url.clone()));
+    }
+
+    /// Returns the path the last payload datagram from this node was received on.
+    pub(super) fn last_received_path(&self) -> Option<&SendAddr> {
+        self.last_received_path.as_ref()
     }
 
     pub(super) fn last_ping(&self, addr: &SendAddr) -> Option<Instant> {
@@ -1168,6 +1271,7 @@ impl NodeState {
     pub(crate) fn get_send_addrs(
         &mut self,
         have_ipv6: bool,
+        multipath: MultipathMode,
         metrics: &MagicsockMetrics,
     ) -> (Option<SocketAddr>, Option<RelayUrl>, Vec<PingAction>) {
         let now = Instant::now();
@@ -1176,7 +1280,7 @@ impl NodeState {
             // this is the first time we are trying to connect to this node
             metrics.nodes_contacted.inc();
         }
-        let (udp_addr, relay_url) = self.addr_for_send(&now, have_ipv6, metrics);
+        let (udp_addr, relay_url) = self.addr_for_send(&now, have_ipv6, multipath, metrics);
         let mut ping_msgs = Vec::new();
 
         if self.want_call_me_maybe(&now) {
@@ -1524,8 +1628,12 @@ mod tests {
                     sent_pings: HashMap::new(),
                     last_used: Some(now),
                     last_call_me_maybe: None,
+                    last_received_path: None,
                     conn_type: Watchable::new(ConnectionType::Direct(ip_port.into())),
+                    path_events: Default::default(),
                     has_been_direct: true,
+                    multipath: None,
+                    multipath_transition_until: None,
                     #[cfg(any(test, feature = "test-utils"))]
                     path_selection: PathSelection::default(),
                 },
@@ -1546,8 +1654,12 @@ mod tests {
                 sent_pings: HashMap::new(),
                 last_used: Some(now),
                 last_call_me_maybe: None,
+                last_received_path: None,
                 conn_type: Watchable::new(ConnectionType::Relay(send_addr.clone())),
+                path_events: Default::default(),
                 has_been_direct: false,
+                multipath: None,
+                multipath_transition_until: None,
                 #[cfg(any(test, feature = "test-utils"))]
                 path_selection: PathSelection::default(),
             }
@@ -1575,8 +1687,12 @@ mod tests {
                 sent_pings: HashMap::new(),
                 last_used: Some(now),
                 last_call_me_maybe: None,
+                last_received_path: None,
                 conn_type: Watchable::new(ConnectionType::Relay(send_addr.clone())),
+                path_events: Default::default(),
                 has_been_direct: false,
+                multipath: None,
+                multipath_transition_until: None,
                 #[cfg(any(test, feature = "test-utils"))]
                 path_selection: PathSelection::default(),
             }
@@ -1614,11 +1730,15 @@ mod tests {
                     sent_pings: HashMap::new(),
                     last_used: Some(now),
                     last_call_me_maybe: None,
+                    last_received_path: None,
                     conn_type: Watchable::new(ConnectionType::Mixed(
                         socket_addr,
                         send_addr.clone(),
                     )),
+                    path_events: Default::default(),
                     has_been_direct: false,
+                    multipath: None,
+                    multipath_transition_until: None,
                     #[cfg(any(test, feature = "test-utils"))]
                     path_selection: PathSelection::default(),
                 },
@@ -1757,4 +1877,52 @@ mod tests {
         // number of pings as direct addresses in the call-me-maybe.
         assert_eq!(ping_messages.len(), my_numbers_count as usize);
     }
+
+    #[test]
+    fn test_multipath_duplicates_via_relay() {
+        let key = SecretKey::generate(rand::thread_rng());
+        let relay_url: RelayUrl = "https://my-relay.com".parse().unwrap();
+        let opts = Options {
+            node_id: key.public(),
+            relay_url: Some(relay_url.clone()),
+            active: true,
+            source: crate::magicsock::Source::NamedApp {
+                name: "test".into(),
+            },
+            path_selection: PathSelection::default(),
+        };
+        let mut ep = NodeState::new(0, opts);
+        let metrics = MagicsockMetrics::default();
+        let now = Instant::now();
+        let addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 1000);
+        ep.udp_paths = NodeUdpPaths::from_parts(
+            BTreeMap::new(),
+            BestAddr::from_parts(
+                addr,
+                Duration::from_millis(10),
+                now,
+                now + Duration::from_secs(100),
+            ),
+        );
+
+        // Without multipath only the direct path is used.
+        let send = ep.addr_for_send(&now, false, MultipathMode::Disabled, &metrics);
+        assert_eq!(send, (Some(addr), None));
+
+        // The connection type just changed, so adaptive multipath duplicates for a while.
+        let send = ep.addr_for_send(&now, false, MultipathMode::Adaptive, &metrics);
+        assert_eq!(send, (Some(addr), Some(relay_url.clone())));
+        let later = now + multipath::TRANSITION_DURATION;
+        let send = ep.addr_for_send(&later, false, MultipathMode::Adaptive, &metrics);
+        assert_eq!(send, (Some(addr), None));
+
+        // The node's own mode takes precedence.
+        ep.set_multipath(Some(MultipathMode::Always));
+        let send = ep.addr_for_send(&later, false, MultipathMode::Disabled, &metrics);
+        assert_eq!(send, (Some(addr), Some(relay_url)));
+
+        // Duplicating does not change the connection type.
+        assert_eq!(ep.conn_type.get(), ConnectionType::Direct(addr));
+        assert_eq!(metrics.send_multipath_duplicated.get(), 2);
+    }
 }
@@ -0,0 +1,244 @@
+//! Events describing how the network paths to a single remote node change.
+//!
+//! The [`NodeState`] and [`BestAddr`] record a [`PathEvent`] whenever they make a decision
+//! about the paths to a remote node.  A short history of these events is kept for each node
+//! and they are also broadcast to any subscribers.
+//!
+//! [`NodeState`]: super::node_state::NodeState
+//! [`BestAddr`]: super::best_addr::BestAddr
+
+use std::{
+    collections::VecDeque,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
+
+use iroh_base::RelayUrl;
+use n0_future::{
+    time::{Duration, SystemTime},
+    Stream, TryStreamExt,
+};
+use tokio::sync::broadcast;
+
+use super::{
+    best_addr::{ClearReason, UntrustReason},
+    node_state::ConnectionType,
+};
+use crate::{disco::SendAddr, discovery::Lagged};
+
+/// Number of [`PathEvent`]s kept in the history of each node.
+const HISTORY_CAPACITY: usize = 32;
+
+/// Number of [`PathEvent`]s buffered for subscribers which do not keep up.
+const BROADCAST_CAPACITY: usize = 64;
+
+/// A network path to a remote node.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, derive_more::Display)]
+pub enum NetworkPath {
+    /// A direct UDP path to the given address.
+    #[display("direct({_0})")]
+    Direct(SocketAddr),
+    /// A path via the given relay server.
+    #[display("relay({_0})")]
+    Relay(RelayUrl),
+}
+
+impl NetworkPath {
+    /// Returns whether this is a path via a relay server.
+    pub fn is_relay(&self) -> bool {
+        matches!(self, Self::Relay(_))
+    }
+}
+
+impl From<SendAddr> for NetworkPath {
+    fn from(addr: SendAddr) -> Self {
+        match addr {
+            SendAddr::Udp(addr) => Self::Direct(addr),
+            SendAddr::Relay(url) => Self::Relay(url),
+        }
+    }
+}
+
+/// An event about the network paths to a remote node.
+///
+/// See [`Endpoint::path_events`] for details.
+///
+/// [`Endpoint::path_events`]: crate::endpoint::Endpoint::path_events
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PathEvent {
+    /// When the event happened.
+    pub time: SystemTime,
+    /// What happened.
+    pub kind: PathEventKind,
+}
+
+/// The kind of a [`PathEvent`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum PathEventKind {
+    /// A holepunching attempt started, sending pings to these direct addresses.
+    HolepunchStarted {
+        /// The direct addresses pinged.
+        addrs: Vec<SocketAddr>,
+    },
+    /// A call-me-maybe was sent to the node via its relay.
+    CallMeMaybeSent {
+        /// The relay the call-me-maybe was sent through.
+        relay_url: RelayUrl,
+    },
+    /// A call-me-maybe was received from the node.
+    CallMeMaybeReceived {
+        /// The direct addresses the node asked us to ping.
+        addrs: Vec<SocketAddr>,
+    },
+    /// A pong was received on a path.
+    PongReceived {
+        /// The path the pong was received on.
+        path: NetworkPath,
+        /// The measured round trip time.
+        latency: Duration,
+    },
+    /// No pong was received for a ping sent on a path.
+    PingTimeout {
+        /// The path the ping was sent on.
+        path: NetworkPath,
+    },
+    /// A new best direct address was selected.
+    BestAddrSelected {
+        /// The newly selected address.
+        addr: SocketAddr,
+        /// The latency of the newly selected address.
+        latency: Duration,
+        /// The previously selected address, if any.
+        previous: Option<SocketAddr>,
+    },
+    /// The best direct address was cleared.
+    BestAddrCleared {
+        /// The address which was cleared.
+        addr: SocketAddr,
+        /// Why the address was cleared.
+        reason: ClearReason,
+    },
+    /// The best direct address is no longer trusted and needs to be confirmed again.
+    BestAddrUntrusted {
+        /// The address which is no longer trusted.
+        addr: SocketAddr,
+        /// Why the trust was cleared.
+        reason: UntrustReason,
+    },
+    /// A direct address was removed from the known paths.
+    PathRemoved {
+        /// The removed address.
+        addr: SocketAddr,
+        /// Why the address was removed.
+        reason: ClearReason,
+    },
+    /// The [`ConnectionType`] changed.
+    ConnectionTypeChanged {
+        /// The previous connection type.
+        previous: ConnectionType,
+        /// The new connection type.
+        current: ConnectionType,
+    },
+}
+
+/// Records the [`PathEvent`]s of a single node.
+///
+/// Clones share the same history and subscribers.  Subscriber streams end once all clones
+/// are dropped, which happens when the node is removed from the node map.
+#[derive(Debug, Clone, Default)]
+pub(super) struct PathEvents(Arc<Mutex<Inner>>);
+
+#[derive(Debug, Default)]
+struct Inner {
+    history: VecDeque<PathEvent>,
+    /// Only created once there is a subscriber.
+    sender: Option<broadcast::Sender<PathEvent>>,
+}
+
+impl PathEvents {
+    /// Records a new event.
+    pub(super) fn record(&self, kind: PathEventKind) {
+        let event = PathEvent {
+            time: SystemTime::now(),
+            kind,
+        };
+        let mut inner = self.0.lock().expect("poisoned");
+        if inner.history.len() == HISTORY_CAPACITY {
+            inner.history.pop_front();
+        }
+        inner.history.push_back(event.clone());
+        if let Some(ref sender) = inner.sender {
+            // Errors only if there are no subscribers left, which is fine.
+            sender.send(event).ok();
+        }
+    }
+
+    /// Returns the most recent events, oldest first.
+    pub(super) fn history(&self) -> Vec<PathEvent> {
+        self.0
+            .lock()
+            .expect("poisoned")
+            .history
+            .iter()
+            .cloned()
+            .collect()
+    }
+
+    /// Subscribes to all future events.
+    ///
+    /// The stream ends once the node is removed from the node map.
+    pub(super) fn subscribe(&self) -> impl Stream<Item = Result<PathEvent, Lagged>> {
+        use tokio_stream::wrappers::{errors::BroadcastStreamRecvError, BroadcastStream};
+        let recv = self
+            .0
+            .lock()
+            .expect("poisoned")
+            .sender
+            .get_or_insert_with(|| broadcast::Sender::new(BROADCAST_CAPACITY))
+            .subscribe();
+        BroadcastStream::new(recv).map_err(|BroadcastStreamRecvError::Lagged(n)| Lagged(n))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use n0_future::StreamExt;
+
+    use super::*;
+
+    fn addr(port: u16) -> SocketAddr {
+        (std::net::Ipv4Addr::LOCALHOST, port).into()
+    }
+
+    #[tokio::test]
+    async fn test_history_and_subscribe() {
+        let events = PathEvents::default();
+        for port in 0..(HISTORY_CAPACITY as u16 + 3) {
+            events.record(PathEventKind::PingTimeout {
+                path: NetworkPath::Direct(addr(port)),
+            });
+        }
+        let history = events.history();
+        assert_eq!(history.len(), HISTORY_CAPACITY);
+        assert_eq!(
+            history[0].kind,
+            PathEventKind::PingTimeout {
+                path: NetworkPath::Direct(addr(3))
+            }
+        );
+
+        let mut stream = std::pin::pin!(events.subscribe());
+        let kind = PathEventKind::BestAddrCleared {
+            addr: addr(1),
+            reason: ClearReason::PongTimeout,
+        };
+        events.clone().record(kind.clone());
+        let event = stream.next().await.unwrap().unwrap();
+        assert_eq!(event.kind, kind);
+
+        // Dropping the last clone closes the stream.
+        drop(events);
+        assert!(stream.next().await.is_none());
+    }
+}
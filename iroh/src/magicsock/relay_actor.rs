@@ -26,6 +26,8 @@
 
 #[cfg(test)]
 use std::net::SocketAddr;
+#[cfg(not(wasm_browser))]
+use std::net::{Ipv4Addr, Ipv6Addr};
 use std::{
     collections::{BTreeMap, BTreeSet},
     future::Future,
@@ -63,6 +65,8 @@ use crate::{
     magicsock::{MagicSock, Metrics as MagicsockMetrics, RelayContents, RelayDatagramRecvQueue},
     util::MaybeFuture,
 };
+#[cfg(not(wasm_browser))]
+use crate::{net_report::Report, watchable::Watcher};
 
 /// How long a non-home relay connection needs to be idle (last written to) before we close it.
 const RELAY_INACTIVE_CLEANUP_TIME: Duration = Duration::from_secs(60);
@@ -143,6 +147,9 @@ struct ActiveRelayActor {
     url: RelayUrl,
     /// Builder which can repeatedly build a relay client.
     relay_client_builder: relay::client::ClientBuilder,
+    /// Where to relay over QUIC, if the relay server supports it.
+    #[cfg(not(wasm_browser))]
+    quic_relay: Option<QuicRelayOptions>,
     /// Whether or not this is the home relay server.
     ///
     /// The home relay server needs to maintain it's connection to the relay server, even if
@@ -213,6 +220,37 @@ struct RelayConnectionOptions {
     #[cfg(any(test, feature = "test-utils"))]
     insecure_skip_cert_verify: bool,
     protocol: iroh_relay::http::Protocol,
+    #[cfg(not(wasm_browser))]
+    quic_relay: Option<QuicRelayOptions>,
+}
+
+/// Configuration for relaying over QUIC, see [`ClientBuilder::quic_relay`].
+///
+/// [`ClientBuilder::quic_relay`]: relay::client::ClientBuilder::quic_relay
+#[cfg(not(wasm_browser))]
+#[derive(Debug, Clone)]
+struct QuicRelayOptions {
+    /// The endpoint to make the QUIC connections from.
+    endpoint: quinn::Endpoint,
+    /// The UDP port of the relay server's QUIC server.
+    port: u16,
+    /// The latest net report.
+    net_report: Watcher<Option<Arc<Report>>>,
+}
+
+#[cfg(not(wasm_browser))]
+impl QuicRelayOptions {
+    /// Whether the latest net report found UDP to be working.
+    ///
+    /// Relaying over QUIC is only tried if it is, otherwise every connection attempt would
+    /// first have to wait for the QUIC dial to time out before falling back.
+    fn udp_works(&self) -> bool {
+        self.net_report
+            .get()
+            .ok()
+            .flatten()
+            .is_some_and(|report| report.udp)
+    }
 }
 
 /// Possible reasons for a failed relay connection.
@@ -238,6 +276,8 @@ impl ActiveRelayActor {
             stop_token,
             metrics,
         } = opts;
+        #[cfg(not(wasm_browser))]
+        let quic_relay = connection_opts.quic_relay.clone();
         let relay_client_builder = Self::create_relay_builder(url.clone(), connection_opts);
         ActiveRelayActor {
             prio_inbox,
@@ -246,6 +286,8 @@ impl ActiveRelayActor {
             relay_datagrams_send,
             url,
             relay_client_builder,
+            #[cfg(not(wasm_browser))]
+            quic_relay,
             is_home_relay: false,
             inactive_timeout: Box::pin(time::sleep(RELAY_INACTIVE_CLEANUP_TIME)),
             stop_token,
@@ -266,6 +308,8 @@ impl ActiveRelayActor {
             #[cfg(any(test, feature = "test-utils"))]
             insecure_skip_cert_verify,
             protocol,
+            // QUIC relaying is set up for each dial, see `dial_relay`.
+            ..
         } = opts;
 
         let mut builder = relay::client::ClientBuilder::new(
@@ -453,7 +497,14 @@ impl ActiveRelayActor {
     /// forever.
     // This is using `impl Future` to return a future without a reference to self.
     fn dial_relay(&self) -> impl Future<Output = Result<Client>> {
-        let client_builder = self.relay_client_builder.clone();
+        #[allow(unused_mut)]
+        let mut client_builder = self.relay_client_builder.clone();
+        #[cfg(not(wasm_browser))]
+        if let Some(quic_relay) = self.quic_relay.as_ref().filter(|q| q.udp_works()) {
+            // falls back to the configured protocol if the QUIC connection fails
+            client_builder =
+                client_builder.quic_relay(quic_relay.endpoint.clone(), quic_relay.port);
+        }
         async move {
             match time::timeout(CONNECT_TIMEOUT, client_builder.connect()).await {
                 Ok(Ok(client)) => Ok(client),
@@ -823,6 +874,9 @@ pub(super) struct RelayActor {
     active_relay_tasks: JoinSet<()>,
     cancel_token: CancellationToken,
     protocol: iroh_relay::http::Protocol,
+    /// The endpoint used for relaying over QUIC, bound once first needed.
+    #[cfg(not(wasm_browser))]
+    quic_endpoint: Option<quinn::Endpoint>,
 }
 
 impl RelayActor {
@@ -839,6 +893,8 @@ impl RelayActor {
             active_relay_tasks: JoinSet::new(),
             cancel_token,
             protocol,
+            #[cfg(not(wasm_browser))]
+            quic_endpoint: None,
         }
     }
 
@@ -1031,6 +1087,29 @@ impl RelayActor {
         }
     }
 
+    /// Returns the options for relaying over QUIC to `url`, if the relay server supports it.
+    #[cfg(not(wasm_browser))]
+    fn quic_relay_options(&mut self, url: &RelayUrl) -> Option<QuicRelayOptions> {
+        let port = self.msock.relay_map.get_node(url)?.quic.as_ref()?.port;
+        if self.quic_endpoint.is_none() {
+            // A dual-stack socket if possible, so both address families can be dialed.
+            let endpoint = quinn::Endpoint::client((Ipv6Addr::UNSPECIFIED, 0).into())
+                .or_else(|_| quinn::Endpoint::client((Ipv4Addr::UNSPECIFIED, 0).into()));
+            match endpoint {
+                Ok(endpoint) => self.quic_endpoint = Some(endpoint),
+                Err(err) => {
+                    warn!("Failed to bind endpoint for relaying over QUIC: {err:#}");
+                    return None;
+                }
+            }
+        }
+        Some(QuicRelayOptions {
+            endpoint: self.quic_endpoint.clone()?,
+            port,
+            net_report: self.msock.net_report(),
+        })
+    }
+
     fn start_active_relay(&mut self, url: RelayUrl) -> ActiveRelayHandle {
         debug!(?url, "Adding relay connection");
 
@@ -1043,6 +1122,8 @@ impl RelayActor {
             #[cfg(any(test, feature = "test-utils"))]
             insecure_skip_cert_verify: self.msock.insecure_skip_relay_cert_verify,
             protocol: self.protocol,
+            #[cfg(not(wasm_browser))]
+            quic_relay: self.quic_relay_options(&url),
         };
 
         // TODO: Replace 64 with PER_CLIENT_SEND_QUEUE_DEPTH once that's unused
@@ -1346,6 +1427,7 @@ mod tests {
                 prefer_ipv6: Arc::new(AtomicBool::new(true)),
                 insecure_skip_cert_verify: true,
                 protocol: iroh_relay::http::Protocol::default(),
+                quic_relay: None,
             },
             stop_token,
             metrics: Default::default(),
@@ -8,7 +8,10 @@ pub use iroh_relay::server::Metrics as RelayMetrics;
 pub use portmapper::Metrics as PortmapMetrics;
 use serde::{Deserialize, Serialize};
 
-pub use crate::{magicsock::Metrics as MagicsockMetrics, net_report::Metrics as NetReportMetrics};
+pub use crate::{
+    endpoint::pool::Metrics as PoolMetrics, magicsock::Metrics as MagicsockMetrics,
+    net_report::Metrics as NetReportMetrics,
+};
 
 /// Metrics collected by an [`crate::endpoint::Endpoint`].
 ///
@@ -36,7 +36,98 @@
 //!     }
 //! }
 //! ```
-use std::{collections::BTreeMap, sync::Arc};
+//!
+//! ## Middleware
+//!
+//! Checks that are shared between protocols can be added to all or selected protocols as
+//! [`ProtocolLayer`]s, instead of implementing them in every [`ProtocolHandler::accept`]:
+//!
+//! ```no_run
+//! # use anyhow::Result;
+//! # use iroh::{protocol::{AcceptMetrics, Authorize, ConnectionLimit, ProtocolHandler, Router}, Endpoint};
+//! # async fn test_compile(echo: impl ProtocolHandler) -> Result<()> {
+//! let endpoint = Endpoint::builder().discovery_n0().bind().await?;
+//! let metrics = AcceptMetrics::new();
+//!
+//! let router = Router::builder(endpoint)
+//!     .accept(b"/my/alpn", echo)
+//!     .layer(metrics.clone())
+//!     .layer(Authorize::new(|conn| {
+//!         let node_id = conn.remote_node_id();
+//!         async move { node_id.is_ok() }
+//!     }))
+//!     .layer_for([b"/my/alpn"], ConnectionLimit::new(4))
+//!     .spawn();
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! ## Protocol versions
+//!
+//! A protocol with several versions, each with its own ALPN, can be accepted with a single
+//! handler which is told the negotiated version:
+//!
+//! ```no_run
+//! # use anyhow::Result;
+//! # use n0_future::boxed::BoxFuture;
+//! # use iroh::{endpoint::Connection, protocol::{Router, VersionedAlpn, VersionedProtocolHandler}, Endpoint, NodeId};
+//! # async fn test_compile(remote: NodeId) -> Result<()> {
+//! #[derive(Debug)]
+//! struct MyProto;
+//!
+//! impl VersionedProtocolHandler for MyProto {
+//!     fn accept(&self, connection: Connection, version: u32) -> BoxFuture<Result<()>> {
+//!         Box::pin(async move {
+//!             // Speak `version` of the protocol.
+//!             Ok(())
+//!         })
+//!     }
+//! }
+//!
+//! // Accepts `myproto/2` and `myproto/1`, preferring version 2.
+//! let alpn = VersionedAlpn::new("myproto", [2, 1]);
+//!
+//! let endpoint = Endpoint::builder().discovery_n0().bind().await?;
+//! let router = Router::builder(endpoint.clone())
+//!     .accept_versioned(&alpn, MyProto)
+//!     .spawn();
+//!
+//! // Connects with the most preferred version the remote supports.
+//! let (connection, version) = alpn.connect(&endpoint, remote).await?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! ## Rejecting connections early
+//!
+//! Layers only see connections after their handshake completed.  To protect a node under a
+//! flood of connection attempts, the router can limit the number of concurrent handshakes and
+//! decide about connection attempts before any cryptographic work is done for them:
+//!
+//! ```no_run
+//! # use anyhow::Result;
+//! # use iroh::{protocol::{IncomingAction, ProtocolHandler, Router}, Endpoint};
+//! # async fn test_compile(echo: impl ProtocolHandler) -> Result<()> {
+//! let endpoint = Endpoint::builder().discovery_n0().bind().await?;
+//!
+//! let router = Router::builder(endpoint)
+//!     .accept(b"/my/alpn", echo)
+//!     .max_concurrent_handshakes(256)
+//!     .incoming_policy(|info| {
+//!         if info.connections() >= 1024 {
+//!             IncomingAction::Refuse
+//!         } else if !info.is_relay() && info.handshakes() >= 64 {
+//!             // Make remotes prove their address when under load.
+//!             IncomingAction::Retry
+//!         } else {
+//!             IncomingAction::Accept
+//!         }
+//!     })
+//!     .spawn();
+//! # Ok(())
+//! # }
+//! ```
+use std::sync::Arc;
 
 use anyhow::Result;
 use iroh_base::NodeId;
@@ -44,16 +135,33 @@ use n0_future::{
     boxed::BoxFuture,
     join_all,
     task::{self, AbortOnDropHandle, JoinSet},
+    time::Duration,
 };
 use tokio::sync::Mutex;
 use tokio_util::sync::CancellationToken;
 use tracing::{error, info_span, trace, warn, Instrument};
 
+use self::{
+    incoming::{CountGuard, IncomingGate, IncomingPolicyFn},
+    versioned::Versioned,
+};
+pub use self::{
+    incoming::{IncomingAction, IncomingInfo},
+    middleware::{
+        AcceptMetrics, AlpnStats, Authorize, ConnectionLimit, ConnectionTracing, ProtocolLayer,
+    },
+    versioned::{VersionedAlpn, VersionedProtocolHandler},
+};
 use crate::{
-    endpoint::{Connecting, Connection},
+    endpoint::{Connecting, Connection, DrainState, Incoming},
+    watchable::Watcher,
     Endpoint,
 };
 
+mod incoming;
+mod middleware;
+mod versioned;
+
 /// The built router.
 ///
 /// Construct this using [`Router::builder`].
@@ -94,10 +202,22 @@ pub struct Router {
 }
 
 /// Builder for creating a [`Router`] for accepting protocols.
-#[derive(Debug)]
+#[derive(derive_more::Debug)]
 pub struct RouterBuilder {
     endpoint: Endpoint,
     protocols: ProtocolMap,
+    layers: Vec<LayerEntry>,
+    #[debug("{}", if incoming_policy.is_some() { "Some(policy)" } else { "None" })]
+    incoming_policy: Option<Arc<IncomingPolicyFn>>,
+    max_handshakes: Option<usize>,
+}
+
+/// A layer added to a [`RouterBuilder`].
+#[derive(Debug)]
+struct LayerEntry {
+    /// The ALPNs the layer applies to, `None` for all ALPNs
+    alpns: Option<Vec<Vec<u8>>>,
+    layer: Box<dyn ProtocolLayer>,
 }
 
 /// Handler for incoming connections.
@@ -131,7 +251,7 @@ pub trait ProtocolHandler: Send + Sync + std::fmt::Debug + 'static {
     }
 }
 
-impl<T: ProtocolHandler> ProtocolHandler for Arc<T> {
+impl<T: ProtocolHandler + ?Sized> ProtocolHandler for Arc<T> {
     fn on_connecting(&self, conn: Connecting) -> BoxFuture<Result<Connection>> {
         self.as_ref().on_connecting(conn)
     }
@@ -145,7 +265,7 @@ impl<T: ProtocolHandler> ProtocolHandler for Arc<T> {
     }
 }
 
-impl<T: ProtocolHandler> ProtocolHandler for Box<T> {
+impl<T: ProtocolHandler + ?Sized> ProtocolHandler for Box<T> {
     fn on_connecting(&self, conn: Connecting) -> BoxFuture<Result<Connection>> {
         self.as_ref().on_connecting(conn)
     }
@@ -160,30 +280,54 @@ impl<T: ProtocolHandler> ProtocolHandler for Box<T> {
 }
 
 /// A typed map of protocol handlers, mapping them from ALPNs.
+///
+/// The ALPNs are kept in the order they were first inserted, which is the order of
+/// preference they are advertised in.
 #[derive(Debug, Default)]
-pub(crate) struct ProtocolMap(BTreeMap<Vec<u8>, Box<dyn ProtocolHandler>>);
+pub(crate) struct ProtocolMap(Vec<(Vec<u8>, Arc<dyn ProtocolHandler>)>);
 
 impl ProtocolMap {
     /// Returns the registered protocol handler for an ALPN as a [`Arc<dyn ProtocolHandler>`].
     pub(crate) fn get(&self, alpn: &[u8]) -> Option<&dyn ProtocolHandler> {
-        self.0.get(alpn).map(|p| &**p)
+        self.0
+            .iter()
+            .find(|(a, _)| a == alpn)
+            .map(|(_, handler)| &**handler)
+    }
+
+    /// Inserts a protocol handler, replacing the handler of an already inserted ALPN.
+    pub(crate) fn insert(&mut self, alpn: Vec<u8>, handler: Arc<dyn ProtocolHandler>) {
+        match self.0.iter_mut().find(|(a, _)| *a == alpn) {
+            Some((_, existing)) => *existing = handler,
+            None => self.0.push((alpn, handler)),
+        }
     }
 
-    /// Inserts a protocol handler.
-    pub(crate) fn insert(&mut self, alpn: Vec<u8>, handler: Box<dyn ProtocolHandler>) {
-        self.0.insert(alpn, handler);
+    /// Wraps the protocol handlers for `alpns` with a layer, or all handlers if `alpns` is
+    /// `None`.
+    pub(crate) fn layer(&mut self, alpns: Option<&[Vec<u8>]>, layer: &dyn ProtocolLayer) {
+        for (alpn, handler) in self.0.iter_mut() {
+            let selected = match alpns {
+                Some(alpns) => alpns.contains(alpn),
+                None => true,
+            };
+            if selected {
+                *handler = layer.layer(alpn, handler.clone());
+            }
+        }
     }
 
-    /// Returns an iterator of all registered ALPN protocol identifiers.
+    /// Returns an iterator of all registered ALPN protocol identifiers, in order of
+    /// preference.
     pub(crate) fn alpns(&self) -> impl Iterator<Item = &Vec<u8>> {
-        self.0.keys()
+        self.0.iter().map(|(alpn, _)| alpn)
     }
 
     /// Shuts down all protocol handlers.
     ///
     /// Calls and awaits [`ProtocolHandler::shutdown`] for all registered handlers concurrently.
     pub(crate) async fn shutdown(&self) {
-        let handlers = self.0.values().map(|p| p.shutdown());
+        let handlers = self.0.iter().map(|(_, p)| p.shutdown());
         join_all(handlers).await;
     }
 }
@@ -225,9 +369,39 @@ impl Router {
         if let Some(task) = self.task.lock().await.take() {
             task.await?;
         }
-
         Ok(())
     }
+
+    /// Drains the router, then shuts it down.
+    ///
+    /// This is a graceful alternative to [`Router::shutdown`], e.g. for rolling deploys.  The
+    /// endpoint starts draining (see [`Endpoint::start_draining`]), so new connections are
+    /// refused and the node's address is no longer published to discovery.  Then this waits
+    /// up to `timeout` for all connections of the endpoint to be closed, by either side,
+    /// before shutting down like [`Router::shutdown`].  Connections that are still open are
+    /// closed with [`DRAIN_CLOSE_CODE`].
+    ///
+    /// The progress can be observed with [`Router::drain_state`].
+    ///
+    /// [`DRAIN_CLOSE_CODE`]: crate::endpoint::DRAIN_CLOSE_CODE
+    pub async fn drain(&self, timeout: Duration) -> Result<()> {
+        if self.is_shutdown() {
+            return Ok(());
+        }
+        self.endpoint.start_draining();
+        self.endpoint.wait_drained(timeout).await;
+        // Shutting down drops the connections of the handlers, so close them first.
+        self.endpoint.close_drained_connections();
+        self.shutdown().await
+    }
+
+    /// Returns a [`Watcher`] for the progress of draining the router.
+    ///
+    /// This is the drain state of the endpoint, see [`Router::drain`] and
+    /// [`Endpoint::drain_state`].
+    pub fn drain_state(&self) -> Watcher<DrainState> {
+        self.endpoint.drain_state()
+    }
 }
 
 impl RouterBuilder {
@@ -236,24 +410,106 @@ impl RouterBuilder {
         Self {
             endpoint,
             protocols: ProtocolMap::default(),
+            layers: Vec::new(),
+            incoming_policy: None,
+            max_handshakes: None,
         }
     }
 
     /// Configures the router to accept the [`ProtocolHandler`] when receiving a connection
     /// with this `alpn`.
+    ///
+    /// ALPNs are advertised in the order they were first added.  When a connecting node
+    /// offers several of them, the first one in this order is used.
     pub fn accept<T: ProtocolHandler>(mut self, alpn: impl AsRef<[u8]>, handler: T) -> Self {
-        let handler = Box::new(handler);
+        let handler = Arc::new(handler);
         self.protocols.insert(alpn.as_ref().to_vec(), handler);
         self
     }
 
+    /// Configures the router to accept all versions of a protocol with one
+    /// [`VersionedProtocolHandler`].
+    ///
+    /// The ALPNs of all versions are advertised in the order of preference of the
+    /// [`VersionedAlpn`], so a connecting node offering several versions gets the most
+    /// preferred one.  The handler is told which version a connection negotiated.
+    pub fn accept_versioned<T: VersionedProtocolHandler>(
+        mut self,
+        alpn: &VersionedAlpn,
+        handler: T,
+    ) -> Self {
+        for (alpn, handler) in Versioned::handlers(alpn, handler) {
+            self.protocols.insert(alpn, handler);
+        }
+        self
+    }
+
+    /// Wraps the handlers of all protocols with a [`ProtocolLayer`].
+    ///
+    /// Layers are applied when the router is spawned, to all protocols accepted by then. The
+    /// layer added first is the outermost one, it sees each connection first.
+    pub fn layer(mut self, layer: impl ProtocolLayer) -> Self {
+        self.layers.push(LayerEntry {
+            alpns: None,
+            layer: Box::new(layer),
+        });
+        self
+    }
+
+    /// Wraps the handlers of the protocols with the given `alpns` with a [`ProtocolLayer`].
+    ///
+    /// See [`Self::layer`] for the order in which layers are applied.
+    pub fn layer_for(
+        mut self,
+        alpns: impl IntoIterator<Item = impl AsRef<[u8]>>,
+        layer: impl ProtocolLayer,
+    ) -> Self {
+        let alpns = alpns.into_iter().map(|a| a.as_ref().to_vec()).collect();
+        self.layers.push(LayerEntry {
+            alpns: Some(alpns),
+            layer: Box::new(layer),
+        });
+        self
+    }
+
+    /// Sets a policy for incoming connection attempts.
+    ///
+    /// The policy is called for every connection attempt before its handshake, with the
+    /// [`IncomingInfo`] about where it was received from and the current load of the router.
+    /// Connection attempts are refused, retried or ignored according to the returned
+    /// [`IncomingAction`].  Without a policy all connection attempts are accepted.
+    pub fn incoming_policy<F>(mut self, policy: F) -> Self
+    where
+        F: Fn(&IncomingInfo) -> IncomingAction + Send + Sync + 'static,
+    {
+        self.incoming_policy = Some(Arc::new(policy));
+        self
+    }
+
+    /// Limits the number of concurrent handshakes.
+    ///
+    /// Connection attempts while `max` handshakes are in progress are refused, without
+    /// consulting the [`Self::incoming_policy`].  A handshake is in progress until the
+    /// [`ProtocolHandler::on_connecting`] of its protocol returned.  By default the number
+    /// of concurrent handshakes is not limited.
+    pub fn max_concurrent_handshakes(mut self, max: usize) -> Self {
+        self.max_handshakes = Some(max);
+        self
+    }
+
     /// Returns the [`Endpoint`] of the node.
     pub fn endpoint(&self) -> &Endpoint {
         &self.endpoint
     }
 
     /// Spawns an accept loop and returns a handle to it encapsulated as the [`Router`].
-    pub fn spawn(self) -> Router {
+    pub fn spawn(mut self) -> Router {
+        // The first layer is the outermost, so it is applied last.
+        for entry in self.layers.iter().rev() {
+            self.protocols
+                .layer(entry.alpns.as_deref(), entry.layer.as_ref());
+        }
+
         // Update the endpoint with our alpns.
         let alpns = self
             .protocols
@@ -263,6 +519,7 @@ impl RouterBuilder {
 
         let protocols = Arc::new(self.protocols);
         self.endpoint.set_alpns(alpns);
+        let gate = Arc::new(IncomingGate::new(self.incoming_policy, self.max_handshakes));
 
         let mut join_set = JoinSet::new();
         let endpoint = self.endpoint.clone();
@@ -309,11 +566,15 @@ impl RouterBuilder {
                         let Some(incoming) = incoming else {
                             break; // Endpoint is closed.
                         };
+                        let Some((incoming, handshake)) = gate.admit(incoming) else {
+                            continue;
+                        };
 
                         let protocols = protocols.clone();
+                        let gate = gate.clone();
                         let token = cancel_token.child_token();
                         join_set.spawn(async move {
-                            token.run_until_cancelled(handle_connection(incoming, protocols)).await
+                            token.run_until_cancelled(handle_connection(incoming, handshake, protocols, gate)).await
                         }.instrument(info_span!("router.accept")));
                     },
                 }
@@ -347,7 +608,12 @@ async fn shutdown(endpoint: &Endpoint, protocols: Arc<ProtocolMap>) {
     );
 }
 
-async fn handle_connection(incoming: crate::endpoint::Incoming, protocols: Arc<ProtocolMap>) {
+async fn handle_connection(
+    incoming: Incoming,
+    handshake: CountGuard,
+    protocols: Arc<ProtocolMap>,
+    gate: Arc<IncomingGate>,
+) {
     let mut connecting = match incoming.accept() {
         Ok(conn) => conn,
         Err(err) => {
@@ -366,8 +632,11 @@ async fn handle_connection(incoming: crate::endpoint::Incoming, protocols: Arc<P
         warn!("Ignoring connection: unsupported ALPN protocol");
         return;
     };
-    match handler.on_connecting(connecting).await {
+    let connection = handler.on_connecting(connecting).await;
+    drop(handshake);
+    match connection {
         Ok(connection) => {
+            let _connection = gate.connection();
             if let Err(err) = handler.accept(connection).await {
                 warn!("Handling incoming connection ended with error: {err}");
             }
@@ -494,4 +763,249 @@ mod tests {
 
         Ok(())
     }
+
+    #[tokio::test]
+    async fn test_middleware() -> Result<()> {
+        let e1 = Endpoint::builder().bind().await?;
+        let e2 = Endpoint::builder().bind().await?;
+        let e3 = Endpoint::builder().bind().await?;
+        let allowed = e2.node_id();
+        let metrics = AcceptMetrics::new();
+        let limit = ConnectionLimit::new(1);
+        let r1 = Router::builder(e1.clone())
+            .accept(ECHO_ALPN, Echo)
+            .layer(ConnectionTracing)
+            .layer(metrics.clone())
+            .layer(Authorize::new(move |conn| {
+                let node_id = conn.remote_node_id().ok();
+                async move { node_id == Some(allowed) }
+            }))
+            .layer_for([ECHO_ALPN], limit.clone())
+            .spawn();
+        let addr1 = r1.endpoint().node_addr().await?;
+
+        // the allowed node can use the protocol
+        let conn = e2.connect(addr1.clone(), ECHO_ALPN).await?;
+        let (mut send, mut recv) = conn.open_bi().await?;
+        send.write_all(b"hello").await?;
+        send.finish()?;
+        assert_eq!(recv.read_to_end(1000).await?, b"hello");
+        assert_eq!(limit.active(&allowed), 1);
+
+        // a second concurrent connection is over the limit
+        let conn2 = e2.connect(addr1.clone(), ECHO_ALPN).await?;
+        let (_send, mut recv) = conn2.open_bi().await?;
+        let response = recv.read_to_end(1000).await.unwrap_err();
+        assert!(format!("{:#?}", response).contains("too many connections"));
+
+        // other nodes are not allowed
+        let conn3 = e3.connect(addr1.clone(), ECHO_ALPN).await?;
+        let (_send, mut recv) = conn3.open_bi().await?;
+        let response = recv.read_to_end(1000).await.unwrap_err();
+        assert!(format!("{:#?}", response).contains("not allowed"));
+
+        // closing the connection releases it
+        conn.close(0u32.into(), b"done");
+        tokio::time::timeout(std::time::Duration::from_secs(5), async {
+            while limit.active(&allowed) > 0 || metrics.get(ECHO_ALPN).active > 0 {
+                tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+            }
+        })
+        .await?;
+        let stats = metrics.get(ECHO_ALPN);
+        assert_eq!(stats.accepted, 3);
+        assert_eq!(stats.failed, 2);
+
+        r1.shutdown().await?;
+        e2.close().await;
+        e3.close().await;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_incoming_policy() -> Result<()> {
+        let e1 = Endpoint::builder().bind().await?;
+        let e2 = Endpoint::builder().bind().await?;
+        let (infos_tx, infos_rx) = std::sync::mpsc::channel();
+        let r1 = Router::builder(e1.clone())
+            .accept(ECHO_ALPN, Echo)
+            .incoming_policy(move |info| {
+                infos_tx.send(info.clone()).ok();
+                if info.remote_address_validated() {
+                    IncomingAction::Accept
+                } else {
+                    IncomingAction::Retry
+                }
+            })
+            .spawn();
+        let addr1 = r1.endpoint().node_addr().await?;
+
+        // the connection is accepted after validating its address
+        let conn = e2.connect(addr1.clone(), ECHO_ALPN).await?;
+        let (mut send, mut recv) = conn.open_bi().await?;
+        send.write_all(b"hello").await?;
+        send.finish()?;
+        assert_eq!(recv.read_to_end(1000).await?, b"hello");
+
+        let infos = infos_rx.try_iter().collect::<Vec<_>>();
+        assert_eq!(infos.len(), 2);
+        assert!(!infos[0].remote_address_validated());
+        assert!(infos[1].remote_address_validated());
+        assert!(infos[1].remote_addr().is_some());
+        assert!(!infos[1].is_relay());
+
+        r1.shutdown().await?;
+        e2.close().await;
+
+        Ok(())
+    }
+
+    /// Echoes the negotiated version.
+    #[derive(Debug)]
+    struct VersionEcho;
+
+    impl VersionedProtocolHandler for VersionEcho {
+        fn accept(&self, connection: Connection, version: u32) -> BoxFuture<Result<()>> {
+            Box::pin(async move {
+                let mut send = connection.open_uni().await?;
+                send.write_all(&version.to_be_bytes()).await?;
+                send.finish()?;
+                connection.closed().await;
+                Ok(())
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_versioned() -> Result<()> {
+        let e1 = Endpoint::builder().bind().await?;
+        let e2 = Endpoint::builder().bind().await?;
+        let r1 = Router::builder(e1.clone())
+            .accept_versioned(
+                &VersionedAlpn::new("/iroh/version-echo", [3, 2, 1]),
+                VersionEcho,
+            )
+            .spawn();
+        let addr1 = r1.endpoint().node_addr().await?;
+
+        // the dialer's preference wins, unsupported versions are skipped
+        for (versions, expected) in [
+            (vec![1], 1),
+            (vec![1, 2], 1),
+            (vec![2, 3], 2),
+            (vec![4, 1], 1),
+        ] {
+            let alpn = VersionedAlpn::new("/iroh/version-echo", versions);
+            let (conn, version) = alpn.connect(&e2, addr1.clone()).await?;
+            assert_eq!(version, expected);
+            let mut recv = conn.accept_uni().await?;
+            let received = recv.read_to_end(4).await?;
+            assert_eq!(received, expected.to_be_bytes());
+            conn.close(0u32.into(), b"done");
+        }
+
+        let alpn = VersionedAlpn::new("/iroh/version-echo", [4]);
+        assert!(alpn.connect(&e2, addr1.clone()).await.is_err());
+
+        r1.shutdown().await?;
+        e2.close().await;
+
+        Ok(())
+    }
+
+    /// Counts how often it was shut down.
+    #[derive(Debug, Clone, Default)]
+    struct CountShutdown(Arc<std::sync::atomic::AtomicUsize>);
+
+    impl VersionedProtocolHandler for CountShutdown {
+        fn accept(&self, _connection: Connection, _version: u32) -> BoxFuture<Result<()>> {
+            Box::pin(async move { Ok(()) })
+        }
+
+        fn shutdown(&self) -> BoxFuture<()> {
+            self.0.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+            Box::pin(async move {})
+        }
+    }
+
+    #[tokio::test]
+    async fn test_versioned_shutdown() -> Result<()> {
+        let alpn = VersionedAlpn::new("/iroh/count-shutdown", [2, 1]);
+        // the handler is shut down once, also if the ALPN of its first version is replaced
+        for replace in [false, true] {
+            let handler = CountShutdown::default();
+            let mut builder = Router::builder(Endpoint::builder().bind().await?)
+                .accept_versioned(&alpn, handler.clone());
+            if replace {
+                builder = builder.accept(alpn.alpn(2), Echo);
+            }
+            builder.spawn().shutdown().await?;
+            assert_eq!(handler.0.load(std::sync::atomic::Ordering::Relaxed), 1);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_drain() -> Result<()> {
+        let e1 = Endpoint::builder().bind().await?;
+        let e2 = Endpoint::builder().bind().await?;
+        let e3 = Endpoint::builder().bind().await?;
+        let r1 = Router::builder(e1.clone()).accept(ECHO_ALPN, Echo).spawn();
+        let addr1 = r1.endpoint().node_addr().await?;
+
+        let conn = e2.connect(addr1.clone(), ECHO_ALPN).await?;
+        let (mut send, mut recv) = conn.open_bi().await?;
+        send.write_all(b"hello").await?;
+        send.finish()?;
+        assert_eq!(recv.read_to_end(1000).await?, b"hello");
+
+        let drain = tokio::spawn({
+            let r1 = r1.clone();
+            async move { r1.drain(Duration::from_secs(1)).await }
+        });
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(
+            r1.drain_state().get()?,
+            DrainState::Draining { connections: 1 }
+        );
+        assert!(e1.is_draining());
+
+        // new connections are refused while draining
+        assert!(e3.connect(addr1.clone(), ECHO_ALPN).await.is_err());
+
+        // the open connection is closed with the drain code after the timeout
+        let err = conn.closed().await;
+        let quinn::ConnectionError::ApplicationClosed(close) = err else {
+            panic!("unexpected close: {err:?}");
+        };
+        assert_eq!(close.error_code, crate::endpoint::DRAIN_CLOSE_CODE);
+        drain.await??;
+        assert!(r1.is_shutdown());
+        assert_eq!(r1.drain_state().get()?, DrainState::Closed);
+
+        e2.close().await;
+        e3.close().await;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_max_concurrent_handshakes() -> Result<()> {
+        let e1 = Endpoint::builder().bind().await?;
+        let e2 = Endpoint::builder().bind().await?;
+        let r1 = Router::builder(e1.clone())
+            .accept(ECHO_ALPN, Echo)
+            .max_concurrent_handshakes(0)
+            .spawn();
+        let addr1 = r1.endpoint().node_addr().await?;
+
+        let err = e2.connect(addr1, ECHO_ALPN).await.unwrap_err();
+        assert!(format!("{err:#}").contains("refused"), "{err:#}");
+
+        r1.shutdown().await?;
+        e2.close().await;
+
+        Ok(())
+    }
 }
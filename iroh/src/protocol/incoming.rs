@@ -0,0 +1,179 @@
+//! Decisions about incoming connections before their handshake.
+//!
+//! The [`Router`] consults an optional policy for every [`Incoming`] connection attempt,
+//! before any cryptographic work is done for it, and limits the number of concurrent
+//! handshakes.  Both are configured on the [`RouterBuilder`].
+//!
+//! [`Router`]: super::Router
+//! [`RouterBuilder`]: super::RouterBuilder
+use std::{
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+use tracing::{debug, warn};
+
+use crate::endpoint::{Incoming, NetworkPath};
+
+/// What the [`Router`] does with an incoming connection attempt.
+///
+/// [`Router`]: super::Router
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IncomingAction {
+    /// Accepts the connection attempt and starts the handshake.
+    Accept,
+    /// Refuses the connection attempt, sending a `CONNECTION_REFUSED` error to the remote.
+    Refuse,
+    /// Responds with a retry packet, requiring the remote to validate its address.
+    ///
+    /// If the address of the remote is already validated, the connection attempt is
+    /// accepted instead.
+    Retry,
+    /// Ignores the connection attempt, not sending any packet in response.
+    Ignore,
+}
+
+/// Information about an incoming connection attempt, available before its handshake.
+#[derive(Debug, Clone)]
+pub struct IncomingInfo {
+    path: Option<NetworkPath>,
+    remote_address_validated: bool,
+    handshakes: usize,
+    connections: usize,
+}
+
+impl IncomingInfo {
+    /// Returns the path the connection attempt was received on, if known.
+    ///
+    /// See [`Incoming::remote_path`].
+    pub fn path(&self) -> Option<&NetworkPath> {
+        self.path.as_ref()
+    }
+
+    /// Returns the UDP address of the remote, if the attempt was received directly.
+    pub fn remote_addr(&self) -> Option<SocketAddr> {
+        match self.path {
+            Some(NetworkPath::Direct(addr)) => Some(addr),
+            _ => None,
+        }
+    }
+
+    /// Returns whether the connection attempt was received via a relay server.
+    pub fn is_relay(&self) -> bool {
+        self.path.as_ref().is_some_and(NetworkPath::is_relay)
+    }
+
+    /// Returns whether the address of the remote has been validated.
+    ///
+    /// See [`Incoming::remote_address_validated`].
+    pub fn remote_address_validated(&self) -> bool {
+        self.remote_address_validated
+    }
+
+    /// Returns the number of handshakes in progress, not counting this connection attempt.
+    pub fn handshakes(&self) -> usize {
+        self.handshakes
+    }
+
+    /// Returns the number of connections currently handled by the protocol handlers.
+    pub fn connections(&self) -> usize {
+        self.connections
+    }
+}
+
+pub(super) type IncomingPolicyFn = dyn Fn(&IncomingInfo) -> IncomingAction + Send + Sync + 'static;
+
+/// Applies the incoming policy and handshake limit of a router, and counts handshakes and
+/// connections.
+#[derive(derive_more::Debug, Default)]
+pub(super) struct IncomingGate {
+    #[debug("{}", if policy.is_some() { "Some(policy)" } else { "None" })]
+    policy: Option<Arc<IncomingPolicyFn>>,
+    max_handshakes: Option<usize>,
+    handshakes: Arc<AtomicUsize>,
+    connections: Arc<AtomicUsize>,
+}
+
+impl IncomingGate {
+    pub(super) fn new(
+        policy: Option<Arc<IncomingPolicyFn>>,
+        max_handshakes: Option<usize>,
+    ) -> Self {
+        Self {
+            policy,
+            max_handshakes,
+            ..Default::default()
+        }
+    }
+
+    /// Decides about an incoming connection attempt.
+    ///
+    /// Returns the connection attempt and a guard counting its handshake if it is to be
+    /// accepted, otherwise it is refused, retried or ignored here.
+    pub(super) fn admit(&self, incoming: Incoming) -> Option<(Incoming, CountGuard)> {
+        let handshakes = self.handshakes.load(Ordering::Relaxed);
+        let action = if self.max_handshakes.is_some_and(|max| handshakes >= max) {
+            debug!(%handshakes, "Refusing connection: too many concurrent handshakes");
+            IncomingAction::Refuse
+        } else if let Some(policy) = &self.policy {
+            let info = IncomingInfo {
+                path: incoming.remote_path(),
+                remote_address_validated: incoming.remote_address_validated(),
+                handshakes,
+                connections: self.connections.load(Ordering::Relaxed),
+            };
+            let action = policy(&info);
+            if action != IncomingAction::Accept {
+                debug!(path = ?info.path, ?action, "Incoming connection not accepted by policy");
+            }
+            action
+        } else {
+            IncomingAction::Accept
+        };
+
+        match action {
+            IncomingAction::Accept => {}
+            IncomingAction::Retry if incoming.remote_address_validated() => {}
+            IncomingAction::Retry => {
+                if let Err(err) = incoming.retry() {
+                    warn!("Retrying connection failed: {err:#}");
+                }
+                return None;
+            }
+            IncomingAction::Refuse => {
+                incoming.refuse();
+                return None;
+            }
+            IncomingAction::Ignore => {
+                incoming.ignore();
+                return None;
+            }
+        }
+        Some((incoming, CountGuard::new(&self.handshakes)))
+    }
+
+    /// Counts a connection being handled by a protocol handler.
+    pub(super) fn connection(&self) -> CountGuard {
+        CountGuard::new(&self.connections)
+    }
+}
+
+/// Decrements a counter of an [`IncomingGate`] when dropped.
+#[derive(Debug)]
+pub(super) struct CountGuard(Arc<AtomicUsize>);
+
+impl CountGuard {
+    fn new(count: &Arc<AtomicUsize>) -> Self {
+        count.fetch_add(1, Ordering::Relaxed);
+        Self(count.clone())
+    }
+}
+
+impl Drop for CountGuard {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
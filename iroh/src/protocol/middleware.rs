@@ -0,0 +1,348 @@
+//! Middleware for protocol handlers.
+//!
+//! A [`ProtocolLayer`] wraps the [`ProtocolHandler`]s of a [`Router`] to add behavior that is
+//! shared between protocols, so that it does not need to be implemented in every
+//! [`ProtocolHandler::accept`]. Layers are added with [`RouterBuilder::layer`] for all
+//! protocols, or with [`RouterBuilder::layer_for`] for selected ALPNs.
+//!
+//! [`Router`]: super::Router
+//! [`RouterBuilder::layer`]: super::RouterBuilder::layer
+//! [`RouterBuilder::layer_for`]: super::RouterBuilder::layer_for
+use std::{
+    collections::{BTreeMap, HashMap},
+    future::Future,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::{bail, Result};
+use iroh_base::NodeId;
+use n0_future::{boxed::BoxFuture, time::Instant};
+use tracing::{debug, info_span, Instrument};
+
+use super::ProtocolHandler;
+use crate::endpoint::{Connecting, Connection};
+
+/// Wraps protocol handlers to add behavior to them.
+///
+/// A layer is applied to the handler of every ALPN it is added for, so any state that is
+/// shared between the wrapped handlers, like a connection count, lives in the layer.
+pub trait ProtocolLayer: Send + Sync + std::fmt::Debug + 'static {
+    /// Wraps the `handler` for `alpn`.
+    fn layer(&self, alpn: &[u8], handler: Arc<dyn ProtocolHandler>) -> Arc<dyn ProtocolHandler>;
+}
+
+type AuthorizeFn = dyn Fn(&Connection) -> BoxFuture<bool> + Send + Sync + 'static;
+
+/// Authorizes incoming connections with an async function.
+///
+/// The function is called for every connection once the handshake completed, and returns
+/// whether the connection is allowed. Refused connections are closed with an error code of `0`
+/// and reason `not allowed`, like with [`AccessLimit`](super::AccessLimit).
+#[derive(derive_more::Debug, Clone)]
+pub struct Authorize {
+    #[debug("authorize")]
+    authorize: Arc<AuthorizeFn>,
+}
+
+impl Authorize {
+    /// Creates a new `Authorize` layer.
+    ///
+    /// The connection can be used to look up the remote node id and the ALPN, the returned
+    /// future resolves to `true` if the connection is allowed.
+    pub fn new<F, Fut>(authorize: F) -> Self
+    where
+        F: Fn(&Connection) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = bool> + Send + 'static,
+    {
+        Self {
+            authorize: Arc::new(move |conn| Box::pin(authorize(conn))),
+        }
+    }
+}
+
+impl ProtocolLayer for Authorize {
+    fn layer(&self, _alpn: &[u8], handler: Arc<dyn ProtocolHandler>) -> Arc<dyn ProtocolHandler> {
+        Arc::new(Authorized {
+            handler,
+            authorize: self.authorize.clone(),
+        })
+    }
+}
+
+#[derive(derive_more::Debug)]
+struct Authorized {
+    handler: Arc<dyn ProtocolHandler>,
+    #[debug("authorize")]
+    authorize: Arc<AuthorizeFn>,
+}
+
+impl ProtocolHandler for Authorized {
+    fn on_connecting(&self, connecting: Connecting) -> BoxFuture<Result<Connection>> {
+        self.handler.on_connecting(connecting)
+    }
+
+    fn accept(&self, conn: Connection) -> BoxFuture<Result<()>> {
+        let handler = self.handler.clone();
+        let is_allowed = (self.authorize)(&conn);
+        Box::pin(async move {
+            if !is_allowed.await {
+                conn.close(0u32.into(), b"not allowed");
+                bail!("not allowed");
+            }
+            handler.accept(conn).await
+        })
+    }
+
+    fn shutdown(&self) -> BoxFuture<()> {
+        self.handler.shutdown()
+    }
+}
+
+/// Limits the number of concurrent connections per remote node.
+///
+/// The limit is shared between all ALPNs the layer is added for. Connections over the limit
+/// are closed with an error code of `0` and reason `too many connections`.
+#[derive(Debug, Clone)]
+pub struct ConnectionLimit {
+    max_per_node: usize,
+    active: Arc<Mutex<HashMap<NodeId, usize>>>,
+}
+
+impl ConnectionLimit {
+    /// Creates a new `ConnectionLimit` layer allowing `max_per_node` concurrent connections
+    /// per remote node.
+    pub fn new(max_per_node: usize) -> Self {
+        Self {
+            max_per_node,
+            active: Default::default(),
+        }
+    }
+
+    /// Returns the number of active connections of a remote node.
+    pub fn active(&self, node_id: &NodeId) -> usize {
+        let active = self.active.lock().expect("poisoned");
+        active.get(node_id).copied().unwrap_or_default()
+    }
+
+    /// Counts a connection of `node_id`, if it is within the limit.
+    fn acquire(&self, node_id: NodeId) -> Option<ConnectionGuard> {
+        let mut active = self.active.lock().expect("poisoned");
+        let count = active.entry(node_id).or_default();
+        if *count >= self.max_per_node {
+            if *count == 0 {
+                active.remove(&node_id);
+            }
+            return None;
+        }
+        *count += 1;
+        Some(ConnectionGuard {
+            limit: self.clone(),
+            node_id,
+        })
+    }
+}
+
+impl ProtocolLayer for ConnectionLimit {
+    fn layer(&self, _alpn: &[u8], handler: Arc<dyn ProtocolHandler>) -> Arc<dyn ProtocolHandler> {
+        Arc::new(ConnectionLimited {
+            handler,
+            limit: self.clone(),
+        })
+    }
+}
+
+/// Releases a connection of a [`ConnectionLimit`] when dropped.
+#[derive(Debug)]
+struct ConnectionGuard {
+    limit: ConnectionLimit,
+    node_id: NodeId,
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        let mut active = self.limit.active.lock().expect("poisoned");
+        if let Some(count) = active.get_mut(&self.node_id) {
+            *count -= 1;
+            if *count == 0 {
+                active.remove(&self.node_id);
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+struct ConnectionLimited {
+    handler: Arc<dyn ProtocolHandler>,
+    limit: ConnectionLimit,
+}
+
+impl ProtocolHandler for ConnectionLimited {
+    fn on_connecting(&self, connecting: Connecting) -> BoxFuture<Result<Connection>> {
+        self.handler.on_connecting(connecting)
+    }
+
+    fn accept(&self, conn: Connection) -> BoxFuture<Result<()>> {
+        let handler = self.handler.clone();
+        let limit = self.limit.clone();
+        Box::pin(async move {
+            let Some(_guard) = limit.acquire(conn.remote_node_id()?) else {
+                conn.close(0u32.into(), b"too many connections");
+                bail!("too many connections");
+            };
+            handler.accept(conn).await
+        })
+    }
+
+    fn shutdown(&self) -> BoxFuture<()> {
+        self.handler.shutdown()
+    }
+}
+
+/// Counts the connections of every ALPN.
+///
+/// The counts are shared between all clones, so a clone can be kept to read them after
+/// adding the layer to the router.
+#[derive(Debug, Clone, Default)]
+pub struct AcceptMetrics {
+    stats: Arc<Mutex<BTreeMap<Vec<u8>, AlpnStats>>>,
+}
+
+/// Connection counts of an ALPN, see [`AcceptMetrics`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct AlpnStats {
+    /// Number of connections handed to the handler.
+    pub accepted: u64,
+    /// Number of connections for which the handler returned an error.
+    pub failed: u64,
+    /// Number of connections currently being handled.
+    pub active: u64,
+}
+
+impl AcceptMetrics {
+    /// Creates a new `AcceptMetrics` layer.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the connection counts of an ALPN.
+    pub fn get(&self, alpn: &[u8]) -> AlpnStats {
+        let stats = self.stats.lock().expect("poisoned");
+        stats.get(alpn).copied().unwrap_or_default()
+    }
+
+    /// Returns the connection counts of all ALPNs that had connections.
+    pub fn all(&self) -> BTreeMap<Vec<u8>, AlpnStats> {
+        self.stats.lock().expect("poisoned").clone()
+    }
+
+    fn update(&self, alpn: &[u8], f: impl FnOnce(&mut AlpnStats)) {
+        let mut stats = self.stats.lock().expect("poisoned");
+        match stats.get_mut(alpn) {
+            Some(stats) => f(stats),
+            None => f(stats.entry(alpn.to_vec()).or_default()),
+        }
+    }
+}
+
+impl ProtocolLayer for AcceptMetrics {
+    fn layer(&self, alpn: &[u8], handler: Arc<dyn ProtocolHandler>) -> Arc<dyn ProtocolHandler> {
+        Arc::new(Metered {
+            handler,
+            alpn: alpn.into(),
+            metrics: self.clone(),
+        })
+    }
+}
+
+#[derive(Debug)]
+struct Metered {
+    handler: Arc<dyn ProtocolHandler>,
+    alpn: Arc<[u8]>,
+    metrics: AcceptMetrics,
+}
+
+impl ProtocolHandler for Metered {
+    fn on_connecting(&self, connecting: Connecting) -> BoxFuture<Result<Connection>> {
+        self.handler.on_connecting(connecting)
+    }
+
+    fn accept(&self, conn: Connection) -> BoxFuture<Result<()>> {
+        let handler = self.handler.clone();
+        let alpn = self.alpn.clone();
+        let metrics = self.metrics.clone();
+        metrics.update(&alpn, |stats| {
+            stats.accepted += 1;
+            stats.active += 1;
+        });
+        Box::pin(async move {
+            let res = handler.accept(conn).await;
+            metrics.update(&alpn, |stats| {
+                stats.active -= 1;
+                if res.is_err() {
+                    stats.failed += 1;
+                }
+            });
+            res
+        })
+    }
+
+    fn shutdown(&self) -> BoxFuture<()> {
+        self.handler.shutdown()
+    }
+}
+
+/// Traces connections with their ALPN, remote node and duration.
+///
+/// Every connection is handled in a `connection` span with the ALPN and the remote node id.
+/// When the handler returns, the duration of the connection is logged at debug level.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ConnectionTracing;
+
+impl ProtocolLayer for ConnectionTracing {
+    fn layer(&self, alpn: &[u8], handler: Arc<dyn ProtocolHandler>) -> Arc<dyn ProtocolHandler> {
+        Arc::new(Traced {
+            handler,
+            alpn: String::from_utf8_lossy(alpn).into(),
+        })
+    }
+}
+
+#[derive(Debug)]
+struct Traced {
+    handler: Arc<dyn ProtocolHandler>,
+    alpn: Arc<str>,
+}
+
+impl ProtocolHandler for Traced {
+    fn on_connecting(&self, connecting: Connecting) -> BoxFuture<Result<Connection>> {
+        self.handler.on_connecting(connecting)
+    }
+
+    fn accept(&self, conn: Connection) -> BoxFuture<Result<()>> {
+        let remote = match conn.remote_node_id() {
+            Ok(node_id) => node_id.fmt_short(),
+            Err(_) => "unknown".to_string(),
+        };
+        let span = info_span!("connection", alpn = %self.alpn, %remote);
+        let handler = self.handler.clone();
+        Box::pin(
+            async move {
+                let start = Instant::now();
+                debug!("connection accepted");
+                let res = handler.accept(conn).await;
+                match &res {
+                    Ok(()) => debug!(duration = ?start.elapsed(), "connection closed"),
+                    Err(err) => {
+                        debug!(duration = ?start.elapsed(), "connection failed: {err:#}")
+                    }
+                }
+                res
+            }
+            .instrument(span),
+        )
+    }
+
+    fn shutdown(&self) -> BoxFuture<()> {
+        self.handler.shutdown()
+    }
+}
@@ -0,0 +1,226 @@
+//! Protocols with several versions, each identified by its own ALPN.
+//!
+//! A [`VersionedAlpn`] describes the versions of a protocol.  On the accepting side a single
+//! [`VersionedProtocolHandler`] is registered for all of them with
+//! [`RouterBuilder::accept_versioned`], on the connecting side [`VersionedAlpn::connect`]
+//! tries them in order of preference and returns the version it connected with.
+//!
+//! [`RouterBuilder::accept_versioned`]: super::RouterBuilder::accept_versioned
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+
+use anyhow::{bail, Context, Result};
+use iroh_base::NodeAddr;
+use n0_future::boxed::BoxFuture;
+use tracing::debug;
+
+use super::ProtocolHandler;
+use crate::{
+    endpoint::{Connecting, Connection, ConnectionError, TransportErrorCode},
+    Endpoint,
+};
+
+/// The ALPNs of a protocol with several versions.
+///
+/// Version `v` of the protocol is identified by the ALPN `<prefix>/<v>`, e.g. `myproto/2`
+/// for the prefix `myproto`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VersionedAlpn {
+    prefix: Vec<u8>,
+    versions: Vec<u32>,
+}
+
+impl VersionedAlpn {
+    /// Creates the ALPNs of a protocol supporting `versions`, in order of preference.
+    ///
+    /// Usually the newest version is listed first.  Duplicate versions are ignored.
+    pub fn new(prefix: impl AsRef<[u8]>, versions: impl IntoIterator<Item = u32>) -> Self {
+        let mut unique = Vec::new();
+        for version in versions {
+            if !unique.contains(&version) {
+                unique.push(version);
+            }
+        }
+        Self {
+            prefix: prefix.as_ref().to_vec(),
+            versions: unique,
+        }
+    }
+
+    /// Returns the prefix of the ALPNs.
+    pub fn prefix(&self) -> &[u8] {
+        &self.prefix
+    }
+
+    /// Returns the supported versions, in order of preference.
+    pub fn versions(&self) -> &[u32] {
+        &self.versions
+    }
+
+    /// Returns the ALPN of a version.
+    pub fn alpn(&self, version: u32) -> Vec<u8> {
+        let mut alpn = self.prefix.clone();
+        alpn.push(b'/');
+        alpn.extend_from_slice(version.to_string().as_bytes());
+        alpn
+    }
+
+    /// Returns the ALPNs of all supported versions, in order of preference.
+    pub fn alpns(&self) -> impl Iterator<Item = Vec<u8>> + '_ {
+        self.versions.iter().map(|version| self.alpn(*version))
+    }
+
+    /// Returns the version an ALPN identifies, if it is one of the supported versions.
+    pub fn version(&self, alpn: &[u8]) -> Option<u32> {
+        let version = alpn
+            .strip_prefix(self.prefix.as_slice())?
+            .strip_prefix(b"/")?;
+        let version = std::str::from_utf8(version).ok()?.parse().ok()?;
+        self.versions.contains(&version).then_some(version)
+    }
+
+    /// Connects to a remote node with the most preferred version it supports.
+    ///
+    /// The versions are tried one after another in order of preference, the next version is
+    /// only tried if the remote does not support the ALPN of the previous one.  Any other
+    /// error is returned right away.
+    ///
+    /// Returns the connection and the version it was established with.
+    pub async fn connect(
+        &self,
+        endpoint: &Endpoint,
+        node_addr: impl Into<NodeAddr>,
+    ) -> Result<(Connection, u32)> {
+        if self.versions.is_empty() {
+            bail!("no protocol versions to connect with");
+        }
+        let node_addr = node_addr.into();
+        for version in &self.versions {
+            let connecting = endpoint
+                .connect_with_opts(node_addr.clone(), &self.alpn(*version), Default::default())
+                .await?;
+            match connecting.await {
+                Ok(conn) => return Ok((conn, *version)),
+                Err(err) if is_alpn_mismatch(&err) => {
+                    debug!(version, "remote does not support protocol version");
+                }
+                Err(err) => return Err(err).context("failed connecting to remote endpoint"),
+            }
+        }
+        bail!("remote supports none of the protocol versions")
+    }
+}
+
+/// Returns whether a handshake failed because the remote does not support the offered ALPN.
+fn is_alpn_mismatch(err: &ConnectionError) -> bool {
+    // the TLS `no_application_protocol` alert
+    matches!(
+        err,
+        ConnectionError::ConnectionClosed(close) if close.error_code == TransportErrorCode::crypto(120)
+    )
+}
+
+/// Handler for incoming connections of a protocol with several versions.
+///
+/// Like a [`ProtocolHandler`], but registered for all versions of a [`VersionedAlpn`] with
+/// [`RouterBuilder::accept_versioned`], and told which version a connection negotiated.
+///
+/// [`RouterBuilder::accept_versioned`]: super::RouterBuilder::accept_versioned
+pub trait VersionedProtocolHandler: Send + Sync + std::fmt::Debug + 'static {
+    /// Optional interception point to handle the `Connecting` state.
+    ///
+    /// See [`ProtocolHandler::on_connecting`].
+    fn on_connecting(&self, connecting: Connecting, version: u32) -> BoxFuture<Result<Connection>> {
+        let _ = version;
+        Box::pin(async move {
+            let conn = connecting.await?;
+            Ok(conn)
+        })
+    }
+
+    /// Handle an incoming connection which negotiated `version` of the protocol.
+    ///
+    /// This runs on a freshly spawned tokio task so this can be long-running.
+    fn accept(&self, connection: Connection, version: u32) -> BoxFuture<Result<()>>;
+
+    /// Called when the node shuts down.
+    ///
+    /// This is called once, not for every version.
+    fn shutdown(&self) -> BoxFuture<()> {
+        Box::pin(async move {})
+    }
+}
+
+/// The [`ProtocolHandler`] for one version of a [`VersionedProtocolHandler`].
+#[derive(Debug)]
+pub(super) struct Versioned<T> {
+    handler: Arc<T>,
+    version: u32,
+    /// Whether [`ProtocolHandler::shutdown`] was forwarded to `handler`.
+    ///
+    /// Shared by the handlers of all versions, so that the handler is shut down once even if
+    /// some of the versions were replaced by other protocol handlers.
+    shutdown: Arc<AtomicBool>,
+}
+
+impl<T: VersionedProtocolHandler> Versioned<T> {
+    /// Returns a protocol handler for every version of `alpn`, with their ALPN.
+    pub(super) fn handlers(
+        alpn: &VersionedAlpn,
+        handler: T,
+    ) -> impl Iterator<Item = (Vec<u8>, Arc<dyn ProtocolHandler>)> + '_ {
+        let handler = Arc::new(handler);
+        let shutdown = Arc::new(AtomicBool::new(false));
+        alpn.versions().iter().map(move |version| {
+            let versioned = Versioned {
+                handler: handler.clone(),
+                version: *version,
+                shutdown: shutdown.clone(),
+            };
+            (
+                alpn.alpn(*version),
+                Arc::new(versioned) as Arc<dyn ProtocolHandler>,
+            )
+        })
+    }
+}
+
+impl<T: VersionedProtocolHandler> ProtocolHandler for Versioned<T> {
+    fn on_connecting(&self, connecting: Connecting) -> BoxFuture<Result<Connection>> {
+        self.handler.on_connecting(connecting, self.version)
+    }
+
+    fn accept(&self, connection: Connection) -> BoxFuture<Result<()>> {
+        self.handler.accept(connection, self.version)
+    }
+
+    fn shutdown(&self) -> BoxFuture<()> {
+        if self.shutdown.swap(true, Ordering::Relaxed) {
+            Box::pin(async move {})
+        } else {
+            self.handler.shutdown()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn versioned_alpn() {
+        let alpn = VersionedAlpn::new("myproto", [2, 1, 2]);
+        assert_eq!(alpn.versions(), &[2, 1]);
+        assert_eq!(
+            alpn.alpns().collect::<Vec<_>>(),
+            vec![b"myproto/2".to_vec(), b"myproto/1".to_vec()]
+        );
+        assert_eq!(alpn.version(b"myproto/1"), Some(1));
+        assert_eq!(alpn.version(b"myproto/3"), None);
+        assert_eq!(alpn.version(b"myproto/x"), None);
+        assert_eq!(alpn.version(b"myproto2/1"), None);
+        assert_eq!(alpn.version(b"other/1"), None);
+    }
+}
@@ -89,6 +89,7 @@ pub async fn run_relay_server_with(
             limits: Default::default(),
             key_cache_capacity: Some(1024),
             access: AccessConfig::Everyone,
+            ..Default::default()
         }),
         quic,
         stun,
@@ -12,8 +12,10 @@ use iroh_base::SecretKey;
 use quinn::crypto::rustls::{QuicClientConfig, QuicServerConfig};
 use tracing::warn;
 
+pub use self::authorization::{AsyncAuthorization, AuthorizedNodes};
 use self::resolver::AlwaysResolvesCert;
 
+mod authorization;
 pub(crate) mod certificate;
 pub(crate) mod name;
 mod resolver;
@@ -54,6 +56,11 @@ pub(crate) struct TlsConfig {
     server_verifier: Arc<verifier::ServerCertificateVerifier>,
     client_verifier: Arc<verifier::ClientCertificateVerifier>,
     session_store: Arc<dyn rustls::client::ClientSessionStore>,
+    /// The nodes handshakes are completed with, all nodes if `None`.
+    ///
+    /// Resumed sessions skip the certificate verification, so resumption and with it 0-RTT
+    /// is disabled if this is set.
+    authorized: Option<AuthorizedNodes>,
 }
 
 impl TlsConfig {
@@ -65,14 +72,34 @@ impl TlsConfig {
             auth,
             secret_key,
             cert_resolver,
-            server_verifier: Arc::new(verifier::ServerCertificateVerifier::new(auth)),
-            client_verifier: Arc::new(verifier::ClientCertificateVerifier::new(auth)),
+            server_verifier: Arc::new(verifier::ServerCertificateVerifier::new(auth, None)),
+            client_verifier: Arc::new(verifier::ClientCertificateVerifier::new(auth, None)),
             session_store: Arc::new(rustls::client::ClientSessionMemoryCache::new(
                 MAX_TLS_TICKETS,
             )),
+            authorized: None,
         }
     }
 
+    /// Only completes handshakes with authorized nodes, both as client and as server.
+    pub(crate) fn with_authorized_nodes(mut self, authorized: AuthorizedNodes) -> Self {
+        self.server_verifier = Arc::new(verifier::ServerCertificateVerifier::new(
+            self.auth,
+            Some(authorized.clone()),
+        ));
+        self.client_verifier = Arc::new(verifier::ClientCertificateVerifier::new(
+            self.auth,
+            Some(authorized.clone()),
+        ));
+        self.authorized = Some(authorized);
+        self
+    }
+
+    /// Returns the nodes handshakes are completed with, if not all nodes.
+    pub(crate) fn authorized_nodes(&self) -> Option<&AuthorizedNodes> {
+        self.authorized.as_ref()
+    }
+
     /// Create a TLS client configuration.
     ///
     /// If *keylog* is `true` this will enable logging of the pre-master key to the file in the
@@ -94,8 +121,12 @@ impl TlsConfig {
         crypto.alpn_protocols = alpn_protocols;
 
         // TODO: enable/disable 0-RTT/storing tickets
-        crypto.resumption = rustls::client::Resumption::store(self.session_store.clone());
-        crypto.enable_early_data = true;
+        if self.authorized.is_some() {
+            crypto.resumption = rustls::client::Resumption::disabled();
+        } else {
+            crypto.resumption = rustls::client::Resumption::store(self.session_store.clone());
+            crypto.enable_early_data = true;
+        }
 
         if keylog {
             warn!("enabling SSLKEYLOGFILE for TLS pre-master keys");
@@ -130,9 +161,14 @@ impl TlsConfig {
             crypto.key_log = Arc::new(rustls::KeyLogFile::new());
         }
 
-        // must be u32::MAX or 0 (the default). Any other value panics with QUIC
-        // This is specified in RFC 9001: https://www.rfc-editor.org/rfc/rfc9001#section-4.6.1
-        crypto.max_early_data_size = u32::MAX;
+        if self.authorized.is_some() {
+            crypto.session_storage = Arc::new(rustls::server::NoServerSessionStorage {});
+            crypto.send_tls13_tickets = 0;
+        } else {
+            // must be u32::MAX or 0 (the default). Any other value panics with QUIC
+            // This is specified in RFC 9001: https://www.rfc-editor.org/rfc/rfc9001#section-4.6.1
+            crypto.max_early_data_size = u32::MAX;
+        }
 
         crypto
             .try_into()
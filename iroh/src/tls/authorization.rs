@@ -0,0 +1,226 @@
+//! Authorization of remote nodes during the TLS handshake.
+
+use std::{
+    collections::{BTreeSet, HashMap},
+    future::Future,
+    sync::{Arc, Mutex},
+};
+
+use iroh_base::NodeId;
+use n0_future::{
+    boxed::BoxFuture,
+    task,
+    time::{Duration, Instant},
+};
+
+/// Maximum number of nodes for which the result of an async authorization function is cached
+const MAX_CACHED_AUTHORIZATIONS: usize = 4096;
+
+/// The nodes an [`Endpoint`] completes TLS handshakes with.
+///
+/// Authentication of remote nodes always happens in the TLS handshake: a node has to prove
+/// that it owns the secret key of its [`NodeId`].  By default any authenticated node can
+/// connect, and it is up to the application to decide what it is allowed to do.  With
+/// [`Builder::authorized_nodes`] the handshake additionally fails for nodes that are not
+/// authorized, so they never get a connection at all.  This applies to incoming as well as
+/// outgoing connections.
+///
+/// Nodes are authorized by a static set, a function, or an async function.  The check runs
+/// inside the certificate verification of the handshake, which can not wait for an async
+/// function, so its results are cached, see [`AuthorizedNodes::from_async_fn`].
+///
+/// [`Endpoint`]: crate::Endpoint
+/// [`Builder::authorized_nodes`]: crate::endpoint::Builder::authorized_nodes
+#[derive(Clone, derive_more::Debug)]
+pub enum AuthorizedNodes {
+    /// Only the nodes in the set are authorized.
+    Set(Arc<BTreeSet<NodeId>>),
+    /// The nodes for which the function returns `true` are authorized.
+    #[debug("Fn")]
+    Fn(Arc<dyn Fn(&NodeId) -> bool + Send + Sync + 'static>),
+    /// The nodes for which the async function returned `true` are authorized.
+    Async(Arc<AsyncAuthorization>),
+}
+
+impl AuthorizedNodes {
+    /// Authorizes a fixed set of nodes.
+    pub fn from_nodes(nodes: impl IntoIterator<Item = NodeId>) -> Self {
+        Self::Set(Arc::new(nodes.into_iter().collect()))
+    }
+
+    /// Authorizes the nodes for which `f` returns `true`.
+    ///
+    /// The function is called in every handshake, so it should return quickly.
+    pub fn from_fn<F>(f: F) -> Self
+    where
+        F: Fn(&NodeId) -> bool + Send + Sync + 'static,
+    {
+        Self::Fn(Arc::new(f))
+    }
+
+    /// Authorizes the nodes for which the async function `f` returns `true`.
+    ///
+    /// The result of `f` is cached for `cache_ttl`, and handshakes use the cached result:
+    ///
+    /// - Before connecting to a node, the [`Endpoint`] waits for `f` unless a result is
+    ///   cached.
+    /// - When accepting a connection, `f` is started for the node the connection attempt
+    ///   most likely comes from, so its result is usually cached by the time the handshake
+    ///   verifies the node.
+    /// - Handshakes with nodes without a cached result fail, and start `f` for the node, so a
+    ///   later attempt of the node succeeds if it is authorized.
+    /// - Handshakes with nodes with an expired result use that result, and start `f` to
+    ///   refresh it.
+    ///
+    /// Use [`AuthorizedNodes::authorize`] to cache the result for a node before it connects.
+    ///
+    /// [`Endpoint`]: crate::Endpoint
+    pub fn from_async_fn<F, Fut>(cache_ttl: Duration, f: F) -> Self
+    where
+        F: Fn(NodeId) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = bool> + Send + 'static,
+    {
+        Self::Async(Arc::new(AsyncAuthorization {
+            f: Box::new(move |node_id| Box::pin(f(node_id))),
+            cache_ttl,
+            cache: Default::default(),
+        }))
+    }
+
+    /// Returns whether a node is authorized.
+    ///
+    /// For [`AuthorizedNodes::from_async_fn`] this returns the cached result, and starts the
+    /// async function if no result or only an expired one is cached.
+    pub fn is_authorized(&self, node_id: &NodeId) -> bool {
+        match self {
+            Self::Set(nodes) => nodes.contains(node_id),
+            Self::Fn(f) => f(node_id),
+            Self::Async(authorization) => authorization.cached(node_id),
+        }
+    }
+
+    /// Returns whether a node is authorized, waiting for the async function if needed.
+    ///
+    /// For [`AuthorizedNodes::from_async_fn`] this runs the async function unless a result is
+    /// cached that has not expired yet, and caches its result.
+    pub async fn authorize(&self, node_id: NodeId) -> bool {
+        match self {
+            Self::Async(authorization) => authorization.authorize(node_id).await,
+            _ => self.is_authorized(&node_id),
+        }
+    }
+}
+
+impl FromIterator<NodeId> for AuthorizedNodes {
+    fn from_iter<T: IntoIterator<Item = NodeId>>(iter: T) -> Self {
+        Self::from_nodes(iter)
+    }
+}
+
+/// An async authorization function with its cached results.
+///
+/// See [`AuthorizedNodes::from_async_fn`].
+#[derive(derive_more::Debug)]
+pub struct AsyncAuthorization {
+    #[debug("Fn")]
+    f: Box<dyn Fn(NodeId) -> BoxFuture<bool> + Send + Sync + 'static>,
+    cache_ttl: Duration,
+    #[debug("{} cached", cache.lock().map(|cache| cache.len()).unwrap_or_default())]
+    cache: Mutex<HashMap<NodeId, CachedAuthorization>>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct CachedAuthorization {
+    /// The last result of the function, `None` until it returned for the first time
+    authorized: Option<bool>,
+    /// When `authorized` was set
+    updated: Instant,
+    /// Whether the function is running for the node
+    refreshing: bool,
+}
+
+impl AsyncAuthorization {
+    /// Returns the cached result for a node, and refreshes it if it is missing or expired.
+    fn cached(self: &Arc<Self>, node_id: &NodeId) -> bool {
+        let now = Instant::now();
+        let mut cache = self.cache.lock().expect("poisoned");
+        let (authorized, refresh) = match cache.get_mut(node_id) {
+            Some(entry) => {
+                let refresh = !entry.refreshing && !self.is_fresh(entry, now);
+                entry.refreshing |= refresh;
+                (entry.authorized.unwrap_or(false), refresh)
+            }
+            None => {
+                let entry = CachedAuthorization {
+                    authorized: None,
+                    updated: now,
+                    refreshing: true,
+                };
+                (false, self.insert(&mut cache, *node_id, entry, now))
+            }
+        };
+        drop(cache);
+        if refresh {
+            let this = self.clone();
+            let node_id = *node_id;
+            task::spawn(async move {
+                this.refresh(node_id).await;
+            });
+        }
+        authorized
+    }
+
+    /// Returns the cached result for a node if it has not expired, or refreshes it.
+    async fn authorize(&self, node_id: NodeId) -> bool {
+        let now = Instant::now();
+        let cached = self
+            .cache
+            .lock()
+            .expect("poisoned")
+            .get(&node_id)
+            .filter(|entry| self.is_fresh(entry, now))
+            .and_then(|entry| entry.authorized);
+        match cached {
+            Some(authorized) => authorized,
+            None => self.refresh(node_id).await,
+        }
+    }
+
+    /// Runs the function for a node and caches its result.
+    async fn refresh(&self, node_id: NodeId) -> bool {
+        let authorized = (self.f)(node_id).await;
+        let now = Instant::now();
+        let entry = CachedAuthorization {
+            authorized: Some(authorized),
+            updated: now,
+            refreshing: false,
+        };
+        let mut cache = self.cache.lock().expect("poisoned");
+        self.insert(&mut cache, node_id, entry, now);
+        authorized
+    }
+
+    fn is_fresh(&self, entry: &CachedAuthorization, now: Instant) -> bool {
+        entry.authorized.is_some() && now.duration_since(entry.updated) < self.cache_ttl
+    }
+
+    /// Inserts an entry, dropping expired entries if the cache is full.
+    ///
+    /// Returns `false` if the cache is still full, the entry is not inserted then.
+    fn insert(
+        &self,
+        cache: &mut HashMap<NodeId, CachedAuthorization>,
+        node_id: NodeId,
+        entry: CachedAuthorization,
+        now: Instant,
+    ) -> bool {
+        if cache.len() >= MAX_CACHED_AUTHORIZATIONS && !cache.contains_key(&node_id) {
+            cache.retain(|_, entry| entry.refreshing || self.is_fresh(entry, now));
+            if cache.len() >= MAX_CACHED_AUTHORIZATIONS {
+                return false;
+            }
+        }
+        cache.insert(node_id, entry);
+        true
+    }
+}
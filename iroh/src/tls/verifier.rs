@@ -9,7 +9,10 @@
 
 use std::sync::Arc;
 
-use ed25519_dalek::pkcs8::EncodePublicKey;
+use ed25519_dalek::{
+    pkcs8::{DecodePublicKey, EncodePublicKey},
+    VerifyingKey,
+};
 use iroh_base::PublicKey;
 use rustls::{
     client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
@@ -19,9 +22,10 @@ use rustls::{
     CertificateError, DigitallySignedStruct, DistinguishedName, OtherError, PeerMisbehaved,
     SignatureScheme, SupportedProtocolVersion,
 };
+use tracing::debug;
 use webpki::{ring as webpki_algs, types::SubjectPublicKeyInfoDer};
 
-use super::{certificate, Authentication};
+use super::{certificate, Authentication, AuthorizedNodes};
 
 /// The only TLS version we support is 1.3
 pub(super) static PROTOCOL_VERSIONS: &[&SupportedProtocolVersion] = &[&rustls::version::TLS13];
@@ -61,6 +65,8 @@ static SUPPORTED_SIG_ALGS: WebPkiSupportedAlgorithms = WebPkiSupportedAlgorithms
 pub(super) struct ServerCertificateVerifier {
     /// Which TLS authentication mode to operate in.
     auth: Authentication,
+    /// The servers we complete handshakes with, all if `None`.
+    authorized: Option<AuthorizedNodes>,
 }
 
 /// We require the following
@@ -72,8 +78,8 @@ pub(super) struct ServerCertificateVerifier {
 ///
 /// or a raw public key.
 impl ServerCertificateVerifier {
-    pub(super) fn new(auth: Authentication) -> Self {
-        Self { auth }
+    pub(super) fn new(auth: Authentication, authorized: Option<AuthorizedNodes>) -> Self {
+        Self { auth, authorized }
     }
 }
 
@@ -116,6 +122,7 @@ impl ServerCertVerifier for ServerCertificateVerifier {
                         PeerMisbehaved::BadCertChainExtensions,
                     ));
                 }
+                check_authorized(self.authorized.as_ref(), &peer_id)?;
 
                 Ok(ServerCertVerified::assertion())
             }
@@ -139,6 +146,7 @@ impl ServerCertVerifier for ServerCertificateVerifier {
                         CertificateError::UnknownIssuer,
                     ));
                 }
+                check_authorized(self.authorized.as_ref(), &remote_peer_id)?;
 
                 Ok(ServerCertVerified::assertion())
             }
@@ -191,6 +199,8 @@ impl ServerCertVerifier for ServerCertificateVerifier {
 pub(super) struct ClientCertificateVerifier {
     /// Which TLS authentication mode to operate in.
     auth: Authentication,
+    /// The clients we complete handshakes with, all if `None`.
+    authorized: Option<AuthorizedNodes>,
 }
 
 /// We require the following
@@ -202,8 +212,8 @@ pub(super) struct ClientCertificateVerifier {
 ///
 /// or a raw public key.
 impl ClientCertificateVerifier {
-    pub(super) fn new(auth: Authentication) -> Self {
-        Self { auth }
+    pub(super) fn new(auth: Authentication, authorized: Option<AuthorizedNodes>) -> Self {
+        Self { auth, authorized }
     }
 }
 
@@ -229,7 +239,8 @@ impl ClientCertVerifier for ClientCertificateVerifier {
     ) -> Result<ClientCertVerified, rustls::Error> {
         match self.auth {
             Authentication::X509 => {
-                verify_presented_certs(end_entity, intermediates)?;
+                let peer_id = verify_presented_certs(end_entity, intermediates)?;
+                check_authorized(self.authorized.as_ref(), &peer_id)?;
                 Ok(ClientCertVerified::assertion())
             }
             Authentication::RawPublicKey => {
@@ -238,6 +249,15 @@ impl ClientCertVerifier for ClientCertificateVerifier {
                         CertificateError::UnknownIssuer,
                     ));
                 }
+                if let Some(authorized) = &self.authorized {
+                    // The key is otherwise only parsed when verifying the handshake signature.
+                    let peer_id = VerifyingKey::from_public_key_der(end_entity)
+                        .map_err(|_| {
+                            rustls::Error::InvalidCertificate(CertificateError::BadEncoding)
+                        })?
+                        .into();
+                    check_authorized(Some(authorized), &peer_id)?;
+                }
 
                 Ok(ClientCertVerified::assertion())
             }
@@ -287,6 +307,22 @@ impl ClientCertVerifier for ClientCertificateVerifier {
     }
 }
 
+/// Fails the handshake if the peer is not authorized.
+fn check_authorized(
+    authorized: Option<&AuthorizedNodes>,
+    peer_id: &PublicKey,
+) -> Result<(), rustls::Error> {
+    match authorized {
+        Some(authorized) if !authorized.is_authorized(peer_id) => {
+            debug!(peer = %peer_id.fmt_short(), "Rejecting handshake: node not authorized");
+            Err(rustls::Error::InvalidCertificate(
+                CertificateError::ApplicationVerificationFailure,
+            ))
+        }
+        _ => Ok(()),
+    }
+}
+
 /// When receiving the certificate chain, an endpoint
 /// MUST check these conditions and abort the connection attempt if
 /// (a) the presented certificate is not yet valid, OR